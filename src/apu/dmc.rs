@@ -0,0 +1,248 @@
+use anyhow::Result;
+
+use crate::mem::Address;
+
+use super::ChannelState;
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// Timer periods (in CPU cycles) for each of the DMC's 16 rate settings on
+/// NTSC consoles.
+#[rustfmt::skip]
+static NTSC_RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+/// Timer periods for PAL consoles.
+#[rustfmt::skip]
+static PAL_RATE_TABLE: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+/// Sample addresses are specified in units of 64 bytes starting from $C000.
+const SAMPLE_BASE_ADDR: u16 = 0xC000;
+
+/// The delta modulation channel (DMC), which plays 1-bit delta-encoded samples
+/// read directly from CPU memory. Each bit of the sample either increments or
+/// decrements a 7-bit output level by 2.
+///
+/// Since the DMC needs to read from the CPU's address space, it cannot fetch
+/// sample bytes itself. Instead, it exposes the address of the next byte it
+/// needs via `pending_read`, and the owner of the memory bus is responsible
+/// for supplying that byte via `fill_buffer`.
+#[derive(Clone)]
+pub struct Dmc {
+    rate_table: &'static [u16; 16],
+    irq_enabled: bool,
+    irq_flag: bool,
+    loop_flag: bool,
+    timer_period: u16,
+    timer: u16,
+
+    // Memory reader.
+    sample_addr: Address,
+    sample_length: u16,
+    current_addr: Address,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+
+    // Output unit.
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    output_level: u8,
+}
+
+impl Dmc {
+    pub fn new(region: Region) -> Self {
+        let rate_table = match region {
+            Region::Ntsc => &NTSC_RATE_TABLE,
+            Region::Pal => &PAL_RATE_TABLE,
+        };
+        Self {
+            rate_table,
+            irq_enabled: false,
+            irq_flag: false,
+            loop_flag: false,
+            timer_period: rate_table[0],
+            timer: 0,
+            sample_addr: Address(SAMPLE_BASE_ADDR),
+            sample_length: 1,
+            current_addr: Address(SAMPLE_BASE_ADDR),
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            output_level: 0,
+        }
+    }
+
+    /// $4010: IL-- RRRR (IRQ enable, loop, rate index).
+    pub fn write_control(&mut self, value: u8) {
+        self.irq_enabled = value & 0x80 > 0;
+        if !self.irq_enabled {
+            self.irq_flag = false;
+        }
+        self.loop_flag = value & 0x40 > 0;
+        self.timer_period = self.rate_table[(value & 0x0F) as usize];
+    }
+
+    /// $4011: -DDD DDDD (direct load of the output level).
+    pub fn write_output_level(&mut self, value: u8) {
+        self.output_level = value & 0x7F;
+    }
+
+    /// $4012: AAAA AAAA (sample address = $C000 + A * 64).
+    pub fn write_sample_addr(&mut self, value: u8) {
+        self.sample_addr = Address(SAMPLE_BASE_ADDR + value as u16 * 64);
+    }
+
+    /// $4013: LLLL LLLL (sample length = L * 16 + 1 bytes).
+    pub fn write_sample_length(&mut self, value: u8) {
+        self.sample_length = value as u16 * 16 + 1;
+    }
+
+    /// Enable or disable sample playback via $4015. Enabling playback only
+    /// restarts the sample if the previous one has finished. Any write to
+    /// $4015 acknowledges the DMC interrupt.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    /// Whether there are sample bytes remaining (reported via $4015).
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    /// Whether the DMC interrupt flag is set (reported via $4015). The flag is
+    /// set when a non-looping sample finishes with IRQs enabled.
+    pub fn irq_flag(&self) -> bool {
+        self.irq_flag
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// If the sample buffer is empty and there are bytes remaining in the
+    /// current sample, return the address of the next byte to fetch.
+    pub fn pending_read(&self) -> Option<Address> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_addr)
+        } else {
+            None
+        }
+    }
+
+    /// Supply the byte requested by `pending_read`.
+    pub fn fill_buffer(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+
+        // The address wraps around to $8000 rather than $0000.
+        self.current_addr = if self.current_addr == Address(0xFFFF) {
+            Address(0x8000)
+        } else {
+            self.current_addr + 1u16
+        };
+
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    /// Clock the channel's timer. Called on every CPU cycle.
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        if !self.silence {
+            if self.shift_register & 1 > 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(byte) => {
+                    self.silence = false;
+                    self.shift_register = byte;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    /// The DMC's output level is reported as its volume, and the number of
+    /// sample bytes remaining as its length.
+    pub fn state(&self) -> ChannelState {
+        ChannelState {
+            period: self.timer_period,
+            volume: self.output_level,
+            length: self.bytes_remaining,
+        }
+    }
+
+    /// Current 7-bit output level.
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+impl Savestate for Dmc {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.irq_enabled);
+        w.write(&self.irq_flag);
+        w.write(&self.loop_flag);
+        w.write(&self.timer_period);
+        w.write(&self.timer);
+        w.write(&self.sample_addr);
+        w.write(&self.sample_length);
+        w.write(&self.current_addr);
+        w.write(&self.bytes_remaining);
+        w.write(&self.sample_buffer);
+        w.write(&self.shift_register);
+        w.write(&self.bits_remaining);
+        w.write(&self.silence);
+        w.write(&self.output_level);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.irq_enabled = r.read()?;
+        self.irq_flag = r.read()?;
+        self.loop_flag = r.read()?;
+        self.timer_period = r.read()?;
+        self.timer = r.read()?;
+        self.sample_addr = r.read()?;
+        self.sample_length = r.read()?;
+        self.current_addr = r.read()?;
+        self.bytes_remaining = r.read()?;
+        self.sample_buffer = r.read()?;
+        self.shift_register = r.read()?;
+        self.bits_remaining = r.read()?;
+        self.silence = r.read()?;
+        self.output_level = r.read()?;
+        Ok(())
+    }
+}
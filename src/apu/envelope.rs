@@ -0,0 +1,82 @@
+use anyhow::Result;
+
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// Volume envelope generator used by the pulse and noise channels.
+///
+/// The envelope can either output a constant volume or a decaying "sawtooth"
+/// volume that starts at 15 and decreases by one every time the divider
+/// expires. The divider's period is set by the same 4 bits that specify the
+/// constant volume.
+#[derive(Clone, Default)]
+pub struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    /// Update the envelope parameters from the low 6 bits of the channel's
+    /// control register (--LC VVVV).
+    pub fn write_control(&mut self, value: u8) {
+        self.looping = value & 0x20 > 0;
+        self.constant = value & 0x10 > 0;
+        self.volume = value & 0x0F;
+    }
+
+    /// Restart the envelope on the next quarter frame clock.
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    /// Clock the envelope. Called on every quarter frame by the frame counter.
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    /// Current 4-bit volume level.
+    pub fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+impl Savestate for Envelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.start);
+        w.write(&self.looping);
+        w.write(&self.constant);
+        w.write(&self.volume);
+        w.write(&self.divider);
+        w.write(&self.decay);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.start = r.read()?;
+        self.looping = r.read()?;
+        self.constant = r.read()?;
+        self.volume = r.read()?;
+        self.divider = r.read()?;
+        self.decay = r.read()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,21 @@
+/// Extra sound hardware on a cartridge, such as the VRC6's extra pulse and
+/// sawtooth channels or the Sunsoft 5B's square wave generator.
+///
+/// On the Famicom, the cartridge connector carries the APU's output through
+/// the cartridge and back, which allows the cartridge to mix in its own audio
+/// before it reaches the TV. (The NES's connector lacks these pins, so games
+/// that relied on this only had expansion audio in Japan.) The chip's registers
+/// are written by the CPU via the mapper, while the APU clocks the chip and
+/// mixes in its output.
+pub trait ExpansionAudio {
+    /// Advance the chip by a single CPU cycle.
+    fn tick(&mut self);
+
+    /// Current output level of the chip, in the range [-1.0, 1.0].
+    fn output(&self) -> f32;
+
+    /// Volume of the chip at full scale relative to the APU's full-scale
+    /// output. This varies widely between chips (and between revisions of
+    /// the same console).
+    fn weight(&self) -> f32;
+}
@@ -0,0 +1,114 @@
+use std::f64::consts::PI;
+
+use serde::Deserialize;
+
+/// The stages of the analog filtering applied to the NES's audio output
+/// before it reaches the TV. Each stage is a first-order RC filter.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+pub enum FilterStage {
+    /// 90 Hz high-pass filter.
+    #[serde(rename = "high_pass_90")]
+    HighPass90,
+    /// 440 Hz high-pass filter.
+    #[serde(rename = "high_pass_440")]
+    HighPass440,
+    /// 14 kHz low-pass filter.
+    #[serde(rename = "low_pass_14k")]
+    LowPass14k,
+}
+
+impl FilterStage {
+    /// The filters present on an NTSC NES, in the order they're applied.
+    pub const HARDWARE: [FilterStage; 3] = [
+        FilterStage::HighPass90,
+        FilterStage::HighPass440,
+        FilterStage::LowPass14k,
+    ];
+
+    fn cutoff(self) -> f64 {
+        match self {
+            FilterStage::HighPass90 => 90.0,
+            FilterStage::HighPass440 => 440.0,
+            FilterStage::LowPass14k => 14_000.0,
+        }
+    }
+
+    fn high_pass(self) -> bool {
+        !matches!(self, FilterStage::LowPass14k)
+    }
+}
+
+/// A first-order IIR filter, the digital equivalent of a simple RC circuit.
+struct Filter {
+    stage: FilterStage,
+    alpha: f32,
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl Filter {
+    fn new(stage: FilterStage, sample_rate: f64) -> Self {
+        let mut filter = Self {
+            stage,
+            alpha: 0.0,
+            prev_input: 0.0,
+            prev_output: 0.0,
+        };
+        filter.set_sample_rate(sample_rate);
+        filter
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        let rc = 1.0 / (2.0 * PI * self.stage.cutoff());
+        let dt = 1.0 / sample_rate;
+        self.alpha = if self.stage.high_pass() {
+            rc / (rc + dt)
+        } else {
+            dt / (rc + dt)
+        } as f32;
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = if self.stage.high_pass() {
+            self.alpha * (self.prev_output + input - self.prev_input)
+        } else {
+            self.prev_output + self.alpha * (input - self.prev_output)
+        };
+        self.prev_input = input;
+        self.prev_output = output;
+        output
+    }
+}
+
+/// Applies a series of filter stages to the APU's mixed output.
+///
+/// Besides making the output sound more like real hardware, the high-pass
+/// stages remove the mixer's DC offset, centering the output around zero.
+pub struct OutputFilter {
+    filters: Vec<Filter>,
+}
+
+impl OutputFilter {
+    pub fn new(stages: &[FilterStage], sample_rate: f64) -> Self {
+        Self {
+            filters: stages
+                .iter()
+                .map(|&stage| Filter::new(stage, sample_rate))
+                .collect(),
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        for filter in &mut self.filters {
+            filter.set_sample_rate(sample_rate);
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            for filter in &mut self.filters {
+                *sample = filter.process(*sample);
+            }
+        }
+    }
+}
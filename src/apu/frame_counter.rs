@@ -0,0 +1,155 @@
+use anyhow::Result;
+
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// CPU cycle counts at which each step of the frame sequence occurs. The
+/// final step differs between the 4-step and 5-step modes.
+struct Timing {
+    steps: [u32; 3],
+    four_step_end: u32,
+    five_step_end: u32,
+}
+
+static NTSC_TIMING: Timing = Timing {
+    steps: [7457, 14913, 22371],
+    four_step_end: 29829,
+    five_step_end: 37281,
+};
+
+static PAL_TIMING: Timing = Timing {
+    steps: [8313, 16627, 24939],
+    four_step_end: 33253,
+    five_step_end: 41565,
+};
+
+/// Which units the frame counter clocked on a given cycle.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FrameClock {
+    /// Clocks envelopes and the triangle's linear counter.
+    pub quarter: bool,
+    /// Clocks length counters and sweep units.
+    pub half: bool,
+}
+
+/// The frame counter (or "frame sequencer") generates the low-frequency clocks
+/// that drive the channels' envelopes, sweeps, and length counters. It runs in
+/// either a 4-step or 5-step sequence, and in 4-step mode can optionally
+/// generate an IRQ at the end of each sequence.
+#[derive(Clone)]
+pub struct FrameCounter {
+    timing: &'static Timing,
+    five_step: bool,
+    irq_inhibit: bool,
+    irq_flag: bool,
+    cycle: u32,
+    /// A write to $4017 whose effects haven't yet taken effect, along with
+    /// the number of cycles remaining until they do.
+    pending_write: Option<(u8, u8)>,
+}
+
+impl FrameCounter {
+    pub fn new(region: Region) -> Self {
+        let timing = match region {
+            Region::Ntsc => &NTSC_TIMING,
+            Region::Pal => &PAL_TIMING,
+        };
+        Self {
+            timing,
+            five_step: false,
+            irq_inhibit: false,
+            irq_flag: false,
+            cycle: 0,
+            pending_write: None,
+        }
+    }
+
+    /// $4017: MI-- ---- (mode, IRQ inhibit).
+    ///
+    /// The IRQ inhibit flag takes effect immediately, but the sequencer is only
+    /// reset (and the new mode applied) 3 or 4 CPU cycles later, depending on
+    /// whether the write lands on an even or odd CPU cycle.
+    pub fn write(&mut self, value: u8, odd_cycle: bool) {
+        self.irq_inhibit = value & 0x40 > 0;
+        if self.irq_inhibit {
+            self.irq_flag = false;
+        }
+        let delay = if odd_cycle { 4 } else { 3 };
+        self.pending_write = Some((value, delay));
+    }
+
+    /// Whether the frame IRQ flag is set (reported via $4015).
+    pub fn irq_flag(&self) -> bool {
+        self.irq_flag
+    }
+
+    /// Reading $4015 acknowledges the frame interrupt.
+    pub fn clear_irq_flag(&mut self) {
+        self.irq_flag = false;
+    }
+
+    /// Advance the frame counter by one CPU cycle.
+    pub fn clock(&mut self) -> FrameClock {
+        if let Some((value, delay)) = self.pending_write {
+            if delay > 1 {
+                self.pending_write = Some((value, delay - 1));
+            } else {
+                self.pending_write = None;
+                self.five_step = value & 0x80 > 0;
+                self.cycle = 0;
+
+                // Switching to 5-step mode immediately clocks all of the
+                // units, as if the sequence had just finished.
+                if self.five_step {
+                    return FrameClock {
+                        quarter: true,
+                        half: true,
+                    };
+                }
+                return FrameClock::default();
+            }
+        }
+
+        self.cycle += 1;
+
+        let timing = self.timing;
+        let mut clock = FrameClock::default();
+        if self.cycle == timing.steps[0] || self.cycle == timing.steps[2] {
+            clock.quarter = true;
+        } else if self.cycle == timing.steps[1] {
+            clock.quarter = true;
+            clock.half = true;
+        } else if !self.five_step && self.cycle == timing.four_step_end {
+            clock.quarter = true;
+            clock.half = true;
+            if !self.irq_inhibit {
+                self.irq_flag = true;
+            }
+            self.cycle = 0;
+        } else if self.five_step && self.cycle == timing.five_step_end {
+            clock.quarter = true;
+            clock.half = true;
+            self.cycle = 0;
+        }
+        clock
+    }
+}
+
+impl Savestate for FrameCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.five_step);
+        w.write(&self.irq_inhibit);
+        w.write(&self.irq_flag);
+        w.write(&self.cycle);
+        w.write(&self.pending_write);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.five_step = r.read()?;
+        self.irq_inhibit = r.read()?;
+        self.irq_flag = r.read()?;
+        self.cycle = r.read()?;
+        self.pending_write = r.read()?;
+        Ok(())
+    }
+}
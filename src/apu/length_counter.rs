@@ -0,0 +1,79 @@
+use anyhow::Result;
+
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// Lookup table used to convert the 5-bit value written to a channel's length
+/// register into the actual number of half frames the channel should play for.
+#[rustfmt::skip]
+static LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20,  2, 40,  4, 80,  6, 160,  8, 60, 10, 14, 12, 26, 14,
+    12,  16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// Length counter shared by the pulse, triangle, and noise channels. When the
+/// counter reaches zero, the channel is silenced. This allows games to play
+/// notes of fixed duration without needing to explicitly stop them.
+///
+/// Channels are enabled and disabled via $4015 by way of their length
+/// counters: disabling a channel immediately zeroes its counter, and the
+/// counter can't be loaded while the channel is disabled.
+#[derive(Clone, Default)]
+pub struct LengthCounter {
+    enabled: bool,
+    counter: u8,
+    halt: bool,
+}
+
+impl LengthCounter {
+    /// Load the counter using a 5-bit index into the length table. Has no
+    /// effect if the channel is disabled.
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(index & 0x1F) as usize];
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn set_halt(&mut self, halt: bool) {
+        self.halt = halt;
+    }
+
+    /// Clock the length counter. Called on every half frame by the frame
+    /// counter.
+    pub fn clock(&mut self) {
+        if self.counter > 0 && !self.halt {
+            self.counter -= 1;
+        }
+    }
+
+    /// Number of half frames remaining.
+    pub fn value(&self) -> u8 {
+        self.counter
+    }
+
+    /// Whether the channel is currently allowed to make sound.
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+impl Savestate for LengthCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.enabled);
+        w.write(&self.counter);
+        w.write(&self.halt);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.enabled = r.read()?;
+        self.counter = r.read()?;
+        self.halt = r.read()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+/// Number of entries in the pulse lookup table. The two pulse channels each
+/// output a 4-bit value, so their sum ranges from 0 to 30.
+const PULSE_TABLE_SIZE: usize = 31;
+
+/// Number of entries in the triangle/noise/DMC lookup table. The table is
+/// indexed by 3 * triangle + 2 * noise + dmc, which ranges from 0 to 202.
+const TND_TABLE_SIZE: usize = 203;
+
+/// The NES does not simply sum the outputs of its sound channels. Instead, the
+/// channels are combined by a pair of resistor-ladder DACs whose outputs are
+/// nonlinear functions of their inputs. In practice, this means that a loud
+/// channel will "duck" the other channels sharing its DAC, and that the volume
+/// of each channel relative to the others varies with their levels.
+///
+/// Rather than evaluating the exact formulas for each sample, this mixer uses
+/// the standard lookup-table approximation described on the [NesDev wiki],
+/// which is accurate to within a fraction of a percent.
+///
+/// [NesDev wiki]: https://www.nesdev.org/wiki/APU_Mixer
+pub struct Mixer {
+    pulse_table: [f32; PULSE_TABLE_SIZE],
+    tnd_table: [f32; TND_TABLE_SIZE],
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        let mut pulse_table = [0.0; PULSE_TABLE_SIZE];
+        for (n, entry) in pulse_table.iter_mut().enumerate().skip(1) {
+            *entry = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+
+        let mut tnd_table = [0.0; TND_TABLE_SIZE];
+        for (n, entry) in tnd_table.iter_mut().enumerate().skip(1) {
+            *entry = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+
+        Self {
+            pulse_table,
+            tnd_table,
+        }
+    }
+
+    /// Combine the current output levels of the five APU channels into a
+    /// single sample in the range [0.0, 1.0]. The pulse, triangle, and noise
+    /// channels output 4-bit values, while the DMC outputs a 7-bit value.
+    pub fn mix(&self, pulse1: u8, pulse2: u8, triangle: u8, noise: u8, dmc: u8) -> f32 {
+        let pulse = self.pulse_table[(pulse1 + pulse2) as usize];
+        let tnd_index = 3 * triangle as usize + 2 * noise as usize + dmc as usize;
+        let tnd = self.tnd_table[tnd_index];
+        pulse + tnd
+    }
+
+    /// Mix the output of an expansion audio chip into the APU's (already
+    /// mixed) output. Unlike the APU's own channels, expansion audio is mixed
+    /// in linearly, with the given weight relative to the APU.
+    pub fn mix_expansion(&self, apu_output: f32, expansion_output: f32, weight: f32) -> f32 {
+        apu_output + expansion_output * weight
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nonlinear_mixing() {
+        let mixer = Mixer::new();
+
+        // Silence should produce no output.
+        assert_eq!(mixer.mix(0, 0, 0, 0, 0), 0.0);
+
+        // All channels at full volume should produce (just under) full scale.
+        let max = mixer.mix(15, 15, 15, 15, 127);
+        assert!(max > 0.99 && max <= 1.0, "unexpected peak output: {}", max);
+
+        // Channels sharing a DAC should be quieter together than the sum of
+        // their individual outputs.
+        let single = mixer.mix(15, 0, 0, 0, 0);
+        let both = mixer.mix(15, 15, 0, 0, 0);
+        assert!(both < 2.0 * single);
+
+        // Channels on different DACs do not affect each other.
+        let triangle = mixer.mix(0, 0, 15, 0, 0);
+        let combined = mixer.mix(15, 0, 15, 0, 0);
+        assert!((combined - (single + triangle)).abs() < f32::EPSILON);
+    }
+}
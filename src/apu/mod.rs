@@ -0,0 +1,458 @@
+//! The NES's audio processing unit (APU).
+//!
+//! The APU is part of the Ricoh 2A03 chip that also contains the NES's CPU. It
+//! consists of five sound channels: two pulse wave channels, a triangle wave
+//! channel, a noise channel, and a delta modulation channel (DMC) that plays
+//! back sampled audio. The channels are controlled via memory mapped registers
+//! at $4000-$4013, $4015, and $4017, and their outputs are combined by a
+//! nonlinear mixer into a single audio signal.
+//!
+//! The [NesDev wiki](https://www.nesdev.org/wiki/APU) was the primary reference
+//! for this implementation.
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::io::IoRegister;
+use crate::mem::Address;
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+pub use expansion::ExpansionAudio;
+pub use filter::FilterStage;
+
+use dmc::Dmc;
+use filter::OutputFilter;
+use frame_counter::FrameCounter;
+use mixer::Mixer;
+use noise::Noise;
+use pulse::{Pulse, PulseChannel};
+use resampler::Resampler;
+use triangle::Triangle;
+
+mod dmc;
+mod envelope;
+mod expansion;
+mod filter;
+mod frame_counter;
+mod length_counter;
+mod mixer;
+mod noise;
+mod pulse;
+mod resampler;
+mod triangle;
+
+/// Sample rate used for generated audio until an audio backend requests a
+/// different rate.
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+/// The APU's sound channels.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 5] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::Dmc,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Snapshot of a channel's state, for debugging.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ChannelState {
+    /// Raw value of the channel's timer period.
+    pub period: u16,
+    /// Current volume (see the individual channels for what this means for
+    /// channels without volume control).
+    pub volume: u8,
+    /// Current value of the length counter.
+    pub length: u16,
+}
+
+/// When capturing channel levels for an oscilloscope view, record the levels
+/// once every this many CPU cycles.
+pub const SCOPE_DECIMATION: u64 = 8;
+
+/// A copy of the state of the APU's channels, from `Apu::snapshot`.
+#[derive(Clone)]
+pub struct ApuSnapshot {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    frame_counter: FrameCounter,
+    cycle: u64,
+}
+
+pub struct Apu {
+    region: Region,
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    frame_counter: FrameCounter,
+    mixer: Mixer,
+    resampler: Resampler,
+    filter: OutputFilter,
+    sample_rate: f64,
+    /// Separate resamplers for the output of each channel, in the order given
+    /// by `Channel::ALL`. These are only present while per-channel output is
+    /// being captured (e.g., for recording), since resampling is expensive.
+    channel_resamplers: Option<Vec<Resampler>>,
+    /// Channels that have been muted or soloed by the user, indexed in the
+    /// order given by `Channel::ALL`. These only affect the mixed output.
+    muted: [bool; 5],
+    soloed: [bool; 5],
+    /// Recent output levels of each channel, if being captured.
+    scope: Option<Vec<[u8; 5]>>,
+    cycle: u64,
+}
+
+impl Apu {
+    pub fn new(region: Region) -> Self {
+        Self {
+            region,
+            pulse1: Pulse::new(PulseChannel::One),
+            pulse2: Pulse::new(PulseChannel::Two),
+            triangle: Triangle::default(),
+            noise: Noise::new(region),
+            dmc: Dmc::new(region),
+            frame_counter: FrameCounter::new(region),
+            mixer: Mixer::new(),
+            resampler: Resampler::new(region.cpu_clock_hz(), DEFAULT_SAMPLE_RATE as f64),
+            filter: OutputFilter::new(&FilterStage::HARDWARE, DEFAULT_SAMPLE_RATE as f64),
+            sample_rate: DEFAULT_SAMPLE_RATE as f64,
+            channel_resamplers: None,
+            muted: [false; 5],
+            soloed: [false; 5],
+            scope: None,
+            cycle: 0,
+        }
+    }
+
+    /// Handle a write to one of the APU's registers.
+    pub fn write_register(&mut self, reg: IoRegister, value: u8) {
+        use IoRegister::*;
+        match reg {
+            Sq1Vol => self.pulse1.write_control(value),
+            Sq1Sweep => self.pulse1.write_sweep(value),
+            Sq1Lo => self.pulse1.write_timer_low(value),
+            Sq1Hi => self.pulse1.write_timer_high(value),
+            Sq2Vol => self.pulse2.write_control(value),
+            Sq2Sweep => self.pulse2.write_sweep(value),
+            Sq2Lo => self.pulse2.write_timer_low(value),
+            Sq2Hi => self.pulse2.write_timer_high(value),
+            TriLinear => self.triangle.write_linear(value),
+            TriLo => self.triangle.write_timer_low(value),
+            TriHi => self.triangle.write_timer_high(value),
+            NoiseVol => self.noise.write_control(value),
+            NoiseLo => self.noise.write_period(value),
+            NoiseHi => self.noise.write_length(value),
+            DmcFreq => self.dmc.write_control(value),
+            DmcRaw => self.dmc.write_output_level(value),
+            DmcStart => self.dmc.write_sample_addr(value),
+            DmcLen => self.dmc.write_sample_length(value),
+            SndChn => self.write_status(value),
+            // $4017 is shared with the second controller port, but writes to
+            // it configure the APU's frame counter.
+            Joy2 => self.frame_counter.write(value, self.cycle % 2 == 1),
+            OamDma | Joy1 => {
+                log::warn!("Ignoring write to non-APU register {}: {:#X}", reg, value)
+            }
+        }
+    }
+
+    /// $4015 write: ---D NT21 (enable DMC, noise, triangle, pulse 2, pulse 1).
+    fn write_status(&mut self, value: u8) {
+        self.pulse1.set_enabled(value & 0x01 > 0);
+        self.pulse2.set_enabled(value & 0x02 > 0);
+        self.triangle.set_enabled(value & 0x04 > 0);
+        self.noise.set_enabled(value & 0x08 > 0);
+        self.dmc.set_enabled(value & 0x10 > 0);
+    }
+
+    /// $4015 read: IF-D NT21 (DMC interrupt, frame interrupt, DMC active,
+    /// length counter status for noise, triangle, pulse 2, and pulse 1).
+    /// Reading this register clears the frame interrupt flag.
+    pub fn read_status(&mut self) -> u8 {
+        let mut value = 0;
+        value |= self.pulse1.active() as u8;
+        value |= (self.pulse2.active() as u8) << 1;
+        value |= (self.triangle.active() as u8) << 2;
+        value |= (self.noise.active() as u8) << 3;
+        value |= (self.dmc.active() as u8) << 4;
+        value |= (self.frame_counter.irq_flag() as u8) << 6;
+        value |= (self.dmc.irq_flag() as u8) << 7;
+        self.frame_counter.clear_irq_flag();
+        value
+    }
+
+    /// Whether the APU is asserting the CPU's IRQ line, due to either the frame
+    /// counter or the DMC. The line stays asserted until the interrupt is
+    /// acknowledged by the program.
+    pub fn irq(&self) -> bool {
+        self.frame_counter.irq_flag() || self.dmc.irq_flag()
+    }
+
+    /// Address of the next sample byte that the DMC needs to read, if any.
+    /// The byte should be loaded from the CPU's address space and passed to
+    /// `Apu::fill_dmc_buffer`.
+    pub fn dmc_pending_read(&self) -> Option<Address> {
+        self.dmc.pending_read()
+    }
+
+    /// Supply the sample byte requested via `Apu::dmc_pending_read`.
+    pub fn fill_dmc_buffer(&mut self, value: u8) {
+        self.dmc.fill_buffer(value);
+    }
+
+    /// Advance the APU by a single CPU cycle. If the cartridge has expansion
+    /// audio hardware, it is clocked as well, and its output is mixed into the
+    /// APU's output.
+    pub fn tick(&mut self, expansion: Option<&mut dyn ExpansionAudio>) {
+        // The triangle, noise, and DMC timers are clocked every CPU cycle,
+        // while the pulse timers are clocked every APU cycle (which is half
+        // the frequency of the CPU clock).
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycle % 2 == 1 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+
+        let clock = self.frame_counter.clock();
+        if clock.quarter {
+            self.pulse1.clock_quarter_frame();
+            self.pulse2.clock_quarter_frame();
+            self.triangle.clock_quarter_frame();
+            self.noise.clock_quarter_frame();
+        }
+        if clock.half {
+            self.pulse1.clock_half_frame();
+            self.pulse2.clock_half_frame();
+            self.triangle.clock_half_frame();
+            self.noise.clock_half_frame();
+        }
+
+        let mut output = self.output();
+        if let Some(expansion) = expansion {
+            expansion.tick();
+            output = self
+                .mixer
+                .mix_expansion(output, expansion.output(), expansion.weight());
+        }
+        self.resampler.push(output);
+        let levels = self.channel_levels();
+        if let Some(resamplers) = &mut self.channel_resamplers {
+            for (i, resampler) in resamplers.iter_mut().enumerate() {
+                let mut solo = [0; 5];
+                solo[i] = levels[i];
+                let [p1, p2, t, n, d] = solo;
+                resampler.push(self.mixer.mix(p1, p2, t, n, d));
+            }
+        }
+        if let Some(scope) = &mut self.scope {
+            if self.cycle.is_multiple_of(SCOPE_DECIMATION) {
+                scope.push(levels);
+            }
+        }
+
+        self.cycle += 1;
+    }
+
+    /// Current output of the APU, combining all of the audible channels via
+    /// the mixer. The output is a value in the range [0.0, 1.0]. This doesn't
+    /// include any expansion audio.
+    pub fn output(&self) -> f32 {
+        let mut levels = self.channel_levels();
+        for (channel, level) in Channel::ALL.iter().zip(&mut levels) {
+            if !self.audible(*channel) {
+                *level = 0;
+            }
+        }
+        let [p1, p2, t, n, d] = levels;
+        self.mixer.mix(p1, p2, t, n, d)
+    }
+
+    /// Current state of the given channel.
+    pub fn channel_state(&self, channel: Channel) -> ChannelState {
+        match channel {
+            Channel::Pulse1 => self.pulse1.state(),
+            Channel::Pulse2 => self.pulse2.state(),
+            Channel::Triangle => self.triangle.state(),
+            Channel::Noise => self.noise.state(),
+            Channel::Dmc => self.dmc.state(),
+        }
+    }
+
+    /// Start or stop recording the output level of each channel every
+    /// `SCOPE_DECIMATION` cycles, for display in an oscilloscope view.
+    pub fn capture_scope(&mut self, enabled: bool) {
+        self.scope = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    /// Take the channel levels recorded since the last call, in the order
+    /// given by `Channel::ALL`.
+    pub fn take_scope(&mut self) -> Vec<[u8; 5]> {
+        self.scope.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Whether the given channel is included in the mixed output. Muting a
+    /// channel silences it, while soloing a channel silences all of the
+    /// channels that aren't soloed.
+    pub fn audible(&self, channel: Channel) -> bool {
+        let any_soloed = self.soloed.iter().any(|&soloed| soloed);
+        !self.muted[channel.index()] && (!any_soloed || self.soloed[channel.index()])
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel.index()] = muted;
+    }
+
+    pub fn muted(&self, channel: Channel) -> bool {
+        self.muted[channel.index()]
+    }
+
+    pub fn set_soloed(&mut self, channel: Channel, soloed: bool) {
+        self.soloed[channel.index()] = soloed;
+    }
+
+    pub fn soloed(&self, channel: Channel) -> bool {
+        self.soloed[channel.index()]
+    }
+
+    /// Current output level of each channel, in the order given by
+    /// `Channel::ALL`.
+    fn channel_levels(&self) -> [u8; 5] {
+        [
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+        ]
+    }
+
+    /// Set the rate at which output samples are generated. This need not be
+    /// an integer; the audio backend may nudge the rate slightly up or down in
+    /// order to keep its buffer at the desired fill level.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.resampler.set_output_rate(sample_rate);
+        self.filter.set_sample_rate(sample_rate);
+        for resampler in self.channel_resamplers.iter_mut().flatten() {
+            resampler.set_output_rate(sample_rate);
+        }
+    }
+
+    /// Select which of the analog output filters to emulate. All of the
+    /// hardware's filters are enabled by default.
+    pub fn set_filters(&mut self, stages: &[FilterStage]) {
+        self.filter = OutputFilter::new(stages, self.sample_rate);
+    }
+
+    /// Take all of the audio samples generated since the last call. The
+    /// samples have been passed through the output filters, so they will
+    /// generally be centered around zero rather than in the range [0.0, 1.0].
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        let mut samples = self.resampler.take_samples();
+        self.filter.process(&mut samples);
+        samples
+    }
+
+    /// Start or stop generating a separate stream of samples for each channel
+    /// at the given sample rate, as if it were the only channel playing.
+    pub fn capture_channels(&mut self, sample_rate: Option<f64>) {
+        self.channel_resamplers = sample_rate.map(|rate| {
+            Channel::ALL
+                .iter()
+                .map(|_| Resampler::new(self.region.cpu_clock_hz(), rate))
+                .collect()
+        });
+    }
+
+    /// Take the samples generated for each channel since the last call, if
+    /// per-channel output is being captured.
+    pub fn take_channel_samples(&mut self) -> Option<Vec<Vec<f32>>> {
+        let resamplers = self.channel_resamplers.as_mut()?;
+        Some(resamplers.iter_mut().map(Resampler::take_samples).collect())
+    }
+}
+
+impl Apu {
+    /// Copy the channels' state. As with save states, the audio output stages
+    /// are left out.
+    pub fn snapshot(&self) -> ApuSnapshot {
+        ApuSnapshot {
+            pulse1: self.pulse1.clone(),
+            pulse2: self.pulse2.clone(),
+            triangle: self.triangle.clone(),
+            noise: self.noise.clone(),
+            dmc: self.dmc.clone(),
+            frame_counter: self.frame_counter.clone(),
+            cycle: self.cycle,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &ApuSnapshot) {
+        self.pulse1 = snapshot.pulse1.clone();
+        self.pulse2 = snapshot.pulse2.clone();
+        self.triangle = snapshot.triangle.clone();
+        self.noise = snapshot.noise.clone();
+        self.dmc = snapshot.dmc.clone();
+        self.frame_counter = snapshot.frame_counter.clone();
+        self.cycle = snapshot.cycle;
+    }
+}
+
+/// Only the channels' state is saved. The audio output stages (resampling and
+/// filtering) depend on the host's sample rate, so they're left as they are.
+impl Savestate for Apu {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.pulse1.save_state(w);
+        self.pulse2.save_state(w);
+        self.triangle.save_state(w);
+        self.noise.save_state(w);
+        self.dmc.save_state(w);
+        self.frame_counter.save_state(w);
+        w.write(&self.cycle);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.pulse1.load_state(r)?;
+        self.pulse2.load_state(r)?;
+        self.triangle.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.dmc.load_state(r)?;
+        self.frame_counter.load_state(r)?;
+        self.cycle = r.read()?;
+        Ok(())
+    }
+}
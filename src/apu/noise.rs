@@ -0,0 +1,142 @@
+use anyhow::Result;
+
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use super::ChannelState;
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// Timer periods (in CPU cycles) for each of the noise channel's 16 frequency
+/// settings on NTSC consoles.
+#[rustfmt::skip]
+static NTSC_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+/// Timer periods for PAL consoles.
+#[rustfmt::skip]
+static PAL_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+/// The noise channel, which produces pseudo-random output using a 15-bit
+/// linear feedback shift register.
+#[derive(Clone)]
+pub struct Noise {
+    period_table: &'static [u16; 16],
+    mode: bool,
+    shift_register: u16,
+    timer_period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length_counter: LengthCounter,
+}
+
+impl Noise {
+    pub fn new(region: Region) -> Self {
+        let period_table = match region {
+            Region::Ntsc => &NTSC_PERIOD_TABLE,
+            Region::Pal => &PAL_PERIOD_TABLE,
+        };
+        Self {
+            period_table,
+            mode: false,
+            // The shift register is loaded with 1 on power-up.
+            shift_register: 1,
+            timer_period: period_table[0],
+            timer: 0,
+            envelope: Envelope::default(),
+            length_counter: LengthCounter::default(),
+        }
+    }
+
+    /// $400C: --LC VVVV (length halt/envelope loop, constant volume,
+    /// volume/envelope period).
+    pub fn write_control(&mut self, value: u8) {
+        // As with the pulse channels, the halt and loop flags share a bit.
+        self.length_counter.set_halt(value & 0x20 > 0);
+        self.envelope.write_control(value);
+    }
+
+    /// $400E: M--- PPPP (mode, period index).
+    pub fn write_period(&mut self, value: u8) {
+        self.mode = value & 0x80 > 0;
+        self.timer_period = self.period_table[(value & 0x0F) as usize];
+    }
+
+    /// $400F: LLLL L--- (length counter load).
+    pub fn write_length(&mut self, value: u8) {
+        self.length_counter.load(value >> 3);
+        self.envelope.restart();
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length_counter.set_enabled(enabled);
+    }
+
+    /// Whether the length counter is nonzero (reported via $4015).
+    pub fn active(&self) -> bool {
+        self.length_counter.active()
+    }
+
+    /// Clock the channel's timer. Called on every CPU cycle.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+
+            // In "short" mode, the feedback bit is taken from bit 6 rather than
+            // bit 1, which produces a much shorter (and more tonal) sequence.
+            let other_bit = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> other_bit)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length_counter.clock();
+    }
+
+    pub fn state(&self) -> ChannelState {
+        ChannelState {
+            period: self.timer_period,
+            volume: self.envelope.output(),
+            length: self.length_counter.value() as u16,
+        }
+    }
+
+    /// Current 4-bit output level.
+    pub fn output(&self) -> u8 {
+        if !self.length_counter.active() || self.shift_register & 1 > 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl Savestate for Noise {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.mode);
+        w.write(&self.shift_register);
+        w.write(&self.timer_period);
+        w.write(&self.timer);
+        self.envelope.save_state(w);
+        self.length_counter.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.mode = r.read()?;
+        self.shift_register = r.read()?;
+        self.timer_period = r.read()?;
+        self.timer = r.read()?;
+        self.envelope.load_state(r)?;
+        self.length_counter.load_state(r)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+use anyhow::Result;
+
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use super::ChannelState;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// Waveforms for each of the four duty cycle settings (12.5%, 25%, 50%, and
+/// 25% negated).
+static DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+/// The two pulse channels are identical except for how their sweep units
+/// compute negative period adjustments. Pulse 1 uses one's complement
+/// arithmetic, whereas pulse 2 uses two's complement.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PulseChannel {
+    One,
+    Two,
+}
+
+/// A pulse (square) wave channel.
+#[derive(Clone)]
+pub struct Pulse {
+    channel: PulseChannel,
+    duty: u8,
+    sequence_pos: u8,
+    timer_period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length_counter: LengthCounter,
+    sweep: Sweep,
+}
+
+impl Pulse {
+    pub fn new(channel: PulseChannel) -> Self {
+        Self {
+            channel,
+            duty: 0,
+            sequence_pos: 0,
+            timer_period: 0,
+            timer: 0,
+            envelope: Envelope::default(),
+            length_counter: LengthCounter::default(),
+            sweep: Sweep::default(),
+        }
+    }
+
+    /// $4000/$4004: DDLC VVVV (duty, length halt/envelope loop, constant
+    /// volume, volume/envelope period).
+    pub fn write_control(&mut self, value: u8) {
+        self.duty = value >> 6;
+        // The same bit both halts the length counter and makes the envelope
+        // loop, so a looping envelope always plays indefinitely.
+        self.length_counter.set_halt(value & 0x20 > 0);
+        self.envelope.write_control(value);
+    }
+
+    /// $4001/$4005: EPPP NSSS (sweep enabled, period, negate, shift).
+    pub fn write_sweep(&mut self, value: u8) {
+        self.sweep.write(value);
+    }
+
+    /// $4002/$4006: Low 8 bits of the timer period.
+    pub fn write_timer_low(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x0700) | value as u16;
+    }
+
+    /// $4003/$4007: LLLL LTTT (length counter load, high 3 bits of timer).
+    /// Also restarts the envelope and resets the sequencer's phase (but not
+    /// its timer), which causes an audible click if written mid-note.
+    pub fn write_timer_high(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0x07) << 8);
+        self.length_counter.load(value >> 3);
+        self.envelope.restart();
+        self.sequence_pos = 0;
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length_counter.set_enabled(enabled);
+    }
+
+    /// Whether the length counter is nonzero (reported via $4015).
+    pub fn active(&self) -> bool {
+        self.length_counter.active()
+    }
+
+    /// Clock the channel's timer. The pulse timers are clocked once every APU
+    /// cycle (i.e., every other CPU cycle).
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_pos = (self.sequence_pos + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length_counter.clock();
+        let target = self.sweep.target_period(self.timer_period, self.channel);
+        if self.sweep.clock() && !self.muted(target) {
+            self.timer_period = target;
+        }
+    }
+
+    /// The sweep unit mutes the channel if the current period is too small or
+    /// if the period it is sweeping towards would overflow the 11-bit timer.
+    /// This happens regardless of whether the sweep unit is enabled.
+    fn muted(&self, target: u16) -> bool {
+        self.timer_period < 8 || target > 0x7FF
+    }
+
+    pub fn state(&self) -> ChannelState {
+        ChannelState {
+            period: self.timer_period,
+            volume: self.envelope.output(),
+            length: self.length_counter.value() as u16,
+        }
+    }
+
+    /// Current 4-bit output level.
+    pub fn output(&self) -> u8 {
+        let target = self.sweep.target_period(self.timer_period, self.channel);
+        if !self.length_counter.active()
+            || self.muted(target)
+            || DUTY_TABLE[self.duty as usize][self.sequence_pos as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+/// Sweep unit, which periodically adjusts a pulse channel's period up or down.
+#[derive(Clone, Default)]
+struct Sweep {
+    enabled: bool,
+    period: u8,
+    negate: bool,
+    shift: u8,
+    divider: u8,
+    reload: bool,
+}
+
+impl Sweep {
+    fn write(&mut self, value: u8) {
+        self.enabled = value & 0x80 > 0;
+        self.period = (value >> 4) & 0x07;
+        self.negate = value & 0x08 > 0;
+        self.shift = value & 0x07;
+        self.reload = true;
+    }
+
+    /// Compute the period that the channel would be adjusted to on the next
+    /// sweep clock.
+    fn target_period(&self, current: u16, channel: PulseChannel) -> u16 {
+        let change = current >> self.shift;
+        if self.negate {
+            match channel {
+                PulseChannel::One => current.saturating_sub(change + 1),
+                PulseChannel::Two => current.saturating_sub(change),
+            }
+        } else {
+            current + change
+        }
+    }
+
+    /// Clock the sweep unit's divider. Returns true if the channel's period
+    /// should be updated to the target period.
+    fn clock(&mut self) -> bool {
+        let update = self.divider == 0 && self.enabled && self.shift > 0;
+        if self.divider == 0 || self.reload {
+            self.divider = self.period;
+            self.reload = false;
+        } else {
+            self.divider -= 1;
+        }
+        update
+    }
+}
+
+impl Savestate for Pulse {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.duty);
+        w.write(&self.sequence_pos);
+        w.write(&self.timer_period);
+        w.write(&self.timer);
+        self.envelope.save_state(w);
+        self.length_counter.save_state(w);
+        self.sweep.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.duty = r.read()?;
+        self.sequence_pos = r.read()?;
+        self.timer_period = r.read()?;
+        self.timer = r.read()?;
+        self.envelope.load_state(r)?;
+        self.length_counter.load_state(r)?;
+        self.sweep.load_state(r)?;
+        Ok(())
+    }
+}
+
+impl Savestate for Sweep {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.enabled);
+        w.write(&self.period);
+        w.write(&self.negate);
+        w.write(&self.shift);
+        w.write(&self.divider);
+        w.write(&self.reload);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.enabled = r.read()?;
+        self.period = r.read()?;
+        self.negate = r.read()?;
+        self.shift = r.read()?;
+        self.divider = r.read()?;
+        self.reload = r.read()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+use std::f64::consts::PI;
+
+/// Number of output samples on each side of a step that it affects.
+const KERNEL_HALF_WIDTH: usize = 8;
+const KERNEL_WIDTH: usize = 2 * KERNEL_HALF_WIDTH;
+
+/// Number of sub-sample positions for which the kernel is precomputed. Steps
+/// are rounded to the nearest of these positions.
+const KERNEL_PHASES: usize = 32;
+
+/// Cutoff frequency of the kernel's low-pass filter, relative to the output
+/// Nyquist frequency. Setting this a bit below 1 leaves room for the filter's
+/// transition band so that little energy gets folded back as aliasing.
+const CUTOFF: f64 = 0.9;
+
+/// Converts the APU's output, which can change on every CPU cycle, into a
+/// stream of samples at the (much lower) audio output rate.
+///
+/// Simply picking every Nth value of the APU's output (decimation) would
+/// alias any frequencies above the output's Nyquist frequency back into the
+/// audible range. The APU's square and triangle waves are full of such
+/// harmonics, which makes naive decimation sound harsh and out of tune for
+/// high-pitched notes. Instead, this works in the manner of Shay Green's
+/// [blip_buf]: since the APU's output is a series of steps, each change in
+/// the output is added to the output buffer as a band-limited step (i.e.,
+/// a step that has been passed through a low-pass filter below the output
+/// Nyquist frequency). The buffer stores the differences between adjacent
+/// output samples, which are summed when the samples are read out.
+///
+/// The output rate can be adjusted on the fly, which allows the audio backend
+/// to make small corrections to keep its buffer from overflowing or running
+/// dry (see `audio::RateControl`).
+///
+/// [blip_buf]: https://code.google.com/archive/p/blip-buf/
+pub struct Resampler {
+    clock_rate: f64,
+    samples_per_cycle: f64,
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+
+    /// Current time in units of output samples, relative to the start of
+    /// `deltas`.
+    time: f64,
+    /// Input value as of the last call to `push`.
+    last_value: f32,
+    /// Differences between consecutive output samples.
+    deltas: Vec<f32>,
+    /// Running sum of deltas that have been read out.
+    integrator: f32,
+}
+
+impl Resampler {
+    pub fn new(clock_rate: f64, output_rate: f64) -> Self {
+        Self {
+            clock_rate,
+            samples_per_cycle: output_rate / clock_rate,
+            kernel: build_kernel(),
+            time: 0.0,
+            last_value: 0.0,
+            deltas: vec![0.0; KERNEL_WIDTH],
+            integrator: 0.0,
+        }
+    }
+
+    pub fn set_output_rate(&mut self, output_rate: f64) {
+        self.samples_per_cycle = output_rate / self.clock_rate;
+    }
+
+    /// Feed in the APU's output for a single cycle.
+    pub fn push(&mut self, value: f32) {
+        if value != self.last_value {
+            self.add_step(value - self.last_value);
+            self.last_value = value;
+        }
+        self.time += self.samples_per_cycle;
+    }
+
+    /// Add a band-limited step of the given height at the current time.
+    fn add_step(&mut self, delta: f32) {
+        let whole = self.time.floor();
+        let phase = ((self.time - whole) * KERNEL_PHASES as f64).round() as usize;
+
+        // The kernel is centered on the step, so the first affected sample
+        // is several samples before the current time. Rounding up to the
+        // next phase may push the step into the following sample.
+        let start = whole as usize + phase / KERNEL_PHASES;
+        let taps = &self.kernel[phase % KERNEL_PHASES];
+
+        let end = start + KERNEL_WIDTH;
+        if self.deltas.len() < end {
+            self.deltas.resize(end, 0.0);
+        }
+        for (out, tap) in self.deltas[start..end].iter_mut().zip(taps) {
+            *out += delta * tap;
+        }
+    }
+
+    /// Take all of the output samples generated since the last call.
+    ///
+    /// Since each step is spread out over several samples, the output lags
+    /// the input by just under `KERNEL_HALF_WIDTH` samples.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        let ready = self.time.floor() as usize;
+        if self.deltas.len() < ready {
+            self.deltas.resize(ready, 0.0);
+        }
+
+        let mut samples = Vec::with_capacity(ready);
+        for delta in self.deltas.drain(..ready) {
+            self.integrator += delta;
+            samples.push(self.integrator);
+        }
+
+        self.time -= ready as f64;
+        samples
+    }
+}
+
+/// Precompute the band-limited impulse (a windowed sinc function) for each
+/// sub-sample phase. Each row is normalized to sum to 1 so that a step of
+/// height N raises the output by exactly N once it has settled.
+fn build_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+    (0..KERNEL_PHASES)
+        .map(|phase| {
+            let offset = phase as f64 / KERNEL_PHASES as f64;
+            let mut taps = [0.0f64; KERNEL_WIDTH];
+            for (i, tap) in taps.iter_mut().enumerate() {
+                // Distance (in output samples) between this tap and the step.
+                let x = i as f64 - (KERNEL_HALF_WIDTH - 1) as f64 - offset;
+                *tap = sinc(x * CUTOFF) * blackman(x / KERNEL_HALF_WIDTH as f64);
+            }
+
+            let sum: f64 = taps.iter().sum();
+            let mut row = [0.0f32; KERNEL_WIDTH];
+            for (out, tap) in row.iter_mut().zip(&taps) {
+                *out = (tap / sum) as f32;
+            }
+            row
+        })
+        .collect()
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Blackman window over the interval [-1, 1].
+fn blackman(x: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        return 0.0;
+    }
+    let t = PI * (x + 1.0);
+    0.42 - 0.5 * t.cos() + 0.08 * (2.0 * t).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_band_limited_step() {
+        // Use a clock rate that isn't a multiple of the output rate so that
+        // steps land at many different sub-sample phases.
+        let mut resampler = Resampler::new(1_789_773.0, 44_100.0);
+
+        // A square wave well below the Nyquist frequency should settle at its
+        // high and low levels between transitions.
+        let mut samples = Vec::new();
+        for cycle in 0..200_000 {
+            let high = (cycle / 10_000) % 2 == 1;
+            resampler.push(if high { 1.0 } else { 0.0 });
+            samples.extend(resampler.take_samples());
+        }
+
+        // Roughly one output sample per 40.6 input cycles.
+        assert!((4900..=4930).contains(&samples.len()));
+
+        // Each half period is ~246 output samples long; check the middle of
+        // each one, away from the ringing around the transitions.
+        let half_period = samples.len() as f64 / 20.0;
+        for i in 0..20 {
+            let mid = ((i as f64 + 0.5) * half_period) as usize;
+            let expected = if i % 2 == 1 { 1.0 } else { 0.0 };
+            assert!((samples[mid] - expected).abs() < 1e-3);
+        }
+
+        // Band-limiting causes some overshoot, but it should be small.
+        let max = samples.iter().cloned().fold(f32::MIN, f32::max);
+        assert!(max > 1.0 && max < 1.15);
+    }
+}
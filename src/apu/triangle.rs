@@ -0,0 +1,129 @@
+use anyhow::Result;
+
+use super::length_counter::LengthCounter;
+use super::ChannelState;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// The triangle channel steps through this 32-step sequence to produce a
+/// (quantized) triangle wave.
+#[rustfmt::skip]
+static TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10,  9,  8,  7,  6,  5,  4,  3,  2,  1,  0,
+     0,  1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15,
+];
+
+/// The triangle wave channel. Unlike the other channels, it has no volume
+/// control; instead, it has a second "linear" counter that provides finer
+/// grained control over note duration than the length counter.
+#[derive(Clone, Default)]
+pub struct Triangle {
+    control: bool,
+    sequence_pos: u8,
+    timer_period: u16,
+    timer: u16,
+    length_counter: LengthCounter,
+    linear_counter: u8,
+    linear_reload_value: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    /// $4008: CRRR RRRR (length halt/linear counter control, linear counter
+    /// reload value).
+    pub fn write_linear(&mut self, value: u8) {
+        self.control = value & 0x80 > 0;
+        self.length_counter.set_halt(self.control);
+        self.linear_reload_value = value & 0x7F;
+    }
+
+    /// $400A: Low 8 bits of the timer period.
+    pub fn write_timer_low(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x0700) | value as u16;
+    }
+
+    /// $400B: LLLL LTTT (length counter load, high 3 bits of timer).
+    pub fn write_timer_high(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0x07) << 8);
+        self.length_counter.load(value >> 3);
+        self.linear_reload = true;
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length_counter.set_enabled(enabled);
+    }
+
+    /// Whether the length counter is nonzero (reported via $4015).
+    pub fn active(&self) -> bool {
+        self.length_counter.active()
+    }
+
+    /// Clock the channel's timer. The triangle timer is clocked on every CPU
+    /// cycle, which is why it plays an octave lower than a pulse channel with
+    /// the same period.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.linear_counter > 0 && self.length_counter.active() {
+                self.sequence_pos = (self.sequence_pos + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length_counter.clock();
+    }
+
+    /// The triangle channel has no volume control, so its linear counter is
+    /// reported in place of the volume.
+    pub fn state(&self) -> ChannelState {
+        ChannelState {
+            period: self.timer_period,
+            volume: self.linear_counter,
+            length: self.length_counter.value() as u16,
+        }
+    }
+
+    /// Current 4-bit output level. Note that silencing the triangle channel
+    /// just stops the sequencer, so it continues to output its last value.
+    pub fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.sequence_pos as usize]
+    }
+}
+
+impl Savestate for Triangle {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.control);
+        w.write(&self.sequence_pos);
+        w.write(&self.timer_period);
+        w.write(&self.timer);
+        self.length_counter.save_state(w);
+        w.write(&self.linear_counter);
+        w.write(&self.linear_reload_value);
+        w.write(&self.linear_reload);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.control = r.read()?;
+        self.sequence_pos = r.read()?;
+        self.timer_period = r.read()?;
+        self.timer = r.read()?;
+        self.length_counter.load_state(r)?;
+        self.linear_counter = r.read()?;
+        self.linear_reload_value = r.read()?;
+        self.linear_reload = r.read()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+//! Debug view of the APU's channels.
+//!
+//! Each channel gets a row containing an oscilloscope plot of its recent
+//! output, followed by three meters showing (from left to right) its current
+//! volume, timer period (on a log scale, so higher bars mean lower pitches),
+//! and length counter. Muted channels are drawn dimmed.
+
+use std::collections::VecDeque;
+
+use crate::apu::{Channel, ChannelState};
+
+pub const VIEW_WIDTH: usize = 320;
+pub const VIEW_HEIGHT: usize = 240;
+
+const SCOPE_WIDTH: usize = 256;
+const ROW_HEIGHT: usize = VIEW_HEIGHT / 5;
+const METER_WIDTH: usize = 16;
+const METER_GAP: usize = 4;
+
+/// Number of recent levels to keep for each channel. Only the last
+/// `SCOPE_WIDTH` are shown at a time, but keeping more allows the plot to be
+/// aligned to a rising edge so that periodic waveforms appear stationary.
+const HISTORY_LEN: usize = 4 * SCOPE_WIDTH;
+
+const BACKGROUND: [u8; 4] = [0x10, 0x10, 0x10, 0xFF];
+const GRID: [u8; 4] = [0x30, 0x30, 0x30, 0xFF];
+
+const CHANNEL_COLORS: [[u8; 4]; 5] = [
+    [0xF8, 0x58, 0x58, 0xFF],
+    [0xF8, 0xB8, 0x40, 0xFF],
+    [0x58, 0xD8, 0x54, 0xFF],
+    [0x3C, 0xBC, 0xFC, 0xFF],
+    [0xD8, 0x78, 0xF8, 0xFF],
+];
+
+/// Maximum output level of each channel.
+const MAX_LEVEL: [u8; 5] = [15, 15, 15, 15, 127];
+
+/// Maximum length counter value of each channel. For the DMC, this is the
+/// longest possible sample in bytes.
+const MAX_LENGTH: [u16; 5] = [254, 254, 254, 254, 4081];
+
+/// Maximum timer period of each channel.
+const MAX_PERIOD: [u16; 5] = [0x7FF, 0x7FF, 0x7FF, 4068, 428];
+
+#[derive(Default)]
+pub struct ApuView {
+    history: VecDeque<[u8; 5]>,
+}
+
+impl ApuView {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add channel levels captured from the APU (see `Apu::take_scope`).
+    pub fn push(&mut self, levels: &[[u8; 5]]) {
+        self.history.extend(levels);
+        let excess = self.history.len().saturating_sub(HISTORY_LEN);
+        self.history.drain(..excess);
+    }
+
+    /// Render the view into a frame of `VIEW_WIDTH` x `VIEW_HEIGHT` pixels.
+    pub fn draw(&self, frame: &mut [u8], states: &[ChannelState; 5], audible: &[bool; 5]) {
+        for pixel in frame.chunks_exact_mut(4) {
+            pixel.copy_from_slice(&BACKGROUND);
+        }
+
+        for i in 0..Channel::ALL.len() {
+            let top = i * ROW_HEIGHT;
+            let mut color = CHANNEL_COLORS[i];
+            if !audible[i] {
+                for c in &mut color[..3] {
+                    *c /= 3;
+                }
+            }
+
+            // Separate the rows, and mark the boundary between the plot and
+            // the meters.
+            for x in 0..VIEW_WIDTH {
+                set_pixel(frame, x, top, GRID);
+            }
+            for y in top..top + ROW_HEIGHT {
+                set_pixel(frame, SCOPE_WIDTH, y, GRID);
+            }
+
+            self.draw_scope(frame, i, top, color);
+
+            let state = &states[i];
+            let period =
+                ((state.period as f32 + 1.0).log2() / (MAX_PERIOD[i] as f32 + 1.0).log2()).min(1.0);
+            let meters = [
+                state.volume as f32 / MAX_LEVEL[i] as f32,
+                period,
+                state.length as f32 / MAX_LENGTH[i] as f32,
+            ];
+            for (j, &fraction) in meters.iter().enumerate() {
+                let left = SCOPE_WIDTH + METER_GAP + j * (METER_WIDTH + METER_GAP);
+                draw_meter(frame, left, top, fraction, color);
+            }
+        }
+    }
+
+    /// Plot the recent output of the given channel.
+    fn draw_scope(&self, frame: &mut [u8], channel: usize, top: usize, color: [u8; 4]) {
+        if self.history.len() < SCOPE_WIDTH {
+            return;
+        }
+
+        let start = self.trigger(channel);
+        let level_y = |level: u8| {
+            let height = ROW_HEIGHT - 4;
+            top + 2 + height - level as usize * height / MAX_LEVEL[channel] as usize
+        };
+
+        let mut prev_y = level_y(self.history[start][channel]);
+        for x in 0..SCOPE_WIDTH {
+            // Connect each point to the previous one with a vertical line so
+            // that sharp edges are visible.
+            let y = level_y(self.history[start + x][channel]);
+            for y in prev_y.min(y)..=prev_y.max(y) {
+                set_pixel(frame, x, y, color);
+            }
+            prev_y = y;
+        }
+    }
+
+    /// Find where the plot of the given channel should start: at the most
+    /// recent point where the channel's output rises through the midpoint of
+    /// its range (like an oscilloscope's trigger), or just the last
+    /// `SCOPE_WIDTH` levels if there is no such point.
+    fn trigger(&self, channel: usize) -> usize {
+        let latest = self.history.len() - SCOPE_WIDTH;
+        let (min, max) = self
+            .history
+            .iter()
+            .map(|levels| levels[channel])
+            .fold((u8::MAX, 0), |(min, max), level| {
+                (min.min(level), max.max(level))
+            });
+        if min == max {
+            return latest;
+        }
+
+        let mid = (min as u16 + max as u16).div_ceil(2);
+        (1..=latest)
+            .rev()
+            .find(|&i| {
+                (self.history[i - 1][channel] as u16) < mid
+                    && self.history[i][channel] as u16 >= mid
+            })
+            .unwrap_or(latest)
+    }
+}
+
+/// Draw a vertical bar filled to the given fraction of the row's height.
+fn draw_meter(frame: &mut [u8], left: usize, top: usize, fraction: f32, color: [u8; 4]) {
+    let height = ROW_HEIGHT - 4;
+    let filled = (fraction.clamp(0.0, 1.0) * height as f32).round() as usize;
+    let bottom = top + 2 + height;
+    for y in top + 2..bottom {
+        let color = if y >= bottom - filled { color } else { GRID };
+        for x in left..left + METER_WIDTH {
+            set_pixel(frame, x, y, color);
+        }
+    }
+}
+
+fn set_pixel(frame: &mut [u8], x: usize, y: usize, color: [u8; 4]) {
+    let i = (y * VIEW_WIDTH + x) * 4;
+    frame[i..i + 4].copy_from_slice(&color);
+}
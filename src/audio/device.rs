@@ -0,0 +1,271 @@
+//! Playback on the host's audio device.
+//!
+//! The emulator produces audio samples in bursts (once per emulated frame),
+//! whereas the host's audio device consumes them at a steady rate from a
+//! separate thread. Samples are passed between the two via a shared buffer.
+//!
+//! Since the emulated NES runs at a slightly different rate than the host's
+//! display and audio clocks, the buffer would slowly drain or fill up if the
+//! samples were generated at exactly the nominal output rate, eventually
+//! causing audible crackles (on underrun) or ever-increasing latency (on
+//! overrun). To avoid this, `RateControl` implements "dynamic rate control":
+//! the output sample rate is continuously nudged by a tiny amount based on the
+//! buffer's fill level so that it hovers around the target latency. The pitch
+//! change this causes is far too small to be audible.
+//!
+//! Playback requires the `audio` feature (which depends on cpal). Without it,
+//! `AudioOutput::open` always fails.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+
+use super::AudioSink;
+
+/// Amount of audio to keep buffered by default, in milliseconds.
+const DEFAULT_LATENCY_MS: u32 = 50;
+
+/// Least amount of audio to keep buffered, in milliseconds, whatever the
+/// requested latency.
+const MIN_LATENCY_MS: u32 = 5;
+
+/// Maximum amount by which dynamic rate control may adjust the sample rate,
+/// as a fraction of the nominal rate.
+const MAX_RATE_DELTA: f64 = 0.005;
+
+/// Buffer of samples shared between the emulator and the audio thread.
+type SampleBuffer = Arc<Mutex<VecDeque<f32>>>;
+
+/// Settings for the audio output stream.
+///
+/// Lower latency makes the audio more responsive, but leaves less slack for
+/// the emulator to fall behind before the buffer runs dry and the audio
+/// crackles. The device's buffer size determines how much audio the device
+/// requests at a time; the target latency is always at least two device
+/// buffers, since otherwise a single request could drain the buffer, and
+/// never less than `MIN_LATENCY_MS`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AudioOptions {
+    /// Amount of audio to keep buffered, in milliseconds.
+    pub latency_ms: Option<u32>,
+    /// Size of the device's buffer, in sample frames. If unset, or if the
+    /// device doesn't support the requested size, the device's default is
+    /// used.
+    pub buffer_size: Option<u32>,
+}
+
+/// Handle to an audio output stream on the host's default audio device.
+pub struct AudioOutput {
+    buffer: SampleBuffer,
+    sample_rate: u32,
+    target_len: usize,
+    rate_control: RateControl,
+    _stream: Stream,
+}
+
+impl AudioOutput {
+    /// Open an output stream on the host's default audio device.
+    pub fn open(options: AudioOptions) -> Result<Self> {
+        let buffer = SampleBuffer::default();
+        let (stream, sample_rate, buffer_size) = open_stream(buffer.clone(), options.buffer_size)?;
+
+        let latency_ms = options.latency_ms.unwrap_or(DEFAULT_LATENCY_MS);
+        let mut target_len = samples_for(sample_rate, latency_ms);
+        let min_len = samples_for(sample_rate, MIN_LATENCY_MS).max(1);
+        if target_len < min_len {
+            log::warn!(
+                "Audio latency of {} ms is too low; using {} ms",
+                latency_ms,
+                MIN_LATENCY_MS
+            );
+            target_len = min_len;
+        }
+        if let Some(buffer_size) = buffer_size {
+            let min_len = 2 * buffer_size as usize;
+            if target_len < min_len {
+                log::warn!(
+                    "Audio latency of {} ms is too low for buffer size {}; using {} ms",
+                    latency_ms,
+                    buffer_size,
+                    min_len as u64 * 1000 / sample_rate as u64
+                );
+                target_len = min_len;
+            }
+        }
+
+        Ok(Self {
+            buffer,
+            sample_rate,
+            target_len,
+            rate_control: RateControl::new(sample_rate),
+            _stream: stream,
+        })
+    }
+
+    /// Fraction of the buffer that is currently filled. A value of 0.5 means
+    /// the buffer contains exactly the target amount of audio.
+    fn fill_level(&self) -> f64 {
+        let len = self.buffer.lock().unwrap().len();
+        len as f64 / (2 * self.target_len) as f64
+    }
+}
+
+impl AudioSink for AudioOutput {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Queue samples for playback.
+    fn push(&mut self, samples: &[f32]) -> Result<()> {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.extend(samples);
+        Ok(())
+    }
+
+    /// The rate at which the emulator should currently generate samples,
+    /// taking dynamic rate control into account.
+    fn adjusted_sample_rate(&self) -> f64 {
+        self.rate_control.adjusted_rate(self.fill_level())
+    }
+
+    fn buffered(&self) -> Option<Duration> {
+        let len = self.buffer.lock().unwrap().len();
+        Some(Duration::from_secs_f64(
+            len as f64 / self.sample_rate as f64,
+        ))
+    }
+
+    fn is_full(&self) -> bool {
+        self.fill_level() >= 1.0
+    }
+}
+
+/// Number of samples that play in the given number of milliseconds.
+fn samples_for(sample_rate: u32, ms: u32) -> usize {
+    (sample_rate as u64 * ms as u64 / 1000) as usize
+}
+
+#[cfg(feature = "audio")]
+type Stream = cpal::Stream;
+
+#[cfg(not(feature = "audio"))]
+type Stream = ();
+
+/// Start a stream on the default output device that plays samples from the
+/// given buffer, using the requested buffer size if the device supports it.
+/// Returns the stream along with its sample rate and the buffer size that was
+/// actually used (if known).
+#[cfg(feature = "audio")]
+fn open_stream(
+    buffer: SampleBuffer,
+    buffer_size: Option<u32>,
+) -> Result<(Stream, u32, Option<u32>)> {
+    use anyhow::{anyhow, bail};
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use cpal::{BufferSize, SampleFormat, SupportedBufferSize};
+
+    let host = cpal::default_host();
+    let device = host
+        .default_output_device()
+        .ok_or_else(|| anyhow!("No audio output device available"))?;
+    let config = device.default_output_config()?;
+    log::info!(
+        "Opening audio device {:?} with config {:?}",
+        device.name(),
+        &config
+    );
+
+    let buffer_size = buffer_size.filter(|&size| match config.buffer_size() {
+        SupportedBufferSize::Range { min, max } if (*min..=*max).contains(&size) => true,
+        supported => {
+            log::warn!(
+                "Audio buffer size {} not supported by device (supported: {:?}); using default",
+                size,
+                supported
+            );
+            false
+        }
+    });
+
+    let sample_rate = config.sample_rate().0;
+    let sample_format = config.sample_format();
+    let mut stream_config: cpal::StreamConfig = config.into();
+    if let Some(size) = buffer_size {
+        stream_config.buffer_size = BufferSize::Fixed(size);
+    }
+
+    let stream = match sample_format {
+        SampleFormat::F32 => build_stream::<f32>(&device, &stream_config, buffer)?,
+        SampleFormat::I16 => build_stream::<i16>(&device, &stream_config, buffer)?,
+        SampleFormat::U16 => build_stream::<u16>(&device, &stream_config, buffer)?,
+        format => bail!("Unsupported audio sample format: {:?}", format),
+    };
+    stream.play()?;
+
+    Ok((stream, sample_rate, buffer_size))
+}
+
+#[cfg(not(feature = "audio"))]
+fn open_stream(
+    _buffer: SampleBuffer,
+    _buffer_size: Option<u32>,
+) -> Result<(Stream, u32, Option<u32>)> {
+    anyhow::bail!("Audio support not enabled (rebuild with `--features audio`)")
+}
+
+#[cfg(feature = "audio")]
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    buffer: SampleBuffer,
+) -> Result<Stream>
+where
+    T: cpal::SizedSample + cpal::FromSample<f32>,
+{
+    use cpal::traits::DeviceTrait;
+
+    let channels = config.channels as usize;
+    let mut last = 0.0;
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            let mut buffer = buffer.lock().unwrap();
+            for frame in data.chunks_mut(channels) {
+                // On underrun, repeat the last sample rather than dropping to
+                // zero, which would cause an audible click.
+                last = buffer.pop_front().unwrap_or(last);
+                for sample in frame.iter_mut() {
+                    *sample = T::from_sample(last);
+                }
+            }
+        },
+        |e| log::error!("Audio stream error: {}", e),
+        None,
+    )?;
+    Ok(stream)
+}
+
+/// Dynamic rate control. Computes the sample rate that the emulator should
+/// generate audio at in order to keep the output buffer at its target level.
+struct RateControl {
+    nominal_rate: f64,
+}
+
+impl RateControl {
+    fn new(nominal_rate: u32) -> Self {
+        Self {
+            nominal_rate: nominal_rate as f64,
+        }
+    }
+
+    /// Given the current fill level of the output buffer (see
+    /// `AudioOutput::fill_level`), return the adjusted sample rate. When the
+    /// buffer is more than half full, the rate is lowered so that fewer
+    /// samples are produced, and vice versa.
+    fn adjusted_rate(&self, fill_level: f64) -> f64 {
+        let fill_level = fill_level.clamp(0.0, 1.0);
+        self.nominal_rate * (1.0 + MAX_RATE_DELTA * (1.0 - 2.0 * fill_level))
+    }
+}
@@ -0,0 +1,63 @@
+//! Audio output.
+//!
+//! The APU generates a stream of samples, which the emulator sends to an
+//! `AudioSink` at the end of each frame. The sink decides what to do with
+//! them: play them on the host's audio device (see `device`), write them to a
+//! WAV file (see `wav`), or simply discard them (`NullSink`). This keeps the
+//! emulator itself independent of any particular audio library, and allows
+//! frontends to plug in their own audio output.
+
+use std::time::Duration;
+
+use anyhow::Result;
+
+pub use device::{AudioOptions, AudioOutput};
+pub use speed::SpeedAdapter;
+pub use wav::AudioRecorder;
+
+use crate::apu::DEFAULT_SAMPLE_RATE;
+
+mod device;
+mod speed;
+mod wav;
+
+/// Destination for the samples generated by the APU.
+pub trait AudioSink {
+    /// The nominal rate at which the sink expects samples to be generated.
+    fn sample_rate(&self) -> u32;
+
+    /// Consume a batch of samples.
+    fn push(&mut self, samples: &[f32]) -> Result<()>;
+
+    /// The rate at which samples should currently be generated. Sinks that
+    /// play audio in real time may adjust this slightly from the nominal rate
+    /// to keep their buffers from overflowing or running dry.
+    fn adjusted_sample_rate(&self) -> f64 {
+        self.sample_rate() as f64
+    }
+
+    /// Amount of audio waiting to be played, for sinks that play in real
+    /// time.
+    fn buffered(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Whether the sink has fallen behind, in which case the emulator should
+    /// wait rather than generating more audio.
+    fn is_full(&self) -> bool {
+        false
+    }
+}
+
+/// Sink that discards all samples, for running without audio.
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn sample_rate(&self) -> u32 {
+        DEFAULT_SAMPLE_RATE
+    }
+
+    fn push(&mut self, _samples: &[f32]) -> Result<()> {
+        Ok(())
+    }
+}
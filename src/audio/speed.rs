@@ -0,0 +1,81 @@
+/// Length of the fade used to smooth over the gaps left by dropped audio, in
+/// samples (about 1.5 ms at typical sample rates).
+const FADE_LEN: usize = 64;
+
+/// Adapts the emulator's audio to the current emulation speed.
+///
+/// When the emulator runs faster than real time (e.g., while fast-forwarding),
+/// it generates audio faster than it can be played. Simply playing all of it
+/// would cause the audio to fall further and further behind, while playing
+/// it back faster would raise its pitch into a garbled squeal. Instead, this
+/// drops whole frames of audio so that the remaining frames play at their
+/// normal pitch. At 2x speed, for example, every other frame is dropped. Each
+/// frame following a gap is faded in from the last sample that was played to
+/// avoid a click at the discontinuity.
+///
+/// Since frames are dropped rather than resampled, the amount of audio passed
+/// on per unit of emulated time is proportional to `1 / speed`. This means the
+/// audio sink's buffer naturally throttles the emulator to the desired speed.
+///
+/// When the emulator runs slower than real time, there isn't enough audio to
+/// keep playing, so all of it is dropped rather than letting it stutter.
+pub struct SpeedAdapter {
+    speed: f64,
+    /// Fraction of a frame that may be passed through. Each frame adds
+    /// `1 / speed`, and each frame passed through uses up 1.
+    credit: f64,
+    last_sample: f32,
+    dropped: bool,
+}
+
+impl SpeedAdapter {
+    pub fn new() -> Self {
+        Self {
+            speed: 1.0,
+            credit: 0.0,
+            last_sample: 0.0,
+            dropped: false,
+        }
+    }
+
+    /// Set the current emulation speed, as a multiple of real time. At 1x,
+    /// the audio is left untouched.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+        self.credit = 0.0;
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Process a single frame of audio, returning the samples that should be
+    /// played (which may be none).
+    pub fn process(&mut self, mut samples: Vec<f32>) -> Vec<f32> {
+        if self.speed < 1.0 {
+            self.dropped |= !samples.is_empty();
+            return Vec::new();
+        }
+        if self.speed > 1.0 {
+            self.credit += 1.0 / self.speed;
+            if self.credit < 1.0 {
+                self.dropped |= !samples.is_empty();
+                return Vec::new();
+            }
+            self.credit -= 1.0;
+        }
+
+        if self.dropped {
+            let len = samples.len().min(FADE_LEN);
+            for (i, sample) in samples[..len].iter_mut().enumerate() {
+                let t = i as f32 / FADE_LEN as f32;
+                *sample = self.last_sample + (*sample - self.last_sample) * t;
+            }
+            self.dropped = false;
+        }
+        if let Some(&last) = samples.last() {
+            self.last_sample = last;
+        }
+        samples
+    }
+}
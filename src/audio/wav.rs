@@ -0,0 +1,166 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use super::AudioSink;
+use crate::apu::Channel;
+
+/// Size of the RIFF/WAVE header written by `WavWriter`.
+const HEADER_SIZE: u32 = 44;
+
+/// Writes mono 16-bit PCM WAV files.
+///
+/// The header contains the size of the audio data, which isn't known until
+/// recording has finished. A placeholder header is written up front and then
+/// patched with the actual sizes by `WavWriter::finish` (or when the writer is
+/// dropped, if it wasn't finished explicitly).
+pub struct WavWriter {
+    file: BufWriter<File>,
+    sample_rate: u32,
+    num_samples: u32,
+    finished: bool,
+}
+
+impl WavWriter {
+    pub fn create(path: impl AsRef<Path>, sample_rate: u32) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path).with_context(|| format!("Failed to create {:?}", path))?;
+        let mut writer = Self {
+            file: BufWriter::new(file),
+            sample_rate,
+            num_samples: 0,
+            finished: false,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let data_size = self.num_samples * 2;
+        let byte_rate = self.sample_rate * 2;
+
+        let f = &mut self.file;
+        f.write_all(b"RIFF")?;
+        f.write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        f.write_all(b"WAVE")?;
+
+        f.write_all(b"fmt ")?;
+        f.write_all(&16u32.to_le_bytes())?; // Size of fmt chunk.
+        f.write_all(&1u16.to_le_bytes())?; // PCM format.
+        f.write_all(&1u16.to_le_bytes())?; // Mono.
+        f.write_all(&self.sample_rate.to_le_bytes())?;
+        f.write_all(&byte_rate.to_le_bytes())?;
+        f.write_all(&2u16.to_le_bytes())?; // Bytes per sample frame.
+        f.write_all(&16u16.to_le_bytes())?; // Bits per sample.
+
+        f.write_all(b"data")?;
+        f.write_all(&data_size.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Append samples in the range [-1.0, 1.0] to the file.
+    pub fn write(&mut self, samples: &[f32]) -> Result<()> {
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+        self.num_samples += samples.len() as u32;
+        Ok(())
+    }
+
+    /// Fill in the final sizes in the header and flush the file to disk.
+    pub fn finish(mut self) -> Result<()> {
+        self.finalize()
+    }
+
+    fn finalize(&mut self) -> Result<()> {
+        self.finished = true;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = self.finalize() {
+                log::error!("Failed to finish writing WAV file: {}", e);
+            }
+        }
+    }
+}
+
+/// Records the APU's mixed output to a WAV file, optionally along with the
+/// output of each individual channel (written to separate files alongside the
+/// main one, e.g. `music.wav` and `music.pulse1.wav`).
+///
+/// The recorder can also be used as an `AudioSink` in its own right (e.g., to
+/// capture audio from a headless run), in which case only the mixed output is
+/// recorded.
+pub struct AudioRecorder {
+    path: PathBuf,
+    sample_rate: u32,
+    mix: WavWriter,
+    channels: Option<Vec<WavWriter>>,
+}
+
+impl AudioRecorder {
+    pub fn create(path: impl AsRef<Path>, sample_rate: u32, per_channel: bool) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mix = WavWriter::create(&path, sample_rate)?;
+        let channels = if per_channel {
+            let writers = Channel::ALL
+                .iter()
+                .map(|channel| {
+                    let channel_path = path.with_extension(format!("{}.wav", channel.name()));
+                    WavWriter::create(channel_path, sample_rate)
+                })
+                .collect::<Result<_>>()?;
+            Some(writers)
+        } else {
+            None
+        };
+        log::info!("Recording audio to {:?}", &path);
+        Ok(Self {
+            path,
+            sample_rate,
+            mix,
+            channels,
+        })
+    }
+
+    /// Write the mixed samples and, if recording them, the samples for each
+    /// channel (in the order given by `Channel::ALL`).
+    pub fn write(&mut self, mix: &[f32], channels: Option<&[Vec<f32>]>) -> Result<()> {
+        self.mix.write(mix)?;
+        if let (Some(writers), Some(channels)) = (&mut self.channels, channels) {
+            for (writer, samples) in writers.iter_mut().zip(channels) {
+                writer.write(samples)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        self.mix.finish()?;
+        for writer in self.channels.into_iter().flatten() {
+            writer.finish()?;
+        }
+        log::info!("Finished recording audio to {:?}", &self.path);
+        Ok(())
+    }
+}
+
+impl AudioSink for AudioRecorder {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn push(&mut self, samples: &[f32]) -> Result<()> {
+        self.write(samples, None)
+    }
+}
@@ -0,0 +1,156 @@
+//! Rolling capture of the last few seconds of the game, which can be saved as
+//! an animated GIF with a hotkey (e.g., to share a bug or a good moment)
+//! without having to record continuously.
+//!
+//! Every other frame is kept, since GIF delays are in hundredths of a second
+//! and many viewers won't play them any faster than that allows anyway.
+//! Frames are converted to indexed color as they're captured, which takes a
+//! quarter of the memory of RGBA. The PPU rarely shows more than a few dozen
+//! colors in a frame, so this is lossless unless a game changes the color
+//! emphasis bits many times in one frame.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use anyhow::{Context, Result};
+
+use crate::ppu::{FRAME_HEIGHT, FRAME_WIDTH};
+
+/// The maximum number of colors in a GIF frame's palette.
+const MAX_COLORS: usize = 256;
+
+/// A frame in indexed color, with its palette as RGB triples.
+#[derive(Clone)]
+struct ClipFrame {
+    pixels: Vec<u8>,
+    palette: Vec<u8>,
+}
+
+impl ClipFrame {
+    fn new(rgba: &[u8]) -> Self {
+        let mut colors: Vec<[u8; 3]> = Vec::new();
+        let pixels = rgba
+            .chunks_exact(4)
+            .map(|pixel| {
+                let color = [pixel[0], pixel[1], pixel[2]];
+                match colors.iter().position(|&c| c == color) {
+                    Some(i) => i as u8,
+                    None if colors.len() < MAX_COLORS => {
+                        colors.push(color);
+                        (colors.len() - 1) as u8
+                    }
+                    None => 0,
+                }
+            })
+            .collect();
+        Self {
+            pixels,
+            palette: colors.concat(),
+        }
+    }
+}
+
+/// The most recent frames, up to a fixed number of seconds.
+pub struct ClipBuffer {
+    frames: VecDeque<ClipFrame>,
+    capacity: usize,
+    /// Number of frames kept per second.
+    frame_rate: f64,
+    /// Whether to skip the next frame.
+    skip: bool,
+}
+
+impl ClipBuffer {
+    /// Keep the given number of seconds of frames from a console producing
+    /// frames at the given rate.
+    pub fn new(seconds: f64, console_frame_rate: f64) -> Self {
+        let frame_rate = console_frame_rate / 2.0;
+        Self {
+            frames: VecDeque::new(),
+            capacity: (seconds * frame_rate).round() as usize,
+            frame_rate,
+            skip: false,
+        }
+    }
+
+    /// Capture an RGBA frame, dropping the oldest one if the buffer is full.
+    pub fn push(&mut self, frame: &[u8]) {
+        self.skip = !self.skip;
+        if !self.skip || self.capacity == 0 {
+            return;
+        }
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(ClipFrame::new(frame));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Save the captured frames to a GIF file. Encoding takes a while, so
+    /// it's done on another thread, which logs the result.
+    pub fn save(&self, path: PathBuf) {
+        let frames: Vec<ClipFrame> = self.frames.iter().cloned().collect();
+        let frame_rate = self.frame_rate;
+        thread::spawn(move || match write_gif(&path, &frames, frame_rate) {
+            Ok(()) => log::info!("Saved clip to {:?}", &path),
+            Err(e) => log::error!("Failed to save clip: {:#}", e),
+        });
+    }
+}
+
+fn write_gif(path: &Path, frames: &[ClipFrame], frame_rate: f64) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create {:?}", path))?;
+    let mut encoder = gif::Encoder::new(
+        BufWriter::new(file),
+        FRAME_WIDTH as u16,
+        FRAME_HEIGHT as u16,
+        &[],
+    )?;
+    encoder.set_repeat(gif::Repeat::Infinite)?;
+    for (i, frame) in frames.iter().enumerate() {
+        let mut gif_frame = gif::Frame::from_palette_pixels(
+            FRAME_WIDTH as u16,
+            FRAME_HEIGHT as u16,
+            &frame.pixels[..],
+            &frame.palette[..],
+            None,
+        );
+        gif_frame.delay = frame_delay(i, frame_rate);
+        encoder.write_frame(&gif_frame)?;
+    }
+    Ok(())
+}
+
+/// Delay after the given frame in hundredths of a second, rounded so that the
+/// total time stays in step with the frame rate.
+fn frame_delay(i: usize, frame_rate: f64) -> u16 {
+    let end = |i: usize| (i as f64 * 100.0 / frame_rate).round() as u16;
+    end(i + 1) - end(i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clip_buffer() {
+        let mut clip = ClipBuffer::new(1.0, 60.0);
+        let frame = vec![0x20; FRAME_WIDTH * FRAME_HEIGHT * 4];
+        for _ in 0..100 {
+            clip.push(&frame);
+        }
+        assert_eq!(clip.frames.len(), 30);
+        assert_eq!(clip.frames[0].palette, [0x20, 0x20, 0x20]);
+        assert!(clip.frames[0].pixels.iter().all(|&p| p == 0));
+
+        // 30 frames per second alternate between 3 and 4 hundredths.
+        let delays: Vec<u16> = (0..3).map(|i| frame_delay(i, 30.0)).collect();
+        assert_eq!(delays, [3, 4, 3]);
+    }
+}
@@ -0,0 +1,356 @@
+//! The RGB colors that the PPU's 6-bit color indexes are displayed as.
+//!
+//! In a real NES, the PPU directly outputs an analog video signal, which means
+//! that there is no exact mapping between color indexes and RGB values. A
+//! palette is just an approximation of what each color looked like on a TV,
+//! and people disagree on which is best, so several are available and can be
+//! switched between while a game runs:
+//!
+//! - FBX, the default, which was made to match a real console on a CRT.
+//! - NTSC, which is decoded from the PPU's signal levels the way a TV would.
+//! - Sony CXA, which decodes the signal the way the CXA2025AS chip in many
+//!   Sony TVs does, with its strong reds.
+//! - NESDev consensus, which decodes the signal levels measured on the
+//!   NESDev wiki with a textbook NTSC decoder and no adjustments.
+//! - Any palette files listed in the config.
+//!
+//! Each palette also comes in color-blind-friendly variants, which shift
+//! colors that would look alike to someone with a color vision deficiency
+//! towards colors they can tell apart.
+
+use std::f64::consts::PI;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context, Result};
+
+static FBX_COLORS: &[u8] = include_bytes!("../data/FBX-Final.pal");
+
+/// Size of a palette file: 64 colors of 3 bytes each. Some palette files also
+/// have the colors for each combination of emphasis bits after these, which
+/// are ignored.
+const PAL_FILE_SIZE: usize = 64 * 3;
+
+/// The RGB value of each of the 64 color indexes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Colors([[u8; 3]; 64]);
+
+impl Colors {
+    pub fn fbx() -> Self {
+        Self::from_bytes(FBX_COLORS)
+    }
+
+    /// Generate colors by decoding the PPU's composite video signal. Each
+    /// color is a square wave that is high for half of the 12 phases of the
+    /// color subcarrier, with its hue giving the phase it starts at and its
+    /// level giving the voltages it swings between.
+    pub fn ntsc() -> Self {
+        // Voltages of the signal's low and high points for each level, and of
+        // black and white, normalized so that sync is at 0.
+        const LOW: [f64; 4] = [0.350, 0.518, 0.962, 1.550];
+        const HIGH: [f64; 4] = [1.094, 1.506, 1.962, 1.962];
+        const BLACK: f64 = 0.518;
+        const WHITE: f64 = 1.962;
+        // The decoder's phase offset and saturation, which are tuned to
+        // roughly match the hue and saturation of the FBX palette.
+        const PHASE_OFFSET: f64 = 4.0;
+        const SATURATION: f64 = 0.75;
+
+        let mut colors = [[0; 3]; 64];
+        for (index, rgb) in colors.iter_mut().enumerate() {
+            let hue = index & 0xF;
+            let level = index >> 4;
+            let (low, high) = match hue {
+                0 => (HIGH[level], HIGH[level]),
+                1..=12 => (LOW[level], HIGH[level]),
+                13 => (LOW[level], LOW[level]),
+                _ => (BLACK, BLACK),
+            };
+
+            let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+            for phase in 0..12 {
+                let voltage = square_wave(hue, phase, low, high);
+                let v = (voltage - BLACK) / (WHITE - BLACK) / 12.0;
+                let angle = PI * (phase as f64 + PHASE_OFFSET) / 6.0;
+                y += v;
+                i += v * angle.cos() * 2.0 * SATURATION;
+                q += v * angle.sin() * 2.0 * SATURATION;
+            }
+
+            let r = y + 0.946882 * i + 0.623557 * q;
+            let g = y - 0.274788 * i - 0.635691 * q;
+            let b = y - 1.108545 * i + 1.709007 * q;
+            *rgb = [r, g, b].map(to_byte);
+        }
+        Self(colors)
+    }
+
+    /// Generate colors the way Sony's CXA2025AS decoder chip would display
+    /// them. It demodulates R-Y at a wider angle and with more gain than the
+    /// standard, which is what gives these colors their warmer reds.
+    pub fn sony_cxa() -> Self {
+        // Angles and gains from the "NTSC (US)" demodulation settings in the
+        // Sony CXA2025AS datasheet.
+        Self::decode([(112.0, 0.83), (252.0, 0.30), (0.0, 1.0)])
+    }
+
+    /// Generate colors by decoding the signal levels measured on the NESDev
+    /// wiki with a textbook NTSC decoder, with none of the hue or saturation
+    /// tweaks that most palettes add.
+    pub fn nesdev_consensus() -> Self {
+        // The standard axes: R-Y and B-Y at right angles, with the gains
+        // that undo the scaling of V (0.877) and U (0.493) in the encoder,
+        // and G-Y worked out from the other two.
+        Self::decode([(90.0, 0.562), (235.9, 0.346), (0.0, 1.0)])
+    }
+
+    /// Generate colors by decoding the PPU's signal with a decoder whose R-Y,
+    /// G-Y and B-Y demodulators are at the given angles (in degrees from the
+    /// B-Y axis), with the given gains relative to B-Y.
+    ///
+    /// The signal levels, in volts, and the phase of each hue relative to
+    /// the color burst (which is in phase with hue 8) are from the NESDev
+    /// wiki: https://www.nesdev.org/wiki/NTSC_video
+    fn decode(axes: [(f64, f64); 3]) -> Self {
+        const LOW: [f64; 4] = [0.228, 0.312, 0.552, 0.880];
+        const HIGH: [f64; 4] = [0.616, 0.840, 1.100, 1.100];
+        const BLACK: f64 = 0.312;
+        const WHITE: f64 = 1.100;
+        // Converts the chroma's amplitude to B-Y.
+        const B_Y_GAIN: f64 = 1.0 / 0.493;
+
+        let mut colors = [[0; 3]; 64];
+        for (index, rgb) in colors.iter_mut().enumerate() {
+            let hue = index & 0xF;
+            let level = index >> 4;
+            let (low, high) = match hue {
+                0 => (HIGH[level], HIGH[level]),
+                1..=12 => (LOW[level], HIGH[level]),
+                13 => (LOW[level], LOW[level]),
+                _ => (BLACK, BLACK),
+            };
+
+            let (mut y, mut u, mut v) = (0.0, 0.0, 0.0);
+            for phase in 0..12 {
+                let voltage = square_wave(hue, phase, low, high);
+                let level = (voltage - BLACK) / (WHITE - BLACK) / 12.0;
+                // Later phases are further behind the burst, which is at 180
+                // degrees from B-Y.
+                let angle = PI * (0.5 - phase as f64) / 6.0;
+                y += level;
+                u += level * angle.cos() * 2.0;
+                v += level * angle.sin() * 2.0;
+            }
+
+            *rgb = axes.map(|(angle, gain)| {
+                let angle = angle.to_radians();
+                to_byte(y + gain * B_Y_GAIN * (u * angle.cos() + v * angle.sin()))
+            });
+        }
+        Self(colors)
+    }
+
+    /// Load colors from a palette file, which holds 64 RGB colors of 3 bytes
+    /// each (as used by most emulators).
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        ensure!(
+            bytes.len() >= PAL_FILE_SIZE,
+            "Palette file is too small: expected {} bytes, got {}",
+            PAL_FILE_SIZE,
+            bytes.len()
+        );
+        Ok(Self::from_bytes(&bytes))
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut colors = [[0; 3]; 64];
+        for (rgb, bytes) in colors.iter_mut().zip(bytes.chunks_exact(3)) {
+            rgb.copy_from_slice(bytes);
+        }
+        Self(colors)
+    }
+
+    /// Adjust the colors for the given color vision deficiency.
+    ///
+    /// This uses daltonization: the colors are simulated as they would be
+    /// seen with the deficiency (using the model from Machado et al., 2009),
+    /// and the detail that is lost is shifted into the channels that can still
+    /// be seen.
+    pub fn corrected(&self, deficiency: ColorDeficiency) -> Self {
+        let m = deficiency.simulation();
+        let mut colors = self.0;
+        for rgb in &mut colors {
+            let color = rgb.map(|c| c as f64 / 255.0);
+            let simulated =
+                [0, 1, 2].map(|row| (0..3).map(|col| m[row][col] * color[col]).sum::<f64>());
+            let error = [0, 1, 2].map(|c| color[c] - simulated[c]);
+            let shift = [0.0, 0.7 * error[0] + error[1], 0.7 * error[0] + error[2]];
+            *rgb = [0, 1, 2].map(|c| to_byte(color[c] + shift[c]));
+        }
+        Self(colors)
+    }
+
+    /// Get the RGBA value of a color index.
+    pub fn rgba(&self, color: u8) -> [u8; 4] {
+        let [r, g, b] = self.0[(color & 0x3F) as usize];
+        [r, g, b, 0xFF]
+    }
+}
+
+/// Get the perceived brightness of a color index in the default (FBX)
+/// palette, whichever palette is being displayed. Emulated hardware that
+/// senses light, like the Zapper, uses this, so that games (and movies of
+/// them) play the same way with any palette.
+pub fn reference_luma(color: u8) -> u8 {
+    let i = (color & 0x3F) as usize * 3;
+    luma([FBX_COLORS[i], FBX_COLORS[i + 1], FBX_COLORS[i + 2]])
+}
+
+/// Perceived brightness of an RGB color, using the Rec. 601 weights.
+fn luma(rgb: [u8; 3]) -> u8 {
+    let [r, g, b] = rgb.map(|c| c as u32);
+    ((r * 299 + g * 587 + b * 114) / 1000) as u8
+}
+
+impl Default for Colors {
+    fn default() -> Self {
+        Self::fbx()
+    }
+}
+
+/// The voltage of the PPU's signal for a hue at one of the 12 phases of the
+/// color subcarrier: a square wave that is high for half of them, starting
+/// at a phase that depends on the hue.
+fn square_wave(hue: usize, phase: usize, low: f64, high: f64) -> f64 {
+    if (hue + phase) % 12 < 6 {
+        high
+    } else {
+        low
+    }
+}
+
+/// Convert a color channel from the range 0 to 1.
+fn to_byte(value: f64) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// Color vision deficiencies that palettes can be corrected for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColorDeficiency {
+    /// Red-blindness.
+    Protanopia,
+    /// Green-blindness, the most common.
+    Deuteranopia,
+    /// Blue-blindness.
+    Tritanopia,
+}
+
+impl ColorDeficiency {
+    pub const ALL: [ColorDeficiency; 3] = [
+        ColorDeficiency::Protanopia,
+        ColorDeficiency::Deuteranopia,
+        ColorDeficiency::Tritanopia,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ColorDeficiency::Protanopia => "Protanopia",
+            ColorDeficiency::Deuteranopia => "Deuteranopia",
+            ColorDeficiency::Tritanopia => "Tritanopia",
+        }
+    }
+
+    /// The matrix that transforms an RGB color into how it looks with this
+    /// deficiency.
+    fn simulation(self) -> [[f64; 3]; 3] {
+        match self {
+            ColorDeficiency::Protanopia => [
+                [0.152286, 1.052583, -0.204868],
+                [0.114503, 0.786281, 0.099216],
+                [-0.003882, -0.048116, 1.051998],
+            ],
+            ColorDeficiency::Deuteranopia => [
+                [0.367322, 0.860646, -0.227968],
+                [0.280085, 0.672501, 0.047413],
+                [-0.011820, 0.042940, 0.968881],
+            ],
+            ColorDeficiency::Tritanopia => [
+                [1.255528, -0.076749, -0.178779],
+                [-0.078411, 0.930809, 0.147602],
+                [0.004733, 0.691367, 0.303900],
+            ],
+        }
+    }
+}
+
+/// Get the palettes to choose from: the built-in palettes, followed by the
+/// given palette files (named after the files), each followed by its
+/// color-blind-friendly variants. Palette files that fail to load are
+/// skipped.
+pub fn palettes(files: &[PathBuf]) -> Vec<(String, Colors)> {
+    let mut bases = vec![
+        ("FBX".to_string(), Colors::fbx()),
+        ("NTSC".to_string(), Colors::ntsc()),
+        ("Sony CXA".to_string(), Colors::sony_cxa()),
+        ("NESDev consensus".to_string(), Colors::nesdev_consensus()),
+    ];
+    for path in files {
+        match Colors::load(path) {
+            Ok(colors) => {
+                let name = path.file_stem().unwrap_or_default();
+                bases.push((name.to_string_lossy().into_owned(), colors));
+            }
+            Err(e) => log::error!("Failed to load palette: {:#}", e),
+        }
+    }
+
+    let mut palettes = Vec::new();
+    for (name, colors) in bases {
+        let variants = ColorDeficiency::ALL.map(|deficiency| {
+            let variant = format!("{} ({})", name, deficiency.name());
+            (variant, colors.corrected(deficiency))
+        });
+        palettes.push((name, colors));
+        palettes.extend(variants);
+    }
+    palettes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_colors() {
+        let ntsc = Colors::ntsc();
+        assert_eq!(ntsc.rgba(0x0F), [0, 0, 0, 0xFF]);
+        assert_eq!(ntsc.rgba(0x30), [0xFF, 0xFF, 0xFF, 0xFF]);
+
+        // The CXA's demodulator pushes reds further than the standard one.
+        let (cxa, consensus) = (Colors::sony_cxa(), Colors::nesdev_consensus());
+        assert_eq!(cxa.rgba(0x0F), [0, 0, 0, 0xFF]);
+        assert_eq!(consensus.rgba(0x00), cxa.rgba(0x00));
+        assert!(cxa.rgba(0x16)[0] > consensus.rgba(0x16)[0]);
+
+        // Greys look the same with any deficiency, so they're left (nearly)
+        // as they are, but reds are shifted.
+        let fbx = Colors::fbx();
+        let corrected = fbx.corrected(ColorDeficiency::Deuteranopia);
+        for color in [0x00, 0x10, 0x20, 0x30] {
+            let diff = (0..3).map(|c| fbx.rgba(color)[c].abs_diff(corrected.rgba(color)[c]));
+            assert!(diff.max().unwrap() <= 2, "color {:02X}", color);
+        }
+        assert_ne!(fbx.rgba(0x16), corrected.rgba(0x16));
+
+        assert_eq!(reference_luma(0x0F), 0);
+        assert!(reference_luma(0x30) > 0xF0);
+        assert_eq!(reference_luma(0x70), reference_luma(0x30));
+
+        let names: Vec<String> = palettes(&[]).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names[..2], ["FBX", "FBX (Protanopia)"]);
+        assert_eq!(names[4], "NTSC");
+        assert_eq!(names[8], "Sony CXA");
+        assert_eq!(names[12], "NESDev consensus");
+    }
+}
@@ -0,0 +1,233 @@
+//! User configuration.
+//!
+//! Settings are read from a TOML file, which is either given explicitly on the
+//! command line or found at the default location in the platform's config
+//! directory (e.g., `~/.config/nes/config.toml` on Linux). All settings are
+//! optional; missing settings take their default values.
+
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::apu::{Channel, FilterStage};
+use crate::controller::DeviceKind;
+use crate::input::Bindings;
+use crate::ui::Pacing;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub audio: AudioConfig,
+    pub saves: SaveConfig,
+    pub bindings: Bindings,
+    pub input: InputConfig,
+    pub emulation: EmulationConfig,
+    pub screenshots: ScreenshotConfig,
+    pub video: VideoConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    /// Channels that start out muted.
+    pub mute: Vec<Channel>,
+    /// Channels that start out soloed. If any channels are soloed, all of the
+    /// other channels are silenced.
+    pub solo: Vec<Channel>,
+    /// Analog output filters to emulate, in the order they're applied.
+    /// Defaults to the filters present in the hardware; set this to an empty
+    /// list to hear the unfiltered output of the mixer.
+    pub filters: Vec<FilterStage>,
+    /// Amount of audio to keep buffered, in milliseconds.
+    pub latency_ms: Option<NonZeroU32>,
+    /// Size of the audio device's buffer, in sample frames.
+    pub buffer_size: Option<NonZeroU32>,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            mute: Vec::new(),
+            solo: Vec::new(),
+            filters: FilterStage::HARDWARE.to_vec(),
+            latency_ms: None,
+            buffer_size: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SaveConfig {
+    /// Directory for battery-backed save files. Defaults to storing each
+    /// save file next to its ROM.
+    pub dir: Option<PathBuf>,
+    /// Directory for save states, which holds a subdirectory for each game.
+    /// Defaults to a directory in the platform's data directory.
+    pub state_dir: Option<PathBuf>,
+    /// Save the state when the emulator exits, and resume from it the next
+    /// time the same game is started.
+    pub auto_resume: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputConfig {
+    /// The device plugged into controller port 2.
+    pub port2: DeviceKind,
+    /// Plug a Four Score into both ports, for up to 4 players.
+    pub four_score: bool,
+    /// Show the buttons held on each controller over the game (toggle with
+    /// F8).
+    pub overlay: bool,
+    /// Release opposing directions (Up and Down, or Left and Right) that are
+    /// held at once, as a real controller can't press both. Turn this off to
+    /// let TASes use the glitches this causes.
+    pub filter_opposing_directions: bool,
+    /// Sample the host's input when the game strobes the controllers, rather
+    /// than only at the start of each frame. This is turned off while a
+    /// movie is being played or recorded.
+    pub poll_on_strobe: bool,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        Self {
+            port2: DeviceKind::default(),
+            four_score: false,
+            overlay: false,
+            filter_opposing_directions: true,
+            poll_on_strobe: true,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmulationConfig {
+    /// Maximum speed while fast-forwarding, as a multiple of normal speed
+    /// (e.g., 2.0 or 4.0). Defaults to running as fast as possible, which
+    /// mutes the audio.
+    pub fast_forward_speed: Option<f64>,
+    /// Pause while the window isn't focused (e.g., when switching to another
+    /// program), and resume when it's focused again.
+    pub pause_on_focus_loss: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScreenshotConfig {
+    /// Directory to save screenshots and clips in. Defaults to the current
+    /// directory.
+    pub dir: Option<PathBuf>,
+    /// Also save the frame with the input overlay and any messages drawn
+    /// over it. The CRT filter isn't applied to it.
+    pub save_overlays: bool,
+    /// Number of seconds of the game to keep for saving as a clip. Set this
+    /// to 0 to turn clips off.
+    pub clip_seconds: f64,
+}
+
+impl Default for ScreenshotConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            save_overlays: false,
+            clip_seconds: 10.0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoConfig {
+    /// How to time frames: by the host's clock (`timer`), which runs games
+    /// at the right speed, or by the display's refresh (`vsync`).
+    pub pacing: Pacing,
+    /// Initial size of the window, as a multiple of the NES's resolution.
+    pub scale: u32,
+    /// Start with the CRT filter on, which draws scanlines over the picture.
+    pub crt: bool,
+    /// Name of the palette to start with (e.g., "NTSC" or "FBX
+    /// (Deuteranopia)"). Defaults to FBX. The palette hotkey (F3) cycles
+    /// through the rest.
+    pub palette: Option<String>,
+    /// Palette files to choose from as well as the built-in palettes, each
+    /// named after its file. These hold 64 RGB colors of 3 bytes each.
+    pub palettes: Vec<PathBuf>,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        Self {
+            pacing: Pacing::default(),
+            scale: 1,
+            crt: false,
+            palette: None,
+            palettes: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Load the config from the given path, or from the default location if
+    /// no path is given. It is not an error for the default config file to be
+    /// missing.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_path() {
+                Some(path) if path.is_file() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+
+        log::info!("Loading config: {:?}", &path);
+        let contents =
+            fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", &path))?;
+        toml::from_str(&contents).with_context(|| format!("Failed to parse {:?}", &path))
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("nes").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [audio]
+            mute = ["noise", "dmc"]
+            solo = ["pulse1"]
+            filters = ["low_pass_14k"]
+
+            [saves]
+            dir = "/tmp/saves"
+
+            [emulation]
+            fast_forward_speed = 4.0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.audio.mute, [Channel::Noise, Channel::Dmc]);
+        assert_eq!(config.audio.solo, [Channel::Pulse1]);
+        assert_eq!(config.audio.filters, [FilterStage::LowPass14k]);
+        assert_eq!(config.saves.dir.as_deref(), Some(Path::new("/tmp/saves")));
+        assert_eq!(config.emulation.fast_forward_speed, Some(4.0));
+
+        let config: Config = toml::from_str("").unwrap();
+        assert!(config.audio.mute.is_empty());
+        assert_eq!(config.audio.filters, FilterStage::HARDWARE);
+
+        assert!(toml::from_str::<Config>("[audio]\nlatency_ms = 0").is_err());
+        assert!(toml::from_str::<Config>("[audio]\nbuffer_size = 0").is_err());
+    }
+}
@@ -0,0 +1,128 @@
+//! The NES Four Score adapter, which lets four controllers share the two
+//! ports.
+//!
+//! Each port gets two controllers: players 1 and 3 on port 1, and players 2
+//! and 4 on port 2. After a strobe, each port reports 24 bits: the 8 buttons
+//! of its first controller, the 8 buttons of its second controller, and then
+//! a signature byte that lets games detect the adapter ($10 on port 1, and
+//! $20 on port 2, least significant bit first). Like a standard controller,
+//! it returns 1 after that.
+
+use anyhow::Result;
+
+use super::{Buttons, PortDevice, PortInput, Screen};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const PORT1_SIGNATURE: u8 = 0x10;
+const PORT2_SIGNATURE: u8 = 0x20;
+
+/// One port's half of a Four Score.
+#[derive(Debug)]
+pub struct FourScore {
+    signature: u8,
+    buttons: [Buttons; 2],
+    shift: u32,
+    strobe: bool,
+}
+
+impl FourScore {
+    /// The half of the adapter on port 1, for players 1 and 3.
+    pub fn port1() -> Self {
+        Self::new(PORT1_SIGNATURE)
+    }
+
+    /// The half of the adapter on port 2, for players 2 and 4.
+    pub fn port2() -> Self {
+        Self::new(PORT2_SIGNATURE)
+    }
+
+    fn new(signature: u8) -> Self {
+        Self {
+            signature,
+            buttons: [Buttons::empty(); 2],
+            shift: 0,
+            strobe: false,
+        }
+    }
+
+    fn report(&self) -> u32 {
+        self.buttons[0].bits() as u32
+            | (self.buttons[1].bits() as u32) << 8
+            | (self.signature as u32) << 16
+    }
+}
+
+impl PortDevice for FourScore {
+    fn update(&mut self, input: &PortInput) {
+        self.buttons = input.buttons;
+        if self.strobe {
+            self.shift = self.report();
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 0x01 > 0;
+        if self.strobe {
+            self.shift = self.report();
+        }
+    }
+
+    fn read(&mut self, _screen: &mut dyn Screen) -> u8 {
+        if self.strobe {
+            return self.buttons[0].bits() & 0x01;
+        }
+        let bit = (self.shift & 0x01) as u8;
+        // Ones are shifted in behind the report.
+        self.shift = (self.shift >> 1) | 1 << 23;
+        bit
+    }
+}
+
+impl Savestate for FourScore {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.buttons.map(|buttons| buttons.bits()));
+        w.write(&self.shift);
+        w.write(&self.strobe);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.buttons = r.read::<[u8; 2]>()?.map(Buttons::from_bits_retain);
+        self.shift = r.read()?;
+        self.strobe = r.read()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoScreen;
+
+    impl Screen for NoScreen {
+        fn beam(&self) -> (u16, u16) {
+            (0, 0)
+        }
+
+        fn brightness(&mut self, _x: usize, _y: usize) -> u8 {
+            0
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let mut four_score = FourScore::port2();
+        four_score.update(&PortInput {
+            buttons: [Buttons::A, Buttons::START | Buttons::RIGHT],
+            ..Default::default()
+        });
+        four_score.write(1);
+        four_score.write(0);
+
+        let bits: Vec<u8> = (0..26).map(|_| four_score.read(&mut NoScreen)).collect();
+        assert_eq!(&bits[..8], [1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&bits[8..16], [0, 0, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(&bits[16..24], [0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(&bits[24..], [1, 1]);
+    }
+}
@@ -0,0 +1,323 @@
+//! Devices that plug into the console's controller ports.
+//!
+//! Each port has a device that the CPU talks to through $4016 and $4017:
+//! normally a standard controller, but games can also use other devices,
+//! such as the Zapper light gun.
+//!
+//! A controller is an 8-bit parallel-in, serial-out shift register. While the
+//! CPU holds the strobe line high (by writing 1 to bit 0 of $4016), the
+//! register is continuously reloaded with the state of the buttons; once the
+//! strobe goes low, each read of the controller's port returns the next
+//! button, in the order A, B, Select, Start, Up, Down, Left, Right. After
+//! all 8 buttons have been read, an official controller returns 1.
+
+use anyhow::Result;
+use bitflags::bitflags;
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+mod four_score;
+mod power_pad;
+mod zapper;
+
+pub use four_score::FourScore;
+pub use power_pad::PowerPad;
+pub use zapper::Zapper;
+
+bitflags! {
+    /// The buttons on a standard controller, in the order they're read.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Buttons: u8 {
+        const A = 1;
+        const B = 1 << 1;
+        const SELECT = 1 << 2;
+        const START = 1 << 3;
+        const UP = 1 << 4;
+        const DOWN = 1 << 5;
+        const LEFT = 1 << 6;
+        const RIGHT = 1 << 7;
+    }
+}
+
+impl Buttons {
+    /// Release both directions of any opposing pair (Up and Down, or Left and
+    /// Right) that are held at once. This can't happen on a real controller,
+    /// and some games glitch badly if it does.
+    pub fn without_opposing_directions(self) -> Self {
+        let mut buttons = self;
+        for pair in [Self::UP | Self::DOWN, Self::LEFT | Self::RIGHT] {
+            if buttons.contains(pair) {
+                buttons.remove(pair);
+            }
+        }
+        buttons
+    }
+}
+
+/// Bits of $4016 and $4017 that are driven by the devices in the ports. The
+/// upper bits aren't connected, so they read as open bus (usually $40, the
+/// high byte of the address).
+pub const PORT_BITS: u8 = 0x1F;
+
+/// Bit of $4016 that is set while the Famicom's microphone hears sound.
+const MICROPHONE: u8 = 1 << 2;
+
+/// The state of the host's input devices that is relevant to one port.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PortInput {
+    /// Buttons held on the port's controller, followed by those held on the
+    /// controller plugged into a Four Score's extra socket for the port
+    /// (player 3 for port 1, and player 4 for port 2).
+    pub buttons: [Buttons; 2],
+    /// Position of the mouse cursor within the frame, if it's in the window.
+    pub cursor: Option<(usize, usize)>,
+    /// Whether the mouse button used as the Zapper's trigger is held.
+    pub trigger: bool,
+    /// Power Pad sensors that are pressed, with sensor `n` in bit `n - 1`.
+    pub power_pad: u16,
+}
+
+/// The picture being drawn by the PPU, as seen by a light gun pointed at the
+/// TV.
+pub trait Screen {
+    /// The position of the electron beam, as a scanline and a dot.
+    fn beam(&self) -> (u16, u16);
+
+    /// The brightness (0-255) of the pixel at the given position in the frame.
+    fn brightness(&mut self, x: usize, y: usize) -> u8;
+}
+
+/// A device that can be plugged into a controller port.
+pub trait PortDevice: Savestate {
+    /// Update the device with the latest input from the host.
+    fn update(&mut self, input: &PortInput);
+
+    /// Handle a write to $4016, whose lowest bit drives the strobe line.
+    fn write(&mut self, value: u8);
+
+    /// Handle a read from the device's port. Light guns may look at the
+    /// picture being drawn on the screen.
+    fn read(&mut self, screen: &mut dyn Screen) -> u8;
+}
+
+/// Provides the host's input on demand, so that the controllers can sample it
+/// at the moment the game strobes them instead of once per frame.
+pub trait InputSource {
+    fn poll(&mut self) -> [PortInput; 2];
+}
+
+/// The types of devices that can be plugged into the ports.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeviceKind {
+    #[default]
+    Controller,
+    Zapper,
+    PowerPad,
+}
+
+impl DeviceKind {
+    pub fn create(self) -> Box<dyn PortDevice> {
+        match self {
+            DeviceKind::Controller => Box::new(Controller::new()),
+            DeviceKind::Zapper => Box::new(Zapper::new()),
+            DeviceKind::PowerPad => Box::new(PowerPad::new()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Controller {
+    buttons: Buttons,
+    shift: u8,
+    strobe: bool,
+}
+
+impl Controller {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update which buttons are being held down.
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift = buttons.bits();
+        }
+    }
+
+    /// Handle a write to $4016, whose lowest bit drives the strobe line.
+    pub fn write_strobe(&mut self, value: u8) {
+        self.strobe = value & 0x01 > 0;
+        if self.strobe {
+            self.shift = self.buttons.bits();
+        }
+    }
+
+    /// Read the next button from the shift register. Only bit 0 is driven.
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons.bits() & 0x01;
+        }
+        let bit = self.shift & 0x01;
+        // Ones are shifted in behind the buttons.
+        self.shift = (self.shift >> 1) | 0x80;
+        bit
+    }
+}
+
+impl PortDevice for Controller {
+    fn update(&mut self, input: &PortInput) {
+        self.set_buttons(input.buttons[0]);
+    }
+
+    fn write(&mut self, value: u8) {
+        self.write_strobe(value);
+    }
+
+    fn read(&mut self, _screen: &mut dyn Screen) -> u8 {
+        Controller::read(self)
+    }
+}
+
+/// The devices plugged into the console's controller ports.
+pub struct Controllers {
+    pub port1: Box<dyn PortDevice>,
+    pub port2: Box<dyn PortDevice>,
+    /// Whether the microphone on the Famicom's (hardwired) second controller
+    /// hears anything.
+    pub microphone: bool,
+    /// Whether to release opposing directions that are held at once (see
+    /// `Buttons::without_opposing_directions`).
+    pub filter_opposing: bool,
+    /// Where to get fresh input from when the game strobes the controllers.
+    /// Without a source, input only changes when `update` is called.
+    source: Option<Box<dyn InputSource>>,
+}
+
+impl Controllers {
+    pub fn new() -> Self {
+        Self {
+            port1: Box::new(Controller::new()),
+            port2: Box::new(Controller::new()),
+            microphone: false,
+            filter_opposing: true,
+            source: None,
+        }
+    }
+
+    /// Update the devices in both ports with the latest input from the host.
+    pub fn update(&mut self, inputs: &[PortInput; 2]) {
+        let mut inputs = *inputs;
+        if self.filter_opposing {
+            for input in &mut inputs {
+                input.buttons = input.buttons.map(Buttons::without_opposing_directions);
+            }
+        }
+        self.port1.update(&inputs[0]);
+        self.port2.update(&inputs[1]);
+    }
+
+    pub fn set_source(&mut self, source: Option<Box<dyn InputSource>>) {
+        self.source = source;
+    }
+
+    /// Handle a write to $4016, which is seen by the devices in both ports.
+    /// Raising the strobe line polls the input source, if there is one, so
+    /// that the devices latch the newest input.
+    pub fn write(&mut self, value: u8) {
+        if value & 0x01 > 0 {
+            if let Some(inputs) = self.source.as_mut().map(|source| source.poll()) {
+                self.update(&inputs);
+            }
+        }
+        self.port1.write(value);
+        self.port2.write(value);
+    }
+
+    /// Handle a read from $4016 (port 1), which also reports the Famicom's
+    /// microphone.
+    pub fn read_port1(&mut self, screen: &mut dyn Screen) -> u8 {
+        let value = self.port1.read(screen);
+        if self.microphone {
+            value | MICROPHONE
+        } else {
+            value
+        }
+    }
+
+    /// Handle a read from $4017 (port 2). Writes to $4017 go to the APU's
+    /// frame counter instead.
+    pub fn read_port2(&mut self, screen: &mut dyn Screen) -> u8 {
+        self.port2.read(screen)
+    }
+}
+
+impl Savestate for Controller {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.buttons.bits());
+        w.write(&self.shift);
+        w.write(&self.strobe);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.buttons = Buttons::from_bits_retain(r.read()?);
+        self.shift = r.read()?;
+        self.strobe = r.read()?;
+        Ok(())
+    }
+}
+
+/// The devices' states are saved, but not which devices are plugged in, so a
+/// state must be loaded with the same devices that it was saved with.
+impl Savestate for Controllers {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.port1.save_state(w);
+        self.port2.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.port1.load_state(r)?;
+        self.port2.load_state(r)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_buttons() {
+        let mut controller = Controller::new();
+        controller.set_buttons(Buttons::A | Buttons::START | Buttons::RIGHT);
+
+        // While strobing, reads keep returning A.
+        controller.write_strobe(1);
+        assert_eq!(controller.read(), 1);
+        assert_eq!(controller.read(), 1);
+
+        controller.write_strobe(0);
+        let bits: Vec<u8> = (0..10).map(|_| controller.read()).collect();
+        assert_eq!(bits, [1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+
+        // Buttons pressed after the strobe aren't seen until the next one.
+        controller.set_buttons(Buttons::B);
+        assert_eq!(controller.read(), 1);
+        controller.write_strobe(1);
+        controller.write_strobe(0);
+        assert_eq!(controller.read(), 0);
+        assert_eq!(controller.read(), 1);
+    }
+
+    #[test]
+    fn test_opposing_directions() {
+        let buttons = Buttons::UP | Buttons::DOWN | Buttons::LEFT | Buttons::A;
+        assert_eq!(
+            buttons.without_opposing_directions(),
+            Buttons::LEFT | Buttons::A
+        );
+    }
+}
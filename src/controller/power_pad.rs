@@ -0,0 +1,133 @@
+//! The Power Pad (or Family Trainer) exercise mat.
+//!
+//! The mat has 12 pressure sensors, numbered like this on side B:
+//!
+//! ```text
+//!  1  2  3  4
+//!  5  6  7  8
+//!  9 10 11 12
+//! ```
+//!
+//! After a strobe, it reports the sensors over two serial lines at once: bit
+//! 3 of each read returns sensors 2, 1, 5, 9, 6, 10, 11 and 7, and bit 4
+//! returns sensors 4, 3, 12 and 8. Each line returns 1 after its last sensor.
+
+use anyhow::Result;
+
+use super::{PortDevice, PortInput, Screen};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// The order that the sensors are read out on bit 3.
+const LOW_SENSORS: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+/// The order that the sensors are read out on bit 4.
+const HIGH_SENSORS: [u8; 4] = [4, 3, 12, 8];
+
+#[derive(Debug, Default)]
+pub struct PowerPad {
+    /// Sensors that are pressed, with sensor `n` in bit `n - 1`.
+    sensors: u16,
+    low: u8,
+    high: u8,
+    strobe: bool,
+}
+
+impl PowerPad {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the sensors into the shift registers for each line.
+    fn latch(&mut self) {
+        let sensors = self.sensors;
+        let pressed = |sensor: u8| (sensors >> (sensor - 1)) & 1 > 0;
+        self.low = serialize(&LOW_SENSORS, pressed);
+        // Only 4 sensors are read on bit 4, so the rest are already ones.
+        self.high = serialize(&HIGH_SENSORS, pressed) | 0xF0;
+    }
+}
+
+/// Pack the given sensors into a byte, in the order they're read.
+fn serialize(sensors: &[u8], pressed: impl Fn(u8) -> bool) -> u8 {
+    sensors
+        .iter()
+        .enumerate()
+        .filter(|&(_, &sensor)| pressed(sensor))
+        .fold(0, |bits, (i, _)| bits | 1 << i)
+}
+
+impl PortDevice for PowerPad {
+    fn update(&mut self, input: &PortInput) {
+        self.sensors = input.power_pad;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 0x01 > 0;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self, _screen: &mut dyn Screen) -> u8 {
+        let value = (self.low & 0x01) << 3 | (self.high & 0x01) << 4;
+        if !self.strobe {
+            self.low = (self.low >> 1) | 0x80;
+            self.high = (self.high >> 1) | 0x80;
+        }
+        value
+    }
+}
+
+impl Savestate for PowerPad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.sensors);
+        w.write(&self.low);
+        w.write(&self.high);
+        w.write(&self.strobe);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.sensors = r.read()?;
+        self.low = r.read()?;
+        self.high = r.read()?;
+        self.strobe = r.read()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoScreen;
+
+    impl Screen for NoScreen {
+        fn beam(&self) -> (u16, u16) {
+            (0, 0)
+        }
+
+        fn brightness(&mut self, _x: usize, _y: usize) -> u8 {
+            0
+        }
+    }
+
+    #[test]
+    fn test_read_sensors() {
+        let mut pad = PowerPad::new();
+        // Sensors 1, 6 and 12.
+        pad.update(&PortInput {
+            power_pad: 1 | 1 << 5 | 1 << 11,
+            ..Default::default()
+        });
+        pad.write(1);
+        pad.write(0);
+
+        let reads: Vec<u8> = (0..9).map(|_| pad.read(&mut NoScreen)).collect();
+        let low: Vec<u8> = reads.iter().map(|value| value >> 3 & 1).collect();
+        let high: Vec<u8> = reads.iter().map(|value| value >> 4 & 1).collect();
+        assert_eq!(low, [0, 1, 0, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(high, [0, 0, 1, 0, 1, 1, 1, 1, 1]);
+    }
+}
@@ -0,0 +1,139 @@
+//! The Zapper light gun, emulated with the mouse.
+//!
+//! The Zapper has a photodiode behind its lens that notices when the part of
+//! the screen it's pointed at lights up. Games check it by blanking the
+//! screen and drawing white boxes over their targets for a frame, and then
+//! reading the sensor as the beam passes the target. Because the sensor only
+//! reacts once the beam has drawn a bright pixel in front of it, and stays
+//! on for a while afterward, the light bit depends on where the PPU is in
+//! the frame when the game reads it.
+//!
+//! Reads from the Zapper's port return the light bit in bit 3 (0 when light
+//! is detected) and the trigger in bit 4 (1 while it's pulled).
+
+use anyhow::Result;
+
+use super::{PortDevice, PortInput, Screen};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const LIGHT_NOT_DETECTED: u8 = 1 << 3;
+const TRIGGER_PULLED: u8 = 1 << 4;
+
+/// Number of scanlines that the sensor keeps reporting light for after the
+/// beam draws the pixel under it. On hardware this is somewhere between 19
+/// and 26, depending on how bright the pixel is.
+const SENSOR_SCANLINES: usize = 20;
+
+/// Pixels at least this bright are seen by the sensor. Only the lightest
+/// colors (such as the white used for targets) are bright enough.
+const BRIGHTNESS_THRESHOLD: u8 = 0xC0;
+
+#[derive(Debug, Default)]
+pub struct Zapper {
+    /// The position in the frame that the gun is pointed at, or `None` if
+    /// it's pointed away from the screen.
+    aim: Option<(usize, usize)>,
+    trigger: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the sensor sees light at the current point in the frame.
+    fn detects_light(&self, screen: &mut dyn Screen) -> bool {
+        let Some((x, y)) = self.aim else {
+            return false;
+        };
+        let (scanline, dot) = screen.beam();
+        let (scanline, dot) = (scanline as usize, dot as usize);
+        let drawn = scanline > y || (scanline == y && dot > x);
+        drawn && scanline < y + SENSOR_SCANLINES && screen.brightness(x, y) >= BRIGHTNESS_THRESHOLD
+    }
+}
+
+impl PortDevice for Zapper {
+    fn update(&mut self, input: &PortInput) {
+        self.aim = input.cursor;
+        self.trigger = input.trigger;
+    }
+
+    // The Zapper ignores the strobe.
+    fn write(&mut self, _value: u8) {}
+
+    fn read(&mut self, screen: &mut dyn Screen) -> u8 {
+        let mut value = 0;
+        if !self.detects_light(screen) {
+            value |= LIGHT_NOT_DETECTED;
+        }
+        if self.trigger {
+            value |= TRIGGER_PULLED;
+        }
+        value
+    }
+}
+
+/// The Zapper has no state of its own, since it just reports what the host's
+/// mouse is doing.
+impl Savestate for Zapper {
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A white box at (100, 50)-(115, 65) on a black screen.
+    struct TestScreen {
+        beam: (u16, u16),
+    }
+
+    impl Screen for TestScreen {
+        fn beam(&self) -> (u16, u16) {
+            self.beam
+        }
+
+        fn brightness(&mut self, x: usize, y: usize) -> u8 {
+            if (100..116).contains(&x) && (50..66).contains(&y) {
+                0xEC
+            } else {
+                0
+            }
+        }
+    }
+
+    #[test]
+    fn test_light_sensor() {
+        let mut zapper = Zapper::new();
+        zapper.update(&PortInput {
+            cursor: Some((105, 60)),
+            trigger: true,
+            ..Default::default()
+        });
+        let mut read = |beam| zapper.read(&mut TestScreen { beam });
+
+        // Before the beam reaches the box, and after the sensor has gone dark.
+        assert_eq!(read((59, 200)), LIGHT_NOT_DETECTED | TRIGGER_PULLED);
+        assert_eq!(read((60, 100)), LIGHT_NOT_DETECTED | TRIGGER_PULLED);
+        assert_eq!(read((80, 0)), LIGHT_NOT_DETECTED | TRIGGER_PULLED);
+
+        // While the sensor sees the box.
+        assert_eq!(read((60, 110)), TRIGGER_PULLED);
+        assert_eq!(read((70, 0)), TRIGGER_PULLED);
+
+        // Aiming at the black part of the screen, without pulling the trigger.
+        zapper.update(&PortInput {
+            cursor: Some((20, 60)),
+            ..Default::default()
+        });
+        assert_eq!(
+            zapper.read(&mut TestScreen { beam: (70, 0) }),
+            LIGHT_NOT_DETECTED
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! Disassembly of 6502 machine code, for debug views.
+//!
+//! Only the official opcodes are decoded. Anything else is shown as `???`
+//! and taken to be a single byte, so disassembly past an undocumented
+//! instruction may be out of step until it reaches a branch target.
+
+use crate::mem::Address;
+
+#[derive(Copy, Clone)]
+enum Mode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndexedIndirect,
+    IndirectIndexed,
+    Relative,
+}
+
+impl Mode {
+    /// Number of operand bytes following the opcode.
+    fn operand_len(self) -> u16 {
+        use Mode::*;
+        match self {
+            Implied | Accumulator => 0,
+            Absolute | AbsoluteX | AbsoluteY | Indirect => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// A decoded instruction.
+pub struct Disassembly {
+    /// The instruction's bytes, in hex.
+    pub bytes: String,
+    /// The instruction in assembly syntax (e.g., `LDA $0200,X`).
+    pub text: String,
+    /// Number of bytes the instruction takes up.
+    pub len: u16,
+}
+
+/// Disassemble the instruction at the given address, reading memory with
+/// `peek` (which returns `None` for memory that can't be read without side
+/// effects, like I/O registers).
+pub fn disassemble(mut peek: impl FnMut(Address) -> Option<u8>, pc: Address) -> Disassembly {
+    let Some(opcode) = peek(pc) else {
+        return Disassembly {
+            bytes: "--".to_string(),
+            text: "???".to_string(),
+            len: 1,
+        };
+    };
+    let Some((mnemonic, mode)) = decode(opcode) else {
+        return Disassembly {
+            bytes: format!("{:02X}", opcode),
+            text: "???".to_string(),
+            len: 1,
+        };
+    };
+
+    let operands: Vec<Option<u8>> = (1..=mode.operand_len()).map(|i| peek(pc + i)).collect();
+    let mut bytes = format!("{:02X}", opcode);
+    for byte in &operands {
+        match byte {
+            Some(byte) => bytes += &format!(" {:02X}", byte),
+            None => bytes += " --",
+        }
+    }
+    let byte = operands.first().copied().flatten().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, operands.get(1).copied().flatten().unwrap_or(0)]);
+
+    use Mode::*;
+    let operand = match mode {
+        Implied => String::new(),
+        Accumulator => " A".to_string(),
+        Immediate => format!(" #${:02X}", byte),
+        ZeroPage => format!(" ${:02X}", byte),
+        ZeroPageX => format!(" ${:02X},X", byte),
+        ZeroPageY => format!(" ${:02X},Y", byte),
+        Absolute => format!(" ${:04X}", word),
+        AbsoluteX => format!(" ${:04X},X", word),
+        AbsoluteY => format!(" ${:04X},Y", word),
+        Indirect => format!(" (${:04X})", word),
+        IndexedIndirect => format!(" (${:02X},X)", byte),
+        IndirectIndexed => format!(" (${:02X}),Y", byte),
+        Relative => {
+            let target = (pc.0 as i32 + 2 + byte as i8 as i32) as u16;
+            format!(" ${:04X}", target)
+        }
+    };
+    Disassembly {
+        bytes,
+        text: format!("{}{}", mnemonic, operand),
+        len: 1 + mode.operand_len(),
+    }
+}
+
+/// Look up the mnemonic and addressing mode of an official opcode.
+fn decode(opcode: u8) -> Option<(&'static str, Mode)> {
+    use Mode::*;
+    Some(match opcode {
+        0x69 => ("ADC", Immediate),
+        0x65 => ("ADC", ZeroPage),
+        0x75 => ("ADC", ZeroPageX),
+        0x6D => ("ADC", Absolute),
+        0x7D => ("ADC", AbsoluteX),
+        0x79 => ("ADC", AbsoluteY),
+        0x61 => ("ADC", IndexedIndirect),
+        0x71 => ("ADC", IndirectIndexed),
+        0x29 => ("AND", Immediate),
+        0x25 => ("AND", ZeroPage),
+        0x35 => ("AND", ZeroPageX),
+        0x2D => ("AND", Absolute),
+        0x3D => ("AND", AbsoluteX),
+        0x39 => ("AND", AbsoluteY),
+        0x21 => ("AND", IndexedIndirect),
+        0x31 => ("AND", IndirectIndexed),
+        0x0A => ("ASL", Accumulator),
+        0x06 => ("ASL", ZeroPage),
+        0x16 => ("ASL", ZeroPageX),
+        0x0E => ("ASL", Absolute),
+        0x1E => ("ASL", AbsoluteX),
+        0x90 => ("BCC", Relative),
+        0xB0 => ("BCS", Relative),
+        0xF0 => ("BEQ", Relative),
+        0x24 => ("BIT", ZeroPage),
+        0x2C => ("BIT", Absolute),
+        0x30 => ("BMI", Relative),
+        0xD0 => ("BNE", Relative),
+        0x10 => ("BPL", Relative),
+        0x00 => ("BRK", Implied),
+        0x50 => ("BVC", Relative),
+        0x70 => ("BVS", Relative),
+        0x18 => ("CLC", Implied),
+        0xD8 => ("CLD", Implied),
+        0x58 => ("CLI", Implied),
+        0xB8 => ("CLV", Implied),
+        0xC9 => ("CMP", Immediate),
+        0xC5 => ("CMP", ZeroPage),
+        0xD5 => ("CMP", ZeroPageX),
+        0xCD => ("CMP", Absolute),
+        0xDD => ("CMP", AbsoluteX),
+        0xD9 => ("CMP", AbsoluteY),
+        0xC1 => ("CMP", IndexedIndirect),
+        0xD1 => ("CMP", IndirectIndexed),
+        0xE0 => ("CPX", Immediate),
+        0xE4 => ("CPX", ZeroPage),
+        0xEC => ("CPX", Absolute),
+        0xC0 => ("CPY", Immediate),
+        0xC4 => ("CPY", ZeroPage),
+        0xCC => ("CPY", Absolute),
+        0xC6 => ("DEC", ZeroPage),
+        0xD6 => ("DEC", ZeroPageX),
+        0xCE => ("DEC", Absolute),
+        0xDE => ("DEC", AbsoluteX),
+        0xCA => ("DEX", Implied),
+        0x88 => ("DEY", Implied),
+        0x49 => ("EOR", Immediate),
+        0x45 => ("EOR", ZeroPage),
+        0x55 => ("EOR", ZeroPageX),
+        0x4D => ("EOR", Absolute),
+        0x5D => ("EOR", AbsoluteX),
+        0x59 => ("EOR", AbsoluteY),
+        0x41 => ("EOR", IndexedIndirect),
+        0x51 => ("EOR", IndirectIndexed),
+        0xE6 => ("INC", ZeroPage),
+        0xF6 => ("INC", ZeroPageX),
+        0xEE => ("INC", Absolute),
+        0xFE => ("INC", AbsoluteX),
+        0xE8 => ("INX", Implied),
+        0xC8 => ("INY", Implied),
+        0x4C => ("JMP", Absolute),
+        0x6C => ("JMP", Indirect),
+        0x20 => ("JSR", Absolute),
+        0xA9 => ("LDA", Immediate),
+        0xA5 => ("LDA", ZeroPage),
+        0xB5 => ("LDA", ZeroPageX),
+        0xAD => ("LDA", Absolute),
+        0xBD => ("LDA", AbsoluteX),
+        0xB9 => ("LDA", AbsoluteY),
+        0xA1 => ("LDA", IndexedIndirect),
+        0xB1 => ("LDA", IndirectIndexed),
+        0xA2 => ("LDX", Immediate),
+        0xA6 => ("LDX", ZeroPage),
+        0xB6 => ("LDX", ZeroPageY),
+        0xAE => ("LDX", Absolute),
+        0xBE => ("LDX", AbsoluteY),
+        0xA0 => ("LDY", Immediate),
+        0xA4 => ("LDY", ZeroPage),
+        0xB4 => ("LDY", ZeroPageX),
+        0xAC => ("LDY", Absolute),
+        0xBC => ("LDY", AbsoluteX),
+        0x4A => ("LSR", Accumulator),
+        0x46 => ("LSR", ZeroPage),
+        0x56 => ("LSR", ZeroPageX),
+        0x4E => ("LSR", Absolute),
+        0x5E => ("LSR", AbsoluteX),
+        0xEA => ("NOP", Implied),
+        0x09 => ("ORA", Immediate),
+        0x05 => ("ORA", ZeroPage),
+        0x15 => ("ORA", ZeroPageX),
+        0x0D => ("ORA", Absolute),
+        0x1D => ("ORA", AbsoluteX),
+        0x19 => ("ORA", AbsoluteY),
+        0x01 => ("ORA", IndexedIndirect),
+        0x11 => ("ORA", IndirectIndexed),
+        0x48 => ("PHA", Implied),
+        0x08 => ("PHP", Implied),
+        0x68 => ("PLA", Implied),
+        0x28 => ("PLP", Implied),
+        0x2A => ("ROL", Accumulator),
+        0x26 => ("ROL", ZeroPage),
+        0x36 => ("ROL", ZeroPageX),
+        0x2E => ("ROL", Absolute),
+        0x3E => ("ROL", AbsoluteX),
+        0x6A => ("ROR", Accumulator),
+        0x66 => ("ROR", ZeroPage),
+        0x76 => ("ROR", ZeroPageX),
+        0x6E => ("ROR", Absolute),
+        0x7E => ("ROR", AbsoluteX),
+        0x40 => ("RTI", Implied),
+        0x60 => ("RTS", Implied),
+        0xE9 => ("SBC", Immediate),
+        0xE5 => ("SBC", ZeroPage),
+        0xF5 => ("SBC", ZeroPageX),
+        0xED => ("SBC", Absolute),
+        0xFD => ("SBC", AbsoluteX),
+        0xF9 => ("SBC", AbsoluteY),
+        0xE1 => ("SBC", IndexedIndirect),
+        0xF1 => ("SBC", IndirectIndexed),
+        0x38 => ("SEC", Implied),
+        0xF8 => ("SED", Implied),
+        0x78 => ("SEI", Implied),
+        0x85 => ("STA", ZeroPage),
+        0x95 => ("STA", ZeroPageX),
+        0x8D => ("STA", Absolute),
+        0x9D => ("STA", AbsoluteX),
+        0x99 => ("STA", AbsoluteY),
+        0x81 => ("STA", IndexedIndirect),
+        0x91 => ("STA", IndirectIndexed),
+        0x86 => ("STX", ZeroPage),
+        0x96 => ("STX", ZeroPageY),
+        0x8E => ("STX", Absolute),
+        0x84 => ("STY", ZeroPage),
+        0x94 => ("STY", ZeroPageX),
+        0x8C => ("STY", Absolute),
+        0xAA => ("TAX", Implied),
+        0xA8 => ("TAY", Implied),
+        0xBA => ("TSX", Implied),
+        0x8A => ("TXA", Implied),
+        0x9A => ("TXS", Implied),
+        0x98 => ("TYA", Implied),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let code = [0xBD, 0x00, 0x02, 0xD0, 0xFB, 0x02];
+        let peek = |addr: Address| code.get(addr.as_usize() - 0xC000).copied();
+        let lines: Vec<(String, String)> = [0xC000, 0xC003, 0xC005]
+            .iter()
+            .map(|&pc| {
+                let line = disassemble(peek, Address(pc));
+                (line.bytes, line.text)
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                ("BD 00 02".to_string(), "LDA $0200,X".to_string()),
+                ("D0 FB".to_string(), "BNE $C000".to_string()),
+                ("02".to_string(), "???".to_string()),
+            ]
+        );
+    }
+}
@@ -309,11 +309,17 @@ pub(super) enum Instruction {
     // NOP - Illegal NOP.
     UNop,
 
-    // NOP - NOP that loads (and discards) a value.
+    // NOP - NOP that loads (and discards) a value. The operand is decoded so
+    // that the program counter skips over it, but the value isn't read.
+    #[allow(dead_code)]
     UNopI(Immediate),
+    #[allow(dead_code)]
     UNopZ(ZeroPage),
+    #[allow(dead_code)]
     UNopZX(ZeroPageX),
+    #[allow(dead_code)]
     UNopA(Absolute),
+    #[allow(dead_code)]
     UNopAX(AbsoluteX),
 
     // RLA - Rotate left, then AND the value with the accumulator.
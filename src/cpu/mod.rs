@@ -15,13 +15,19 @@
 
 use std::cmp;
 
+use anyhow::Result;
+
 use crate::mem::{Address, Bus};
+use crate::savestate::{Savestate, StateReader, StateWriter};
 
 use addressing::{Absolute, AddressingMode, Relative};
 use instruction::Instruction;
 use registers::{Flags, Registers};
 
+pub use disasm::disassemble;
+
 mod addressing;
+mod disasm;
 mod instruction;
 mod registers;
 
@@ -70,9 +76,13 @@ static CYCLE_TABLE: [u8; 256] = [
 ];
 
 /// Emulated MOS 6502 CPU.
+#[derive(Clone)]
 pub struct Cpu {
     registers: Registers,
     irq_pending: bool,
+    /// State of the IRQ input line, which is held low (asserted) by devices
+    /// until their interrupt is acknowledged.
+    irq_line: bool,
     cycles_remaining: u8,
     cycle: u64,
 }
@@ -82,6 +92,7 @@ impl Cpu {
         Self {
             registers: Registers::new(),
             irq_pending: false,
+            irq_line: false,
             cycles_remaining: 0,
             cycle: 0,
         }
@@ -111,23 +122,33 @@ impl Cpu {
 
         // Loop until we hit the end address (or forever if not specified).
         self.reset(&mut memory);
-        while end.map_or(true, |end| self.registers.pc != end) {
+        while end != Some(self.registers.pc) {
             // Note that we don't keep track of cycle timing here since the
             // CPU is running in isolation.
+            let pc = self.registers.pc;
             let _ = self.step(&mut memory);
+
+            // Crash if we detect an infinite loop. This is useful for test
+            // programs that intentionally enter an infinite loop to signal a
+            // test failure. (NES games, on the other hand, commonly spin in
+            // place while waiting for an interrupt, so this check is only
+            // done when running raw binaries.)
+            if pc == self.registers.pc {
+                panic!(
+                    "Detected infinite loop at {}; Registers: {}",
+                    pc, self.registers
+                );
+            }
         }
     }
 
     /// Manually set the address stored in the CPU's reset vector. Program
     /// execution will begin from this address on CPU startup or reset.
     pub fn set_reset_vector(&mut self, memory: &mut dyn Bus, addr: Address) {
-        let [low, high] = <[u8; 2]>::from(addr);
-        memory.store(Address::from(RESET_VECTOR[0]), low);
-        memory.store(Address::from(RESET_VECTOR[1]), high);
+        memory.store_range(Address::from(RESET_VECTOR[0]), &<[u8; 2]>::from(addr));
     }
 
     /// Examine the current state of the CPU's registers.
-    #[cfg(test)]
     pub fn registers(&self) -> &Registers {
         &self.registers
     }
@@ -147,7 +168,8 @@ impl Cpu {
 
         // If there is a pending interrupt and interrupts are not disabled,
         // service it immediately.
-        if self.irq_pending && !self.registers.p.contains(Flags::INTERRUPT_DISABLE) {
+        let irq = self.irq_pending || self.irq_line;
+        if irq && !self.registers.p.contains(Flags::INTERRUPT_DISABLE) {
             log::trace!("Handling pending IRQ");
             self.irq_pending = false;
             self.irq(memory);
@@ -165,15 +187,6 @@ impl Cpu {
         );
         log::trace!("Registers: {}", &self.registers);
 
-        // Crash if we detect an infinite loop. This is useful for test ROMs
-        // that intentionally enter an infinite loop to signal a test failure.
-        if pc == self.registers.pc {
-            panic!(
-                "Detected infinite loop at {}; Registers: {}",
-                pc, self.registers
-            );
-        }
-
         CYCLE_TABLE[opcode as usize]
     }
 
@@ -194,6 +207,13 @@ impl Cpu {
         self.cycle += 1;
     }
 
+    /// Whether the next call to `tick` will start a new instruction, rather
+    /// than waiting for the current one to finish. Debuggers stop here, so
+    /// that the registers are always seen between instructions.
+    pub fn at_instruction_boundary(&self) -> bool {
+        self.cycles_remaining == 0
+    }
+
     /// Reset the CPU by disabling interrupts and jumping to the location
     /// specified by the initialization vector.
     pub fn reset(&mut self, memory: &mut dyn Bus) {
@@ -220,6 +240,13 @@ impl Cpu {
         }
     }
 
+    /// Set the state of the IRQ line. Unlike `Cpu::irq`, which requests a
+    /// single interrupt, the line is level-triggered: the CPU will keep taking
+    /// interrupts (whenever they are enabled) until the line is deasserted.
+    pub fn set_irq_line(&mut self, asserted: bool) {
+        self.irq_line = asserted;
+    }
+
     /// Non-maskable interrupt.
     #[allow(dead_code)]
     pub fn nmi(&mut self, memory: &mut dyn Bus) {
@@ -445,8 +472,15 @@ impl Cpu {
     /// interrupt vector. The brk parameter allows specifying whether this was a
     /// software or hardware interrupt.
     fn interrupt(&mut self, memory: &mut dyn Bus, vector: &[u16; 2], brk: bool) {
-        // Push program counter to stack.
-        let [low, high] = <[u8; 2]>::from(self.registers.pc + 1u8);
+        // Push program counter to stack. BRK is followed by a padding byte,
+        // which is skipped on return. Hardware interrupts occur between
+        // instructions, so they return directly to the next instruction.
+        let pc = if brk {
+            self.registers.pc + 1u8
+        } else {
+            self.registers.pc
+        };
+        let [low, high] = <[u8; 2]>::from(pc);
         self.push_stack(memory, high);
         self.push_stack(memory, low);
 
@@ -557,7 +591,7 @@ impl Cpu {
     /// Branch if carry clear.
     fn bcc(&mut self, am: Relative, memory: &mut dyn Bus) {
         if !self.registers.p.contains(Flags::CARRY) {
-            let addr = am.address(memory, &mut self.registers);
+            let addr = am.address(memory, &self.registers);
             self.registers.pc = addr;
         }
     }
@@ -565,7 +599,7 @@ impl Cpu {
     /// Branch if carry set.
     fn bcs(&mut self, am: Relative, memory: &mut dyn Bus) {
         if self.registers.p.contains(Flags::CARRY) {
-            let addr = am.address(memory, &mut self.registers);
+            let addr = am.address(memory, &self.registers);
             self.registers.pc = addr;
         }
     }
@@ -573,7 +607,7 @@ impl Cpu {
     /// Branch if equal.
     fn beq(&mut self, am: impl AddressingMode, memory: &mut dyn Bus) {
         if self.registers.p.contains(Flags::ZERO) {
-            let addr = am.address(memory, &mut self.registers);
+            let addr = am.address(memory, &self.registers);
             self.registers.pc = addr;
         }
     }
@@ -590,7 +624,7 @@ impl Cpu {
     /// Branch if minus.
     fn bmi(&mut self, am: Relative, memory: &mut dyn Bus) {
         if self.registers.p.contains(Flags::NEGATIVE) {
-            let addr = am.address(memory, &mut self.registers);
+            let addr = am.address(memory, &self.registers);
             self.registers.pc = addr;
         }
     }
@@ -598,7 +632,7 @@ impl Cpu {
     /// Branch if not equal.
     fn bne(&mut self, am: Relative, memory: &mut dyn Bus) {
         if !self.registers.p.contains(Flags::ZERO) {
-            let addr = am.address(memory, &mut self.registers);
+            let addr = am.address(memory, &self.registers);
             self.registers.pc = addr;
         }
     }
@@ -606,7 +640,7 @@ impl Cpu {
     /// Branch if positive.
     fn bpl(&mut self, am: Relative, memory: &mut dyn Bus) {
         if !self.registers.p.contains(Flags::NEGATIVE) {
-            let addr = am.address(memory, &mut self.registers);
+            let addr = am.address(memory, &self.registers);
             self.registers.pc = addr;
         }
     }
@@ -619,7 +653,7 @@ impl Cpu {
     /// Branch if overflow clear.
     fn bvc(&mut self, am: Relative, memory: &mut dyn Bus) {
         if !self.registers.p.contains(Flags::OVERFLOW) {
-            let addr = am.address(memory, &mut self.registers);
+            let addr = am.address(memory, &self.registers);
             self.registers.pc = addr;
         }
     }
@@ -627,7 +661,7 @@ impl Cpu {
     /// Branch if overflow set.
     fn bvs(&mut self, am: Relative, memory: &mut dyn Bus) {
         if self.registers.p.contains(Flags::OVERFLOW) {
-            let addr = am.address(memory, &mut self.registers);
+            let addr = am.address(memory, &self.registers);
             self.registers.pc = addr;
         }
     }
@@ -725,7 +759,7 @@ impl Cpu {
 
     /// Jump.
     fn jmp(&mut self, am: impl AddressingMode, memory: &mut dyn Bus) {
-        self.registers.pc = am.address(memory, &mut self.registers);
+        self.registers.pc = am.address(memory, &self.registers);
     }
 
     /// Jump to subroutine.
@@ -734,7 +768,7 @@ impl Cpu {
         let [low, high] = <[u8; 2]>::from(ret);
         self.push_stack(memory, high);
         self.push_stack(memory, low);
-        self.registers.pc = am.address(memory, &mut self.registers);
+        self.registers.pc = am.address(memory, &self.registers);
     }
 
     /// Load accumulator.
@@ -998,6 +1032,37 @@ fn twos_complement_overflow(n: u8, m: u8, res: u8) -> bool {
     (n ^ res) & (m ^ res) & (1 << 7) > 0
 }
 
+impl Savestate for Cpu {
+    fn save_state(&self, w: &mut StateWriter) {
+        let r = &self.registers;
+        w.write(&r.a);
+        w.write(&r.x);
+        w.write(&r.y);
+        w.write(&r.s);
+        w.write(&r.pc);
+        w.write(&r.p.bits());
+        w.write(&self.irq_pending);
+        w.write(&self.irq_line);
+        w.write(&self.cycles_remaining);
+        w.write(&self.cycle);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        let regs = &mut self.registers;
+        regs.a = r.read()?;
+        regs.x = r.read()?;
+        regs.y = r.read()?;
+        regs.s = r.read()?;
+        regs.pc = r.read()?;
+        regs.p = Flags::from_bits_retain(r.read()?);
+        self.irq_pending = r.read()?;
+        self.irq_line = r.read()?;
+        self.cycles_remaining = r.read()?;
+        self.cycle = r.read()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -4,7 +4,7 @@ use bitflags::bitflags;
 
 use crate::mem::Address;
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct Registers {
     // Accumulator.
     pub a: u8,
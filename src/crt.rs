@@ -0,0 +1,54 @@
+//! A filter that gives the picture the look of a CRT, applied on the CPU
+//! before the frame is scaled to the window.
+//!
+//! The frame is doubled in size, and every other line is darkened to show the
+//! gaps between a CRT's scanlines. The second pixel of each pair is blended
+//! with the pixel to its right, softening the edges between pixels the way a
+//! CRT's beam does. Since the filter works on the frame rather than the
+//! window, the scanlines keep the same proportions at any window size.
+
+/// How much larger the filtered frame is than the original, in each
+/// direction.
+pub const SCALE: usize = 2;
+
+/// Brightness of the gaps between scanlines, out of 256.
+const GAP_BRIGHTNESS: u16 = 160;
+
+/// Filter an RGBA frame of the given width into `dst`, which must be `SCALE`
+/// times as wide and as high.
+pub fn apply(src: &[u8], width: usize, dst: &mut [u8]) {
+    let dst_width = width * SCALE;
+    for (y, row) in src.chunks_exact(width * 4).enumerate() {
+        for (x, pixel) in row.chunks_exact(4).enumerate() {
+            let next = row.get((x + 1) * 4..(x + 2) * 4).unwrap_or(pixel);
+            let blended: [u8; 4] =
+                std::array::from_fn(|c| ((pixel[c] as u16 + next[c] as u16) / 2) as u8);
+            for (dx, &color) in [pixel, &blended[..]].iter().enumerate() {
+                let i = ((y * SCALE) * dst_width + x * SCALE + dx) * 4;
+                dst[i..i + 4].copy_from_slice(color);
+                let gap = i + dst_width * 4;
+                for c in 0..3 {
+                    dst[gap + c] = (color[c] as u16 * GAP_BRIGHTNESS / 256) as u8;
+                }
+                dst[gap + 3] = color[3];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let src = [200, 100, 0, 255, 0, 100, 200, 255];
+        let mut dst = [0; 8 * SCALE * SCALE];
+        apply(&src, 2, &mut dst);
+        #[rustfmt::skip]
+        assert_eq!(dst, [
+            200, 100, 0, 255, 100, 100, 100, 255, 0, 100, 200, 255, 0, 100, 200, 255,
+            125, 62, 0, 255, 62, 62, 62, 255, 0, 62, 125, 255, 0, 62, 125, 255,
+        ]);
+    }
+}
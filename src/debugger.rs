@@ -0,0 +1,291 @@
+//! Debugger for working on homebrew games, shown in a window of its own.
+//!
+//! The window shows, from top to bottom, buttons for stopping and stepping
+//! the CPU, the CPU's registers, the PPU's registers and position, the
+//! disassembled instructions starting at the program counter, the list of
+//! breakpoints, and a page of memory.
+//!
+//! Everything is controlled with the mouse: clicking an instruction adds a
+//! breakpoint there (or removes it), clicking an address in the list of
+//! breakpoints removes it, and the memory viewer is scrolled with the mouse
+//! wheel or a page at a time with its buttons. The breakpoints themselves,
+//! and stopping and stepping, are handled by the console (see
+//! `Nes::add_breakpoint`).
+//!
+//! Memory is read with `CpuBus::peek`, so that showing it doesn't disturb
+//! the game. The PPU and APU's registers can't be read that way, so they're
+//! shown as `--`.
+
+use std::ops::Range;
+
+use crate::cpu::{self, Cpu};
+use crate::font;
+use crate::mapper::{Cart, CpuBus};
+use crate::mem::{Address, Ram};
+use crate::ppu::Ppu;
+
+pub const VIEW_WIDTH: usize = 256;
+/// Tall enough for all of the lines described in the module docs.
+pub const VIEW_HEIGHT: usize = 264;
+
+const MARGIN: usize = 4;
+const LINE_HEIGHT: usize = 7;
+/// Width of a character, including the space after it.
+const COLUMN_WIDTH: usize = 4;
+
+/// Number of instructions to disassemble.
+const DISASSEMBLY_LINES: usize = 12;
+
+/// Number of breakpoints that fit on a line.
+const BREAKPOINTS_SHOWN: usize = 12;
+
+/// Number of rows in the memory viewer, and bytes in each row.
+const MEMORY_ROWS: u16 = 16;
+const MEMORY_ROW_LEN: u16 = 16;
+
+const BACKGROUND: [u8; 4] = [0x10, 0x10, 0x10, 0xFF];
+const TEXT: [u8; 4] = [0xE0, 0xE0, 0xE0, 0xFF];
+const HEADING: [u8; 4] = [0x3C, 0xBC, 0xFC, 0xFF];
+const BUTTON: [u8; 4] = [0xF8, 0xB8, 0x00, 0xFF];
+const BREAKPOINT: [u8; 4] = [0xF8, 0x38, 0x00, 0xFF];
+
+/// The parts of the console that the debugger (and the other viewers) show.
+pub struct Console<'a> {
+    pub cpu: &'a Cpu,
+    pub ram: &'a Ram,
+    pub ppu: &'a Ppu,
+    pub cart: &'a mut Cart,
+    pub breakpoints: &'a [Address],
+    /// Whether emulation is stopped (see `Nes::stop`).
+    pub stopped: bool,
+}
+
+impl Console<'_> {
+    /// Read from the CPU's address space, where that has no side effects.
+    fn peek(&self, addr: Address) -> Option<u8> {
+        match addr.0 {
+            0x0000..=0x1FFF => Some(self.ram.peek(addr)),
+            0x4020..=0xFFFF => self.cart.peek(addr),
+            _ => None,
+        }
+    }
+}
+
+/// What the console should do when something is clicked in the debugger.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Stop emulation, or resume it if it's stopped.
+    Pause,
+    /// Run the next instruction, and then stop again.
+    Step,
+    /// Add a breakpoint at the address, or remove the one that's there.
+    ToggleBreakpoint(Address),
+}
+
+/// What happens when something is clicked.
+#[derive(Copy, Clone, Debug)]
+enum Action {
+    Command(Command),
+    /// Scroll the memory viewer by the given number of rows.
+    Scroll(i32),
+}
+
+/// Part of the window that can be clicked.
+struct Target {
+    line: usize,
+    columns: Range<usize>,
+    action: Action,
+}
+
+/// State of the debugger's window.
+#[derive(Default)]
+pub struct Debugger {
+    /// Address of the first byte shown in the memory viewer.
+    memory_start: u16,
+    /// What can be clicked, as of when the window was last drawn.
+    targets: Vec<Target>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle a click at the given position in the window, returning what
+    /// the console should do about it, if anything.
+    pub fn click(&mut self, (x, y): (usize, usize)) -> Option<Command> {
+        if x < MARGIN || y < MARGIN {
+            return None;
+        }
+        let (line, column) = ((y - MARGIN) / LINE_HEIGHT, (x - MARGIN) / COLUMN_WIDTH);
+        let target = self
+            .targets
+            .iter()
+            .find(|target| target.line == line && target.columns.contains(&column))?;
+        match target.action {
+            Action::Command(command) => Some(command),
+            Action::Scroll(rows) => {
+                self.scroll(rows);
+                None
+            }
+        }
+    }
+
+    /// Scroll the memory viewer by the given number of rows (down for
+    /// positive numbers), wrapping around the address space.
+    pub fn scroll(&mut self, rows: i32) {
+        let offset = (rows * MEMORY_ROW_LEN as i32) as u16;
+        self.memory_start = self.memory_start.wrapping_add(offset);
+    }
+
+    /// Draw the window into a frame of `VIEW_WIDTH` by `VIEW_HEIGHT`.
+    pub fn draw(&mut self, frame: &mut [u8], console: &Console) {
+        for pixel in frame.chunks_exact_mut(4) {
+            pixel.copy_from_slice(&BACKGROUND);
+        }
+        let mut layout = Layout::default();
+        let regs = console.cpu.registers();
+
+        let pause = if console.stopped { "[RUN]" } else { "[STOP]" };
+        layout.button(pause, Action::Command(Command::Pause));
+        layout.text(TEXT, " ");
+        layout.button("[STEP]", Action::Command(Command::Step));
+        if console.stopped {
+            layout.text(TEXT, &format!("  STOPPED AT {:04X}", regs.pc.0));
+        }
+
+        layout.heading("CPU");
+        layout.line(
+            TEXT,
+            &format!(
+                "PC {:04X}  A {:02X}  X {:02X}  Y {:02X}  S {:02X}  P {}",
+                regs.pc.0, regs.a, regs.x, regs.y, regs.s, regs.p
+            ),
+        );
+        layout.heading("PPU");
+        layout.line(TEXT, &console.ppu.describe());
+
+        layout.heading("CODE (CLICK TO ADD OR REMOVE BREAKPOINTS)");
+        let mut pc = regs.pc;
+        for i in 0..DISASSEMBLY_LINES {
+            let line = cpu::disassemble(|addr| console.peek(addr), pc);
+            let marker = if i == 0 { '>' } else { ' ' };
+            let (color, breakpoint) = if console.breakpoints.contains(&pc) {
+                (BREAKPOINT, '*')
+            } else {
+                (TEXT, ' ')
+            };
+            layout.line(
+                color,
+                &format!(
+                    "{}{} {:04X}  {:<8}  {}",
+                    marker, breakpoint, pc.0, line.bytes, line.text
+                ),
+            );
+            layout.target(
+                0..usize::MAX,
+                Action::Command(Command::ToggleBreakpoint(pc)),
+            );
+            pc = Address(pc.0.wrapping_add(line.len));
+        }
+
+        layout.heading("BREAKPOINTS (CLICK TO REMOVE)");
+        layout.line(TEXT, "");
+        if console.breakpoints.is_empty() {
+            layout.text(TEXT, "NONE");
+        }
+        for &addr in console.breakpoints.iter().take(BREAKPOINTS_SHOWN) {
+            let action = Action::Command(Command::ToggleBreakpoint(addr));
+            layout.clickable(BREAKPOINT, &format!("{:04X}", addr.0), action);
+            layout.text(TEXT, " ");
+        }
+        if console.breakpoints.len() > BREAKPOINTS_SHOWN {
+            let more = console.breakpoints.len() - BREAKPOINTS_SHOWN;
+            layout.text(TEXT, &format!("+{} MORE", more));
+        }
+
+        layout.heading("MEMORY ");
+        let page = MEMORY_ROWS as i32;
+        layout.button("[<]", Action::Scroll(-page));
+        layout.text(TEXT, " ");
+        layout.button("[>]", Action::Scroll(page));
+        for row in 0..MEMORY_ROWS {
+            let start = self.memory_start.wrapping_add(row * MEMORY_ROW_LEN);
+            let bytes: Vec<String> = (0..MEMORY_ROW_LEN)
+                .map(|col| match console.peek(Address(start.wrapping_add(col))) {
+                    Some(value) => format!("{:02X}", value),
+                    None => "--".to_string(),
+                })
+                .collect();
+            layout.line(TEXT, &format!("{:04X}  {}", start, bytes.join(" ")));
+        }
+
+        for (i, line) in layout.lines.iter().enumerate() {
+            let top = MARGIN + i * LINE_HEIGHT;
+            let mut left = MARGIN;
+            for (color, text) in line {
+                font::draw_text(frame, VIEW_WIDTH, left, top, text, *color);
+                left += text.chars().count() * COLUMN_WIDTH;
+            }
+        }
+        self.targets = layout.targets;
+    }
+}
+
+/// Lines of text to draw, each made up of pieces in different colors, along
+/// with the parts that can be clicked.
+#[derive(Default)]
+struct Layout {
+    lines: Vec<Vec<([u8; 4], String)>>,
+    targets: Vec<Target>,
+}
+
+impl Layout {
+    fn line(&mut self, color: [u8; 4], text: &str) {
+        self.lines.push(Vec::new());
+        self.text(color, text);
+    }
+
+    fn heading(&mut self, text: &str) {
+        self.line(HEADING, text);
+    }
+
+    /// Add text to the end of the last line.
+    fn text(&mut self, color: [u8; 4], text: &str) {
+        if self.lines.is_empty() {
+            self.lines.push(Vec::new());
+        }
+        self.lines
+            .last_mut()
+            .unwrap()
+            .push((color, text.to_string()));
+    }
+
+    /// Add text to the end of the last line that does something when it's
+    /// clicked.
+    fn clickable(&mut self, color: [u8; 4], text: &str, action: Action) {
+        let start = self.len();
+        self.text(color, text);
+        self.target(start..self.len(), action);
+    }
+
+    fn button(&mut self, label: &str, action: Action) {
+        self.clickable(BUTTON, label, action);
+    }
+
+    /// Make the given columns of the last line clickable.
+    fn target(&mut self, columns: Range<usize>, action: Action) {
+        self.targets.push(Target {
+            line: self.lines.len() - 1,
+            columns,
+            action,
+        });
+    }
+
+    /// Number of characters on the last line.
+    fn len(&self) -> usize {
+        self.lines.last().map_or(0, |line| {
+            line.iter().map(|(_, text)| text.chars().count()).sum()
+        })
+    }
+}
@@ -0,0 +1,144 @@
+//! A tiny bitmap font for drawing text over the frame, like status messages.
+//!
+//! Each glyph is 3x5 pixels, which is legible at the NES's resolution while
+//! leaving most of the game visible. Only uppercase letters, digits and some
+//! punctuation are included; lowercase letters are drawn as uppercase, and
+//! anything else as `?`.
+
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+/// Horizontal distance from the start of one character to the next.
+const ADVANCE: usize = GLYPH_WIDTH + 1;
+
+/// Space to leave around text drawn by `draw_label`.
+const PADDING: usize = 2;
+
+/// Rows of each glyph from top to bottom, with the leftmost pixel in bit 2.
+#[rustfmt::skip]
+const GLYPHS: &[(char, [u8; GLYPH_HEIGHT])] = &[
+    (' ', [0b000, 0b000, 0b000, 0b000, 0b000]),
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b001, 0b001, 0b001]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('A', [0b010, 0b101, 0b111, 0b101, 0b101]),
+    ('B', [0b110, 0b101, 0b110, 0b101, 0b110]),
+    ('C', [0b011, 0b100, 0b100, 0b100, 0b011]),
+    ('D', [0b110, 0b101, 0b101, 0b101, 0b110]),
+    ('E', [0b111, 0b100, 0b110, 0b100, 0b111]),
+    ('F', [0b111, 0b100, 0b110, 0b100, 0b100]),
+    ('G', [0b011, 0b100, 0b101, 0b101, 0b011]),
+    ('H', [0b101, 0b101, 0b111, 0b101, 0b101]),
+    ('I', [0b111, 0b010, 0b010, 0b010, 0b111]),
+    ('J', [0b001, 0b001, 0b001, 0b101, 0b010]),
+    ('K', [0b101, 0b101, 0b110, 0b101, 0b101]),
+    ('L', [0b100, 0b100, 0b100, 0b100, 0b111]),
+    ('M', [0b101, 0b111, 0b111, 0b101, 0b101]),
+    ('N', [0b110, 0b101, 0b101, 0b101, 0b101]),
+    ('O', [0b010, 0b101, 0b101, 0b101, 0b010]),
+    ('P', [0b110, 0b101, 0b110, 0b100, 0b100]),
+    ('Q', [0b010, 0b101, 0b101, 0b110, 0b011]),
+    ('R', [0b110, 0b101, 0b110, 0b101, 0b101]),
+    ('S', [0b011, 0b100, 0b010, 0b001, 0b110]),
+    ('T', [0b111, 0b010, 0b010, 0b010, 0b010]),
+    ('U', [0b101, 0b101, 0b101, 0b101, 0b111]),
+    ('V', [0b101, 0b101, 0b101, 0b101, 0b010]),
+    ('W', [0b101, 0b101, 0b111, 0b111, 0b101]),
+    ('X', [0b101, 0b101, 0b010, 0b101, 0b101]),
+    ('Y', [0b101, 0b101, 0b010, 0b010, 0b010]),
+    ('Z', [0b111, 0b001, 0b010, 0b100, 0b111]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    (',', [0b000, 0b000, 0b000, 0b010, 0b100]),
+    (':', [0b000, 0b010, 0b000, 0b010, 0b000]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('+', [0b000, 0b010, 0b111, 0b010, 0b000]),
+    ('=', [0b000, 0b111, 0b000, 0b111, 0b000]),
+    ('_', [0b000, 0b000, 0b000, 0b000, 0b111]),
+    ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+    ('%', [0b101, 0b001, 0b010, 0b100, 0b101]),
+    ('(', [0b001, 0b010, 0b010, 0b010, 0b001]),
+    (')', [0b100, 0b010, 0b010, 0b010, 0b100]),
+    ('!', [0b010, 0b010, 0b010, 0b000, 0b010]),
+    ('#', [0b101, 0b111, 0b101, 0b111, 0b101]),
+    ('$', [0b011, 0b110, 0b010, 0b011, 0b110]),
+    ('<', [0b001, 0b010, 0b100, 0b010, 0b001]),
+    ('>', [0b100, 0b010, 0b001, 0b010, 0b100]),
+    ('[', [0b011, 0b010, 0b010, 0b010, 0b011]),
+    (']', [0b110, 0b010, 0b010, 0b010, 0b110]),
+    ('*', [0b000, 0b101, 0b010, 0b101, 0b000]),
+    ('?', [0b110, 0b001, 0b010, 0b000, 0b010]),
+];
+
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    let c = c.to_ascii_uppercase();
+    GLYPHS
+        .iter()
+        .find(|&&(g, _)| g == c)
+        .or_else(|| GLYPHS.iter().find(|&&(g, _)| g == '?'))
+        .map(|&(_, rows)| rows)
+        .unwrap()
+}
+
+/// Width of the given text in pixels, when drawn on a single line.
+pub fn text_width(text: &str) -> usize {
+    (text.chars().count() * ADVANCE).saturating_sub(1)
+}
+
+/// Draw text over a frame of the given width in pixels, with its top left
+/// corner at the given position. Text that runs off the frame is clipped.
+pub fn draw_text(
+    frame: &mut [u8],
+    frame_width: usize,
+    left: usize,
+    top: usize,
+    text: &str,
+    color: [u8; 4],
+) {
+    let frame_height = frame.len() / 4 / frame_width;
+    for (i, c) in text.chars().enumerate() {
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                let (x, y) = (left + i * ADVANCE + col, top + row);
+                if bits & (0b100 >> col) > 0 && x < frame_width && y < frame_height {
+                    let i = (y * frame_width + x) * 4;
+                    frame[i..i + 4].copy_from_slice(&color);
+                }
+            }
+        }
+    }
+}
+
+/// Size of the box drawn by `draw_label` for the given text.
+pub fn label_size(text: &str) -> (usize, usize) {
+    (text_width(text) + PADDING * 2, GLYPH_HEIGHT + PADDING * 2)
+}
+
+/// Draw white text on a darkened box, so that it's readable over any game.
+pub fn draw_label(frame: &mut [u8], frame_width: usize, left: usize, top: usize, text: &str) {
+    let frame_height = frame.len() / 4 / frame_width;
+    let (width, height) = label_size(text);
+    let right = (left + width).min(frame_width);
+    let bottom = (top + height).min(frame_height);
+    for y in top..bottom {
+        for x in left..right {
+            let i = (y * frame_width + x) * 4;
+            for c in &mut frame[i..i + 3] {
+                *c /= 4;
+            }
+        }
+    }
+    draw_text(
+        frame,
+        frame_width,
+        left + PADDING,
+        top + PADDING,
+        text,
+        [0xFF, 0xFF, 0xFF, 0xFF],
+    );
+}
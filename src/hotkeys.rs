@@ -0,0 +1,153 @@
+//! Hotkeys for emulator controls, as opposed to game input.
+//!
+//! Hotkeys are handled by the UI's event loop before each update, and take
+//! precedence over the controllers: a key that is bound to a hotkey is never
+//! seen by the game, even if it's also bound to a button. They're rebound in
+//! the `[bindings.hotkeys]` section of the config file (see the `input`
+//! module).
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use winit::event::VirtualKeyCode;
+use winit_input_helper::WinitInputHelper;
+
+/// Emulator controls.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Hotkey {
+    /// Start or stop recording audio to a WAV file.
+    RecordAudio,
+    /// Show or hide the controllers' buttons on screen.
+    ToggleInputOverlay,
+    /// Show or hide the emulator's frame rate and other stats.
+    ToggleStats,
+    /// Turn the CRT filter on or off.
+    ToggleCrt,
+    /// Switch to the next palette, or the previous one if shift is held.
+    NextPalette,
+    /// Toggle muting of an APU channel, or soloing if shift is held.
+    MutePulse1,
+    MutePulse2,
+    MuteTriangle,
+    MuteNoise,
+    MuteDmc,
+    /// Save a state to the selected slot.
+    SaveState,
+    /// Load the state in the selected slot.
+    LoadState,
+    /// Select the next save state slot, or the previous one if shift is held.
+    NextSlot,
+    /// Save a screenshot of the current frame.
+    Screenshot,
+    /// Save the last few seconds of the game as a GIF.
+    SaveClip,
+    /// Pause or resume emulation.
+    Pause,
+    /// Run as fast as possible (up to the configured limit) while held.
+    FastForward,
+    /// Turn fast-forward on or off.
+    ToggleFastForward,
+    /// Cycle through slow-motion speeds (50% and 25%) and back to normal
+    /// speed.
+    SlowMotion,
+    /// Run the next frame in TAS mode, or while paused.
+    FrameAdvance,
+    /// Go back one frame in TAS mode, so its input can be changed.
+    UndoFrame,
+}
+
+impl Hotkey {
+    /// Hotkeys that mute each APU channel, in the order given by
+    /// `Channel::ALL`.
+    pub const MUTE_CHANNEL: [Hotkey; 5] = [
+        Hotkey::MutePulse1,
+        Hotkey::MutePulse2,
+        Hotkey::MuteTriangle,
+        Hotkey::MuteNoise,
+        Hotkey::MuteDmc,
+    ];
+}
+
+const DEFAULT_HOTKEYS: &[(Hotkey, VirtualKeyCode)] = &[
+    (Hotkey::NextPalette, VirtualKeyCode::F3),
+    (Hotkey::ToggleCrt, VirtualKeyCode::F4),
+    (Hotkey::SaveState, VirtualKeyCode::F5),
+    (Hotkey::NextSlot, VirtualKeyCode::F6),
+    (Hotkey::LoadState, VirtualKeyCode::F7),
+    (Hotkey::ToggleInputOverlay, VirtualKeyCode::F8),
+    (Hotkey::RecordAudio, VirtualKeyCode::F9),
+    (Hotkey::ToggleStats, VirtualKeyCode::F10),
+    (Hotkey::SaveClip, VirtualKeyCode::F11),
+    (Hotkey::Screenshot, VirtualKeyCode::F12),
+    (Hotkey::MutePulse1, VirtualKeyCode::Key1),
+    (Hotkey::MutePulse2, VirtualKeyCode::Key2),
+    (Hotkey::MuteTriangle, VirtualKeyCode::Key3),
+    (Hotkey::MuteNoise, VirtualKeyCode::Key4),
+    (Hotkey::MuteDmc, VirtualKeyCode::Key5),
+    (Hotkey::Pause, VirtualKeyCode::Pause),
+    (Hotkey::FastForward, VirtualKeyCode::Tab),
+    (Hotkey::ToggleFastForward, VirtualKeyCode::Grave),
+    (Hotkey::SlowMotion, VirtualKeyCode::Minus),
+    (Hotkey::FrameAdvance, VirtualKeyCode::Backslash),
+    (Hotkey::UndoFrame, VirtualKeyCode::Back),
+];
+
+/// The keys bound to each hotkey.
+pub struct Hotkeys {
+    keys: Vec<(VirtualKeyCode, Hotkey)>,
+}
+
+impl Hotkeys {
+    /// Apply the user's bindings on top of the default hotkeys.
+    pub fn new(bindings: &HashMap<Hotkey, VirtualKeyCode>) -> Self {
+        Self {
+            keys: DEFAULT_HOTKEYS
+                .iter()
+                .map(|&(hotkey, key)| (bindings.get(&hotkey).copied().unwrap_or(key), hotkey))
+                .collect(),
+        }
+    }
+
+    /// Whether the given key is bound to a hotkey.
+    pub fn is_bound(&self, key: VirtualKeyCode) -> bool {
+        self.keys.iter().any(|&(k, _)| k == key)
+    }
+
+    /// Whether the key bound to the given hotkey is being held down.
+    pub fn held(&self, hotkey: Hotkey, input: &WinitInputHelper) -> bool {
+        self.keys
+            .iter()
+            .any(|&(key, h)| h == hotkey && input.key_held(key))
+    }
+
+    /// Get the hotkeys that were pressed since the last update.
+    pub fn pressed(&self, input: &WinitInputHelper) -> Vec<Hotkey> {
+        self.keys
+            .iter()
+            .filter(|&&(key, _)| input.key_pressed(key))
+            .map(|&(_, hotkey)| hotkey)
+            .collect()
+    }
+}
+
+impl Default for Hotkeys {
+    fn default() -> Self {
+        Self::new(&HashMap::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hotkeys() {
+        let bindings: HashMap<Hotkey, VirtualKeyCode> =
+            toml::from_str("record_audio = \"F12\"").unwrap();
+        let hotkeys = Hotkeys::new(&bindings);
+        assert!(hotkeys.is_bound(VirtualKeyCode::F12));
+        assert!(!hotkeys.is_bound(VirtualKeyCode::F9));
+        assert!(hotkeys.is_bound(VirtualKeyCode::Key1));
+    }
+}
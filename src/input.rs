@@ -0,0 +1,375 @@
+//! Mapping from keys on the host's keyboard to controller buttons.
+//!
+//! Bindings are given in the `[bindings]` section of the config file (or in a
+//! separate file passed with `--bindings`), as tables mapping each button or
+//! hotkey (see the `hotkeys` module) to the name of a key. Anything that isn't
+//! bound keeps its default key. Players 3 and 4, who are only used with a
+//! Four Score, have no keys by default. For example:
+//!
+//! ```toml
+//! [bindings]
+//! turbo_period = 3
+//!
+//! [bindings.player1]
+//! a = "K"
+//! b = "J"
+//! turbo_a = "I"
+//!
+//! [bindings.player3]
+//! a = "Numpad0"
+//! up = "Numpad8"
+//!
+//! [bindings.hotkeys]
+//! record_audio = "F12"
+//!
+//! [bindings.autofire]
+//! b = [3, 2]
+//! ```
+//!
+//! The keys for the Power Pad's sensors are given as a list, from sensor 1 to
+//! 12 (e.g., `power_pad = ["Key7", "Key8", ...]`).
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use winit::event::VirtualKeyCode;
+use winit_input_helper::WinitInputHelper;
+
+use crate::controller::Buttons;
+use crate::hotkeys::{Hotkey, Hotkeys};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right,
+    /// A and B, but repeatedly pressed and released while held. These have
+    /// no keys by default.
+    TurboA,
+    TurboB,
+    /// The microphone on a Famicom's second controller. Games hear sound
+    /// while this is held.
+    Microphone,
+}
+
+impl Button {
+    /// The controller button(s) that this binding presses.
+    fn buttons(self) -> Buttons {
+        match self {
+            Button::A | Button::TurboA => Buttons::A,
+            Button::B | Button::TurboB => Buttons::B,
+            Button::Select => Buttons::SELECT,
+            Button::Start => Buttons::START,
+            Button::Up => Buttons::UP,
+            Button::Down => Buttons::DOWN,
+            Button::Left => Buttons::LEFT,
+            Button::Right => Buttons::RIGHT,
+            // The microphone isn't read through the shift register.
+            Button::Microphone => Buttons::empty(),
+        }
+    }
+}
+
+const DEFAULT_PLAYER1: &[(Button, VirtualKeyCode)] = &[
+    (Button::A, VirtualKeyCode::X),
+    (Button::B, VirtualKeyCode::Z),
+    (Button::Select, VirtualKeyCode::RShift),
+    (Button::Start, VirtualKeyCode::Return),
+    (Button::Up, VirtualKeyCode::Up),
+    (Button::Down, VirtualKeyCode::Down),
+    (Button::Left, VirtualKeyCode::Left),
+    (Button::Right, VirtualKeyCode::Right),
+];
+
+/// Player 2 is on the left side of the keyboard so that two players can
+/// share it.
+const DEFAULT_PLAYER2: &[(Button, VirtualKeyCode)] = &[
+    (Button::A, VirtualKeyCode::H),
+    (Button::B, VirtualKeyCode::G),
+    (Button::Select, VirtualKeyCode::T),
+    (Button::Start, VirtualKeyCode::Y),
+    (Button::Up, VirtualKeyCode::W),
+    (Button::Down, VirtualKeyCode::S),
+    (Button::Left, VirtualKeyCode::A),
+    (Button::Right, VirtualKeyCode::D),
+    (Button::Microphone, VirtualKeyCode::N),
+];
+
+/// The Power Pad's sensors, numbered as on side B, are laid out as a 4x3 grid
+/// on the right side of the keyboard, away from the controllers' keys.
+const DEFAULT_POWER_PAD: [VirtualKeyCode; 12] = [
+    VirtualKeyCode::U,
+    VirtualKeyCode::I,
+    VirtualKeyCode::O,
+    VirtualKeyCode::P,
+    VirtualKeyCode::J,
+    VirtualKeyCode::K,
+    VirtualKeyCode::L,
+    VirtualKeyCode::Semicolon,
+    VirtualKeyCode::M,
+    VirtualKeyCode::Comma,
+    VirtualKeyCode::Period,
+    VirtualKeyCode::Slash,
+];
+
+/// A repeating pattern of frame counts that an autofire button is alternately
+/// pressed and released for, starting with pressed. For example, `[3, 2]`
+/// holds the button for 3 frames and then releases it for 2.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "Vec<u32>")]
+pub struct AutofirePattern(Vec<u32>);
+
+impl TryFrom<Vec<u32>> for AutofirePattern {
+    type Error = String;
+
+    fn try_from(frames: Vec<u32>) -> Result<Self, Self::Error> {
+        if frames.is_empty() || !frames.len().is_multiple_of(2) {
+            return Err(format!(
+                "autofire pattern must have an even number of entries, got {:?}",
+                frames
+            ));
+        }
+        if frames.contains(&0) {
+            return Err(format!("autofire pattern has a zero entry: {:?}", frames));
+        }
+        Ok(Self(frames))
+    }
+}
+
+impl AutofirePattern {
+    /// Whether the button is pressed on the given frame.
+    fn is_pressed(&self, frame: u64) -> bool {
+        let length: u64 = self.0.iter().map(|&n| n as u64).sum();
+        let mut offset = frame % length;
+        for (i, &n) in self.0.iter().enumerate() {
+            if offset < n as u64 {
+                return i % 2 == 0;
+            }
+            offset -= n as u64;
+        }
+        unreachable!()
+    }
+}
+
+/// Key bindings as given by the user, which override the defaults.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Bindings {
+    pub player1: HashMap<Button, VirtualKeyCode>,
+    pub player2: HashMap<Button, VirtualKeyCode>,
+    pub player3: HashMap<Button, VirtualKeyCode>,
+    pub player4: HashMap<Button, VirtualKeyCode>,
+    pub hotkeys: HashMap<Hotkey, VirtualKeyCode>,
+    /// Keys for the Power Pad's 12 sensors, in order from sensor 1 to 12.
+    pub power_pad: Option<[VirtualKeyCode; 12]>,
+    /// Number of frames that turbo buttons are pressed for, and then
+    /// released for. The default of 2 frames gives 15 presses per second.
+    pub turbo_period: u32,
+    /// Buttons that repeatedly press and release themselves while held,
+    /// following a pattern (e.g., `a = [3, 2]`). This applies to every
+    /// player, and overrides `turbo_period` for the turbo buttons.
+    pub autofire: HashMap<Button, AutofirePattern>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self {
+            player1: HashMap::new(),
+            player2: HashMap::new(),
+            player3: HashMap::new(),
+            player4: HashMap::new(),
+            hotkeys: HashMap::new(),
+            power_pad: None,
+            turbo_period: 2,
+            autofire: HashMap::new(),
+        }
+    }
+}
+
+impl Bindings {
+    /// Load bindings from a file with the same layout as the config file's
+    /// `[bindings]` section.
+    pub fn load(path: &Path) -> Result<Self> {
+        log::info!("Loading key bindings: {:?}", path);
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+        toml::from_str(&contents).with_context(|| format!("Failed to parse {:?}", path))
+    }
+}
+
+/// Apply the user's bindings on top of the defaults.
+fn merge<T: Copy + Eq + std::hash::Hash>(
+    defaults: &[(T, VirtualKeyCode)],
+    bindings: &HashMap<T, VirtualKeyCode>,
+) -> Vec<(VirtualKeyCode, T)> {
+    let mut merged: Vec<_> = defaults
+        .iter()
+        .map(|&(action, key)| (bindings.get(&action).copied().unwrap_or(key), action))
+        .collect();
+    // Actions that aren't bound by default.
+    for (&action, &key) in bindings {
+        if !defaults.iter().any(|&(a, _)| a == action) {
+            merged.push((key, action));
+        }
+    }
+    merged
+}
+
+/// The complete set of key bindings in effect.
+pub struct InputMap {
+    players: [Vec<(VirtualKeyCode, Button)>; 4],
+    power_pad: [VirtualKeyCode; 12],
+    /// Buttons that follow an autofire pattern while held, including the
+    /// turbo buttons.
+    autofire: HashMap<Button, AutofirePattern>,
+}
+
+impl InputMap {
+    /// Create the map from the user's bindings. Keys that are bound to
+    /// hotkeys are left out, since hotkeys take precedence.
+    pub fn new(bindings: &Bindings, hotkeys: &Hotkeys) -> Self {
+        let turbo_period = bindings.turbo_period.max(1);
+        let mut autofire = bindings.autofire.clone();
+        for button in [Button::TurboA, Button::TurboB] {
+            autofire
+                .entry(button)
+                .or_insert_with(|| AutofirePattern(vec![turbo_period; 2]));
+        }
+        let mut map = Self {
+            autofire,
+            players: [
+                merge(DEFAULT_PLAYER1, &bindings.player1),
+                merge(DEFAULT_PLAYER2, &bindings.player2),
+                merge(&[], &bindings.player3),
+                merge(&[], &bindings.player4),
+            ],
+            power_pad: bindings.power_pad.unwrap_or(DEFAULT_POWER_PAD),
+        };
+        for keys in &mut map.players {
+            keys.retain(|&(key, button)| {
+                let bound = hotkeys.is_bound(key);
+                if bound {
+                    log::warn!(
+                        "Ignoring {:?} for {:?}, since it's bound to a hotkey",
+                        key,
+                        button
+                    );
+                }
+                !bound
+            });
+        }
+        map
+    }
+
+    /// Get the buttons of the given player's controller (0 to 3) whose keys
+    /// are currently held, as of the given frame (which determines whether
+    /// autofire buttons are pressed).
+    pub fn buttons(&self, input: &WinitInputHelper, player: usize, frame: u64) -> Buttons {
+        self.players[player]
+            .iter()
+            .filter(|&&(key, button)| {
+                input.key_held(key)
+                    && self
+                        .autofire
+                        .get(&button)
+                        .is_none_or(|pattern| pattern.is_pressed(frame))
+            })
+            .fold(Buttons::empty(), |buttons, &(_, button)| {
+                buttons | button.buttons()
+            })
+    }
+
+    /// Get the buttons of the given player's controller whose keys were
+    /// pressed since the last update, ignoring autofire.
+    pub fn pressed(&self, input: &WinitInputHelper, player: usize) -> Buttons {
+        self.players[player]
+            .iter()
+            .filter(|&&(key, _)| input.key_pressed(key))
+            .fold(Buttons::empty(), |buttons, &(_, button)| {
+                buttons | button.buttons()
+            })
+    }
+
+    /// Whether a key bound to the microphone (on player 2's controller) is
+    /// held.
+    pub fn microphone(&self, input: &WinitInputHelper) -> bool {
+        self.players[1]
+            .iter()
+            .any(|&(key, button)| button == Button::Microphone && input.key_held(key))
+    }
+
+    /// Get the Power Pad sensors whose keys are currently held, with sensor
+    /// `n` in bit `n - 1`.
+    pub fn power_pad(&self, input: &WinitInputHelper) -> u16 {
+        self.power_pad
+            .iter()
+            .enumerate()
+            .filter(|&(_, &key)| input.key_held(key))
+            .fold(0, |sensors, (i, _)| sensors | 1 << i)
+    }
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        Self::new(&Bindings::default(), &Hotkeys::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bindings() {
+        let bindings: Bindings = toml::from_str(
+            r#"
+            [player2]
+            a = "K"
+            start = "Space"
+
+            [hotkeys]
+            record_audio = "F12"
+            "#,
+        )
+        .unwrap();
+        let hotkeys = Hotkeys::new(&bindings.hotkeys);
+        let map = InputMap::new(&bindings, &hotkeys);
+        assert!(map.players[1].contains(&(VirtualKeyCode::K, Button::A)));
+        assert!(map.players[1].contains(&(VirtualKeyCode::G, Button::B)));
+        assert!(map.players[0].contains(&(VirtualKeyCode::X, Button::A)));
+
+        assert!(toml::from_str::<Bindings>("[player1]\nturbo = \"A\"").is_err());
+
+        // Turbo buttons aren't bound by default.
+        assert!(!map.players[0]
+            .iter()
+            .any(|&(_, button)| button == Button::TurboA));
+        let bindings: Bindings = toml::from_str("[player1]\nturbo_a = \"I\"").unwrap();
+        let map = InputMap::new(&bindings, &hotkeys);
+        assert!(map.players[0].contains(&(VirtualKeyCode::I, Button::TurboA)));
+        assert_eq!(map.autofire[&Button::TurboA], AutofirePattern(vec![2, 2]));
+
+        let bindings: Bindings = toml::from_str("[autofire]\nb = [3, 2]").unwrap();
+        let pattern = &bindings.autofire[&Button::B];
+        let pressed: Vec<bool> = (0..6).map(|frame| pattern.is_pressed(frame)).collect();
+        assert_eq!(pressed, [true, true, true, false, false, true]);
+        assert!(toml::from_str::<Bindings>("[autofire]\nb = [3]").is_err());
+
+        // Hotkeys take precedence over buttons.
+        let bindings: Bindings = toml::from_str("[player1]\na = \"F9\"").unwrap();
+        let map = InputMap::new(&bindings, &Hotkeys::default());
+        assert!(!map.players[0]
+            .iter()
+            .any(|&(_, button)| button == Button::A));
+    }
+}
@@ -0,0 +1,86 @@
+//! On-screen display of the buttons held on each controller.
+//!
+//! Each controller is drawn as a small pad along the bottom of the frame, with
+//! player 1 on the left and player 2 on the right (and players 3 and 4 above
+//! them, if a Four Score is in use). Held buttons are lit up, so the display
+//! shows exactly what the game sees on each frame.
+
+use crate::controller::Buttons;
+use crate::ppu::FRAME_WIDTH;
+
+const PAD_WIDTH: usize = 36;
+const PAD_HEIGHT: usize = 14;
+const MARGIN: usize = 4;
+
+/// Position and size of each button within the pad, as (x, y, width, height),
+/// in the order of the bits in `Buttons`.
+const BUTTON_RECTS: [(usize, usize, usize, usize); 8] = [
+    (30, 5, 4, 4), // A
+    (25, 5, 4, 4), // B
+    (13, 8, 4, 2), // Select
+    (19, 8, 4, 2), // Start
+    (6, 2, 3, 3),  // Up
+    (6, 8, 3, 3),  // Down
+    (3, 5, 3, 3),  // Left
+    (9, 5, 3, 3),  // Right
+];
+
+const RELEASED: [u8; 4] = [0x58, 0x58, 0x58, 0xFF];
+const HELD: [u8; 4] = [0xF0, 0xF0, 0xF0, 0xFF];
+const HELD_AB: [u8; 4] = [0xF8, 0x38, 0x38, 0xFF];
+
+/// Draw the given players' controllers over a frame of `FRAME_WIDTH` x
+/// `FRAME_HEIGHT` pixels.
+pub fn draw(frame: &mut [u8], players: &[Buttons]) {
+    let frame_height = frame.len() / 4 / FRAME_WIDTH;
+    for (i, &buttons) in players.iter().enumerate() {
+        let left = if i % 2 == 0 {
+            MARGIN
+        } else {
+            FRAME_WIDTH - MARGIN - PAD_WIDTH
+        };
+        let top = frame_height - (i / 2 + 1) * (PAD_HEIGHT + MARGIN);
+        draw_pad(frame, left, top, buttons);
+    }
+}
+
+fn draw_pad(frame: &mut [u8], left: usize, top: usize, buttons: Buttons) {
+    // Darken the game behind the pad, so that it stands out without hiding
+    // the game completely.
+    for y in top..top + PAD_HEIGHT {
+        for x in left..left + PAD_WIDTH {
+            let i = (y * FRAME_WIDTH + x) * 4;
+            for c in &mut frame[i..i + 3] {
+                *c /= 3;
+            }
+        }
+    }
+
+    for (bit, &(x, y, width, height)) in BUTTON_RECTS.iter().enumerate() {
+        let button = Buttons::from_bits_retain(1 << bit);
+        let color = if !buttons.contains(button) {
+            RELEASED
+        } else if button.intersects(Buttons::A | Buttons::B) {
+            HELD_AB
+        } else {
+            HELD
+        };
+        fill_rect(frame, left + x, top + y, width, height, color);
+    }
+}
+
+fn fill_rect(
+    frame: &mut [u8],
+    left: usize,
+    top: usize,
+    width: usize,
+    height: usize,
+    color: [u8; 4],
+) {
+    for y in top..top + height {
+        for x in left..left + width {
+            let i = (y * FRAME_WIDTH + x) * 4;
+            frame[i..i + 4].copy_from_slice(&color);
+        }
+    }
+}
@@ -2,7 +2,7 @@ use std::fmt;
 
 use crate::mem::Address;
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub enum IoRegister {
     Sq1Vol,
     Sq1Sweep,
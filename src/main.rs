@@ -1,27 +1,68 @@
 // #![deny(warnings)]
 
-use std::fs::File;
+use std::fmt::Display;
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::Parser;
+use serde::Serialize;
 
+mod apu;
+mod apu_view;
+mod audio;
+mod clip;
+mod colors;
+mod config;
+mod controller;
 mod cpu;
+mod crt;
+mod debugger;
+mod font;
+mod hotkeys;
+mod input;
+mod input_overlay;
 mod io;
 mod mapper;
 mod mem;
+mod movie;
 mod nes;
+mod osd;
 mod ppu;
+mod region;
 mod rom;
+mod save;
+mod savestate;
+mod screenshot;
+mod stats;
+mod tas;
+#[cfg(test)]
+mod test_rom;
 mod ui;
+mod viewer;
+mod watch;
 
+use crate::audio::AudioOptions;
+use crate::colors::Colors;
+use crate::config::Config;
+use crate::controller::DeviceKind;
 use crate::cpu::Cpu;
+use crate::input::Bindings;
 use crate::mem::Address;
-use crate::nes::{Nes, ShowPatternUi};
-use crate::rom::Rom;
-use crate::ui::Ui;
+use crate::movie::{Movie, MovieEnd, MovieRom};
+use crate::nes::{Nes, ShowApuUi, ShowPatternUi};
+use crate::ppu::{Palette, FRAME_HEIGHT, FRAME_WIDTH};
+use crate::region::Region;
+use crate::rom::{Nsf, NsfInfo, Rom, RomDb, RomInfo};
+use crate::save::SaveFile;
+use crate::savestate::{StateLocation, StateSlots};
+use crate::screenshot::Screenshots;
+use crate::tas::TasUi;
+use crate::ui::{Pacing, Ui, UiOptions};
+use crate::viewer::Viewer;
 
 #[derive(Debug, Parser)]
 #[clap(name = "nes", about = "A toy NES emulator")]
@@ -29,15 +70,150 @@ enum Command {
     Run(RunArgs),
     RunCpu(RunCpuArgs),
     RunHeadless(RunHeadlessArgs),
+    Screenshot(ScreenshotArgs),
     ShowPattern(ShowPatternArgs),
+    ShowApu(ShowApuArgs),
+    Tas(TasArgs),
+    CompareStates(CompareStatesArgs),
     ShowHeader(ShowHeaderArgs),
+    FixHeader(FixHeaderArgs),
+    DumpChr(DumpChrArgs),
 }
 
 #[derive(Debug, Parser)]
 #[clap(about = "Run a NES ROM file")]
 struct RunArgs {
-    #[clap(help = "Path to ROM file")]
-    rom: PathBuf,
+    #[clap(
+        help = "Path to ROM file (may be a zip or gzip archive) [default: chosen with a file picker, \
+                in builds with the file-dialog feature]"
+    )]
+    rom: Option<PathBuf>,
+    #[clap(long, help = "Name of the ROM to load from within a zip archive")]
+    entry: Option<String>,
+    #[clap(long, help = "Record the audio output to a WAV file (toggle with F9)")]
+    record_audio: Option<PathBuf>,
+    #[clap(
+        long,
+        requires = "record_audio",
+        help = "Also record each APU channel to a separate WAV file"
+    )]
+    record_channels: bool,
+    #[clap(
+        long,
+        value_enum,
+        help = "Console timing to emulate [default: detected from the ROM, or NTSC]"
+    )]
+    region: Option<Region>,
+    #[clap(long, help = "Path to config file")]
+    config: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Path to key bindings file, overriding the config's bindings"
+    )]
+    bindings: Option<PathBuf>,
+    #[clap(
+        long,
+        value_enum,
+        help = "Device plugged into controller port 2 [default: from the config, or controller]"
+    )]
+    port2: Option<DeviceKind>,
+    #[clap(long, help = "Plug a Four Score into both ports, for up to 4 players")]
+    four_score: bool,
+    #[clap(
+        long,
+        help = "Show the buttons held on each controller over the game (toggle with F8)"
+    )]
+    input_overlay: bool,
+    #[clap(
+        long,
+        help = "Play back the controller input from a movie file (or an FCEUX .fm2 file)"
+    )]
+    play_movie: Option<PathBuf>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        requires = "play_movie",
+        help = "What to do when the movie ends"
+    )]
+    movie_end: MovieEnd,
+    #[clap(
+        long,
+        conflicts_with = "play_movie",
+        help = "Record the controller input to a movie file (in FM2 format if it ends in .fm2)"
+    )]
+    record_movie: Option<PathBuf>,
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Amount of audio to buffer, in milliseconds"
+    )]
+    audio_latency_ms: Option<u32>,
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Audio device buffer size, in sample frames"
+    )]
+    audio_buffer_size: Option<u32>,
+    #[clap(
+        long,
+        help = "Directory for battery-backed save files [default: next to the ROM]"
+    )]
+    save_dir: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "SLOT|PATH",
+        help = "Resume from a save state, given as a slot number (0-9) or a file"
+    )]
+    load_state: Option<StateLocation>,
+    #[clap(
+        long,
+        help = "Start from power on, even if auto-resume is on and there's a state to resume"
+    )]
+    no_resume: bool,
+    #[clap(
+        long,
+        help = "Make the same input always give the same output, by starting without the save \
+                file and ignoring host timing (always on for movies)"
+    )]
+    deterministic: bool,
+    #[clap(
+        long,
+        help = "Start with emulation paused, for debugging startup code (resume with Pause)"
+    )]
+    start_paused: bool,
+    #[clap(
+        long,
+        value_enum,
+        help = "How to time frames [default: from the config, or timer]"
+    )]
+    pacing: Option<Pacing>,
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Initial window size, as a multiple of 256x240 [default: from the config, or 1]"
+    )]
+    scale: Option<u32>,
+    #[clap(long, help = "Start with the CRT filter on (toggle with F4)")]
+    crt: bool,
+    #[clap(
+        long,
+        help = "Reload the ROM and power cycle when the file changes (e.g., after rebuilding it)"
+    )]
+    watch: bool,
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        help = "Debug viewers to open in windows of their own (e.g., --viewers patterns,sprites)"
+    )]
+    viewers: Vec<Viewer>,
+    #[clap(
+        long,
+        value_delimiter = ',',
+        help = "Addresses of instructions to stop at, for inspecting with --viewers debugger"
+    )]
+    breakpoints: Vec<Address>,
 }
 
 #[derive(Debug, Parser)]
@@ -61,17 +237,141 @@ struct RunHeadlessArgs {
 }
 
 #[derive(Debug, Parser)]
-#[clap(about = "Display the pattern table from a ROM file")]
+#[clap(about = "Run a NES ROM file without video output, and save a screenshot of the last frame")]
+struct ScreenshotArgs {
+    #[clap(help = "Path to ROM file (may be a zip or gzip archive)")]
+    rom: PathBuf,
+    #[clap(long, help = "Name of the ROM to load from within a zip archive")]
+    entry: Option<String>,
+    #[clap(
+        long,
+        value_enum,
+        help = "Console timing to emulate [default: detected from the ROM, or NTSC]"
+    )]
+    region: Option<Region>,
+    #[clap(long, default_value_t = 60, help = "Number of frames to run first")]
+    frames: u32,
+    #[clap(
+        short,
+        long,
+        help = "Path to write the PNG file to [default: <ROM>.png]"
+    )]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+#[clap(
+    about = "Display the pattern tables from a ROM file while it runs (Page Up/Down to change \
+             CHR bank, Home/End to change palette)"
+)]
 struct ShowPatternArgs {
     #[clap(help = "Path to ROM file")]
     rom: PathBuf,
 }
 
 #[derive(Debug, Parser)]
-#[clap(about = "Display header information from a ROM file")]
+#[clap(about = "Run a NES ROM file while displaying the state of the APU")]
+struct ShowApuArgs {
+    #[clap(help = "Path to ROM file")]
+    rom: PathBuf,
+    #[clap(
+        long,
+        value_enum,
+        help = "Console timing to emulate [default: detected from the ROM, or NTSC]"
+    )]
+    region: Option<Region>,
+    #[clap(long, help = "Path to config file")]
+    config: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+#[clap(about = "Build a movie frame by frame, with the game paused between frames")]
+struct TasArgs {
+    #[clap(help = "Path to ROM file (may be a zip or gzip archive)")]
+    rom: PathBuf,
+    #[clap(help = "Path to the movie file to edit (created if it doesn't exist)")]
+    movie: PathBuf,
+    #[clap(long, help = "Name of the ROM to load from within a zip archive")]
+    entry: Option<String>,
+    #[clap(
+        long,
+        value_enum,
+        help = "Console timing to emulate [default: detected from the ROM, or NTSC]"
+    )]
+    region: Option<Region>,
+    #[clap(long, help = "Path to config file")]
+    config: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+#[clap(
+    about = "Show which parts of two save states differ, and at which offsets",
+    long_about = "Show which parts of two save states differ, and at which offsets. \
+                  Offsets are into each component's saved data, except for RAM, \
+                  where they're CPU addresses. Exits with status 1 if the states \
+                  differ."
+)]
+struct CompareStatesArgs {
+    #[clap(help = "Path to the first save state")]
+    first: PathBuf,
+    #[clap(help = "Path to the second save state")]
+    second: PathBuf,
+    #[clap(
+        long,
+        default_value_t = 32,
+        help = "Maximum number of differing ranges to list for each component"
+    )]
+    max_ranges: usize,
+}
+
+#[derive(Debug, Parser)]
+#[clap(about = "Display header information from a ROM or NSF file")]
 struct ShowHeaderArgs {
     #[clap(help = "Path to ROM file")]
     rom: PathBuf,
+    #[clap(long, help = "Print the report as JSON")]
+    json: bool,
+}
+
+#[derive(Debug, Parser)]
+#[clap(about = "Repair common problems with a ROM file's header")]
+struct FixHeaderArgs {
+    #[clap(help = "Path to ROM file")]
+    rom: PathBuf,
+    #[clap(
+        short,
+        long,
+        help = "Path to write the repaired ROM to [default: <ROM>.fixed.nes]"
+    )]
+    output: Option<PathBuf>,
+    #[clap(long, help = "Path to ROM database")]
+    db: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+#[clap(about = "Export all of the tiles in a ROM's CHR data to a PNG file")]
+struct DumpChrArgs {
+    #[clap(help = "Path to ROM file")]
+    rom: PathBuf,
+    #[clap(
+        short,
+        long,
+        help = "Path to write the PNG file to [default: <ROM>.chr.png]"
+    )]
+    output: Option<PathBuf>,
+    #[clap(
+        long,
+        default_value = "0F,00,10,30",
+        help = "Colors to draw the tiles with, as 4 comma-separated hex color indexes"
+    )]
+    palette: Palette,
+    #[clap(
+        long,
+        default_value_t = 16,
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "Number of tiles in each row"
+    )]
+    tiles_per_row: u16,
 }
 
 fn main() -> Result<()> {
@@ -80,16 +380,171 @@ fn main() -> Result<()> {
         Command::Run(args) => cmd_run(args),
         Command::RunCpu(args) => cmd_run_cpu(args),
         Command::RunHeadless(args) => cmd_run_headless(args),
+        Command::Screenshot(args) => cmd_screenshot(args),
         Command::ShowPattern(args) => cmd_show_pattern(args),
+        Command::ShowApu(args) => cmd_show_apu(args),
+        Command::Tas(args) => cmd_tas(args),
+        Command::CompareStates(args) => cmd_compare_states(args),
         Command::ShowHeader(args) => cmd_show_header(args),
+        Command::FixHeader(args) => cmd_fix_header(args),
+        Command::DumpChr(args) => cmd_dump_chr(args),
     }
 }
 
 fn cmd_run(args: RunArgs) -> Result<()> {
-    log::info!("Loading ROM: {:?}", &args.rom);
-    let rom = Rom::load(&args.rom)?;
-    let nes = Nes::new(rom);
-    nes.run()
+    let (rom_path, (movie_rom, crc32, name, mut nes)) =
+        open_rom(args.rom.clone(), args.entry.as_deref(), |rom_path, rom| {
+            let movie_rom = MovieRom::new(rom_path, &rom);
+            let crc32 = rom.crc32();
+            let name = rom.title_from_db().unwrap_or_else(|| {
+                let path = args.entry.as_deref().map(Path::new).unwrap_or(rom_path);
+                let stem = path.file_stem().unwrap_or_default();
+                stem.to_string_lossy().into_owned()
+            });
+            let nes = Nes::new(rom, args.region)?;
+            Ok((movie_rom, crc32, name, nes))
+        })?;
+    let mut config = Config::load(args.config.as_deref())?;
+    if let Some(path) = &args.bindings {
+        config.bindings = Bindings::load(path)?;
+    }
+    if let Some(device) = args.port2 {
+        config.input.port2 = device;
+    }
+    config.input.four_score |= args.four_score;
+    config.input.overlay |= args.input_overlay;
+    // Movies have to start from power on, and shouldn't replace the state
+    // or the save file that the player left the game in.
+    let movie = args.play_movie.is_some() || args.record_movie.is_some();
+    let deterministic = args.deterministic || movie;
+    nes.configure(&config);
+    nes.set_name(name);
+    if deterministic {
+        nes.set_deterministic();
+    } else {
+        let save_dir = args.save_dir.as_deref().or(config.saves.dir.as_deref());
+        nes.load_save_file(SaveFile::path_for(&rom_path, save_dir))?;
+    }
+    nes.set_state_slots(StateSlots::for_rom(
+        &rom_path,
+        config.saves.state_dir.as_deref(),
+    ));
+    let mut screenshots = Screenshots::for_rom(&rom_path, config.screenshots.dir.as_deref());
+    screenshots.save_overlays = config.screenshots.save_overlays;
+    nes.set_screenshots(screenshots);
+    if config.screenshots.clip_seconds > 0.0 {
+        nes.enable_clips(config.screenshots.clip_seconds);
+    }
+    if config.saves.auto_resume && !deterministic {
+        let path = savestate::auto_save_path(&rom_path, crc32, config.saves.state_dir.as_deref());
+        if args.load_state.is_none() && !args.no_resume && path.is_file() {
+            match nes.load_state_from(&StateLocation::File(path.clone())) {
+                Ok(()) => nes.show_message("Resumed last session".to_string()),
+                Err(e) => log::warn!("Failed to resume last session: {:#}", e),
+            }
+        }
+        nes.set_auto_save(path);
+    }
+    nes.enable_audio(AudioOptions {
+        latency_ms: args
+            .audio_latency_ms
+            .or(config.audio.latency_ms.map(NonZeroU32::get)),
+        buffer_size: args
+            .audio_buffer_size
+            .or(config.audio.buffer_size.map(NonZeroU32::get)),
+    });
+    if let Some(path) = &args.record_audio {
+        nes.start_recording(path, args.record_channels)?;
+    }
+    if let Some(path) = &args.play_movie {
+        let movie = Movie::load(path)?;
+        movie.check_rom(&movie_rom);
+        nes.play_movie(movie, args.movie_end);
+    }
+    if let Some(path) = args.record_movie {
+        let players = if config.input.four_score { 4 } else { 2 };
+        let mut movie = Movie::new(players);
+        movie.rom = Some(movie_rom);
+        nes.record_movie(path, movie);
+    }
+    // Load the state once any movie has been set up, so that the state can
+    // be checked against it.
+    if let Some(location) = &args.load_state {
+        nes.load_state_from(location)
+            .context("Failed to load save state")?;
+    }
+    nes.set_viewers(args.viewers);
+    for addr in args.breakpoints {
+        nes.add_breakpoint(addr);
+    }
+    if args.watch {
+        nes.watch_rom(rom_path.clone(), args.entry.clone());
+    }
+    let result = nes.run_with(UiOptions {
+        start_paused: args.start_paused,
+        pacing: args.pacing.unwrap_or(config.video.pacing),
+        scale: args.scale.unwrap_or(config.video.scale).max(1),
+        crt: args.crt || config.video.crt,
+        pause_on_focus_loss: config.emulation.pause_on_focus_loss,
+    });
+    // Save the game and finish any recordings before reporting an error.
+    drop(nes);
+    result
+}
+
+/// Load the ROM to run, and set it up with `init` (e.g., by inserting it into
+/// a console). If no path is given, or the ROM fails to load or to be set up
+/// (e.g., because its mapper isn't supported), the player is asked to choose
+/// one instead (in builds with the `file-dialog` feature), so that the
+/// emulator is usable when launched from a desktop icon.
+fn open_rom<T>(
+    path: Option<PathBuf>,
+    entry: Option<&str>,
+    mut init: impl FnMut(&Path, Rom) -> Result<T>,
+) -> Result<(PathBuf, T)> {
+    let mut path = path;
+    loop {
+        let path = match path.take().or_else(pick_rom) {
+            Some(path) => path,
+            None if cfg!(feature = "file-dialog") => bail!("No ROM chosen"),
+            None => bail!("No ROM given"),
+        };
+        log::info!("Loading ROM: {:?}", &path);
+        match Rom::load_entry(&path, entry).and_then(|rom| init(&path, rom)) {
+            Ok(value) => return Ok((path, value)),
+            Err(e) if cfg!(feature = "file-dialog") => show_error(&e),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(feature = "file-dialog")]
+fn pick_rom() -> Option<PathBuf> {
+    rfd::FileDialog::new()
+        .set_title("Open ROM")
+        .add_filter("NES ROMs", &["nes", "unf", "unif", "zip", "gz"])
+        .pick_file()
+}
+
+#[cfg(not(feature = "file-dialog"))]
+fn pick_rom() -> Option<PathBuf> {
+    None
+}
+
+/// Tell the player why a ROM they chose can't be run.
+#[cfg(feature = "file-dialog")]
+fn show_error(error: &anyhow::Error) {
+    log::error!("{:#}", error);
+    rfd::MessageDialog::new()
+        .set_level(rfd::MessageLevel::Error)
+        .set_title("Failed to load ROM")
+        .set_description(format!("{:#}", error))
+        .show();
+}
+
+#[cfg(not(feature = "file-dialog"))]
+fn show_error(error: &anyhow::Error) {
+    log::error!("{:#}", error);
 }
 
 fn cmd_run_cpu(args: RunCpuArgs) -> Result<()> {
@@ -112,36 +567,149 @@ fn cmd_run_cpu(args: RunCpuArgs) -> Result<()> {
 fn cmd_run_headless(args: RunHeadlessArgs) -> Result<()> {
     log::info!("Loading ROM: {:?}", &args.rom);
     let rom = Rom::load(&args.rom)?;
-    let mut nes = Nes::new(rom);
+    let mut nes = Nes::new(rom, None)?;
     nes.run_cpu(args.start);
     Ok(())
 }
 
+fn cmd_screenshot(args: ScreenshotArgs) -> Result<()> {
+    log::info!("Loading ROM: {:?}", &args.rom);
+    let rom = Rom::load_entry(&args.rom, args.entry.as_deref())?;
+    let mut nes = Nes::new(rom, args.region)?;
+    let mut frame = vec![0; FRAME_WIDTH * FRAME_HEIGHT * 4];
+    for _ in 0..args.frames {
+        nes.run_one_frame_headless(&mut frame);
+    }
+    let output = match args.output {
+        Some(output) => output,
+        None => args.rom.with_extension("png"),
+    };
+    screenshot::write_png(&output, FRAME_WIDTH, FRAME_HEIGHT, &frame)?;
+    println!("Wrote frame {} to {:?}", args.frames, &output);
+    Ok(())
+}
+
 fn cmd_show_pattern(args: ShowPatternArgs) -> Result<()> {
     log::info!("Displaying pattern table for ROM: {:?}", &args.rom);
     let rom = Rom::load(&args.rom)?;
-    let nes = Nes::new(rom);
-    let ui = ShowPatternUi::new(nes);
+    let chr = if rom.header.has_chr_ram() {
+        Vec::new()
+    } else {
+        rom.chr.clone()
+    };
+    let nes = Nes::new(rom, None)?;
+    let mut ui = ShowPatternUi::new(nes, chr);
+    ui.run()
+}
+
+fn cmd_show_apu(args: ShowApuArgs) -> Result<()> {
+    log::info!("Displaying APU state for ROM: {:?}", &args.rom);
+    let config = Config::load(args.config.as_deref())?;
+    let rom = Rom::load(&args.rom)?;
+    let mut nes = Nes::new(rom, args.region)?;
+    nes.configure(&config);
+    nes.load_save_file(SaveFile::path_for(&args.rom, config.saves.dir.as_deref()))?;
+    nes.enable_audio(AudioOptions {
+        latency_ms: config.audio.latency_ms.map(NonZeroU32::get),
+        buffer_size: config.audio.buffer_size.map(NonZeroU32::get),
+    });
+    let mut ui = ShowApuUi::new(nes);
     ui.run()
 }
 
+fn cmd_tas(args: TasArgs) -> Result<()> {
+    log::info!("Loading ROM: {:?}", &args.rom);
+    let rom = Rom::load_entry(&args.rom, args.entry.as_deref())?;
+    let movie_rom = MovieRom::new(&args.rom, &rom);
+    let mut config = Config::load(args.config.as_deref())?;
+    // The input for each frame has to be fixed before the frame starts.
+    config.input.poll_on_strobe = false;
+    let players = if config.input.four_score { 4 } else { 2 };
+    // The console starts from power on, without a save file, so that
+    // replaying the movie always gives the same result.
+    let mut nes = Nes::new(rom, args.region)?;
+    nes.configure(&config);
+
+    let movie = if args.movie.exists() {
+        let movie = Movie::load(&args.movie)?;
+        movie.check_rom(&movie_rom);
+        movie
+    } else {
+        let mut movie = Movie::new(players);
+        movie.rom = Some(movie_rom);
+        movie.pal = nes.region() == Region::Pal;
+        movie
+    };
+    let mut ui = TasUi::new(nes, movie, args.movie);
+    ui.run()
+}
+
+fn cmd_compare_states(args: CompareStatesArgs) -> Result<()> {
+    let first = savestate::read_state(&args.first)?;
+    let second = savestate::read_state(&args.second)?;
+    let diff = savestate::compare(&first, &second)?;
+    if diff.is_empty() {
+        println!("States are identical");
+        return Ok(());
+    }
+    print!("{}", diff.report(args.max_ranges));
+    exit(1);
+}
+
 fn cmd_show_header(args: ShowHeaderArgs) -> Result<()> {
-    if !log::log_enabled!(log::Level::Info) {
-        log::error!("This command will print nothing at the current log level.");
-        log::error!("Please re-run with: RUST_LOG=info");
+    if let Some(nsf) = Nsf::probe(&args.rom)? {
+        return print_info(&NsfInfo::new(&nsf), args.json);
+    }
+    let rom = Rom::load(&args.rom)?;
+    print_info(&RomInfo::new(&rom), args.json)
+}
+
+fn print_info(info: &(impl Display + Serialize), json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(info)?);
+    } else {
+        println!("{}", info);
     }
+    Ok(())
+}
+
+fn cmd_fix_header(args: FixHeaderArgs) -> Result<()> {
+    let bytes = fs::read(&args.rom).with_context(|| format!("Failed to read {:?}", &args.rom))?;
+    let db = RomDb::load(args.db.as_deref())?;
+    let fix = rom::fix_header(&bytes, db.as_ref())?;
+    if fix.problems.is_empty() {
+        println!("No problems found");
+        return Ok(());
+    }
+    for problem in &fix.problems {
+        println!("{}", problem);
+    }
+    let output = match args.output {
+        Some(output) => output,
+        None => args.rom.with_extension("fixed.nes"),
+    };
+    fs::write(&output, &fix.bytes).with_context(|| format!("Failed to write {:?}", &output))?;
+    println!("Wrote {:?}", &output);
+    Ok(())
+}
 
+fn cmd_dump_chr(args: DumpChrArgs) -> Result<()> {
     let rom = Rom::load(&args.rom)?;
-    log::info!("iNES 1.0 ROM header: {:#?}", &rom.header);
-    log::info!("First 8 bytes of PRG data: {:X?}", &rom.prg[..8]);
-    log::info!(
-        "Last 8 bytes of PRG data: {:X?}",
-        &rom.prg[rom.prg.len() - 8..]
-    );
-    log::info!("First 8 bytes of CHR data: {:X?}", &rom.chr[..8]);
-    log::info!(
-        "Last 8 bytes of CHR data: {:X?}",
-        &rom.chr[rom.chr.len() - 8..]
+    if rom.header.has_chr_ram() {
+        bail!("ROM has no CHR ROM; its graphics are copied to CHR RAM at runtime");
+    }
+    let (width, height, pixels) = ppu::render_chr_sheet(
+        &rom.chr,
+        args.tiles_per_row as usize,
+        args.palette,
+        &Colors::default(),
     );
+
+    let output = match args.output {
+        Some(output) => output,
+        None => args.rom.with_extension("chr.png"),
+    };
+    screenshot::write_png(&output, width, height, &pixels)?;
+    println!("Wrote {} tiles to {:?}", rom.chr.len() / 16, &output);
     Ok(())
 }
@@ -0,0 +1,111 @@
+use anyhow::{ensure, Result};
+
+use crate::mem::Address;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// A region of the CPU or PPU's address space that is divided into equally
+/// sized windows, each of which can be mapped to any bank of a ROM or RAM
+/// chip on the cartridge.
+///
+/// This covers the bank switching done by the vast majority of mappers, which
+/// only differ in the size and number of windows and in how their registers
+/// select banks, so mappers just need to decode their registers and call
+/// `set`. Bank numbers are masked the way the hardware would, by ignoring the
+/// bank lines beyond what the chip needs. Chips whose size isn't a power of
+/// two (which don't exist on real boards, but do in some ROM dumps) wrap
+/// around to the start instead of reading past the end.
+pub(super) struct Banked {
+    start: Address,
+    window_size: usize,
+    /// Size of the memory being banked.
+    len: usize,
+    num_banks: usize,
+    mask: usize,
+    /// The bank mapped to each window.
+    banks: Vec<usize>,
+}
+
+impl Banked {
+    /// Divide `size` bytes of address space starting at `start` into windows
+    /// of `window_size` bytes, over `len` bytes of memory. All windows start
+    /// out mapped to bank 0.
+    pub fn new(start: Address, size: usize, window_size: usize, len: usize) -> Self {
+        // Memory smaller than a window (e.g. 16 KiB of PRG ROM in a 32 KiB
+        // window) is mirrored to fill it.
+        let num_banks = (len / window_size).max(1);
+        Self {
+            start,
+            window_size,
+            len,
+            num_banks,
+            mask: num_banks.next_power_of_two() - 1,
+            banks: vec![0; size / window_size],
+        }
+    }
+
+    /// The last bank, which many mappers fix in place at the top of the
+    /// address space.
+    pub fn last_bank(&self) -> usize {
+        self.num_banks - 1
+    }
+
+    /// Map the given window to a bank.
+    pub fn set(&mut self, window: usize, bank: usize) {
+        self.banks[window] = (bank & self.mask) % self.num_banks;
+    }
+
+    /// Translate an address in this region to an offset into memory.
+    pub fn offset(&self, addr: Address) -> usize {
+        let addr = addr.as_usize() - self.start.as_usize();
+        let bank = self.banks[addr / self.window_size];
+        (bank * self.window_size + addr % self.window_size) % self.len.max(1)
+    }
+}
+
+/// Only the selected banks are saved, since the layout of the windows comes
+/// from the ROM.
+impl Savestate for Banked {
+    fn save_state(&self, w: &mut StateWriter) {
+        for bank in &self.banks {
+            w.write(bank);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        for bank in &mut self.banks {
+            *bank = r.read()?;
+            ensure!(
+                *bank < self.num_banks,
+                "Invalid bank in save state: {}",
+                bank
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_banked() {
+        // Four 8 KiB windows over 48 KiB of memory.
+        let mut prg = Banked::new(Address(0x8000), 0x8000, 0x2000, 0xC000);
+        assert_eq!(prg.last_bank(), 5);
+        prg.set(0, 2);
+        prg.set(3, prg.last_bank());
+        assert_eq!(prg.offset(Address(0x8123)), 0x4123);
+        assert_eq!(prg.offset(Address(0xFFFF)), 0xBFFF);
+
+        // Bank 9 is masked to bank 1, and bank 7 wraps around to bank 1.
+        prg.set(1, 9);
+        assert_eq!(prg.offset(Address(0xA000)), 0x2000);
+        prg.set(1, 7);
+        assert_eq!(prg.offset(Address(0xA000)), 0x2000);
+
+        // A 16 KiB chip is mirrored across a 32 KiB window.
+        let prg = Banked::new(Address(0x8000), 0x8000, 0x8000, 0x4000);
+        assert_eq!(prg.offset(Address(0xC001)), 0x0001);
+    }
+}
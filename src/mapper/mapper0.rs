@@ -1,18 +1,21 @@
+use anyhow::{ensure, Result};
+
 use crate::mem::{Address, Bus};
 use crate::ppu::{PpuBus, Vram, NAMETABLES};
-use crate::rom::{Mirroring, Rom};
+use crate::rom::Rom;
+use crate::savestate::{Savestate, StateReader, StateWriter};
 
-use super::Mapper;
+use super::nametables::NametableMapping;
+use super::prg_ram::{PrgRam, PRG_RAM_START};
+use super::{describe, open_bus, CpuBus, Mapper};
 
 pub(super) struct Mapper0;
 
 impl Mapper for Mapper0 {
-    type CpuMapper = CpuMapper0;
-    type PpuMapper = PpuMapper0;
+    type Cartridge = Nrom;
 
-    fn from_rom(rom: Rom) -> (CpuMapper0, PpuMapper0) {
-        let Rom { header, prg, chr } = rom;
-        (CpuMapper0::new(prg), PpuMapper0::new(chr, header.mirroring))
+    fn from_rom(rom: Rom) -> Result<Nrom> {
+        Nrom::new(rom)
     }
 }
 
@@ -20,62 +23,100 @@ const PRG_BASE_ADDR: usize = 0x8000;
 const NROM_128_SIZE: usize = 0x4000;
 const NROM_256_SIZE: usize = 0x8000;
 
-pub(super) struct CpuMapper0 {
+pub(super) struct Nrom {
     prg: Vec<u8>,
+    /// NROM boards don't normally have PRG RAM (Family BASIC being the
+    /// exception), but providing it is harmless, and test ROMs rely on it.
+    prg_ram: PrgRam,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    nametables: NametableMapping,
 }
 
-impl CpuMapper0 {
-    fn new(prg: Vec<u8>) -> Self {
+impl Nrom {
+    fn new(rom: Rom) -> Result<Self> {
+        let prg_ram = PrgRam::from_rom(&rom);
+        let chr_is_ram = rom.header.has_chr_ram();
+        let Rom {
+            header, prg, chr, ..
+        } = rom;
+
         // This mapper comes in 2 variants: NROM-128, which contains 16 KiB of
         // PRG ROM (128 kilobits), and NROM-256 with 32 KiB (256 kilobits).
-        assert!(prg.len() == NROM_128_SIZE || prg.len() == NROM_256_SIZE);
-        Self { prg }
+        ensure!(
+            prg.len() == NROM_128_SIZE || prg.len() == NROM_256_SIZE,
+            "{} needs 16 or 32 KiB of PRG ROM, but the ROM has {} KiB",
+            describe(0),
+            prg.len() / 1024
+        );
+
+        // This mapper directly maps the CHR ROM into the lower portion of the
+        // PPU's address space, which means it must fit exactly in the space
+        // reserved for the 2 pattern tables (4 KiB each, so 8 KiB total).
+        // Nametable 0 is directly after the pattern tables, so use its base
+        // address to check the size. Boards with CHR RAM instead are mirrored
+        // if they have less.
+        ensure!(
+            chr_is_ram || chr.len() == NAMETABLES[0].as_usize(),
+            "{} needs 8 KiB of CHR ROM, but the ROM has {} KiB",
+            describe(0),
+            chr.len() / 1024
+        );
+
+        Ok(Self {
+            prg,
+            prg_ram,
+            chr,
+            chr_is_ram,
+            nametables: NametableMapping::new(header.mirroring),
+        })
     }
 }
 
-impl Bus for CpuMapper0 {
+impl Bus for Nrom {
     fn load(&mut self, addr: Address) -> u8 {
-        // NROM-256 fills the entire top half of the CPU address space.
-        // NROM-128 only fills half of that space, so it is mirrored.
-        let i = (addr.as_usize() - PRG_BASE_ADDR) % self.prg.len();
-        self.prg[i]
+        self.peek(addr).unwrap_or_else(|| open_bus(addr))
     }
 
-    fn store(&mut self, _addr: Address, _value: u8) {
+    fn store(&mut self, addr: Address, value: u8) {
         // Can't write to ROM.
+        if addr >= PRG_RAM_START && addr.as_usize() < PRG_BASE_ADDR {
+            self.prg_ram.store(addr, value);
+        }
     }
 }
 
-pub(super) struct PpuMapper0 {
-    chr: Vec<u8>,
-    _mirroring: Mirroring,
-}
-
-impl PpuMapper0 {
-    fn new(chr: Vec<u8>, mirroring: Mirroring) -> Self {
-        // This mapper directly maps the CHR RAM into the lower portion of the
-        // PPU's address space, which means it must fit exactly in the space
-        // reserved for the 2 pattern tables (4 KiB each, so 8 KiB total).
-        // Nametable 0 is directly after the pattern tables, so use its base
-        // address to check the size.
-        assert!(chr.len() == NAMETABLES[0].as_usize());
-        Self {
-            chr,
-            _mirroring: mirroring,
+impl CpuBus for Nrom {
+    fn peek(&self, addr: Address) -> Option<u8> {
+        if addr.as_usize() >= PRG_BASE_ADDR {
+            // NROM-256 fills the entire top half of the CPU address space.
+            // NROM-128 only fills half of that space, so it is mirrored.
+            let i = (addr.as_usize() - PRG_BASE_ADDR) % self.prg.len();
+            Some(self.prg[i])
+        } else if addr >= PRG_RAM_START {
+            self.prg_ram.load(addr)
+        } else {
+            None
         }
     }
+
+    fn prg_ram(&self) -> Option<Vec<u8>> {
+        Some(self.prg_ram.data().to_vec())
+    }
+
+    fn restore_prg_ram(&mut self, data: &[u8]) {
+        self.prg_ram.restore(data);
+    }
 }
 
-impl PpuBus for PpuMapper0 {
+impl PpuBus for Nrom {
     fn ppu_load(&mut self, vram: &Vram, palette: &[u8; 32], addr: Address) -> u8 {
         let value = if addr < NAMETABLES[0] {
-            self.chr[addr.as_usize()]
+            self.chr[addr.as_usize() % self.chr.len()]
         } else if addr >= Address(0x3F00) {
             palette[addr.alias(5).as_usize()]
         } else {
-            // TODO: Implement nametable mirroring.
-            let i = addr.as_usize() - NAMETABLES[0].as_usize();
-            vram.0[i]
+            self.nametables.load(vram, addr)
         };
 
         log::trace!(
@@ -93,12 +134,31 @@ impl PpuBus for PpuMapper0 {
             addr,
             value
         );
-        if addr >= NAMETABLES[0] {
-            // TODO: Implement nametable mirroring.
-            let i = addr.as_usize() - NAMETABLES[0].as_usize();
-            vram.0[i] = value;
-        } else if addr >= Address(0x3F00) {
+        if addr >= Address(0x3F00) {
             palette[addr.alias(5).as_usize()] = value;
+        } else if addr >= NAMETABLES[0] {
+            self.nametables.store(vram, addr, value);
+        } else if self.chr_is_ram {
+            let i = addr.as_usize() % self.chr.len();
+            self.chr[i] = value;
+        }
+    }
+}
+
+impl Savestate for Nrom {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        if self.chr_is_ram {
+            w.write(&self.chr);
+        }
+        self.nametables.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.prg_ram.load_state(r)?;
+        if self.chr_is_ram {
+            r.read_into(&mut self.chr)?;
         }
+        self.nametables.load_state(r)
     }
 }
@@ -0,0 +1,231 @@
+use anyhow::Result;
+
+use crate::apu::ExpansionAudio;
+use crate::mem::{Address, Bus};
+use crate::ppu::{PpuBus, Vram, NAMETABLES, PALETTE_ADDR_BITS, PALETTE_BASE_ADDR};
+use crate::rom::Rom;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+use super::banked::Banked;
+use super::n163_audio::N163Audio;
+use super::prg_ram::PrgRam;
+use super::{open_bus, CpuBus, Mapper};
+
+/// Namco 163 (mapper 19), used by many of Namco's later Famicom games.
+///
+/// The N163 has three switchable 8 KiB PRG windows and eight 1 KiB CHR
+/// windows. Besides CHR ROM, the CHR windows and the four nametables can each
+/// be mapped to either half of the console's VRAM, which gives games full
+/// control over mirroring (and allows using CHR ROM as nametables). It also
+/// provides 8 KiB of PRG RAM, a CPU cycle IRQ counter, and a wavetable sound
+/// chip with its own 128 bytes of RAM.
+pub(super) struct Mapper19;
+
+impl Mapper for Mapper19 {
+    type Cartridge = N163;
+
+    fn from_rom(rom: Rom) -> Result<N163> {
+        Ok(N163::new(rom))
+    }
+}
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+const PRG_ROM_START: Address = Address(0x8000);
+
+/// CHR and nametable bank numbers at or above this value select a page of
+/// VRAM instead of CHR ROM.
+const VRAM_BANK: u8 = 0xE0;
+
+/// The IRQ fires when the counter reaches this value.
+const IRQ_COUNTER_MAX: u16 = 0x7FFF;
+
+/// Where a 1 KiB window of PPU memory is mapped.
+enum Page {
+    Chr(usize),
+    Vram(usize),
+}
+
+pub(super) struct N163 {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    prg_ram: PrgRam,
+    /// Three switchable windows, plus the last bank fixed at $E000.
+    prg_banks: Banked,
+    chr_banks: [u8; 8],
+    nametable_banks: [u8; 4],
+    /// Whether VRAM can be selected for the low and high pattern tables.
+    chr_vram_allowed: [bool; 2],
+    irq_counter: u16,
+    irq_enabled: bool,
+    audio: N163Audio,
+}
+
+impl N163 {
+    fn new(rom: Rom) -> Self {
+        let prg_ram = PrgRam::from_rom(&rom);
+        let Rom { prg, chr, .. } = rom;
+        let mut prg_banks = Banked::new(PRG_ROM_START, 0x8000, PRG_BANK_SIZE, prg.len());
+        prg_banks.set(3, prg_banks.last_bank());
+        Self {
+            prg,
+            chr,
+            prg_ram,
+            prg_banks,
+            chr_banks: [0; 8],
+            nametable_banks: [VRAM_BANK; 4],
+            chr_vram_allowed: [true; 2],
+            irq_counter: 0,
+            irq_enabled: false,
+            audio: N163Audio::new(),
+        }
+    }
+
+    /// Find where a PPU address in $0000-$3EFF is mapped.
+    fn page(&self, addr: Address) -> Page {
+        let window = addr.as_usize() / CHR_BANK_SIZE;
+        let offset = addr.as_usize() % CHR_BANK_SIZE;
+        let (bank, vram_allowed) = if addr < NAMETABLES[0] {
+            (self.chr_banks[window], self.chr_vram_allowed[window / 4])
+        } else {
+            (self.nametable_banks[window % 4], true)
+        };
+        if bank >= VRAM_BANK && vram_allowed {
+            Page::Vram((bank as usize & 1) * CHR_BANK_SIZE + offset)
+        } else {
+            let num_banks = self.chr.len() / CHR_BANK_SIZE;
+            Page::Chr((bank as usize % num_banks) * CHR_BANK_SIZE + offset)
+        }
+    }
+
+    fn write_register(&mut self, addr: Address, value: u8) {
+        // Registers are spaced out every $800 bytes.
+        let reg = addr.as_usize() & 0xF800;
+        match reg {
+            0x8000..=0xB800 => self.chr_banks[(reg - 0x8000) / 0x800] = value,
+            0xC000..=0xD800 => self.nametable_banks[(reg - 0xC000) / 0x800] = value,
+            0xE000 => {
+                self.prg_banks.set(0, value as usize & 0x3F);
+                self.audio.set_enabled(value & 0x40 == 0);
+            }
+            0xE800 => {
+                self.prg_banks.set(1, value as usize & 0x3F);
+                self.chr_vram_allowed = [value & 0x40 == 0, value & 0x80 == 0];
+            }
+            0xF000 => self.prg_banks.set(2, value as usize & 0x3F),
+            0xF800 => self.audio.write_addr(value),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Bus for N163 {
+    fn load(&mut self, addr: Address) -> u8 {
+        match addr.as_usize() {
+            // Unlike peeking, reading the sound RAM advances its address.
+            0x4800..=0x4FFF => self.audio.read_data(),
+            _ => self.peek(addr).unwrap_or_else(|| open_bus(addr)),
+        }
+    }
+
+    fn store(&mut self, addr: Address, value: u8) {
+        // Writing to either half of the IRQ counter acknowledges the IRQ,
+        // which is implicit here since the IRQ is asserted based on the
+        // counter's value.
+        match addr.as_usize() {
+            0x4800..=0x4FFF => self.audio.write_data(value),
+            0x5000..=0x57FF => self.irq_counter = (self.irq_counter & 0x7F00) | value as u16,
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | ((value as u16 & 0x7F) << 8);
+                self.irq_enabled = value & 0x80 > 0;
+            }
+            0x6000..=0x7FFF => self.prg_ram.store(addr, value),
+            0x8000..=0xFFFF => self.write_register(addr, value),
+            _ => {}
+        }
+    }
+}
+
+impl CpuBus for N163 {
+    fn peek(&self, addr: Address) -> Option<u8> {
+        match addr.as_usize() {
+            0x4800..=0x4FFF => Some(self.audio.peek_data()),
+            0x5000..=0x57FF => Some(self.irq_counter as u8),
+            0x5800..=0x5FFF => Some((self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7),
+            0x6000..=0x7FFF => self.prg_ram.load(addr),
+            0x8000..=0xFFFF => Some(self.prg[self.prg_banks.offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+
+    fn irq_asserted(&self) -> bool {
+        self.irq_enabled && self.irq_counter == IRQ_COUNTER_MAX
+    }
+
+    fn tick(&mut self) {
+        if self.irq_enabled && self.irq_counter < IRQ_COUNTER_MAX {
+            self.irq_counter += 1;
+        }
+    }
+
+    fn prg_ram(&self) -> Option<Vec<u8>> {
+        Some(self.prg_ram.data().to_vec())
+    }
+
+    fn restore_prg_ram(&mut self, data: &[u8]) {
+        self.prg_ram.restore(data);
+    }
+}
+
+impl PpuBus for N163 {
+    fn ppu_load(&mut self, vram: &Vram, palette: &[u8; 32], addr: Address) -> u8 {
+        if addr >= PALETTE_BASE_ADDR {
+            return palette[addr.alias(PALETTE_ADDR_BITS).as_usize()];
+        }
+        match self.page(addr) {
+            Page::Chr(i) => self.chr[i],
+            Page::Vram(i) => vram.0[i],
+        }
+    }
+
+    fn ppu_store(&mut self, vram: &mut Vram, palette: &mut [u8; 32], addr: Address, value: u8) {
+        if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()] = value;
+            return;
+        }
+        match self.page(addr) {
+            // Can't write to CHR ROM.
+            Page::Chr(_) => {}
+            Page::Vram(i) => vram.0[i] = value,
+        }
+    }
+}
+
+impl Savestate for N163 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        self.prg_banks.save_state(w);
+        w.write(&self.chr_banks);
+        w.write(&self.nametable_banks);
+        w.write(&self.chr_vram_allowed);
+        w.write(&self.irq_counter);
+        w.write(&self.irq_enabled);
+        self.audio.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.prg_ram.load_state(r)?;
+        self.prg_banks.load_state(r)?;
+        self.chr_banks = r.read()?;
+        self.nametable_banks = r.read()?;
+        self.chr_vram_allowed = r.read()?;
+        self.irq_counter = r.read()?;
+        self.irq_enabled = r.read()?;
+        self.audio.load_state(r)
+    }
+}
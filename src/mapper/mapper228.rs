@@ -0,0 +1,168 @@
+use anyhow::Result;
+
+use crate::mem::{Address, Bus};
+use crate::ppu::{PpuBus, Vram, NAMETABLES, PALETTE_ADDR_BITS, PALETTE_BASE_ADDR};
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+use super::banked::Banked;
+use super::nametables::NametableMapping;
+use super::{open_bus, CpuBus, Mapper};
+
+/// Active Enterprises (mapper 228), used by Action 52 and Cheetahmen II.
+///
+/// Rather than decoding the value written, this mapper takes most of its
+/// settings from the address of the write to $8000-$FFFF:
+///
+///   A13:       Mirroring (0 = vertical, 1 = horizontal)
+///   A11-A12:   PRG chip select
+///   A6-A10:    16 KiB PRG bank within the chip
+///   A5:        PRG mode (0 = 32 KiB, 1 = 16 KiB mirrored)
+///   A0-A3, D0-D1: 8 KiB CHR bank (A0-A3 are the high bits)
+///
+/// PRG ROM is split across 512 KiB chips. Action 52 has 1.5 MiB of PRG ROM
+/// in three chips, but the second and third are at chip selects 1 and 3, with
+/// nothing at chip select 2. Cheetahmen II has only 256 KiB, so every chip
+/// select wraps around to its single (partially filled) chip.
+///
+/// The board also has four 4-bit registers at $4020-$5FFF, which the games
+/// use as a tiny amount of RAM.
+pub(super) struct Mapper228;
+
+impl Mapper for Mapper228 {
+    type Cartridge = Action52;
+
+    fn from_rom(rom: Rom) -> Result<Action52> {
+        let Rom {
+            header, prg, chr, ..
+        } = rom;
+        Ok(Action52 {
+            prg,
+            chip: 0,
+            prg_bank: 0,
+            prg_16k_mode: false,
+            ram: [0; 4],
+            chr_bank: Banked::new(Address(0), 0x2000, CHR_BANK_SIZE, chr.len()),
+            chr,
+            nametables: NametableMapping::new(header.mirroring),
+        })
+    }
+}
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const PRG_CHIP_SIZE: usize = 0x80000;
+const CHR_BANK_SIZE: usize = 0x2000;
+
+pub(super) struct Action52 {
+    prg: Vec<u8>,
+    chip: u8,
+    prg_bank: u8,
+    prg_16k_mode: bool,
+    ram: [u8; 4],
+    chr: Vec<u8>,
+    chr_bank: Banked,
+    nametables: NametableMapping,
+}
+
+impl Action52 {
+    /// Find the offset of the given chip in PRG ROM, if it exists.
+    fn chip_offset(&self, chip: u8) -> Option<usize> {
+        let num_chips = self.prg.len().div_ceil(PRG_CHIP_SIZE);
+        let index = match (num_chips, chip) {
+            (3, 2) => return None,
+            (3, 3) => 2,
+            (n, chip) => chip as usize % n,
+        };
+        Some(index * PRG_CHIP_SIZE)
+    }
+}
+
+impl Bus for Action52 {
+    fn load(&mut self, addr: Address) -> u8 {
+        self.peek(addr).unwrap_or_else(|| open_bus(addr))
+    }
+
+    fn store(&mut self, addr: Address, value: u8) {
+        let a = addr.as_usize();
+        match a {
+            0x4020..=0x5FFF => self.ram[a % 4] = value & 0x0F,
+            0x8000..=0xFFFF => {
+                self.chip = ((a >> 11) & 0x03) as u8;
+                self.prg_bank = ((a >> 6) & 0x1F) as u8;
+                self.prg_16k_mode = a & 0x20 > 0;
+
+                self.chr_bank
+                    .set(0, ((a & 0x0F) << 2) | (value as usize & 0x03));
+                self.nametables.set_mirroring(if a & 0x2000 > 0 {
+                    Mirroring::Horizonal
+                } else {
+                    Mirroring::Vertical
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+impl CpuBus for Action52 {
+    fn peek(&self, addr: Address) -> Option<u8> {
+        match addr.as_usize() {
+            0x4020..=0x5FFF => {
+                Some((self.ram[addr.as_usize() % 4] & 0x0F) | (open_bus(addr) & 0xF0))
+            }
+            0x8000..=0xFFFF => {
+                let chip = self.chip_offset(self.chip)?;
+                let bank = if self.prg_16k_mode {
+                    self.prg_bank as usize
+                } else {
+                    (self.prg_bank & !1) as usize | ((addr.as_usize() >> 14) & 1)
+                };
+                let offset = chip + bank * PRG_BANK_SIZE + addr.as_usize() % PRG_BANK_SIZE;
+                Some(self.prg[offset % self.prg.len()])
+            }
+            _ => None,
+        }
+    }
+}
+
+impl PpuBus for Action52 {
+    fn ppu_load(&mut self, vram: &Vram, palette: &[u8; 32], addr: Address) -> u8 {
+        if addr < NAMETABLES[0] {
+            self.chr[self.chr_bank.offset(addr)]
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()]
+        } else {
+            self.nametables.load(vram, addr)
+        }
+    }
+
+    fn ppu_store(&mut self, vram: &mut Vram, palette: &mut [u8; 32], addr: Address, value: u8) {
+        if addr < NAMETABLES[0] {
+            // Can't write to CHR ROM.
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()] = value;
+        } else {
+            self.nametables.store(vram, addr, value);
+        }
+    }
+}
+
+impl Savestate for Action52 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.chip);
+        w.write(&self.prg_bank);
+        w.write(&self.prg_16k_mode);
+        w.write(&self.ram);
+        self.chr_bank.save_state(w);
+        self.nametables.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.chip = r.read()?;
+        self.prg_bank = r.read()?;
+        self.prg_16k_mode = r.read()?;
+        self.ram = r.read()?;
+        self.chr_bank.load_state(r)?;
+        self.nametables.load_state(r)
+    }
+}
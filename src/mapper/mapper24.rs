@@ -0,0 +1,216 @@
+use anyhow::Result;
+
+use crate::apu::ExpansionAudio;
+use crate::mem::{Address, Bus};
+use crate::ppu::{PpuBus, Vram, NAMETABLES, PALETTE_ADDR_BITS, PALETTE_BASE_ADDR};
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+use super::banked::Banked;
+use super::nametables::NametableMapping;
+use super::prg_ram::{PrgRam, PRG_RAM_START};
+use super::vrc6_audio::Vrc6Audio;
+use super::vrc_irq::VrcIrq;
+use super::{open_bus, CpuBus, Mapper};
+
+/// Konami VRC6a (mapper 24), used by Akumajou Densetsu.
+///
+/// The VRC6 provides a switchable 16 KiB and 8 KiB PRG window, eight 1 KiB CHR
+/// windows, 8 KiB of PRG RAM, a VRC-style IRQ counter, and three extra sound
+/// channels which are mixed with the APU's output.
+pub(super) struct Mapper24;
+
+impl Mapper for Mapper24 {
+    type Cartridge = Vrc6;
+
+    fn from_rom(rom: Rom) -> Result<Vrc6> {
+        Ok(Vrc6::new(rom, false))
+    }
+}
+
+/// Konami VRC6b (mapper 26), used by Madara and Esper Dream 2. This is the
+/// same chip as VRC6a, but with the A0 and A1 address lines swapped.
+pub(super) struct Mapper26;
+
+impl Mapper for Mapper26 {
+    type Cartridge = Vrc6;
+
+    fn from_rom(rom: Rom) -> Result<Vrc6> {
+        Ok(Vrc6::new(rom, true))
+    }
+}
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+const PRG_ROM_START: Address = Address(0x8000);
+
+pub(super) struct Vrc6 {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    prg_ram: PrgRam,
+    /// The 16 KiB window at $8000 (as two 8 KiB windows), the 8 KiB window
+    /// at $C000, and the last bank fixed at $E000.
+    prg_banks: Banked,
+    chr_banks: Banked,
+    nametables: NametableMapping,
+    irq: VrcIrq,
+    audio: Vrc6Audio,
+    swap_address_lines: bool,
+}
+
+impl Vrc6 {
+    fn new(rom: Rom, swap_address_lines: bool) -> Self {
+        let mut prg_ram = PrgRam::from_rom(&rom);
+        prg_ram.set_enabled(false);
+        let Rom {
+            header, prg, chr, ..
+        } = rom;
+        let mut prg_banks = Banked::new(PRG_ROM_START, 0x8000, PRG_BANK_SIZE, prg.len());
+        prg_banks.set(1, 1);
+        prg_banks.set(3, prg_banks.last_bank());
+        Self {
+            prg_banks,
+            chr_banks: Banked::new(Address(0), 0x2000, CHR_BANK_SIZE, chr.len()),
+            prg,
+            chr,
+            prg_ram,
+            nametables: NametableMapping::new(header.mirroring),
+            irq: VrcIrq::default(),
+            audio: Vrc6Audio::default(),
+            swap_address_lines,
+        }
+    }
+
+    /// $B003: R--- MMPP (PRG RAM enable, mirroring, PPU banking mode).
+    ///
+    /// Only the standard PPU banking mode (eight 1 KiB CHR windows, with
+    /// nametables in VRAM) is implemented, since that is what all of the
+    /// commercial VRC6 games use.
+    fn write_ppu_control(&mut self, value: u8) {
+        self.prg_ram.set_enabled(value & 0x80 > 0);
+        self.nametables.set_mirroring(match (value >> 2) & 0x03 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizonal,
+            2 => Mirroring::SingleScreenA,
+            _ => Mirroring::SingleScreenB,
+        });
+        if value & 0x03 != 0 {
+            log::warn!("Unsupported VRC6 PPU banking mode: {}", value & 0x03);
+        }
+    }
+
+    fn write_register(&mut self, addr: Address, value: u8) {
+        // The registers are selected by the top 4 address lines and A0-A1.
+        let mut addr = addr.as_usize() as u16 & 0xF003;
+        if self.swap_address_lines {
+            addr = (addr & 0xF000) | ((addr & 0x01) << 1) | ((addr & 0x02) >> 1);
+        }
+        match addr {
+            0x8000..=0x8003 => {
+                let bank = (value as usize & 0x0F) * 2;
+                self.prg_banks.set(0, bank);
+                self.prg_banks.set(1, bank + 1);
+            }
+            0xB003 => self.write_ppu_control(value),
+            0x9000..=0xB002 => self.audio.write_register(addr, value),
+            0xC000..=0xC003 => self.prg_banks.set(2, value as usize & 0x1F),
+            0xD000..=0xD003 => self.chr_banks.set((addr & 0x03) as usize, value as usize),
+            0xE000..=0xE003 => self
+                .chr_banks
+                .set(4 + (addr & 0x03) as usize, value as usize),
+            0xF000 => self.irq.write_latch(value),
+            0xF001 => self.irq.write_control(value),
+            0xF002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+}
+
+impl Bus for Vrc6 {
+    fn load(&mut self, addr: Address) -> u8 {
+        self.peek(addr).unwrap_or_else(|| open_bus(addr))
+    }
+
+    fn store(&mut self, addr: Address, value: u8) {
+        if addr >= PRG_ROM_START {
+            self.write_register(addr, value);
+        } else if addr >= PRG_RAM_START {
+            self.prg_ram.store(addr, value);
+        }
+    }
+}
+
+impl CpuBus for Vrc6 {
+    fn peek(&self, addr: Address) -> Option<u8> {
+        if addr >= PRG_ROM_START {
+            Some(self.prg[self.prg_banks.offset(addr)])
+        } else if addr >= PRG_RAM_START {
+            self.prg_ram.load(addr)
+        } else {
+            None
+        }
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+
+    fn irq_asserted(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn tick(&mut self) {
+        self.irq.tick();
+    }
+
+    fn prg_ram(&self) -> Option<Vec<u8>> {
+        Some(self.prg_ram.data().to_vec())
+    }
+
+    fn restore_prg_ram(&mut self, data: &[u8]) {
+        self.prg_ram.restore(data);
+    }
+}
+
+impl PpuBus for Vrc6 {
+    fn ppu_load(&mut self, vram: &Vram, palette: &[u8; 32], addr: Address) -> u8 {
+        if addr < NAMETABLES[0] {
+            self.chr[self.chr_banks.offset(addr)]
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()]
+        } else {
+            self.nametables.load(vram, addr)
+        }
+    }
+
+    fn ppu_store(&mut self, vram: &mut Vram, palette: &mut [u8; 32], addr: Address, value: u8) {
+        if addr < NAMETABLES[0] {
+            // Can't write to CHR ROM.
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()] = value;
+        } else {
+            self.nametables.store(vram, addr, value);
+        }
+    }
+}
+
+impl Savestate for Vrc6 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        self.prg_banks.save_state(w);
+        self.chr_banks.save_state(w);
+        self.nametables.save_state(w);
+        self.irq.save_state(w);
+        self.audio.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.prg_ram.load_state(r)?;
+        self.prg_banks.load_state(r)?;
+        self.chr_banks.load_state(r)?;
+        self.nametables.load_state(r)?;
+        self.irq.load_state(r)?;
+        self.audio.load_state(r)
+    }
+}
@@ -0,0 +1,181 @@
+use anyhow::Result;
+
+use crate::mem::{Address, Bus};
+use crate::ppu::{PpuBus, Vram, NAMETABLES, PALETTE_ADDR_BITS, PALETTE_BASE_ADDR};
+use crate::rom::Rom;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+use super::banked::Banked;
+use super::nametables::NametableMapping;
+use super::{open_bus, BusConflicts, CpuBus, Mapper};
+
+/// CNROM (mapper 3), which has 16 or 32 KiB of fixed PRG ROM like NROM, plus
+/// a switchable 8 KiB CHR window selected by writing to $8000-$FFFF.
+pub(super) struct Mapper3;
+
+impl Mapper for Mapper3 {
+    type Cartridge = Cnrom;
+
+    fn from_rom(rom: Rom) -> Result<Cnrom> {
+        Ok(init(rom, Board::Cnrom))
+    }
+}
+
+/// Mapper 87, a Jaleco/Konami/Taito CNROM variant where the CHR bank is
+/// selected by writing to $6000-$7FFF, with the two bank bits swapped.
+pub(super) struct Mapper87;
+
+impl Mapper for Mapper87 {
+    type Cartridge = Cnrom;
+
+    fn from_rom(rom: Rom) -> Result<Cnrom> {
+        Ok(init(rom, Board::Mapper87))
+    }
+}
+
+/// Mapper 185, CNROM boards used for copy protection. There is only a single
+/// CHR bank, which is disabled unless the game writes a particular value to
+/// the bank register; with CHR disabled, reads from the pattern tables return
+/// open bus, which the game checks for at startup.
+///
+/// NES 2.0 submappers 4-7 give the value of the low 2 bits that enables CHR.
+/// Otherwise, assume the most common arrangement, where any value with a
+/// nonzero low nibble other than $13 enables it.
+pub(super) struct Mapper185;
+
+impl Mapper for Mapper185 {
+    type Cartridge = Cnrom;
+
+    fn from_rom(rom: Rom) -> Result<Cnrom> {
+        let key = match rom.header.submapper {
+            n @ 4..=7 => Some(n - 4),
+            _ => None,
+        };
+        Ok(init(rom, Board::Mapper185 { key }))
+    }
+}
+
+fn init(rom: Rom, board: Board) -> Cnrom {
+    let bus_conflicts = match board {
+        Board::Cnrom => BusConflicts::from_submapper(rom.header.submapper),
+        // Registers outside of ROM can't conflict with it.
+        Board::Mapper87 => BusConflicts::Absent,
+        // Submappers are used for the CHR key instead.
+        Board::Mapper185 { .. } => BusConflicts::And,
+    };
+    let Rom {
+        header, prg, chr, ..
+    } = rom;
+    Cnrom {
+        board,
+        bus_conflicts,
+        chr_bank: Banked::new(Address(0), 0x2000, CHR_BANK_SIZE, chr.len()),
+        prg,
+        chr,
+        chr_enabled: !matches!(board, Board::Mapper185 { .. }),
+        nametables: NametableMapping::new(header.mirroring),
+    }
+}
+
+const CHR_BANK_SIZE: usize = 0x2000;
+
+const PRG_ROM_START: Address = Address(0x8000);
+
+/// Value returned by pattern table reads while CHR is disabled.
+const OPEN_BUS: u8 = 0xFF;
+
+#[derive(Copy, Clone)]
+enum Board {
+    Cnrom,
+    Mapper87,
+    /// The key is the value of the low 2 bits of the bank register that
+    /// enables CHR, if known.
+    Mapper185 {
+        key: Option<u8>,
+    },
+}
+
+pub(super) struct Cnrom {
+    board: Board,
+    bus_conflicts: BusConflicts,
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    chr_bank: Banked,
+    chr_enabled: bool,
+    nametables: NametableMapping,
+}
+
+impl Bus for Cnrom {
+    fn load(&mut self, addr: Address) -> u8 {
+        self.peek(addr).unwrap_or_else(|| open_bus(addr))
+    }
+
+    fn store(&mut self, addr: Address, value: u8) {
+        let value = if addr >= PRG_ROM_START {
+            self.bus_conflicts.apply(self.load(addr), value)
+        } else {
+            value
+        };
+        match (self.board, addr.as_usize()) {
+            (Board::Cnrom, 0x8000..=0xFFFF) => self.chr_bank.set(0, value as usize),
+            (Board::Mapper87, 0x6000..=0x7FFF) => {
+                let bank = ((value & 0x01) << 1) | ((value & 0x02) >> 1);
+                self.chr_bank.set(0, bank as usize);
+            }
+            (Board::Mapper185 { key }, 0x8000..=0xFFFF) => {
+                self.chr_enabled = match key {
+                    Some(key) => value & 0x03 == key,
+                    None => value & 0x0F != 0 && value != 0x13,
+                };
+            }
+            _ => {}
+        }
+    }
+}
+
+impl CpuBus for Cnrom {
+    fn peek(&self, addr: Address) -> Option<u8> {
+        // As with NROM, 16 KiB of PRG ROM is mirrored.
+        (addr >= PRG_ROM_START)
+            .then(|| self.prg[(addr.as_usize() - PRG_ROM_START.as_usize()) % self.prg.len()])
+    }
+}
+
+impl PpuBus for Cnrom {
+    fn ppu_load(&mut self, vram: &Vram, palette: &[u8; 32], addr: Address) -> u8 {
+        if addr < NAMETABLES[0] {
+            if !self.chr_enabled {
+                return OPEN_BUS;
+            }
+            self.chr[self.chr_bank.offset(addr)]
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()]
+        } else {
+            self.nametables.load(vram, addr)
+        }
+    }
+
+    fn ppu_store(&mut self, vram: &mut Vram, palette: &mut [u8; 32], addr: Address, value: u8) {
+        if addr < NAMETABLES[0] {
+            // Can't write to CHR ROM.
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()] = value;
+        } else {
+            self.nametables.store(vram, addr, value);
+        }
+    }
+}
+
+impl Savestate for Cnrom {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.chr_bank.save_state(w);
+        w.write(&self.chr_enabled);
+        self.nametables.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.chr_bank.load_state(r)?;
+        self.chr_enabled = r.read()?;
+        self.nametables.load_state(r)
+    }
+}
@@ -0,0 +1,339 @@
+use anyhow::{bail, Result};
+
+use crate::mem::{Address, Bus};
+use crate::ppu::{PpuBus, Vram, NAMETABLES, PALETTE_ADDR_BITS, PALETTE_BASE_ADDR};
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateValue, StateWriter};
+
+use super::banked::Banked;
+use super::nametables::NametableMapping;
+use super::{open_bus, BusConflicts, CpuBus, Mapper};
+
+/// UNROM 512 (mapper 30), a homebrew board by RetroUSB and InfiniteNESLives
+/// used by many modern releases, including most games made with NESmaker.
+///
+/// Like UNROM, it has a switchable 16 KiB PRG window at $8000 and the last
+/// bank fixed at $C000, but with up to 512 KiB of PRG ROM and 32 KiB of CHR
+/// RAM in four 8 KiB banks. The bank register is laid out as MCCP PPPP
+/// (single-screen select, CHR bank, PRG bank). Single-screen mirroring is
+/// indicated by setting the header's four-screen bit without the vertical
+/// mirroring bit.
+///
+/// If the header's battery bit is set, PRG ROM is an SST39SF0x0 flash chip
+/// that the game can rewrite to save progress, and writes to $8000-$BFFF go
+/// to the flash chip instead of the bank register. The flash contents are
+/// persisted to the save file as if they were battery-backed RAM. Boards
+/// without flash have bus conflicts.
+pub(super) struct Mapper30;
+
+impl Mapper for Mapper30 {
+    type Cartridge = Unrom512;
+
+    fn from_rom(rom: Rom) -> Result<Unrom512> {
+        Ok(Unrom512::new(rom))
+    }
+}
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x2000;
+const CHR_RAM_SIZE: usize = 0x8000;
+
+const PRG_ROM_START: Address = Address(0x8000);
+
+/// Size of the flash chip's sectors, the smallest unit that can be erased.
+const FLASH_SECTOR_SIZE: usize = 0x1000;
+
+/// Manufacturer ID reported by SST flash chips.
+const FLASH_MANUFACTURER_ID: u8 = 0xBF;
+
+/// Progress through one of the flash chip's command sequences. Every command
+/// starts with the same two unlock writes.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum FlashState {
+    Ready,
+    Unlock1,
+    Unlock2,
+    /// The next write programs a byte.
+    Program,
+    /// Waiting for the second set of unlock writes of an erase command.
+    Erase,
+    EraseUnlock1,
+    EraseUnlock2,
+    /// Reads return the chip's ID until the game exits this mode.
+    SoftwareId,
+}
+
+pub(super) struct Unrom512 {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    prg_banks: Banked,
+    chr_banks: Banked,
+    nametables: NametableMapping,
+    /// Whether the bank register selects single-screen mirroring; otherwise,
+    /// mirroring is fixed by the board.
+    single_screen: bool,
+    flashable: bool,
+    flash_state: FlashState,
+}
+
+impl Unrom512 {
+    fn new(rom: Rom) -> Self {
+        let Rom {
+            header, prg, chr, ..
+        } = rom;
+        // Games that only use 8 KiB of CHR RAM don't care whether there's
+        // more, so provide the full 32 KiB unless the header says otherwise.
+        let chr = if header.has_chr_ram() && header.chr_ram_size.is_none() {
+            vec![0; CHR_RAM_SIZE]
+        } else {
+            chr
+        };
+        let mut prg_banks = Banked::new(PRG_ROM_START, 0x8000, PRG_BANK_SIZE, prg.len());
+        prg_banks.set(1, prg_banks.last_bank());
+        Self {
+            prg_banks,
+            chr_banks: Banked::new(Address(0), 0x2000, CHR_BANK_SIZE, chr.len()),
+            prg,
+            chr,
+            nametables: NametableMapping::new(header.mirroring),
+            single_screen: matches!(header.mirroring, Mirroring::SingleScreenA),
+            flashable: header.has_battery,
+            flash_state: FlashState::Ready,
+        }
+    }
+
+    fn write_bank_register(&mut self, value: u8) {
+        self.prg_banks.set(0, value as usize & 0x1F);
+        self.chr_banks.set(0, (value as usize >> 5) & 0x03);
+        if self.single_screen {
+            self.nametables.set_mirroring(if value & 0x80 > 0 {
+                Mirroring::SingleScreenB
+            } else {
+                Mirroring::SingleScreenA
+            });
+        }
+    }
+
+    /// Handle a write to the flash chip at the given offset into PRG ROM.
+    /// The chip only decodes the low 15 address lines for commands.
+    fn write_flash(&mut self, offset: usize, value: u8) {
+        let command_addr = offset & 0x7FFF;
+        self.flash_state = match (self.flash_state, command_addr, value) {
+            (_, _, 0xF0) => FlashState::Ready,
+            (FlashState::Ready, 0x5555, 0xAA) => FlashState::Unlock1,
+            (FlashState::Unlock1, 0x2AAA, 0x55) => FlashState::Unlock2,
+            (FlashState::Unlock2, 0x5555, 0xA0) => FlashState::Program,
+            (FlashState::Unlock2, 0x5555, 0x80) => FlashState::Erase,
+            (FlashState::Unlock2, 0x5555, 0x90) => FlashState::SoftwareId,
+            (FlashState::Program, _, _) => {
+                // Programming can only clear bits; only erasing sets them.
+                self.prg[offset] &= value;
+                FlashState::Ready
+            }
+            (FlashState::Erase, 0x5555, 0xAA) => FlashState::EraseUnlock1,
+            (FlashState::EraseUnlock1, 0x2AAA, 0x55) => FlashState::EraseUnlock2,
+            (FlashState::EraseUnlock2, 0x5555, 0x10) => {
+                self.prg.fill(0xFF);
+                FlashState::Ready
+            }
+            (FlashState::EraseUnlock2, _, 0x30) => {
+                let start = offset & !(FLASH_SECTOR_SIZE - 1);
+                self.prg[start..start + FLASH_SECTOR_SIZE].fill(0xFF);
+                FlashState::Ready
+            }
+            (FlashState::SoftwareId, _, _) => FlashState::SoftwareId,
+            _ => FlashState::Ready,
+        };
+    }
+
+    /// The device ID of the flash chip, which depends on its size.
+    fn flash_device_id(&self) -> u8 {
+        match self.prg.len() {
+            0..=0x20000 => 0xB5,
+            0x20001..=0x40000 => 0xB6,
+            _ => 0xB7,
+        }
+    }
+}
+
+impl Bus for Unrom512 {
+    fn load(&mut self, addr: Address) -> u8 {
+        self.peek(addr).unwrap_or_else(|| open_bus(addr))
+    }
+
+    fn store(&mut self, addr: Address, value: u8) {
+        match addr.as_usize() {
+            0x8000..=0xBFFF if self.flashable => {
+                let offset = self.prg_banks.offset(addr);
+                self.write_flash(offset, value);
+            }
+            0x8000..=0xFFFF if self.flashable => self.write_bank_register(value),
+            0x8000..=0xFFFF => {
+                let value = BusConflicts::And.apply(self.load(addr), value);
+                self.write_bank_register(value);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl CpuBus for Unrom512 {
+    fn peek(&self, addr: Address) -> Option<u8> {
+        if addr < PRG_ROM_START {
+            return None;
+        }
+        if self.flash_state == FlashState::SoftwareId {
+            return Some(match addr.as_usize() & 0x01 {
+                0 => FLASH_MANUFACTURER_ID,
+                _ => self.flash_device_id(),
+            });
+        }
+        Some(self.prg[self.prg_banks.offset(addr)])
+    }
+
+    fn prg_ram(&self) -> Option<Vec<u8>> {
+        if self.flashable {
+            Some(self.prg.clone())
+        } else {
+            None
+        }
+    }
+
+    fn restore_prg_ram(&mut self, data: &[u8]) {
+        // Ignore saves that aren't a full image of the flash chip, including
+        // the empty save of a game that hasn't been played yet.
+        if self.flashable && data.len() == self.prg.len() {
+            self.prg.copy_from_slice(data);
+        }
+    }
+}
+
+impl PpuBus for Unrom512 {
+    fn ppu_load(&mut self, vram: &Vram, palette: &[u8; 32], addr: Address) -> u8 {
+        if addr < NAMETABLES[0] {
+            self.chr[self.chr_banks.offset(addr)]
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()]
+        } else {
+            self.nametables.load(vram, addr)
+        }
+    }
+
+    fn ppu_store(&mut self, vram: &mut Vram, palette: &mut [u8; 32], addr: Address, value: u8) {
+        if addr < NAMETABLES[0] {
+            let i = self.chr_banks.offset(addr);
+            self.chr[i] = value;
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()] = value;
+        } else {
+            self.nametables.store(vram, addr, value);
+        }
+    }
+}
+
+/// The flash chip's contents are only saved on boards that have one, since
+/// PRG ROM can't change otherwise. CHR is always saved, since the board only
+/// has CHR RAM.
+impl Savestate for Unrom512 {
+    fn save_state(&self, w: &mut StateWriter) {
+        if self.flashable {
+            w.write(&self.prg);
+        }
+        w.write(&self.chr);
+        self.prg_banks.save_state(w);
+        self.chr_banks.save_state(w);
+        self.nametables.save_state(w);
+        w.write(&self.flash_state);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        if self.flashable {
+            r.read_into(&mut self.prg)?;
+        }
+        r.read_into(&mut self.chr)?;
+        self.prg_banks.load_state(r)?;
+        self.chr_banks.load_state(r)?;
+        self.nametables.load_state(r)?;
+        self.flash_state = r.read()?;
+        Ok(())
+    }
+}
+
+impl StateValue for FlashState {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&(*self as u8));
+    }
+
+    fn read(r: &mut StateReader) -> Result<Self> {
+        Ok(match r.read::<u8>()? {
+            0 => FlashState::Ready,
+            1 => FlashState::Unlock1,
+            2 => FlashState::Unlock2,
+            3 => FlashState::Program,
+            4 => FlashState::Erase,
+            5 => FlashState::EraseUnlock1,
+            6 => FlashState::EraseUnlock2,
+            7 => FlashState::SoftwareId,
+            n => bail!("Invalid flash state in save state: {}", n),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flash_rom() -> Rom {
+        // The battery makes the PRG ROM flash memory.
+        let mut rom = Rom::for_test(30, vec![0xFF; 32 * PRG_BANK_SIZE], Vec::new());
+        rom.header.has_battery = true;
+        rom
+    }
+
+    #[test]
+    fn test_flash_program_and_erase() {
+        let mut cart = Mapper30::from_rom(flash_rom()).unwrap();
+        // Flash address $5555 is $9555 in bank 1, and $2AAA is $AAAA in bank
+        // 0, so the game switches banks between each write.
+        let command = |cart: &mut Unrom512, addr: usize, value: u8| {
+            cart.store(Address(0xC000), (addr / PRG_BANK_SIZE) as u8);
+            cart.store(Address((0x8000 + addr % PRG_BANK_SIZE) as u16), value);
+        };
+
+        // Program $42 to the start of bank 3.
+        command(&mut cart, 0x5555, 0xAA);
+        command(&mut cart, 0x2AAA, 0x55);
+        command(&mut cart, 0x5555, 0xA0);
+        command(&mut cart, 3 * PRG_BANK_SIZE, 0x42);
+        assert_eq!(cart.prg[3 * PRG_BANK_SIZE], 0x42);
+        assert_eq!(cart.prg_ram().unwrap()[3 * PRG_BANK_SIZE], 0x42);
+
+        // Erase the sector containing it.
+        command(&mut cart, 0x5555, 0xAA);
+        command(&mut cart, 0x2AAA, 0x55);
+        command(&mut cart, 0x5555, 0x80);
+        command(&mut cart, 0x5555, 0xAA);
+        command(&mut cart, 0x2AAA, 0x55);
+        command(&mut cart, 3 * PRG_BANK_SIZE + 0x123, 0x30);
+        assert_eq!(cart.prg[3 * PRG_BANK_SIZE], 0xFF);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut cart = Mapper30::from_rom(flash_rom()).unwrap();
+        cart.store(Address(0xC000), 0x25);
+        cart.prg[0] = 0x42;
+        let mut w = StateWriter::new();
+        cart.save_state(&mut w);
+        let state = w.into_bytes();
+
+        cart.store(Address(0xC000), 0x00);
+        cart.prg[0] = 0xFF;
+        let mut r = StateReader::new(&state);
+        cart.load_state(&mut r).unwrap();
+        r.finish().unwrap();
+        assert_eq!(cart.prg[0], 0x42);
+        assert_eq!(cart.prg_banks.offset(Address(0x8000)), 5 * PRG_BANK_SIZE);
+        assert_eq!(cart.chr_banks.offset(Address(0)), CHR_BANK_SIZE);
+    }
+}
@@ -0,0 +1,195 @@
+use anyhow::Result;
+
+use crate::mem::{Address, Bus};
+use crate::ppu::{PpuBus, Vram, NAMETABLES, PALETTE_ADDR_BITS, PALETTE_BASE_ADDR};
+use crate::rom::Rom;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+use super::banked::Banked;
+use super::nametables::NametableMapping;
+use super::prg_ram::{PrgRam, PRG_RAM_START};
+use super::{open_bus, BusConflicts, CpuBus, Mapper};
+
+/// Mapper 34, which covers two unrelated boards that both switch PRG ROM in
+/// 32 KiB banks:
+///
+/// - BNROM (submapper 2), used by Deadly Towers, which selects the PRG bank
+///   by writing anywhere in $8000-$FFFF and uses 8 KiB of CHR RAM.
+/// - NINA-001 (submapper 1), used by Impossible Mission II, which has 8 KiB of
+///   PRG RAM at $6000, with registers at the top of that range for selecting
+///   the PRG bank and two 4 KiB CHR ROM banks.
+///
+/// iNES 1.0 headers can't tell the two apart, so fall back to the usual
+/// heuristic: only NINA-001 has more than 8 KiB of CHR ROM. BNROM has bus
+/// conflicts.
+pub(super) struct Mapper34;
+
+impl Mapper for Mapper34 {
+    type Cartridge = BnromNina;
+
+    fn from_rom(rom: Rom) -> Result<BnromNina> {
+        let board = match rom.header.submapper {
+            1 => Board::Nina001,
+            2 => Board::Bnrom,
+            _ if rom.chr.len() > CHR_RAM_SIZE => Board::Nina001,
+            _ => Board::Bnrom,
+        };
+        Ok(BnromNina::new(rom, board))
+    }
+}
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const NINA_CHR_BANK_SIZE: usize = 0x1000;
+const CHR_RAM_SIZE: usize = 0x2000;
+
+const PRG_ROM_START: Address = Address(0x8000);
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Board {
+    Bnrom,
+    Nina001,
+}
+
+/// Either of the two boards, which are similar enough to share an
+/// implementation.
+pub(super) struct BnromNina {
+    board: Board,
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    /// Only NINA-001 has PRG RAM.
+    prg_ram: PrgRam,
+    prg_banks: Banked,
+    /// BNROM's single 8 KiB window, or NINA-001's two 4 KiB windows.
+    chr_banks: Banked,
+    nametables: NametableMapping,
+}
+
+impl BnromNina {
+    fn new(rom: Rom, board: Board) -> Self {
+        let mut prg_ram = PrgRam::from_rom(&rom);
+        prg_ram.set_enabled(board == Board::Nina001);
+        let chr_is_ram = rom.header.has_chr_ram();
+        let Rom {
+            header, prg, chr, ..
+        } = rom;
+        let chr_bank_size = match board {
+            Board::Bnrom => CHR_RAM_SIZE,
+            Board::Nina001 => NINA_CHR_BANK_SIZE,
+        };
+        let mut chr_banks = Banked::new(Address(0), 0x2000, chr_bank_size, chr.len());
+        if board == Board::Nina001 {
+            chr_banks.set(1, 1);
+        }
+        Self {
+            board,
+            prg_banks: Banked::new(PRG_ROM_START, 0x8000, PRG_BANK_SIZE, prg.len()),
+            chr_banks,
+            prg,
+            chr,
+            chr_is_ram,
+            prg_ram,
+            nametables: NametableMapping::new(header.mirroring),
+        }
+    }
+}
+
+impl Bus for BnromNina {
+    fn load(&mut self, addr: Address) -> u8 {
+        self.peek(addr).unwrap_or_else(|| open_bus(addr))
+    }
+
+    fn store(&mut self, addr: Address, value: u8) {
+        // Only BNROM has registers in ROM.
+        let value = if addr >= PRG_ROM_START {
+            BusConflicts::And.apply(self.load(addr), value)
+        } else {
+            value
+        };
+        match (self.board, addr.as_usize()) {
+            (Board::Bnrom, 0x8000..=0xFFFF) => self.prg_banks.set(0, value as usize),
+            (Board::Nina001, 0x6000..=0x7FFF) => {
+                // The registers don't prevent the write from also reaching
+                // the RAM underneath.
+                self.prg_ram.store(addr, value);
+                match addr.as_usize() {
+                    0x7FFD => self.prg_banks.set(0, value as usize & 0x01),
+                    0x7FFE => self.chr_banks.set(0, value as usize & 0x0F),
+                    0x7FFF => self.chr_banks.set(1, value as usize & 0x0F),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl CpuBus for BnromNina {
+    fn peek(&self, addr: Address) -> Option<u8> {
+        if addr >= PRG_ROM_START {
+            Some(self.prg[self.prg_banks.offset(addr)])
+        } else if addr >= PRG_RAM_START {
+            self.prg_ram.load(addr)
+        } else {
+            None
+        }
+    }
+
+    fn prg_ram(&self) -> Option<Vec<u8>> {
+        match self.board {
+            Board::Bnrom => None,
+            Board::Nina001 => Some(self.prg_ram.data().to_vec()),
+        }
+    }
+
+    fn restore_prg_ram(&mut self, data: &[u8]) {
+        self.prg_ram.restore(data);
+    }
+}
+
+impl PpuBus for BnromNina {
+    fn ppu_load(&mut self, vram: &Vram, palette: &[u8; 32], addr: Address) -> u8 {
+        if addr < NAMETABLES[0] {
+            self.chr[self.chr_banks.offset(addr)]
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()]
+        } else {
+            self.nametables.load(vram, addr)
+        }
+    }
+
+    fn ppu_store(&mut self, vram: &mut Vram, palette: &mut [u8; 32], addr: Address, value: u8) {
+        if addr < NAMETABLES[0] {
+            if self.chr_is_ram {
+                let i = self.chr_banks.offset(addr);
+                self.chr[i] = value;
+            }
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()] = value;
+        } else {
+            self.nametables.store(vram, addr, value);
+        }
+    }
+}
+
+impl Savestate for BnromNina {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        if self.chr_is_ram {
+            w.write(&self.chr);
+        }
+        self.prg_banks.save_state(w);
+        self.chr_banks.save_state(w);
+        self.nametables.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.prg_ram.load_state(r)?;
+        if self.chr_is_ram {
+            r.read_into(&mut self.chr)?;
+        }
+        self.prg_banks.load_state(r)?;
+        self.chr_banks.load_state(r)?;
+        self.nametables.load_state(r)
+    }
+}
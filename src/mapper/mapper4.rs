@@ -0,0 +1,474 @@
+use anyhow::{ensure, Result};
+
+use crate::mem::{Address, Bus};
+use crate::ppu::{PpuBus, Vram, NAMETABLES, PALETTE_ADDR_BITS, PALETTE_BASE_ADDR};
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+use super::banked::Banked;
+use super::nametables::NametableMapping;
+use super::prg_ram::{PrgRam, PRG_RAM_START};
+use super::{open_bus, CpuBus, Mapper};
+
+/// MMC3 (and the closely related MMC6), used by TxROM boards.
+///
+/// The MMC3 divides the PRG ROM into 8 KiB banks and the CHR memory into 1 KiB
+/// banks, with two of the four PRG windows and all of the CHR windows being
+/// switchable. It also provides 8 KiB of (optionally battery-backed) PRG RAM,
+/// software-controlled mirroring, and a scanline counter that can generate
+/// IRQs at specific points in the frame (commonly used for split-screen
+/// effects such as status bars).
+///
+/// The scanline counter works by watching address line A12 of the PPU's bus.
+/// When the background and sprites use different pattern tables, A12 rises
+/// exactly once per scanline during rendering.
+pub(super) struct Mapper4;
+
+impl Mapper for Mapper4 {
+    type Cartridge = Mmc3;
+
+    fn from_rom(rom: Rom) -> Result<Mmc3> {
+        Ok(Mmc3::new(rom, Board::Txrom))
+    }
+}
+
+/// TxSROM (mapper 118), an MMC3 board that wires bit 7 of the CHR bank
+/// registers to the VRAM address line that selects the nametable, giving
+/// games independent control over each nametable. Used by Armadillo and
+/// Ys III.
+pub(super) struct Mapper118;
+
+impl Mapper for Mapper118 {
+    type Cartridge = Mmc3;
+
+    fn from_rom(rom: Rom) -> Result<Mmc3> {
+        Ok(Mmc3::new(rom, Board::Txsrom))
+    }
+}
+
+/// TQROM (mapper 119), an MMC3 board with both CHR ROM and 8 KiB of CHR RAM,
+/// where bit 6 of each CHR bank register selects between them. Used by High
+/// Speed and Pin*Bot.
+pub(super) struct Mapper119;
+
+impl Mapper for Mapper119 {
+    type Cartridge = Mmc3;
+
+    fn from_rom(rom: Rom) -> Result<Mmc3> {
+        Ok(Mmc3::new(rom, Board::Tqrom))
+    }
+}
+
+/// Namco 108 (mapper 206), used by DxROM boards and Namco's own boards.
+///
+/// This is the chip the MMC3 was derived from. It has the same bank select and
+/// bank data registers, but without the PRG and CHR mode bits, and with fewer
+/// bank bits. It has no PRG RAM, mirroring control, or IRQ counter. Used by
+/// Gauntlet, Karnov, and many of Namco's arcade ports.
+pub(super) struct Mapper206;
+
+impl Mapper for Mapper206 {
+    type Cartridge = Mmc3;
+
+    fn from_rom(rom: Rom) -> Result<Mmc3> {
+        Ok(Mmc3::new(rom, Board::Namco108))
+    }
+}
+
+/// The boards built around the MMC3 that differ in how they wire it up.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Board {
+    Txrom,
+    Txsrom,
+    Tqrom,
+    Namco108,
+}
+
+/// Where a 1 KiB window of the pattern tables is mapped.
+enum ChrPage {
+    Rom(usize),
+    Ram(usize),
+}
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+const CHR_RAM_SIZE: usize = 0x2000;
+
+const PRG_ROM_START: Address = Address(0x8000);
+
+pub(super) struct Mmc3 {
+    board: Board,
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    /// CHR RAM, for boards without CHR ROM and for TQROM.
+    chr_ram: Vec<u8>,
+    prg_ram: PrgRam,
+
+    /// Index of the bank register that the next write to $8001 updates.
+    bank_select: u8,
+    /// Swap the switchable PRG window at $8000 with the fixed one at $C000.
+    prg_mode: bool,
+    /// Swap the 2 KiB and 1 KiB CHR windows.
+    chr_inversion: bool,
+    /// Bank registers R0-R7. R0-R5 select CHR banks (R0 and R1 select 2 KiB
+    /// banks, in units of 1 KiB), and R6-R7 select PRG banks.
+    banks: [u8; 8],
+    prg_banks: Banked,
+
+    nametables: NametableMapping,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    a12: bool,
+}
+
+impl Mmc3 {
+    fn new(rom: Rom, board: Board) -> Self {
+        let mut prg_ram = PrgRam::from_rom(&rom);
+        // The Namco 108 has no PRG RAM.
+        prg_ram.set_enabled(board != Board::Namco108);
+        let Rom {
+            header, prg, chr, ..
+        } = rom;
+        let (chr, chr_ram) = if header.has_chr_ram() {
+            (Vec::new(), chr)
+        } else if board == Board::Tqrom {
+            (chr, vec![0; CHR_RAM_SIZE])
+        } else {
+            (chr, Vec::new())
+        };
+        let mut mmc3 = Self {
+            board,
+            prg_banks: Banked::new(PRG_ROM_START, 0x8000, PRG_BANK_SIZE, prg.len()),
+            prg,
+            chr,
+            chr_ram,
+            prg_ram,
+            bank_select: 0,
+            prg_mode: false,
+            chr_inversion: false,
+            banks: [0, 2, 4, 5, 6, 7, 0, 1],
+            nametables: NametableMapping::new(header.mirroring),
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            a12: false,
+        };
+        mmc3.update_prg_banks();
+        mmc3.update_txsrom_nametables();
+        mmc3
+    }
+
+    /// Map the PRG windows according to R6, R7, and the PRG mode.
+    fn update_prg_banks(&mut self) {
+        let second_last = self.prg_banks.last_bank() - 1;
+        let (first, third) = if self.prg_mode {
+            (second_last, self.banks[6] as usize)
+        } else {
+            (self.banks[6] as usize, second_last)
+        };
+        self.prg_banks.set(0, first);
+        self.prg_banks.set(1, self.banks[7] as usize);
+        self.prg_banks.set(2, third);
+        self.prg_banks.set(3, self.prg_banks.last_bank());
+    }
+
+    /// The bank selected for the given 1 KiB window of the pattern tables.
+    fn chr_bank(&self, window: usize) -> u8 {
+        let window = if self.chr_inversion {
+            window ^ 4
+        } else {
+            window
+        };
+        match window {
+            0..=1 => (self.banks[0] & !1) + window as u8,
+            2..=3 => (self.banks[1] & !1) + window as u8 - 2,
+            _ => self.banks[window - 2],
+        }
+    }
+
+    /// Find where a PPU address in $0000-$1FFF is mapped.
+    fn chr_page(&self, addr: Address) -> ChrPage {
+        let bank = self.chr_bank(addr.as_usize() / CHR_BANK_SIZE) as usize;
+        let offset = addr.as_usize() % CHR_BANK_SIZE;
+        let ram_banks = self.chr_ram.len() / CHR_BANK_SIZE;
+        if self.chr.is_empty() || (self.board == Board::Tqrom && bank & 0x40 > 0) {
+            ChrPage::Ram((bank % ram_banks) * CHR_BANK_SIZE + offset)
+        } else {
+            let num_banks = self.chr.len() / CHR_BANK_SIZE;
+            ChrPage::Rom((bank % num_banks) * CHR_BANK_SIZE + offset)
+        }
+    }
+
+    /// On TxSROM, each nametable uses the page of VRAM selected by bit 7 of
+    /// the bank for the corresponding window of the first 4 KiB of CHR, so
+    /// the nametables need to be remapped whenever the CHR banks change.
+    fn update_txsrom_nametables(&mut self) {
+        if self.board == Board::Txsrom {
+            for table in 0..4 {
+                let page = (self.chr_bank(table) >> 7) as usize;
+                self.nametables.set_page(table, page);
+            }
+        }
+    }
+
+    fn write_register(&mut self, addr: Address, value: u8) {
+        if self.board == Board::Namco108 {
+            self.write_namco108_register(addr, value);
+            return;
+        }
+        let even = addr.as_usize().is_multiple_of(2);
+        match (addr.as_usize(), even) {
+            (0x8000..=0x9FFF, true) => {
+                self.bank_select = value & 0x07;
+                self.prg_mode = value & 0x40 > 0;
+                self.chr_inversion = value & 0x80 > 0;
+                self.update_prg_banks();
+                self.update_txsrom_nametables();
+            }
+            (0x8000..=0x9FFF, false) => {
+                self.banks[self.bank_select as usize] = value;
+                self.update_prg_banks();
+                self.update_txsrom_nametables();
+            }
+            (0xA000..=0xBFFF, true) => {
+                // TxSROM ignores this register, since its nametables are
+                // controlled by the CHR banks instead.
+                if self.board != Board::Txsrom {
+                    self.nametables.set_mirroring(if value & 1 > 0 {
+                        Mirroring::Horizonal
+                    } else {
+                        Mirroring::Vertical
+                    });
+                }
+            }
+            (0xA000..=0xBFFF, false) => {
+                self.prg_ram.set_enabled(value & 0x80 > 0);
+                self.prg_ram.set_write_protected(value & 0x40 > 0);
+            }
+            (0xC000..=0xDFFF, true) => self.irq_latch = value,
+            (0xC000..=0xDFFF, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (0xE000..=0xFFFF, true) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (0xE000..=0xFFFF, false) => self.irq_enabled = true,
+            _ => unreachable!(),
+        }
+    }
+
+    /// The Namco 108 only has the bank select and bank data registers, and
+    /// only implements the low 6 bits of the CHR banks and 4 bits of the PRG
+    /// banks.
+    fn write_namco108_register(&mut self, addr: Address, value: u8) {
+        match addr.as_usize() {
+            0x8000..=0x9FFF if addr.as_usize().is_multiple_of(2) => {
+                self.bank_select = value & 0x07;
+            }
+            0x8000..=0x9FFF => {
+                let mask = if self.bank_select < 6 { 0x3F } else { 0x0F };
+                self.banks[self.bank_select as usize] = value & mask;
+                self.update_prg_banks();
+            }
+            _ => {}
+        }
+    }
+
+    /// Watch for rising edges on PPU address line A12, each of which clocks
+    /// the scanline counter.
+    fn observe_ppu_address(&mut self, addr: Address) {
+        let a12 = addr.as_usize() & 0x1000 > 0;
+        if a12 && !self.a12 {
+            self.clock_irq_counter();
+        }
+        self.a12 = a12;
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Bus for Mmc3 {
+    fn load(&mut self, addr: Address) -> u8 {
+        self.peek(addr).unwrap_or_else(|| open_bus(addr))
+    }
+
+    fn store(&mut self, addr: Address, value: u8) {
+        if addr >= PRG_ROM_START {
+            self.write_register(addr, value);
+        } else if addr >= PRG_RAM_START {
+            self.prg_ram.store(addr, value);
+        }
+    }
+}
+
+impl CpuBus for Mmc3 {
+    fn peek(&self, addr: Address) -> Option<u8> {
+        if addr >= PRG_ROM_START {
+            Some(self.prg[self.prg_banks.offset(addr)])
+        } else if addr >= PRG_RAM_START {
+            self.prg_ram.load(addr)
+        } else {
+            None
+        }
+    }
+
+    fn irq_asserted(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn prg_ram(&self) -> Option<Vec<u8>> {
+        match self.board {
+            Board::Namco108 => None,
+            _ => Some(self.prg_ram.data().to_vec()),
+        }
+    }
+
+    fn restore_prg_ram(&mut self, data: &[u8]) {
+        self.prg_ram.restore(data);
+    }
+}
+
+impl PpuBus for Mmc3 {
+    fn ppu_load(&mut self, vram: &Vram, palette: &[u8; 32], addr: Address) -> u8 {
+        if addr < NAMETABLES[0] {
+            match self.chr_page(addr) {
+                ChrPage::Rom(i) => self.chr[i],
+                ChrPage::Ram(i) => self.chr_ram[i],
+            }
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()]
+        } else {
+            self.nametables.load(vram, addr)
+        }
+    }
+
+    fn ppu_store(&mut self, vram: &mut Vram, palette: &mut [u8; 32], addr: Address, value: u8) {
+        if addr < NAMETABLES[0] {
+            // Pages mapped to CHR RAM are writable; writes to CHR ROM pages
+            // are ignored.
+            if let ChrPage::Ram(i) = self.chr_page(addr) {
+                self.chr_ram[i] = value;
+            }
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()] = value;
+        } else {
+            self.nametables.store(vram, addr, value);
+        }
+    }
+
+    fn ppu_observe(&mut self, addr: Address) {
+        self.observe_ppu_address(addr);
+    }
+}
+
+impl Savestate for Mmc3 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.chr_ram);
+        self.prg_ram.save_state(w);
+        w.write(&self.bank_select);
+        w.write(&self.prg_mode);
+        w.write(&self.chr_inversion);
+        w.write(&self.banks);
+        self.prg_banks.save_state(w);
+        self.nametables.save_state(w);
+        w.write(&self.irq_latch);
+        w.write(&self.irq_counter);
+        w.write(&self.irq_reload);
+        w.write(&self.irq_enabled);
+        w.write(&self.irq_pending);
+        w.write(&self.a12);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.read_into(&mut self.chr_ram)?;
+        self.prg_ram.load_state(r)?;
+        self.bank_select = r.read()?;
+        ensure!(
+            self.bank_select < 8,
+            "Invalid MMC3 bank select in save state"
+        );
+        self.prg_mode = r.read()?;
+        self.chr_inversion = r.read()?;
+        self.banks = r.read()?;
+        self.prg_banks.load_state(r)?;
+        self.nametables.load_state(r)?;
+        self.irq_latch = r.read()?;
+        self.irq_counter = r.read()?;
+        self.irq_reload = r.read()?;
+        self.irq_enabled = r.read()?;
+        self.irq_pending = r.read()?;
+        self.a12 = r.read()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_rom() -> Rom {
+        // Fill each 8 KiB PRG bank with its bank number.
+        let prg = (0..16u8)
+            .flat_map(|bank| vec![bank; PRG_BANK_SIZE])
+            .collect();
+        Rom::for_test(4, prg, Vec::new())
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let mut cart = Mapper4::from_rom(test_rom()).unwrap();
+        cart.store(Address(0x8000), 6);
+        cart.store(Address(0x8001), 3);
+        assert_eq!(cart.load(Address(0x8000)), 3);
+        assert_eq!(cart.load(Address(0xC000)), 14);
+        assert_eq!(cart.load(Address(0xE000)), 15);
+
+        // Swap the $8000 and $C000 windows.
+        cart.store(Address(0x8000), 0x46);
+        assert_eq!(cart.load(Address(0x8000)), 14);
+        assert_eq!(cart.load(Address(0xC000)), 3);
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut cart = Mapper4::from_rom(test_rom()).unwrap();
+        cart.store(Address(0xC000), 2);
+        cart.store(Address(0xC001), 0);
+        cart.store(Address(0xE001), 0);
+
+        let scanline = |cart: &mut Mmc3| {
+            cart.ppu_observe(Address(0x0000));
+            cart.ppu_observe(Address(0x1000));
+        };
+
+        // The first rising edge reloads the counter, and the next two count
+        // it down to zero.
+        scanline(&mut cart);
+        scanline(&mut cart);
+        assert!(!cart.irq_asserted());
+        scanline(&mut cart);
+        assert!(cart.irq_asserted());
+
+        // Writing to $E000 acknowledges the interrupt.
+        cart.store(Address(0xE000), 0);
+        assert!(!cart.irq_asserted());
+    }
+}
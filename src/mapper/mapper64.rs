@@ -0,0 +1,339 @@
+use anyhow::{ensure, Result};
+
+use crate::mem::{Address, Bus};
+use crate::ppu::{PpuBus, Vram, NAMETABLES, PALETTE_ADDR_BITS, PALETTE_BASE_ADDR};
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+use super::banked::Banked;
+use super::nametables::NametableMapping;
+use super::{open_bus, CpuBus, Mapper};
+
+/// Tengen RAMBO-1 (mapper 64), used by Tengen's unlicensed releases such as
+/// Klax, Shinobi, and Skull & Crossbones.
+///
+/// The RAMBO-1 is an MMC3 clone with a few extensions: a third switchable PRG
+/// window, a mode that splits the two 2 KiB CHR windows into four 1 KiB ones,
+/// and an IRQ counter that can count either scanlines (by watching A12, like
+/// the MMC3) or CPU cycles. The counter also behaves differently from the
+/// MMC3's when reloaded, and the IRQ is only asserted a few cycles after the
+/// counter reaches zero; games are sensitive to both.
+pub(super) struct Mapper64;
+
+impl Mapper for Mapper64 {
+    type Cartridge = Rambo1;
+
+    fn from_rom(rom: Rom) -> Result<Rambo1> {
+        Ok(Rambo1::new(rom))
+    }
+}
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+const PRG_ROM_START: Address = Address(0x8000);
+
+/// In CPU cycle mode, the counter is clocked every 4 CPU cycles.
+const CYCLE_MODE_PRESCALER: u8 = 4;
+
+/// Number of CPU cycles between the counter reaching zero and the IRQ being
+/// asserted.
+const IRQ_DELAY: u8 = 2;
+
+pub(super) struct Rambo1 {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+
+    bank_select: u8,
+    prg_mode: bool,
+    chr_inversion: bool,
+    /// Whether R8 and R9 are used to split the 2 KiB CHR windows in two.
+    chr_1k_mode: bool,
+    /// Bank registers R0-RF (only R0-R9 and RF are used).
+    banks: [u8; 16],
+    prg_banks: Banked,
+    chr_banks: Banked,
+    nametables: NametableMapping,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_cycle_mode: bool,
+    prescaler: u8,
+    /// Cycles remaining until a pending IRQ is asserted.
+    irq_delay: Option<u8>,
+    irq_asserted: bool,
+    a12: bool,
+}
+
+impl Rambo1 {
+    fn new(rom: Rom) -> Self {
+        let Rom {
+            header, prg, chr, ..
+        } = rom;
+        let mut rambo1 = Self {
+            prg_banks: Banked::new(PRG_ROM_START, 0x8000, PRG_BANK_SIZE, prg.len()),
+            chr_banks: Banked::new(Address(0), 0x2000, CHR_BANK_SIZE, chr.len()),
+            prg,
+            chr,
+            bank_select: 0,
+            prg_mode: false,
+            chr_inversion: false,
+            chr_1k_mode: false,
+            banks: [0; 16],
+            nametables: NametableMapping::new(header.mirroring),
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_cycle_mode: false,
+            prescaler: 0,
+            irq_delay: None,
+            irq_asserted: false,
+            a12: false,
+        };
+        rambo1.update_banks();
+        rambo1
+    }
+
+    /// Map the PRG and CHR windows according to the bank registers and the
+    /// current banking modes.
+    fn update_banks(&mut self) {
+        let [r0, r1, r2, r3, r4, r5, r6, r7, r8, r9, .., rf] = self.banks.map(|bank| bank as usize);
+
+        let prg = if self.prg_mode {
+            [rf, r6, r7]
+        } else {
+            [r6, r7, rf]
+        };
+        for (window, &bank) in prg.iter().enumerate() {
+            self.prg_banks.set(window, bank);
+        }
+        self.prg_banks.set(3, self.prg_banks.last_bank());
+
+        let (second, fourth) = if self.chr_1k_mode {
+            (r8, r9)
+        } else {
+            (r0 + 1, r1 + 1)
+        };
+        let chr = [r0, second, r1, fourth, r2, r3, r4, r5];
+        for (window, &bank) in chr.iter().enumerate() {
+            let window = if self.chr_inversion {
+                window ^ 4
+            } else {
+                window
+            };
+            self.chr_banks.set(window, bank);
+        }
+    }
+
+    fn write_register(&mut self, addr: Address, value: u8) {
+        let even = addr.as_usize().is_multiple_of(2);
+        match (addr.as_usize(), even) {
+            (0x8000..=0x9FFF, true) => {
+                self.bank_select = value & 0x0F;
+                self.chr_1k_mode = value & 0x20 > 0;
+                self.prg_mode = value & 0x40 > 0;
+                self.chr_inversion = value & 0x80 > 0;
+                self.update_banks();
+            }
+            (0x8000..=0x9FFF, false) => {
+                self.banks[self.bank_select as usize] = value;
+                self.update_banks();
+            }
+            (0xA000..=0xBFFF, true) => {
+                self.nametables.set_mirroring(if value & 1 > 0 {
+                    Mirroring::Horizonal
+                } else {
+                    Mirroring::Vertical
+                });
+            }
+            (0xA000..=0xBFFF, false) => {}
+            (0xC000..=0xDFFF, true) => self.irq_latch = value,
+            (0xC000..=0xDFFF, false) => {
+                self.irq_cycle_mode = value & 0x01 > 0;
+                self.irq_reload = true;
+                self.prescaler = 0;
+            }
+            (0xE000..=0xFFFF, true) => {
+                self.irq_enabled = false;
+                self.irq_delay = None;
+                self.irq_asserted = false;
+            }
+            (0xE000..=0xFFFF, false) => self.irq_enabled = true,
+            _ => unreachable!(),
+        }
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_reload {
+            // Unlike the MMC3, reloading the counter adds one (or two, for
+            // latch values above 1) to the latch value.
+            self.irq_counter = if self.irq_latch <= 1 {
+                self.irq_latch + 1
+            } else {
+                self.irq_latch.wrapping_add(2)
+            };
+            self.irq_reload = false;
+        } else if self.irq_counter == 0 {
+            self.irq_counter = self.irq_latch.wrapping_add(1);
+        }
+        self.irq_counter = self.irq_counter.wrapping_sub(1);
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_delay = Some(IRQ_DELAY);
+        }
+    }
+
+    fn tick(&mut self) {
+        if let Some(delay) = self.irq_delay {
+            if delay == 0 {
+                self.irq_asserted = true;
+                self.irq_delay = None;
+            } else {
+                self.irq_delay = Some(delay - 1);
+            }
+        }
+        if self.irq_cycle_mode {
+            self.prescaler += 1;
+            if self.prescaler == CYCLE_MODE_PRESCALER {
+                self.prescaler = 0;
+                self.clock_irq_counter();
+            }
+        }
+    }
+
+    fn observe_ppu_address(&mut self, addr: Address) {
+        let a12 = addr.as_usize() & 0x1000 > 0;
+        if a12 && !self.a12 && !self.irq_cycle_mode {
+            self.clock_irq_counter();
+        }
+        self.a12 = a12;
+    }
+}
+
+impl Bus for Rambo1 {
+    fn load(&mut self, addr: Address) -> u8 {
+        self.peek(addr).unwrap_or_else(|| open_bus(addr))
+    }
+
+    fn store(&mut self, addr: Address, value: u8) {
+        if addr >= PRG_ROM_START {
+            self.write_register(addr, value);
+        }
+    }
+}
+
+impl CpuBus for Rambo1 {
+    fn peek(&self, addr: Address) -> Option<u8> {
+        (addr >= PRG_ROM_START).then(|| self.prg[self.prg_banks.offset(addr)])
+    }
+
+    fn irq_asserted(&self) -> bool {
+        self.irq_asserted
+    }
+
+    fn tick(&mut self) {
+        self.tick();
+    }
+}
+
+impl PpuBus for Rambo1 {
+    fn ppu_load(&mut self, vram: &Vram, palette: &[u8; 32], addr: Address) -> u8 {
+        if addr < NAMETABLES[0] {
+            self.chr[self.chr_banks.offset(addr)]
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()]
+        } else {
+            self.nametables.load(vram, addr)
+        }
+    }
+
+    fn ppu_store(&mut self, vram: &mut Vram, palette: &mut [u8; 32], addr: Address, value: u8) {
+        if addr < NAMETABLES[0] {
+            // Can't write to CHR ROM.
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()] = value;
+        } else {
+            self.nametables.store(vram, addr, value);
+        }
+    }
+
+    fn ppu_observe(&mut self, addr: Address) {
+        self.observe_ppu_address(addr);
+    }
+}
+
+impl Savestate for Rambo1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.bank_select);
+        w.write(&self.prg_mode);
+        w.write(&self.chr_inversion);
+        w.write(&self.chr_1k_mode);
+        w.write(&self.banks);
+        self.prg_banks.save_state(w);
+        self.chr_banks.save_state(w);
+        self.nametables.save_state(w);
+        w.write(&self.irq_latch);
+        w.write(&self.irq_counter);
+        w.write(&self.irq_reload);
+        w.write(&self.irq_enabled);
+        w.write(&self.irq_cycle_mode);
+        w.write(&self.prescaler);
+        w.write(&self.irq_delay);
+        w.write(&self.irq_asserted);
+        w.write(&self.a12);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.bank_select = r.read()?;
+        ensure!(
+            self.bank_select < 16,
+            "Invalid RAMBO-1 bank select in save state"
+        );
+        self.prg_mode = r.read()?;
+        self.chr_inversion = r.read()?;
+        self.chr_1k_mode = r.read()?;
+        self.banks = r.read()?;
+        self.prg_banks.load_state(r)?;
+        self.chr_banks.load_state(r)?;
+        self.nametables.load_state(r)?;
+        self.irq_latch = r.read()?;
+        self.irq_counter = r.read()?;
+        self.irq_reload = r.read()?;
+        self.irq_enabled = r.read()?;
+        self.irq_cycle_mode = r.read()?;
+        self.prescaler = r.read()?;
+        self.irq_delay = r.read()?;
+        self.irq_asserted = r.read()?;
+        self.a12 = r.read()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cycle_mode_irq() {
+        let rom = Rom::for_test(64, vec![0; 0x8000], vec![0; 0x2000]);
+        let mut cart = Mapper64::from_rom(rom).unwrap();
+        cart.store(Address(0xC000), 1);
+        cart.store(Address(0xC001), 1);
+        cart.store(Address(0xE001), 0);
+
+        // A latch of 1 is reloaded as 2, so the counter reaches zero on the
+        // second clock (after 8 cycles), and the IRQ follows shortly after.
+        let mut cycles = 0;
+        while !cart.irq_asserted() {
+            cart.tick();
+            cycles += 1;
+            assert!(cycles < 100);
+        }
+        assert_eq!(cycles, 8 + IRQ_DELAY as u32 + 1);
+
+        cart.store(Address(0xE000), 0);
+        assert!(!cart.irq_asserted());
+    }
+}
@@ -0,0 +1,222 @@
+use anyhow::Result;
+
+use crate::apu::ExpansionAudio;
+use crate::mem::{Address, Bus};
+use crate::ppu::{PpuBus, Vram, NAMETABLES, PALETTE_ADDR_BITS, PALETTE_BASE_ADDR};
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+use super::banked::Banked;
+use super::nametables::NametableMapping;
+use super::prg_ram::{PrgRam, PRG_RAM_START};
+use super::sunsoft5b_audio::Sunsoft5bAudio;
+use super::{open_bus, CpuBus, Mapper};
+
+/// Sunsoft FME-7 and 5A/5B (mapper 69), used by Batman: Return of the Joker,
+/// Gimmick!, and others.
+///
+/// The FME-7 is programmed via a command register at $8000 and a parameter
+/// register at $A000. It has four switchable 8 KiB PRG windows (one of which,
+/// at $6000, can map PRG RAM instead of ROM), eight 1 KiB CHR windows, and a
+/// 16-bit IRQ counter that counts down every CPU cycle. The 5B additionally
+/// contains an AY-3-8910 compatible sound chip. Since the header doesn't say
+/// which chip a board uses, the sound chip is always present; FME-7 games
+/// never write to its registers anyway.
+pub(super) struct Mapper69;
+
+impl Mapper for Mapper69 {
+    type Cartridge = Fme7;
+
+    fn from_rom(rom: Rom) -> Result<Fme7> {
+        Ok(Fme7::new(rom))
+    }
+}
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+const PRG_ROM_START: Address = Address(0x8000);
+
+pub(super) struct Fme7 {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    prg_ram: PrgRam,
+    /// Windows at $6000, $8000, $A000, and $C000, plus the last bank fixed at
+    /// $E000.
+    prg_banks: Banked,
+    /// Whether the $6000 window maps PRG RAM rather than ROM.
+    prg_ram_selected: bool,
+    chr_banks: Banked,
+    nametables: NametableMapping,
+    command: u8,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_pending: bool,
+    audio: Sunsoft5bAudio,
+}
+
+impl Fme7 {
+    fn new(rom: Rom) -> Self {
+        let mut prg_ram = PrgRam::from_rom(&rom);
+        prg_ram.set_enabled(false);
+        let Rom {
+            header, prg, chr, ..
+        } = rom;
+        let mut prg_banks = Banked::new(PRG_RAM_START, 0xA000, PRG_BANK_SIZE, prg.len());
+        prg_banks.set(4, prg_banks.last_bank());
+        Self {
+            prg_banks,
+            chr_banks: Banked::new(Address(0), 0x2000, CHR_BANK_SIZE, chr.len()),
+            prg,
+            chr,
+            prg_ram,
+            prg_ram_selected: false,
+            nametables: NametableMapping::new(header.mirroring),
+            command: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_pending: false,
+            audio: Sunsoft5bAudio::new(),
+        }
+    }
+
+    /// $A000: Parameter for the command selected via $8000.
+    fn write_parameter(&mut self, value: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks.set(self.command as usize, value as usize),
+            0x8 => {
+                self.prg_ram.set_enabled(value & 0x80 > 0);
+                self.prg_ram_selected = value & 0x40 > 0;
+                self.prg_banks.set(0, value as usize & 0x3F);
+            }
+            0x9..=0xB => {
+                self.prg_banks
+                    .set(self.command as usize - 8, value as usize & 0x3F);
+            }
+            0xC => {
+                self.nametables.set_mirroring(match value & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizonal,
+                    2 => Mirroring::SingleScreenA,
+                    _ => Mirroring::SingleScreenB,
+                });
+            }
+            0xD => {
+                self.irq_enabled = value & 0x01 > 0;
+                self.irq_counter_enabled = value & 0x80 > 0;
+                self.irq_pending = false;
+            }
+            0xE => self.irq_counter = (self.irq_counter & 0xFF00) | value as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00FF) | (value as u16) << 8,
+        }
+    }
+}
+
+impl Bus for Fme7 {
+    fn load(&mut self, addr: Address) -> u8 {
+        self.peek(addr).unwrap_or_else(|| open_bus(addr))
+    }
+
+    fn store(&mut self, addr: Address, value: u8) {
+        match addr.as_usize() {
+            0x6000..=0x7FFF if self.prg_ram_selected => self.prg_ram.store(addr, value),
+            0x8000..=0x9FFF => self.command = value & 0x0F,
+            0xA000..=0xBFFF => self.write_parameter(value),
+            0xC000..=0xDFFF => self.audio.select_register(value),
+            0xE000..=0xFFFF => self.audio.write_register(value),
+            _ => {}
+        }
+    }
+}
+
+impl CpuBus for Fme7 {
+    fn peek(&self, addr: Address) -> Option<u8> {
+        if addr >= PRG_ROM_START || (addr >= PRG_RAM_START && !self.prg_ram_selected) {
+            Some(self.prg[self.prg_banks.offset(addr)])
+        } else if addr >= PRG_RAM_START {
+            self.prg_ram.load(addr)
+        } else {
+            None
+        }
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+
+    fn irq_asserted(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn tick(&mut self) {
+        if !self.irq_counter_enabled {
+            return;
+        }
+        self.irq_counter = self.irq_counter.wrapping_sub(1);
+        if self.irq_counter == 0xFFFF && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+
+    fn prg_ram(&self) -> Option<Vec<u8>> {
+        Some(self.prg_ram.data().to_vec())
+    }
+
+    fn restore_prg_ram(&mut self, data: &[u8]) {
+        self.prg_ram.restore(data);
+    }
+}
+
+impl PpuBus for Fme7 {
+    fn ppu_load(&mut self, vram: &Vram, palette: &[u8; 32], addr: Address) -> u8 {
+        if addr < NAMETABLES[0] {
+            self.chr[self.chr_banks.offset(addr)]
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()]
+        } else {
+            self.nametables.load(vram, addr)
+        }
+    }
+
+    fn ppu_store(&mut self, vram: &mut Vram, palette: &mut [u8; 32], addr: Address, value: u8) {
+        if addr < NAMETABLES[0] {
+            // Can't write to CHR ROM.
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()] = value;
+        } else {
+            self.nametables.store(vram, addr, value);
+        }
+    }
+}
+
+impl Savestate for Fme7 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        self.prg_banks.save_state(w);
+        w.write(&self.prg_ram_selected);
+        self.chr_banks.save_state(w);
+        self.nametables.save_state(w);
+        w.write(&self.command);
+        w.write(&self.irq_counter);
+        w.write(&self.irq_enabled);
+        w.write(&self.irq_counter_enabled);
+        w.write(&self.irq_pending);
+        self.audio.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.prg_ram.load_state(r)?;
+        self.prg_banks.load_state(r)?;
+        self.prg_ram_selected = r.read()?;
+        self.chr_banks.load_state(r)?;
+        self.nametables.load_state(r)?;
+        self.command = r.read()?;
+        self.irq_counter = r.read()?;
+        self.irq_enabled = r.read()?;
+        self.irq_counter_enabled = r.read()?;
+        self.irq_pending = r.read()?;
+        self.audio.load_state(r)
+    }
+}
@@ -0,0 +1,125 @@
+use anyhow::Result;
+
+use crate::mem::{Address, Bus};
+use crate::ppu::{PpuBus, Vram, NAMETABLES, PALETTE_ADDR_BITS, PALETTE_BASE_ADDR};
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+use super::banked::Banked;
+use super::nametables::NametableMapping;
+use super::{open_bus, CpuBus, Mapper};
+
+/// Camerica/Codemasters BF909x (mapper 71), used by Micro Machines, Bee 52,
+/// and the rest of the Codemasters catalog.
+///
+/// Much like UxROM, this has a switchable 16 KiB PRG window at $8000, with the
+/// last bank fixed at $C000, and 8 KiB of CHR RAM. The bank is selected by
+/// writing to $C000-$FFFF. The BF9097 variant used by Fire Hawk (submapper 1)
+/// additionally selects single-screen mirroring via bit 4 of writes to
+/// $8000-$9FFF. Since iNES 1.0 headers can't identify Fire Hawk, mirroring
+/// control is also enabled the first time a game writes to $9000-$9FFF, which
+/// other games never do.
+pub(super) struct Mapper71;
+
+impl Mapper for Mapper71 {
+    type Cartridge = Bf909x;
+
+    fn from_rom(rom: Rom) -> Result<Bf909x> {
+        let Rom {
+            header, prg, chr, ..
+        } = rom;
+        let mut prg_banks = Banked::new(PRG_ROM_START, 0x8000, PRG_BANK_SIZE, prg.len());
+        prg_banks.set(1, prg_banks.last_bank());
+        Ok(Bf909x {
+            prg,
+            prg_banks,
+            chr,
+            mirroring_control: header.submapper == 1,
+            nametables: NametableMapping::new(header.mirroring),
+        })
+    }
+}
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+const PRG_ROM_START: Address = Address(0x8000);
+
+pub(super) struct Bf909x {
+    prg: Vec<u8>,
+    /// The switchable window at $8000 and the last bank fixed at $C000.
+    prg_banks: Banked,
+    chr: Vec<u8>,
+    /// Whether this is a BF9097 board, which controls mirroring.
+    mirroring_control: bool,
+    nametables: NametableMapping,
+}
+
+impl Bus for Bf909x {
+    fn load(&mut self, addr: Address) -> u8 {
+        self.peek(addr).unwrap_or_else(|| open_bus(addr))
+    }
+
+    fn store(&mut self, addr: Address, value: u8) {
+        match addr.as_usize() {
+            0x8000..=0x9FFF => {
+                if addr.as_usize() >= 0x9000 {
+                    self.mirroring_control = true;
+                }
+                if self.mirroring_control {
+                    self.nametables.set_mirroring(if value & 0x10 > 0 {
+                        Mirroring::SingleScreenB
+                    } else {
+                        Mirroring::SingleScreenA
+                    });
+                }
+            }
+            0xC000..=0xFFFF => self.prg_banks.set(0, value as usize),
+            _ => {}
+        }
+    }
+}
+
+impl CpuBus for Bf909x {
+    fn peek(&self, addr: Address) -> Option<u8> {
+        (addr >= PRG_ROM_START).then(|| self.prg[self.prg_banks.offset(addr)])
+    }
+}
+
+impl PpuBus for Bf909x {
+    fn ppu_load(&mut self, vram: &Vram, palette: &[u8; 32], addr: Address) -> u8 {
+        if addr < NAMETABLES[0] {
+            self.chr[addr.as_usize() % self.chr.len()]
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()]
+        } else {
+            self.nametables.load(vram, addr)
+        }
+    }
+
+    fn ppu_store(&mut self, vram: &mut Vram, palette: &mut [u8; 32], addr: Address, value: u8) {
+        if addr < NAMETABLES[0] {
+            let i = addr.as_usize() % self.chr.len();
+            self.chr[i] = value;
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()] = value;
+        } else {
+            self.nametables.store(vram, addr, value);
+        }
+    }
+}
+
+impl Savestate for Bf909x {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_banks.save_state(w);
+        w.write(&self.chr);
+        w.write(&self.mirroring_control);
+        self.nametables.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.prg_banks.load_state(r)?;
+        r.read_into(&mut self.chr)?;
+        self.mirroring_control = r.read()?;
+        self.nametables.load_state(r)
+    }
+}
@@ -0,0 +1,203 @@
+use anyhow::Result;
+
+use crate::apu::ExpansionAudio;
+use crate::mem::{Address, Bus};
+use crate::ppu::{PpuBus, Vram, NAMETABLES, PALETTE_ADDR_BITS, PALETTE_BASE_ADDR};
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+use super::banked::Banked;
+use super::nametables::NametableMapping;
+use super::prg_ram::{PrgRam, PRG_RAM_START};
+use super::vrc7_audio::Vrc7Audio;
+use super::vrc_irq::VrcIrq;
+use super::{open_bus, CpuBus, Mapper};
+
+/// Konami VRC7 (mapper 85), used by Lagrange Point and Tiny Toon Adventures 2.
+///
+/// The VRC7 provides three switchable 8 KiB PRG windows, eight 1 KiB CHR
+/// windows, 8 KiB of PRG RAM, a VRC-style IRQ counter, and (on the board used
+/// by Lagrange Point) an FM synthesizer.
+pub(super) struct Mapper85;
+
+impl Mapper for Mapper85 {
+    type Cartridge = Vrc7;
+
+    fn from_rom(rom: Rom) -> Result<Vrc7> {
+        Ok(Vrc7::new(rom))
+    }
+}
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+const PRG_ROM_START: Address = Address(0x8000);
+
+pub(super) struct Vrc7 {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_ram: PrgRam,
+    /// Three switchable windows, plus the last bank fixed at $E000.
+    prg_banks: Banked,
+    chr_banks: Banked,
+    nametables: NametableMapping,
+    irq: VrcIrq,
+    audio: Vrc7Audio,
+}
+
+impl Vrc7 {
+    fn new(rom: Rom) -> Self {
+        let mut prg_ram = PrgRam::from_rom(&rom);
+        prg_ram.set_enabled(false);
+        let chr_is_ram = rom.header.has_chr_ram();
+        let Rom {
+            header, prg, chr, ..
+        } = rom;
+        let mut prg_banks = Banked::new(PRG_ROM_START, 0x8000, PRG_BANK_SIZE, prg.len());
+        prg_banks.set(3, prg_banks.last_bank());
+        Self {
+            prg_banks,
+            chr_banks: Banked::new(Address(0), 0x2000, CHR_BANK_SIZE, chr.len()),
+            prg,
+            chr,
+            chr_is_ram,
+            prg_ram,
+            nametables: NametableMapping::new(header.mirroring),
+            irq: VrcIrq::default(),
+            audio: Vrc7Audio::new(),
+        }
+    }
+
+    fn write_register(&mut self, addr: Address, value: u8) {
+        // Each pair of registers is distinguished by A4 on VRC7a and by A3 on
+        // VRC7b, so accept either. The audio data port additionally uses A5.
+        let addr = addr.as_usize();
+        let high = addr & 0x08 > 0 || addr & 0x10 > 0;
+        match (addr & 0xF000, high) {
+            (0x8000, false) => self.prg_banks.set(0, value as usize & 0x3F),
+            (0x8000, true) => self.prg_banks.set(1, value as usize & 0x3F),
+            (0x9000, _) if addr & 0x20 > 0 => self.audio.write_register(value),
+            (0x9000, false) => self.prg_banks.set(2, value as usize & 0x3F),
+            (0x9000, true) => self.audio.select_register(value),
+            (0xA000..=0xD000, _) => {
+                let i = ((addr & 0xF000) - 0xA000) / 0x800 + high as usize;
+                self.chr_banks.set(i, value as usize);
+            }
+            (0xE000, false) => {
+                // RS-- --MM (PRG RAM enable, sound reset, mirroring).
+                self.prg_ram.set_enabled(value & 0x80 > 0);
+                if value & 0x40 > 0 {
+                    self.audio.reset();
+                }
+                self.nametables.set_mirroring(match value & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizonal,
+                    2 => Mirroring::SingleScreenA,
+                    _ => Mirroring::SingleScreenB,
+                });
+            }
+            (0xE000, true) => self.irq.write_latch(value),
+            (0xF000, false) => self.irq.write_control(value),
+            (0xF000, true) => self.irq.acknowledge(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Bus for Vrc7 {
+    fn load(&mut self, addr: Address) -> u8 {
+        self.peek(addr).unwrap_or_else(|| open_bus(addr))
+    }
+
+    fn store(&mut self, addr: Address, value: u8) {
+        if addr >= PRG_ROM_START {
+            self.write_register(addr, value);
+        } else if addr >= PRG_RAM_START {
+            self.prg_ram.store(addr, value);
+        }
+    }
+}
+
+impl CpuBus for Vrc7 {
+    fn peek(&self, addr: Address) -> Option<u8> {
+        if addr >= PRG_ROM_START {
+            Some(self.prg[self.prg_banks.offset(addr)])
+        } else if addr >= PRG_RAM_START {
+            self.prg_ram.load(addr)
+        } else {
+            None
+        }
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+
+    fn irq_asserted(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn tick(&mut self) {
+        self.irq.tick();
+    }
+
+    fn prg_ram(&self) -> Option<Vec<u8>> {
+        Some(self.prg_ram.data().to_vec())
+    }
+
+    fn restore_prg_ram(&mut self, data: &[u8]) {
+        self.prg_ram.restore(data);
+    }
+}
+
+impl PpuBus for Vrc7 {
+    fn ppu_load(&mut self, vram: &Vram, palette: &[u8; 32], addr: Address) -> u8 {
+        if addr < NAMETABLES[0] {
+            self.chr[self.chr_banks.offset(addr)]
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()]
+        } else {
+            self.nametables.load(vram, addr)
+        }
+    }
+
+    fn ppu_store(&mut self, vram: &mut Vram, palette: &mut [u8; 32], addr: Address, value: u8) {
+        if addr < NAMETABLES[0] {
+            if self.chr_is_ram {
+                let i = self.chr_banks.offset(addr);
+                self.chr[i] = value;
+            }
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()] = value;
+        } else {
+            self.nametables.store(vram, addr, value);
+        }
+    }
+}
+
+impl Savestate for Vrc7 {
+    fn save_state(&self, w: &mut StateWriter) {
+        if self.chr_is_ram {
+            w.write(&self.chr);
+        }
+        self.prg_ram.save_state(w);
+        self.prg_banks.save_state(w);
+        self.chr_banks.save_state(w);
+        self.nametables.save_state(w);
+        self.irq.save_state(w);
+        self.audio.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        if self.chr_is_ram {
+            r.read_into(&mut self.chr)?;
+        }
+        self.prg_ram.load_state(r)?;
+        self.prg_banks.load_state(r)?;
+        self.chr_banks.load_state(r)?;
+        self.nametables.load_state(r)?;
+        self.irq.load_state(r)?;
+        self.audio.load_state(r)
+    }
+}
@@ -0,0 +1,196 @@
+use anyhow::{bail, Result};
+
+use crate::mem::{Address, Bus};
+use crate::ppu::{PpuBus, Vram, NAMETABLES, PALETTE_ADDR_BITS, PALETTE_BASE_ADDR};
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateValue, StateWriter};
+
+use super::banked::Banked;
+use super::nametables::NametableMapping;
+use super::{open_bus, CpuBus, Mapper};
+
+/// MMC2, used by PxROM boards (only Mike Tyson's Punch-Out!! and its
+/// successor).
+///
+/// The MMC2 has a single switchable 8 KiB PRG window, with the rest of PRG ROM
+/// fixed to the last three banks. Its distinguishing feature is that each
+/// 4 KiB CHR window has two bank registers, with a latch selecting between
+/// them. The latches are flipped by the PPU itself when it fetches tile $FD or
+/// $FE, which lets a game switch CHR banks partway through the frame without
+/// any CPU involvement.
+pub(super) struct Mapper9;
+
+impl Mapper for Mapper9 {
+    type Cartridge = Mmc2;
+
+    fn from_rom(rom: Rom) -> Result<Mmc2> {
+        Ok(Mmc2::new(rom))
+    }
+}
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x1000;
+
+const PRG_ROM_START: Address = Address(0x8000);
+
+/// Which of a CHR window's two bank registers is in use, named after the tile
+/// that selects it.
+#[derive(Copy, Clone)]
+enum Latch {
+    Fd,
+    Fe,
+}
+
+pub(super) struct Mmc2 {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    prg_banks: Banked,
+    chr_banks: Banked,
+    /// Bank registers for each CHR window, indexed by latch state.
+    chr_registers: [[u8; 2]; 2],
+    latches: [Latch; 2],
+    nametables: NametableMapping,
+}
+
+impl Mmc2 {
+    fn new(rom: Rom) -> Self {
+        let Rom {
+            header, prg, chr, ..
+        } = rom;
+        let mut prg_banks = Banked::new(PRG_ROM_START, 0x8000, PRG_BANK_SIZE, prg.len());
+        // The last three windows are fixed to the last three banks.
+        for window in 1..4 {
+            prg_banks.set(window, prg_banks.last_bank() + window - 3);
+        }
+        Self {
+            prg_banks,
+            chr_banks: Banked::new(Address(0), 0x2000, CHR_BANK_SIZE, chr.len()),
+            prg,
+            chr,
+            chr_registers: [[0; 2]; 2],
+            latches: [Latch::Fe; 2],
+            nametables: NametableMapping::new(header.mirroring),
+        }
+    }
+
+    /// Map each CHR window to the bank selected by its latch.
+    fn update_chr_banks(&mut self) {
+        for window in 0..2 {
+            let bank = self.chr_registers[window][self.latches[window] as usize];
+            self.chr_banks.set(window, bank as usize);
+        }
+    }
+
+    fn write_register(&mut self, addr: Address, value: u8) {
+        match addr.as_usize() {
+            0x8000..=0x9FFF => {}
+            0xA000..=0xAFFF => self.prg_banks.set(0, value as usize & 0x0F),
+            0xB000..=0xEFFF => {
+                let i = (addr.as_usize() - 0xB000) / 0x1000;
+                self.chr_registers[i / 2][i % 2] = value & 0x1F;
+                self.update_chr_banks();
+            }
+            0xF000..=0xFFFF => {
+                self.nametables.set_mirroring(if value & 1 > 0 {
+                    Mirroring::Horizonal
+                } else {
+                    Mirroring::Vertical
+                });
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Flip the CHR latches when the PPU fetches the high plane of tile $FD or
+    /// $FE. The new bank takes effect starting with the next tile. Note that
+    /// the first window's latch only responds to the first row of the tile.
+    fn observe_ppu_address(&mut self, addr: Address) {
+        let latch = match addr.as_usize() {
+            0x0FD8 | 0x1FD8..=0x1FDF => Latch::Fd,
+            0x0FE8 | 0x1FE8..=0x1FEF => Latch::Fe,
+            _ => return,
+        };
+        self.latches[addr.as_usize() / CHR_BANK_SIZE] = latch;
+        self.update_chr_banks();
+    }
+}
+
+impl Bus for Mmc2 {
+    fn load(&mut self, addr: Address) -> u8 {
+        self.peek(addr).unwrap_or_else(|| open_bus(addr))
+    }
+
+    fn store(&mut self, addr: Address, value: u8) {
+        if addr >= PRG_ROM_START {
+            self.write_register(addr, value);
+        }
+    }
+}
+
+impl CpuBus for Mmc2 {
+    fn peek(&self, addr: Address) -> Option<u8> {
+        (addr >= PRG_ROM_START).then(|| self.prg[self.prg_banks.offset(addr)])
+    }
+}
+
+impl PpuBus for Mmc2 {
+    fn ppu_load(&mut self, vram: &Vram, palette: &[u8; 32], addr: Address) -> u8 {
+        if addr < NAMETABLES[0] {
+            self.chr[self.chr_banks.offset(addr)]
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()]
+        } else {
+            self.nametables.load(vram, addr)
+        }
+    }
+
+    fn ppu_store(&mut self, vram: &mut Vram, palette: &mut [u8; 32], addr: Address, value: u8) {
+        if addr < NAMETABLES[0] {
+            // Can't write to CHR ROM.
+        } else if addr >= PALETTE_BASE_ADDR {
+            palette[addr.alias(PALETTE_ADDR_BITS).as_usize()] = value;
+        } else {
+            self.nametables.store(vram, addr, value);
+        }
+    }
+
+    fn ppu_observe(&mut self, addr: Address) {
+        self.observe_ppu_address(addr);
+    }
+}
+
+impl Savestate for Mmc2 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_banks.save_state(w);
+        self.chr_banks.save_state(w);
+        w.write(&self.chr_registers);
+        for latch in &self.latches {
+            w.write(latch);
+        }
+        self.nametables.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.prg_banks.load_state(r)?;
+        self.chr_banks.load_state(r)?;
+        self.chr_registers = r.read()?;
+        for latch in &mut self.latches {
+            *latch = r.read()?;
+        }
+        self.nametables.load_state(r)
+    }
+}
+
+impl StateValue for Latch {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&(*self as u8));
+    }
+
+    fn read(r: &mut StateReader) -> Result<Self> {
+        Ok(match r.read::<u8>()? {
+            0 => Latch::Fd,
+            1 => Latch::Fe,
+            n => bail!("Invalid MMC2 latch in save state: {}", n),
+        })
+    }
+}
@@ -1,8 +1,32 @@
+use anyhow::{bail, Result};
+
+use crate::apu::ExpansionAudio;
 use crate::mem::{Address, Bus};
 use crate::ppu::{PpuBus, Vram};
 use crate::rom::Rom;
+use crate::savestate::{Savestate, StateReader, StateWriter};
 
+mod banked;
 mod mapper0;
+mod mapper19;
+mod mapper228;
+mod mapper24;
+mod mapper3;
+mod mapper30;
+mod mapper34;
+mod mapper4;
+mod mapper64;
+mod mapper69;
+mod mapper71;
+mod mapper85;
+mod mapper9;
+mod n163_audio;
+mod nametables;
+mod prg_ram;
+mod sunsoft5b_audio;
+mod vrc6_audio;
+mod vrc7_audio;
+mod vrc_irq;
 
 /// Trait representing a cartridge's mapper.
 ///
@@ -13,27 +37,211 @@ mod mapper0;
 ///
 /// The mapper maps the content of the cartridge into both the CPU and PPU's
 /// address space (mapping the contents of the PRG memory into the former and
-/// CHR memory into the latter). As such, this trait splits the mapper into
-/// a CPU mapper and a PPU mapper, which can share state depending on the
-/// implementation, but operate on different address buses.
+/// CHR memory into the latter), so each mapper produces a single `Cartridge`
+/// that is connected to both address buses.
 trait Mapper {
-    type CpuMapper: Bus;
-    type PpuMapper: PpuBus;
+    type Cartridge: Cartridge;
+
+    /// Set up the cartridge for the ROM. Fails if the ROM's contents don't
+    /// fit the board (e.g., if it has more PRG ROM than the board can map).
+    fn from_rom(rom: Rom) -> Result<Self::Cartridge>;
+}
+
+/// A game cartridge, which sits on both the CPU's address bus (as `CpuBus`)
+/// and the PPU's (as `PpuBus`). Since it's a single object, banking state can
+/// be shared freely between the two sides, just like on the real hardware.
+///
+/// Cartridges also save their bank registers, IRQ counters, and any RAM or
+/// flash as part of save states (see `Savestate`). Anything that comes from
+/// the ROM (including the ROM's contents) is left out, since loading a state
+/// requires the same ROM anyway.
+pub trait Cartridge: CpuBus + PpuBus + Savestate {}
+
+impl<T: CpuBus + PpuBus + Savestate> Cartridge for T {}
+
+/// The CPU side of a cartridge. Besides mapping the CPU's accesses to the
+/// cartridge, this also provides access to any extra hardware that the
+/// cartridge contains.
+pub trait CpuBus: Bus {
+    /// The value that loading from the given address would return, without
+    /// any of the side effects of loading it (e.g., advancing an auto-
+    /// incrementing address), for debuggers. `None` if nothing is mapped
+    /// there.
+    fn peek(&self, addr: Address) -> Option<u8>;
+
+    /// Sound hardware on the cartridge, if any, whose output should be mixed
+    /// with the APU's.
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        None
+    }
+
+    /// Whether the cartridge is asserting the CPU's IRQ line.
+    fn irq_asserted(&self) -> bool {
+        false
+    }
+
+    /// Advance any hardware on the cartridge that is clocked by the CPU, such
+    /// as cycle-based IRQ counters. Called once per CPU cycle.
+    fn tick(&mut self) {}
+
+    /// Contents of the cartridge's PRG RAM, if it has any. Used to persist
+    /// battery-backed saves. Cartridges that save to some other kind of
+    /// memory, like flash, return that instead.
+    fn prg_ram(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Overwrite the contents of the cartridge's PRG RAM (if any), e.g. with
+    /// a save file from a previous session.
+    fn restore_prg_ram(&mut self, _data: &[u8]) {}
+}
+
+/// Initialize the appropriate mapper for this ROM file. Fails if the ROM uses
+/// a mapper that isn't supported, or doesn't fit its board.
+pub fn init(rom: Rom) -> Result<Cart> {
+    match rom.header.mapper {
+        0 => boxed::<mapper0::Mapper0>(rom),
+        3 => boxed::<mapper3::Mapper3>(rom),
+        4 => boxed::<mapper4::Mapper4>(rom),
+        9 => boxed::<mapper9::Mapper9>(rom),
+        19 => boxed::<mapper19::Mapper19>(rom),
+        24 => boxed::<mapper24::Mapper24>(rom),
+        26 => boxed::<mapper24::Mapper26>(rom),
+        30 => boxed::<mapper30::Mapper30>(rom),
+        34 => boxed::<mapper34::Mapper34>(rom),
+        64 => boxed::<mapper64::Mapper64>(rom),
+        69 => boxed::<mapper69::Mapper69>(rom),
+        71 => boxed::<mapper71::Mapper71>(rom),
+        85 => boxed::<mapper85::Mapper85>(rom),
+        87 => boxed::<mapper3::Mapper87>(rom),
+        118 => boxed::<mapper4::Mapper118>(rom),
+        119 => boxed::<mapper4::Mapper119>(rom),
+        185 => boxed::<mapper3::Mapper185>(rom),
+        206 => boxed::<mapper4::Mapper206>(rom),
+        228 => boxed::<mapper228::Mapper228>(rom),
+        n => bail!("{} not supported", describe(n)),
+    }
+}
+
+/// Names of the boards (or mapper chips) that commonly use each mapper
+/// number, for display purposes. Many mapper numbers cover several boards;
+/// these are the names they're best known by.
+const BOARD_NAMES: &[(u8, &str)] = &[
+    (0, "NROM"),
+    (1, "MMC1"),
+    (2, "UxROM"),
+    (3, "CNROM"),
+    (4, "MMC3"),
+    (5, "MMC5"),
+    (7, "AxROM"),
+    (9, "MMC2"),
+    (10, "MMC4"),
+    (11, "Color Dreams"),
+    (13, "CPROM"),
+    (16, "Bandai FCG"),
+    (18, "Jaleco SS88006"),
+    (19, "Namco 163"),
+    (21, "VRC4a/VRC4c"),
+    (22, "VRC2a"),
+    (23, "VRC2b/VRC4e"),
+    (24, "VRC6a"),
+    (25, "VRC4b/VRC4d"),
+    (26, "VRC6b"),
+    (30, "UNROM 512"),
+    (32, "Irem G-101"),
+    (33, "Taito TC0190"),
+    (34, "BNROM/NINA-001"),
+    (48, "Taito TC0690"),
+    (64, "RAMBO-1"),
+    (66, "GxROM"),
+    (69, "Sunsoft FME-7"),
+    (71, "Camerica BF909x"),
+    (73, "VRC3"),
+    (75, "VRC1"),
+    (79, "NINA-003/NINA-006"),
+    (85, "VRC7"),
+    (87, "Jaleco/Konami CNROM"),
+    (105, "NES-EVENT"),
+    (118, "TxSROM"),
+    (119, "TQROM"),
+    (152, "Bandai 74161"),
+    (180, "UNROM (Crazy Climber)"),
+    (185, "CNROM with copy protection"),
+    (206, "Namco 108"),
+    (210, "Namco 175/340"),
+    (228, "Action 52"),
+    (232, "Camerica BF9096"),
+];
+
+/// The name of the board commonly associated with a mapper number, if known.
+pub fn board_name(mapper: u8) -> Option<&'static str> {
+    BOARD_NAMES
+        .iter()
+        .find(|(number, _)| *number == mapper)
+        .map(|(_, name)| *name)
+}
+
+/// Describe a mapper by its number and board name, e.g. "mapper 4 / MMC3".
+pub fn describe(mapper: u8) -> String {
+    match board_name(mapper) {
+        Some(name) => format!("mapper {} / {}", mapper, name),
+        None => format!("mapper {}", mapper),
+    }
+}
+
+fn boxed<M>(rom: Rom) -> Result<Cart>
+where
+    M: Mapper,
+    M::Cartridge: 'static,
+{
+    Ok(Box::new(M::from_rom(rom)?))
+}
+
+/// Value read from an address where nothing is mapped. On real hardware, the
+/// CPU reads whatever was last on the data bus, which is usually the high byte
+/// of the address (the last byte of the instruction's operand).
+pub fn open_bus(addr: Address) -> u8 {
+    (addr.as_usize() >> 8) as u8
+}
 
-    fn from_rom(rom: Rom) -> (Self::CpuMapper, Self::PpuMapper);
+/// Whether writes to PRG ROM are subject to bus conflicts.
+///
+/// Boards built from discrete logic chips don't disable the ROM's output
+/// while the CPU writes to it, so both drive the data bus at once. In
+/// practice, the value that reaches the mapper's register is the bitwise AND
+/// of the two, which is why games for these boards write their bank numbers
+/// to an address in ROM holding the same value.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum BusConflicts {
+    Absent,
+    And,
 }
 
-/// Initialize the appropriate mappers for this ROM file.
-pub fn init(rom: Rom) -> (CpuMapper, PpuMapper) {
-    // TODO: Read mapper number from ROM header to select appropriate mapper.
-    let (cpu_mapper, ppu_mapper) = mapper0::Mapper0::from_rom(rom);
-    (Box::new(cpu_mapper), Box::new(ppu_mapper))
+impl BusConflicts {
+    /// For discrete mappers like CNROM, NES 2.0 submapper 1 means the board
+    /// has no bus conflicts, and submapper 2 means it does. Assume it does
+    /// if the header doesn't say.
+    fn from_submapper(submapper: u8) -> Self {
+        match submapper {
+            1 => BusConflicts::Absent,
+            _ => BusConflicts::And,
+        }
+    }
+
+    /// The value seen by the mapper when the CPU writes `value` to an address
+    /// where the ROM contains `rom_value`.
+    fn apply(self, rom_value: u8, value: u8) -> u8 {
+        match self {
+            BusConflicts::Absent => value,
+            BusConflicts::And => rom_value & value,
+        }
+    }
 }
 
-/// CPU mapper trait object that delegates to boxed mapper.
-pub type CpuMapper = Box<dyn Bus>;
+/// Cartridge trait object that delegates to the boxed cartridge.
+pub type Cart = Box<dyn Cartridge>;
 
-impl Bus for CpuMapper {
+impl Bus for Cart {
     fn load(&mut self, addr: Address) -> u8 {
         (**self).load(addr)
     }
@@ -43,10 +251,43 @@ impl Bus for CpuMapper {
     }
 }
 
-/// PPU mapper trait object that delegates to inner boxed mapper.
-pub type PpuMapper = Box<dyn PpuBus>;
+impl CpuBus for Cart {
+    fn peek(&self, addr: Address) -> Option<u8> {
+        (**self).peek(addr)
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        (**self).expansion_audio()
+    }
+
+    fn irq_asserted(&self) -> bool {
+        (**self).irq_asserted()
+    }
+
+    fn tick(&mut self) {
+        (**self).tick()
+    }
+
+    fn prg_ram(&self) -> Option<Vec<u8>> {
+        (**self).prg_ram()
+    }
+
+    fn restore_prg_ram(&mut self, data: &[u8]) {
+        (**self).restore_prg_ram(data)
+    }
+}
+
+impl Savestate for Cart {
+    fn save_state(&self, w: &mut StateWriter) {
+        (**self).save_state(w)
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        (**self).load_state(r)
+    }
+}
 
-impl PpuBus for PpuMapper {
+impl PpuBus for Cart {
     fn ppu_load(&mut self, vram: &Vram, palette: &[u8; 32], addr: Address) -> u8 {
         (**self).ppu_load(vram, palette, addr)
     }
@@ -54,4 +295,8 @@ impl PpuBus for PpuMapper {
     fn ppu_store(&mut self, vram: &mut Vram, palette: &mut [u8; 32], addr: Address, value: u8) {
         (**self).ppu_store(vram, palette, addr, value)
     }
+
+    fn ppu_observe(&mut self, addr: Address) {
+        (**self).ppu_observe(addr)
+    }
 }
@@ -0,0 +1,184 @@
+use anyhow::{ensure, Result};
+
+use crate::apu::ExpansionAudio;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// Size of the chip's internal RAM, which holds both the channel registers
+/// and the waveform data.
+const RAM_SIZE: usize = 128;
+
+/// Channel registers occupy the top of internal RAM, 8 bytes per channel,
+/// with channel 7 at the very end.
+const CHANNEL_BASE: usize = 0x40;
+
+/// The chip updates one channel every 15 CPU cycles.
+const CYCLES_PER_UPDATE: u8 = 15;
+
+/// Volume of the N163 at full scale relative to the APU. This varies a lot
+/// between boards, since each uses different resistors to mix the chip's
+/// output with the APU's.
+const WEIGHT: f32 = 0.8;
+
+/// The Namco 163's sound hardware: up to 8 wavetable channels.
+///
+/// Each channel plays a waveform of 4-bit samples stored in the chip's
+/// internal RAM, which is shared with the channel registers. The chip updates
+/// the channels one at a time in round-robin fashion and outputs only the
+/// most recently updated channel, so enabling more channels lowers the rate
+/// at which each one is updated. The hardware relies on the TV to average out
+/// the resulting high-pitched whine; this implementation averages the
+/// channels directly, which is what most players expect to hear.
+pub(super) struct N163Audio {
+    ram: [u8; RAM_SIZE],
+    /// Internal RAM address for the next access via $4800.
+    addr: u8,
+    auto_increment: bool,
+    enabled: bool,
+    cycle: u8,
+    /// Channel to update next.
+    channel: usize,
+    outputs: [i16; 8],
+}
+
+impl N163Audio {
+    pub fn new() -> Self {
+        Self {
+            ram: [0; RAM_SIZE],
+            addr: 0,
+            auto_increment: false,
+            enabled: true,
+            cycle: 0,
+            channel: 7,
+            outputs: [0; 8],
+        }
+    }
+
+    /// $F800: IAAA AAAA (auto-increment, internal RAM address).
+    pub fn write_addr(&mut self, value: u8) {
+        self.addr = value & 0x7F;
+        self.auto_increment = value & 0x80 > 0;
+    }
+
+    /// $4800: Read from internal RAM.
+    pub fn read_data(&mut self) -> u8 {
+        let value = self.ram[self.addr as usize];
+        self.advance_addr();
+        value
+    }
+
+    /// $4800: The value a read would return, without advancing the address.
+    pub fn peek_data(&self) -> u8 {
+        self.ram[self.addr as usize]
+    }
+
+    /// $4800: Write to internal RAM.
+    pub fn write_data(&mut self, value: u8) {
+        self.ram[self.addr as usize] = value;
+        self.advance_addr();
+    }
+
+    fn advance_addr(&mut self) {
+        if self.auto_increment {
+            self.addr = (self.addr + 1) & 0x7F;
+        }
+    }
+
+    /// Sound is disabled by bit 6 of $E000.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Number of enabled channels, which is stored in the register block of
+    /// channel 7. Channels are enabled from 7 downwards.
+    fn num_channels(&self) -> usize {
+        ((self.ram[0x7F] >> 4) & 0x07) as usize + 1
+    }
+
+    /// Advance the phase of a channel and compute its new output.
+    fn update_channel(&mut self, channel: usize) {
+        let regs = CHANNEL_BASE + channel * 8;
+        let reg = |i: usize| self.ram[regs + i] as u32;
+
+        let freq = reg(0) | reg(2) << 8 | (reg(4) & 0x03) << 16;
+        let phase = reg(1) | reg(3) << 8 | reg(5) << 16;
+        let length = 256 - (reg(4) & 0xFC);
+        let wave_addr = reg(6);
+        let volume = reg(7) & 0x0F;
+
+        let phase = (phase + freq) % (length << 16);
+        self.ram[regs + 1] = phase as u8;
+        self.ram[regs + 3] = (phase >> 8) as u8;
+        self.ram[regs + 5] = (phase >> 16) as u8;
+
+        // Samples are packed two per byte, low nibble first.
+        let sample_addr = (wave_addr + (phase >> 16)) & 0xFF;
+        let byte = self.ram[(sample_addr / 2) as usize];
+        let sample = if sample_addr.is_multiple_of(2) {
+            byte & 0x0F
+        } else {
+            byte >> 4
+        };
+        self.outputs[channel] = (sample as i16 - 8) * volume as i16;
+    }
+}
+
+impl ExpansionAudio for N163Audio {
+    fn tick(&mut self) {
+        self.cycle += 1;
+        if self.cycle < CYCLES_PER_UPDATE {
+            return;
+        }
+        self.cycle = 0;
+
+        let first = 8 - self.num_channels();
+        if self.channel < first {
+            self.channel = 7;
+        }
+        self.update_channel(self.channel);
+        self.channel = if self.channel == first {
+            7
+        } else {
+            self.channel - 1
+        };
+    }
+
+    fn output(&self) -> f32 {
+        if !self.enabled {
+            return 0.0;
+        }
+        let first = 8 - self.num_channels();
+        let sum: i16 = self.outputs[first..].iter().sum();
+        sum as f32 / (self.num_channels() as f32 * 128.0)
+    }
+
+    fn weight(&self) -> f32 {
+        WEIGHT
+    }
+}
+
+impl Savestate for N163Audio {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.ram);
+        w.write(&self.addr);
+        w.write(&self.auto_increment);
+        w.write(&self.enabled);
+        w.write(&self.cycle);
+        w.write(&self.channel);
+        w.write(&self.outputs);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.ram = r.read()?;
+        self.addr = r.read()?;
+        self.auto_increment = r.read()?;
+        self.enabled = r.read()?;
+        self.cycle = r.read()?;
+        self.channel = r.read()?;
+        self.outputs = r.read()?;
+        ensure!(
+            (self.addr as usize) < RAM_SIZE && self.channel < 8,
+            "Invalid N163 audio state"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use anyhow::{ensure, Result};
+
+use crate::mem::Address;
+use crate::ppu::{Vram, NAMETABLES};
+use crate::rom::Mirroring;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// Size of a single nametable (including its attribute table).
+const NAMETABLE_SIZE: usize = 0x400;
+
+/// Maps the four nametables at $2000-$2FFF (mirrored at $3000-$3EFF) onto
+/// physical 1 KiB pages of memory.
+///
+/// The console only has enough VRAM for two nametables (pages 0 and 1), so
+/// the cartridge decides how the four are arranged. Many boards hardwire a
+/// horizontal or vertical arrangement, while many mappers can change it at
+/// runtime. Four-screen boards instead provide another 2 KiB of RAM (pages 2
+/// and 3) so that all four nametables are distinct.
+pub(super) struct NametableMapping {
+    /// The page that each nametable is mapped to.
+    pages: [usize; 4],
+    /// RAM for pages 2 and 3 on four-screen boards; empty otherwise.
+    extra_vram: Vec<u8>,
+}
+
+impl NametableMapping {
+    pub fn new(mirroring: Mirroring) -> Self {
+        let extra_vram = match mirroring {
+            Mirroring::FourScreen => vec![0; 2 * NAMETABLE_SIZE],
+            _ => Vec::new(),
+        };
+        Self {
+            pages: pages(mirroring),
+            extra_vram,
+        }
+    }
+
+    /// Switch to one of the standard arrangements. Ignored on four-screen
+    /// boards, whose arrangement is fixed.
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        if self.extra_vram.is_empty() {
+            self.pages = pages(mirroring);
+        }
+    }
+
+    /// Map a single nametable (0-3) to the given page of VRAM, for mappers
+    /// that control each nametable individually.
+    pub fn set_page(&mut self, table: usize, page: usize) {
+        self.pages[table] = page;
+    }
+
+    /// Find the page and offset within the page for a PPU address in
+    /// $2000-$3EFF.
+    fn locate(&self, addr: Address) -> (usize, usize) {
+        let offset = (addr.as_usize() - NAMETABLES[0].as_usize()) % (4 * NAMETABLE_SIZE);
+        let page = self.pages[offset / NAMETABLE_SIZE];
+        // Only four-screen boards have pages 2 and 3.
+        let page = if self.extra_vram.is_empty() {
+            page % 2
+        } else {
+            page
+        };
+        (page, offset % NAMETABLE_SIZE)
+    }
+
+    pub fn load(&self, vram: &Vram, addr: Address) -> u8 {
+        match self.locate(addr) {
+            (page @ 0..=1, offset) => vram.0[page * NAMETABLE_SIZE + offset],
+            (page, offset) => self.extra_vram[(page - 2) * NAMETABLE_SIZE + offset],
+        }
+    }
+
+    pub fn store(&mut self, vram: &mut Vram, addr: Address, value: u8) {
+        match self.locate(addr) {
+            (page @ 0..=1, offset) => vram.0[page * NAMETABLE_SIZE + offset] = value,
+            (page, offset) => self.extra_vram[(page - 2) * NAMETABLE_SIZE + offset] = value,
+        }
+    }
+}
+
+impl Savestate for NametableMapping {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.pages);
+        w.write(&self.extra_vram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        let pages: [usize; 4] = r.read()?;
+        ensure!(
+            pages.iter().all(|&page| page < 4),
+            "Invalid nametable page in save state"
+        );
+        self.pages = pages;
+        r.read_into(&mut self.extra_vram)
+    }
+}
+
+/// Pages for each nametable in one of the standard arrangements.
+fn pages(mirroring: Mirroring) -> [usize; 4] {
+    match mirroring {
+        Mirroring::Horizonal => [0, 0, 1, 1],
+        Mirroring::Vertical => [0, 1, 0, 1],
+        Mirroring::SingleScreenA => [0; 4],
+        Mirroring::SingleScreenB => [1; 4],
+        Mirroring::FourScreen => [0, 1, 2, 3],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mirroring() {
+        let mut vram = Vram([0; 0x800]);
+        let mut nametables = NametableMapping::new(Mirroring::Vertical);
+        nametables.store(&mut vram, Address(0x2400), 1);
+        assert_eq!(nametables.load(&vram, Address(0x2C00)), 1);
+        assert_eq!(nametables.load(&vram, Address(0x2000)), 0);
+
+        nametables.set_mirroring(Mirroring::Horizonal);
+        assert_eq!(nametables.load(&vram, Address(0x2400)), 0);
+        assert_eq!(nametables.load(&vram, Address(0x2800)), 1);
+
+        // Four-screen boards ignore attempts to change the arrangement.
+        let mut nametables = NametableMapping::new(Mirroring::FourScreen);
+        nametables.set_mirroring(Mirroring::SingleScreenA);
+        nametables.store(&mut vram, Address(0x2C00), 2);
+        assert_eq!(nametables.load(&vram, Address(0x3C00)), 2);
+        assert_eq!(nametables.load(&vram, Address(0x2400)), 1);
+    }
+}
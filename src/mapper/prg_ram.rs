@@ -0,0 +1,112 @@
+use anyhow::Result;
+
+use crate::mem::Address;
+use crate::rom::Rom;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// Start of the region of the CPU's address space ($6000-$7FFF) where
+/// cartridges map their RAM.
+pub const PRG_RAM_START: Address = Address(0x6000);
+
+/// Size of the $6000-$7FFF region.
+const PRG_RAM_WINDOW_SIZE: usize = 0x2000;
+
+/// Where the ROM's trainer (if any) is loaded, relative to `PRG_RAM_START`
+/// ($7000).
+const TRAINER_OFFSET: usize = 0x1000;
+
+/// Work RAM on the cartridge (often called PRG RAM or WRAM), mapped into
+/// $6000-$7FFF. Games use this as extra memory beyond the console's 2 KiB,
+/// and when it's battery-backed, to hold saved games.
+///
+/// Many mappers can disable or write-protect the RAM. While disabled, reads
+/// return `None`, and the mapper should return open bus.
+pub(super) struct PrgRam {
+    data: Vec<u8>,
+    enabled: bool,
+    write_protected: bool,
+}
+
+impl PrgRam {
+    /// Create PRG RAM with the size given in the ROM header, preloaded with
+    /// the ROM's trainer, if it has one.
+    pub fn from_rom(rom: &Rom) -> Self {
+        let mut prg_ram = Self::new(rom.header.prg_ram_size());
+        if let Some(trainer) = &rom.trainer {
+            if let Some(dest) = prg_ram
+                .data
+                .get_mut(TRAINER_OFFSET..TRAINER_OFFSET + trainer.len())
+            {
+                dest.copy_from_slice(trainer);
+            }
+        }
+        prg_ram
+    }
+
+    /// Create PRG RAM of the given size. RAM smaller than 8 KiB is mirrored
+    /// to fill the window, and only the first 8 KiB of larger RAM is mapped.
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: vec![0; size],
+            enabled: true,
+            write_protected: false,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn set_write_protected(&mut self, write_protected: bool) {
+        self.write_protected = write_protected;
+    }
+
+    /// The full contents of the RAM, regardless of whether it's enabled.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Overwrite the contents of the RAM, e.g. with a saved game from disk.
+    /// Data that doesn't fit is ignored.
+    pub fn restore(&mut self, data: &[u8]) {
+        let len = data.len().min(self.data.len());
+        self.data[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn offset(&self, addr: Address) -> usize {
+        (addr.as_usize() - PRG_RAM_START.as_usize()) % PRG_RAM_WINDOW_SIZE.min(self.data.len())
+    }
+
+    /// Read from an address in $6000-$7FFF, if the RAM is enabled.
+    pub fn load(&self, addr: Address) -> Option<u8> {
+        if self.enabled && !self.data.is_empty() {
+            Some(self.data[self.offset(addr)])
+        } else {
+            None
+        }
+    }
+
+    /// Write to an address in $6000-$7FFF, if the RAM is enabled and not
+    /// write-protected.
+    pub fn store(&mut self, addr: Address, value: u8) {
+        if self.enabled && !self.write_protected && !self.data.is_empty() {
+            let i = self.offset(addr);
+            self.data[i] = value;
+        }
+    }
+}
+
+impl Savestate for PrgRam {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.data);
+        w.write(&self.enabled);
+        w.write(&self.write_protected);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.read_into(&mut self.data)?;
+        self.enabled = r.read()?;
+        self.write_protected = r.read()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+use anyhow::Result;
+
+use crate::apu::ExpansionAudio;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// The chip's counters are clocked once every 16 CPU cycles.
+const CLOCK_DIVIDER: u8 = 16;
+
+/// Volume of the 5B at full scale relative to the APU.
+const WEIGHT: f32 = 0.8;
+
+/// The Sunsoft 5B's sound hardware, a licensed copy of the General Instrument
+/// AY-3-8910 programmable sound generator: three square wave channels, a
+/// noise generator, and an envelope generator, any of which can be combined
+/// on each channel.
+///
+/// Registers are written indirectly, by selecting a register via $C000 and
+/// then writing its value via $E000.
+pub(super) struct Sunsoft5bAudio {
+    selected: u8,
+    tones: [Tone; 3],
+    /// Bits 0-2 disable the tone and bits 3-5 disable the noise on each
+    /// channel.
+    mixer: u8,
+    noise_period: u8,
+    noise_counter: u16,
+    /// 17-bit linear feedback shift register.
+    noise: u32,
+    envelope: Envelope,
+    divider: u8,
+}
+
+impl Sunsoft5bAudio {
+    pub fn new() -> Self {
+        Self {
+            selected: 0,
+            tones: Default::default(),
+            mixer: 0,
+            noise_period: 0,
+            noise_counter: 0,
+            noise: 1,
+            envelope: Envelope::default(),
+            divider: 0,
+        }
+    }
+
+    /// $C000: Select the register to write.
+    pub fn select_register(&mut self, value: u8) {
+        self.selected = value & 0x0F;
+    }
+
+    /// $E000: Write to the selected register.
+    pub fn write_register(&mut self, value: u8) {
+        match self.selected {
+            0x0 | 0x2 | 0x4 => {
+                let tone = &mut self.tones[self.selected as usize / 2];
+                tone.period = (tone.period & 0x0F00) | value as u16;
+            }
+            0x1 | 0x3 | 0x5 => {
+                let tone = &mut self.tones[self.selected as usize / 2];
+                tone.period = (tone.period & 0x00FF) | ((value as u16 & 0x0F) << 8);
+            }
+            0x6 => self.noise_period = value & 0x1F,
+            0x7 => self.mixer = value,
+            0x8..=0xA => self.tones[self.selected as usize - 8].volume = value & 0x1F,
+            0xB => self.envelope.period = (self.envelope.period & 0xFF00) | value as u16,
+            0xC => self.envelope.period = (self.envelope.period & 0x00FF) | (value as u16) << 8,
+            0xD => self.envelope.set_shape(value),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        for tone in &mut self.tones {
+            tone.clock();
+        }
+
+        // Noise and the envelope run at half the rate of the tone counters.
+        self.noise_counter += 1;
+        if self.noise_counter >= 2 * self.noise_period.max(1) as u16 {
+            self.noise_counter = 0;
+            let feedback = (self.noise ^ (self.noise >> 3)) & 1;
+            self.noise = (self.noise >> 1) | (feedback << 16);
+        }
+        self.envelope.clock();
+    }
+}
+
+impl ExpansionAudio for Sunsoft5bAudio {
+    fn tick(&mut self) {
+        self.divider += 1;
+        if self.divider == CLOCK_DIVIDER {
+            self.divider = 0;
+            self.clock();
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let noise = self.noise & 1 > 0;
+        let mut sum = 0.0;
+        for (i, tone) in self.tones.iter().enumerate() {
+            let tone_on = tone.high || self.mixer & (1 << i) > 0;
+            let noise_on = noise || self.mixer & (8 << i) > 0;
+            if !(tone_on && noise_on) {
+                continue;
+            }
+            let volume = if tone.volume & 0x10 > 0 {
+                self.envelope.level()
+            } else {
+                tone.volume & 0x0F
+            };
+            sum += amplitude(volume);
+        }
+        sum / 3.0
+    }
+
+    fn weight(&self) -> f32 {
+        WEIGHT
+    }
+}
+
+/// Convert a 4-bit volume to a linear amplitude. Each step is 3 dB, with 0
+/// being silent.
+fn amplitude(volume: u8) -> f32 {
+    if volume == 0 {
+        0.0
+    } else {
+        10f32.powf((volume as f32 - 15.0) * 3.0 / 20.0)
+    }
+}
+
+#[derive(Default)]
+struct Tone {
+    period: u16,
+    counter: u16,
+    high: bool,
+    /// Bit 4 selects the envelope, otherwise bits 0-3 are the volume.
+    volume: u8,
+}
+
+impl Tone {
+    fn clock(&mut self) {
+        self.counter += 1;
+        if self.counter >= self.period.max(1) {
+            self.counter = 0;
+            self.high = !self.high;
+        }
+    }
+}
+
+/// The envelope generator ramps the volume up or down over 16 steps, and can
+/// then hold, repeat, or reverse direction depending on its shape.
+#[derive(Default)]
+struct Envelope {
+    period: u16,
+    counter: u32,
+    /// Bits: continue, attack, alternate, hold.
+    shape: u8,
+    step: u8,
+    attack: bool,
+    holding: bool,
+}
+
+impl Envelope {
+    /// Writing the shape also restarts the envelope.
+    fn set_shape(&mut self, value: u8) {
+        self.shape = value & 0x0F;
+        self.attack = self.shape & 0x04 > 0;
+        self.step = 0;
+        self.counter = 0;
+        self.holding = false;
+    }
+
+    fn clock(&mut self) {
+        self.counter += 1;
+        if self.counter < 2 * self.period.max(1) as u32 {
+            return;
+        }
+        self.counter = 0;
+        if self.holding {
+            return;
+        }
+
+        self.step += 1;
+        if self.step < 16 {
+            return;
+        }
+        let continue_ = self.shape & 0x08 > 0;
+        let alternate = self.shape & 0x02 > 0;
+        let hold = self.shape & 0x01 > 0;
+        if !continue_ {
+            // Fall silent after a single ramp.
+            self.holding = true;
+            self.attack = false;
+            self.step = 15;
+        } else if hold {
+            self.holding = true;
+            if alternate {
+                self.attack = !self.attack;
+            }
+            self.step = 15;
+        } else {
+            if alternate {
+                self.attack = !self.attack;
+            }
+            self.step = 0;
+        }
+    }
+
+    fn level(&self) -> u8 {
+        if self.attack {
+            self.step
+        } else {
+            15 - self.step
+        }
+    }
+}
+
+impl Savestate for Sunsoft5bAudio {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.selected);
+        for tone in &self.tones {
+            tone.save_state(w);
+        }
+        w.write(&self.mixer);
+        w.write(&self.noise_period);
+        w.write(&self.noise_counter);
+        w.write(&self.noise);
+        self.envelope.save_state(w);
+        w.write(&self.divider);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.selected = r.read()?;
+        for tone in &mut self.tones {
+            tone.load_state(r)?;
+        }
+        self.mixer = r.read()?;
+        self.noise_period = r.read()?;
+        self.noise_counter = r.read()?;
+        self.noise = r.read()?;
+        self.envelope.load_state(r)?;
+        self.divider = r.read()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Tone {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.period);
+        w.write(&self.counter);
+        w.write(&self.high);
+        w.write(&self.volume);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.period = r.read()?;
+        self.counter = r.read()?;
+        self.high = r.read()?;
+        self.volume = r.read()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Envelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.period);
+        w.write(&self.counter);
+        w.write(&self.shape);
+        w.write(&self.step);
+        w.write(&self.attack);
+        w.write(&self.holding);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.period = r.read()?;
+        self.counter = r.read()?;
+        self.shape = r.read()?;
+        self.step = r.read()?;
+        self.attack = r.read()?;
+        self.holding = r.read()?;
+        Ok(())
+    }
+}
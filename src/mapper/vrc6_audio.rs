@@ -0,0 +1,246 @@
+use anyhow::Result;
+
+use crate::apu::ExpansionAudio;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// Maximum combined output of the VRC6's channels: two 4-bit pulses and a
+/// 5-bit sawtooth.
+const MAX_OUTPUT: f32 = 15.0 + 15.0 + 31.0;
+
+/// Volume of the VRC6 at full scale relative to the APU. A VRC6 pulse channel
+/// is about as loud as an APU pulse channel at the same volume.
+const WEIGHT: f32 = 0.6;
+
+/// The VRC6's sound hardware: two pulse channels and a sawtooth channel.
+///
+/// The register addresses given here are for VRC6a (mapper 24). The mapper
+/// is responsible for undoing the address line swap used by VRC6b.
+#[derive(Default)]
+pub(super) struct Vrc6Audio {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    sawtooth: Sawtooth,
+    /// All channels are halted while set.
+    halt: bool,
+    /// Right shift applied to each channel's period, speeding them up by a
+    /// factor of 16 or 256.
+    period_shift: u8,
+}
+
+impl Vrc6Audio {
+    /// Handle a write to one of the audio registers ($9000-$9003,
+    /// $A000-$A002, $B000-$B002).
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+        match addr & 0xF003 {
+            0x9000..=0x9002 => self.pulse1.write(addr & 3, value),
+            0x9003 => {
+                self.halt = value & 0x01 > 0;
+                self.period_shift = if value & 0x04 > 0 {
+                    8
+                } else if value & 0x02 > 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0xA000..=0xA002 => self.pulse2.write(addr & 3, value),
+            0xB000..=0xB002 => self.sawtooth.write(addr & 3, value),
+            _ => {}
+        }
+    }
+}
+
+impl ExpansionAudio for Vrc6Audio {
+    fn tick(&mut self) {
+        if self.halt {
+            return;
+        }
+        self.pulse1.clock_timer(self.period_shift);
+        self.pulse2.clock_timer(self.period_shift);
+        self.sawtooth.clock_timer(self.period_shift);
+    }
+
+    fn output(&self) -> f32 {
+        let sum = self.pulse1.output() + self.pulse2.output() + self.sawtooth.output();
+        sum as f32 / MAX_OUTPUT
+    }
+
+    fn weight(&self) -> f32 {
+        WEIGHT
+    }
+}
+
+/// A VRC6 pulse channel. Unlike the APU's pulse channels, these have 8 duty
+/// cycle settings (from 1/16 to 8/16), plus a "digitized" mode which outputs
+/// the volume directly, allowing the channel to be used as a 4-bit DAC.
+#[derive(Default)]
+struct Pulse {
+    volume: u8,
+    duty: u8,
+    digitized: bool,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+}
+
+impl Pulse {
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            // MDDD VVVV (mode, duty, volume).
+            0 => {
+                self.digitized = value & 0x80 > 0;
+                self.duty = (value >> 4) & 0x07;
+                self.volume = value & 0x0F;
+            }
+            1 => self.period = (self.period & 0x0F00) | value as u16,
+            // E--- PPPP (enable, high 4 bits of period).
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.enabled = value & 0x80 > 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock_timer(&mut self, period_shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> period_shift;
+            self.step = self.step.checked_sub(1).unwrap_or(15);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.digitized || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+/// The VRC6 sawtooth channel, which repeatedly adds a rate value to an 8-bit
+/// accumulator and outputs its top 5 bits, resetting it every 7 additions.
+#[derive(Default)]
+struct Sawtooth {
+    rate: u8,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Sawtooth {
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            // --RR RRRR (accumulator rate).
+            0 => self.rate = value & 0x3F,
+            1 => self.period = (self.period & 0x0F00) | value as u16,
+            // E--- PPPP (enable, high 4 bits of period).
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.enabled = value & 0x80 > 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock_timer(&mut self, period_shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> period_shift;
+
+        // The rate is added on every other clock, and the accumulator is
+        // reset on the 14th.
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step.is_multiple_of(2) {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+impl Savestate for Vrc6Audio {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.pulse1.save_state(w);
+        self.pulse2.save_state(w);
+        self.sawtooth.save_state(w);
+        w.write(&self.halt);
+        w.write(&self.period_shift);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.pulse1.load_state(r)?;
+        self.pulse2.load_state(r)?;
+        self.sawtooth.load_state(r)?;
+        self.halt = r.read()?;
+        self.period_shift = r.read()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Pulse {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.volume);
+        w.write(&self.duty);
+        w.write(&self.digitized);
+        w.write(&self.enabled);
+        w.write(&self.period);
+        w.write(&self.timer);
+        w.write(&self.step);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.volume = r.read()?;
+        self.duty = r.read()?;
+        self.digitized = r.read()?;
+        self.enabled = r.read()?;
+        self.period = r.read()?;
+        self.timer = r.read()?;
+        self.step = r.read()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Sawtooth {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.rate);
+        w.write(&self.enabled);
+        w.write(&self.period);
+        w.write(&self.timer);
+        w.write(&self.step);
+        w.write(&self.accumulator);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.rate = r.read()?;
+        self.enabled = r.read()?;
+        self.period = r.read()?;
+        self.timer = r.read()?;
+        self.step = r.read()?;
+        self.accumulator = r.read()?;
+        Ok(())
+    }
+}
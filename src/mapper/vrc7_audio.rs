@@ -0,0 +1,591 @@
+use std::f32::consts::PI;
+
+use anyhow::{bail, ensure, Result};
+
+use crate::apu::ExpansionAudio;
+use crate::savestate::{Savestate, StateReader, StateValue, StateWriter};
+
+/// The VRC7's sound chip runs at 3.58 MHz and takes 72 clocks to produce each
+/// sample, which works out to one sample every 36 CPU cycles (about 49.7 kHz).
+const CYCLES_PER_SAMPLE: u32 = 36;
+const SAMPLE_RATE: f32 = 1_789_773.0 / CYCLES_PER_SAMPLE as f32;
+
+const NUM_CHANNELS: usize = 6;
+
+/// Volume of the VRC7 at full scale relative to the APU.
+const WEIGHT: f32 = 1.0;
+
+/// Phase is tracked as a fixed-point fraction of a cycle, using the same
+/// 19-bit precision as the frequency registers.
+const PHASE_BITS: u32 = 19;
+const PHASE_MASK: u32 = (1 << PHASE_BITS) - 1;
+
+/// Maximum envelope attenuation, in dB. The envelope generator is silent at
+/// this level.
+const MAX_ATTENUATION: f32 = 48.0;
+
+/// Frequency multipliers (times 2, to handle the 0.5 setting).
+static MULTIPLIERS: [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+
+/// Key scale level attenuation (in dB) for block 7, indexed by the top 4 bits
+/// of the frequency number. Lower blocks are attenuated 6 dB less per octave.
+#[rustfmt::skip]
+static KSL_TABLE: [f32; 16] = [
+    0.0, 18.0, 24.0, 27.75, 30.0, 32.25, 33.75, 35.25,
+    36.0, 37.5, 38.25, 39.0, 39.75, 40.5, 41.25, 42.0,
+];
+
+/// Scale factors for the key scale level table, for settings of 0, 1.5, 3,
+/// and 6 dB per octave.
+static KSL_SCALE: [f32; 4] = [0.0, 0.5, 1.0, 2.0];
+
+/// How far the modulator's feedback shifts its own phase, in cycles at full
+/// scale, for each feedback setting.
+static FEEDBACK: [f32; 8] = [
+    0.0,
+    1.0 / 64.0,
+    1.0 / 32.0,
+    1.0 / 16.0,
+    1.0 / 8.0,
+    1.0 / 4.0,
+    1.0 / 2.0,
+    1.0,
+];
+
+/// How far the modulator shifts the carrier's phase, in cycles at full scale.
+const MODULATION_DEPTH: f32 = 2.0;
+
+/// Tremolo (AM) and vibrato (VIB) settings, which are fixed on this chip.
+const AM_RATE_HZ: f32 = 3.7;
+const AM_DEPTH_DB: f32 = 4.8;
+const VIB_RATE_HZ: f32 = 6.4;
+const VIB_DEPTH_CENTS: f32 = 14.0;
+
+/// The VRC7's built-in instruments (1-15). Instrument 0 is the custom
+/// instrument defined by registers $00-$07.
+#[rustfmt::skip]
+static BUILTIN_PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27], // Buzzy bell
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12], // Guitar
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12], // Wurly
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27], // Flute
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28], // Clarinet
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4], // Synth
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07], // Trumpet
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17], // Organ
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01], // Bells
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02], // Vibes
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12], // Vibraphone
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16], // Tutti
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02], // Fretless
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6], // Synth bass
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06], // Sweep
+];
+
+/// The VRC7's sound hardware: a cut-down Yamaha YM2413 (OPLL) FM synthesizer
+/// with 6 two-operator channels and 15 built-in instruments.
+///
+/// Each channel consists of a modulator operator, whose output shifts the
+/// phase of a carrier operator, which produces the channel's output. Each
+/// operator is a sine wave generator with its own frequency multiplier and
+/// ADSR envelope.
+///
+/// Rather than reproducing the chip's log-sin and exponent lookup tables bit
+/// for bit, this implementation computes the waveforms and envelopes in
+/// floating point, with envelope timings taken from the OPL family's
+/// documented rates. This gets the character of the sound right without
+/// matching the hardware sample for sample.
+pub(super) struct Vrc7Audio {
+    /// Register selected via $9010 for the next write to $9030.
+    selected: u8,
+    custom_patch: [u8; 8],
+    channels: [FmChannel; NUM_CHANNELS],
+    am_phase: f32,
+    vib_phase: f32,
+    cycle: u32,
+    output: f32,
+}
+
+impl Vrc7Audio {
+    pub fn new() -> Self {
+        Self {
+            selected: 0,
+            custom_patch: [0; 8],
+            channels: Default::default(),
+            am_phase: 0.0,
+            vib_phase: 0.0,
+            cycle: 0,
+            output: 0.0,
+        }
+    }
+
+    /// $9010: Select the register to write.
+    pub fn select_register(&mut self, value: u8) {
+        self.selected = value;
+    }
+
+    /// $9030: Write to the selected register.
+    pub fn write_register(&mut self, value: u8) {
+        let reg = self.selected;
+        let channel = (reg & 0x0F) as usize;
+        match reg {
+            0x00..=0x07 => self.custom_patch[reg as usize] = value,
+            0x10..=0x15 => {
+                let ch = &mut self.channels[channel];
+                ch.fnum = (ch.fnum & 0x100) | value as u16;
+            }
+            0x20..=0x25 => {
+                let ch = &mut self.channels[channel];
+                ch.fnum = (ch.fnum & 0xFF) | ((value as u16 & 0x01) << 8);
+                ch.block = (value >> 1) & 0x07;
+                ch.sustain = value & 0x20 > 0;
+                ch.set_key(value & 0x10 > 0);
+            }
+            0x30..=0x35 => {
+                let ch = &mut self.channels[channel];
+                ch.instrument = value >> 4;
+                ch.volume = value & 0x0F;
+            }
+            _ => {}
+        }
+    }
+
+    /// Silence the chip and reset its state, as done by $E000 bit 6.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn patch(&self, instrument: u8) -> Patch {
+        match instrument {
+            0 => Patch::from_bytes(&self.custom_patch),
+            n => Patch::from_bytes(&BUILTIN_PATCHES[n as usize - 1]),
+        }
+    }
+
+    /// Generate the next sample.
+    fn generate(&mut self) -> f32 {
+        self.am_phase = (self.am_phase + AM_RATE_HZ / SAMPLE_RATE) % 1.0;
+        self.vib_phase = (self.vib_phase + VIB_RATE_HZ / SAMPLE_RATE) % 1.0;
+        let lfo = Lfo {
+            am: (1.0 - (2.0 * PI * self.am_phase).cos()) / 2.0 * AM_DEPTH_DB,
+            vib: 2f32.powf((2.0 * PI * self.vib_phase).sin() * VIB_DEPTH_CENTS / 1200.0),
+        };
+
+        let mut sum = 0.0;
+        for i in 0..NUM_CHANNELS {
+            let patch = self.patch(self.channels[i].instrument);
+            sum += self.channels[i].generate(&patch, &lfo);
+        }
+        sum / NUM_CHANNELS as f32
+    }
+}
+
+impl ExpansionAudio for Vrc7Audio {
+    fn tick(&mut self) {
+        self.cycle += 1;
+        if self.cycle == CYCLES_PER_SAMPLE {
+            self.cycle = 0;
+            self.output = self.generate();
+        }
+    }
+
+    fn output(&self) -> f32 {
+        self.output
+    }
+
+    fn weight(&self) -> f32 {
+        WEIGHT
+    }
+}
+
+/// Current output of the chip's low frequency oscillators.
+struct Lfo {
+    /// Tremolo attenuation, in dB.
+    am: f32,
+    /// Vibrato frequency multiplier.
+    vib: f32,
+}
+
+/// Instrument settings for one operator.
+#[derive(Copy, Clone)]
+struct OperatorPatch {
+    am: bool,
+    vib: bool,
+    /// If set, the envelope holds at the sustain level while the key is held.
+    /// Otherwise, it keeps decaying (at the release rate), like a struck or
+    /// plucked instrument.
+    sustained: bool,
+    ksr: bool,
+    multiplier: u8,
+    ksl: u8,
+    rectify: bool,
+    attack: u8,
+    decay: u8,
+    sustain_level: u8,
+    release: u8,
+}
+
+/// An instrument, as defined by 8 bytes of patch data.
+struct Patch {
+    modulator: OperatorPatch,
+    carrier: OperatorPatch,
+    /// Attenuation of the modulator, in 0.75 dB steps.
+    modulator_level: u8,
+    feedback: u8,
+}
+
+impl Patch {
+    fn from_bytes(bytes: &[u8; 8]) -> Self {
+        let operator = |i: usize, ksl: u8, rectify: bool| OperatorPatch {
+            am: bytes[i] & 0x80 > 0,
+            vib: bytes[i] & 0x40 > 0,
+            sustained: bytes[i] & 0x20 > 0,
+            ksr: bytes[i] & 0x10 > 0,
+            multiplier: bytes[i] & 0x0F,
+            ksl,
+            rectify,
+            attack: bytes[4 + i] >> 4,
+            decay: bytes[4 + i] & 0x0F,
+            sustain_level: bytes[6 + i] >> 4,
+            release: bytes[6 + i] & 0x0F,
+        };
+        Self {
+            modulator: operator(0, bytes[2] >> 6, bytes[3] & 0x08 > 0),
+            carrier: operator(1, bytes[3] >> 6, bytes[3] & 0x10 > 0),
+            modulator_level: bytes[2] & 0x3F,
+            feedback: bytes[3] & 0x07,
+        }
+    }
+}
+
+#[derive(Default)]
+struct FmChannel {
+    fnum: u16,
+    block: u8,
+    key_on: bool,
+    sustain: bool,
+    instrument: u8,
+    /// Attenuation of the carrier, in 3 dB steps.
+    volume: u8,
+    modulator: Operator,
+    carrier: Operator,
+    /// The modulator's last two outputs, for feedback.
+    feedback: [f32; 2],
+}
+
+impl FmChannel {
+    fn set_key(&mut self, on: bool) {
+        if on && !self.key_on {
+            self.modulator.key_on();
+            self.carrier.key_on();
+        } else if !on && self.key_on {
+            self.modulator.key_off();
+            self.carrier.key_off();
+        }
+        self.key_on = on;
+    }
+
+    fn generate(&mut self, patch: &Patch, lfo: &Lfo) -> f32 {
+        let ksl = {
+            let level = KSL_TABLE[(self.fnum >> 5) as usize] - 6.0 * (7 - self.block) as f32;
+            level.max(0.0)
+        };
+        // Used to speed up the envelopes of higher notes.
+        let key_scale = (self.block << 1) | (self.fnum >> 8) as u8;
+        let base_increment = (self.fnum as u32) << self.block;
+
+        let m = &patch.modulator;
+        self.modulator
+            .update(m, base_increment, key_scale, self.sustain, lfo);
+        let feedback =
+            (self.feedback[0] + self.feedback[1]) / 2.0 * FEEDBACK[patch.feedback as usize];
+        let level = patch.modulator_level as f32 * 0.75 + ksl * KSL_SCALE[m.ksl as usize];
+        let modulation = self.modulator.output(m, feedback, level, lfo);
+        self.feedback = [modulation, self.feedback[0]];
+
+        let c = &patch.carrier;
+        self.carrier
+            .update(c, base_increment, key_scale, self.sustain, lfo);
+        let level = self.volume as f32 * 3.0 + ksl * KSL_SCALE[c.ksl as usize];
+        self.carrier
+            .output(c, modulation * MODULATION_DEPTH, level, lfo)
+    }
+}
+
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    #[default]
+    Off,
+}
+
+struct Operator {
+    phase: u32,
+    stage: Stage,
+    /// Envelope attenuation, in dB.
+    attenuation: f32,
+}
+
+impl Default for Operator {
+    fn default() -> Self {
+        Self {
+            phase: 0,
+            stage: Stage::Off,
+            attenuation: MAX_ATTENUATION,
+        }
+    }
+}
+
+impl Operator {
+    fn key_on(&mut self) {
+        self.phase = 0;
+        self.stage = Stage::Attack;
+    }
+
+    fn key_off(&mut self) {
+        if self.stage != Stage::Off {
+            self.stage = Stage::Release;
+        }
+    }
+
+    /// Advance the phase and envelope generators by one sample.
+    fn update(
+        &mut self,
+        patch: &OperatorPatch,
+        base_increment: u32,
+        key_scale: u8,
+        channel_sustain: bool,
+        lfo: &Lfo,
+    ) {
+        let mut increment = (base_increment * MULTIPLIERS[patch.multiplier as usize]) as f32 / 2.0;
+        if patch.vib {
+            increment *= lfo.vib;
+        }
+        self.phase = (self.phase + increment as u32) & PHASE_MASK;
+
+        let rate = match self.stage {
+            Stage::Attack => patch.attack,
+            Stage::Decay => patch.decay,
+            Stage::Sustain if patch.sustained => 0,
+            Stage::Sustain => patch.release,
+            Stage::Release if channel_sustain => 5,
+            Stage::Release if patch.sustained => patch.release,
+            Stage::Release => 7,
+            Stage::Off => 0,
+        };
+        let rate = effective_rate(rate, key_scale, patch.ksr);
+
+        match self.stage {
+            Stage::Attack => {
+                self.attenuation -= attack_step(rate);
+                if self.attenuation <= 0.0 {
+                    self.attenuation = 0.0;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                let sustain_level = patch.sustain_level as f32 * 3.0;
+                self.attenuation += decay_step(rate);
+                if self.attenuation >= sustain_level {
+                    self.attenuation = sustain_level;
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain | Stage::Release => {
+                self.attenuation += decay_step(rate);
+                if self.attenuation >= MAX_ATTENUATION {
+                    self.attenuation = MAX_ATTENUATION;
+                    self.stage = Stage::Off;
+                }
+            }
+            Stage::Off => {}
+        }
+    }
+
+    /// Compute the operator's output, in the range [-1.0, 1.0], given a phase
+    /// offset (in cycles) and additional attenuation (in dB).
+    fn output(&self, patch: &OperatorPatch, phase_offset: f32, level: f32, lfo: &Lfo) -> f32 {
+        if self.stage == Stage::Off {
+            return 0.0;
+        }
+        let phase = self.phase as f32 / (1 << PHASE_BITS) as f32 + phase_offset;
+        let mut sample = (2.0 * PI * phase).sin();
+        if patch.rectify && sample < 0.0 {
+            sample = 0.0;
+        }
+        let mut attenuation = self.attenuation + level;
+        if patch.am {
+            attenuation += lfo.am;
+        }
+        sample * 10f32.powf(-attenuation / 20.0)
+    }
+}
+
+/// Combine a 4-bit envelope rate with the key scaling offset to get a rate in
+/// the range 0-63. A rate of 0 always means that the envelope doesn't move.
+fn effective_rate(rate: u8, key_scale: u8, ksr: bool) -> u8 {
+    if rate == 0 {
+        return 0;
+    }
+    let offset = if ksr { key_scale } else { key_scale >> 2 };
+    (rate * 4 + offset).min(63)
+}
+
+/// Duration of a rate-4 attack from silence to full volume, and of a rate-4
+/// decay across 96 dB, in milliseconds. Each increase of 4 in the rate halves
+/// these times.
+const ATTACK_TIME_MS: f32 = 2826.0;
+const DECAY_TIME_MS: f32 = 39280.0;
+
+/// Decrease in attenuation per sample during the attack phase.
+fn attack_step(rate: u8) -> f32 {
+    match rate {
+        0..=3 => 0.0,
+        60.. => MAX_ATTENUATION,
+        _ => {
+            let time_ms = ATTACK_TIME_MS / 2f32.powf((rate - 4) as f32 / 4.0);
+            MAX_ATTENUATION / (time_ms * SAMPLE_RATE / 1000.0)
+        }
+    }
+}
+
+/// Increase in attenuation per sample during the decay, sustain, and release
+/// phases.
+fn decay_step(rate: u8) -> f32 {
+    match rate {
+        0..=3 => 0.0,
+        _ => {
+            let time_ms = DECAY_TIME_MS / 2f32.powf((rate - 4) as f32 / 4.0);
+            96.0 / (time_ms * SAMPLE_RATE / 1000.0)
+        }
+    }
+}
+
+/// Patches aren't saved, since they're derived from the registers each time
+/// they're used.
+impl Savestate for Vrc7Audio {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.selected);
+        w.write(&self.custom_patch);
+        for channel in &self.channels {
+            channel.save_state(w);
+        }
+        w.write(&self.am_phase);
+        w.write(&self.vib_phase);
+        w.write(&self.cycle);
+        w.write(&self.output);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.selected = r.read()?;
+        self.custom_patch = r.read()?;
+        for channel in &mut self.channels {
+            channel.load_state(r)?;
+        }
+        self.am_phase = r.read()?;
+        self.vib_phase = r.read()?;
+        self.cycle = r.read()?;
+        self.output = r.read()?;
+        ensure!(self.cycle < CYCLES_PER_SAMPLE, "Invalid VRC7 audio state");
+        Ok(())
+    }
+}
+
+impl Savestate for FmChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.fnum);
+        w.write(&self.block);
+        w.write(&self.key_on);
+        w.write(&self.sustain);
+        w.write(&self.instrument);
+        w.write(&self.volume);
+        self.modulator.save_state(w);
+        self.carrier.save_state(w);
+        w.write(&self.feedback);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.fnum = r.read()?;
+        self.block = r.read()?;
+        self.key_on = r.read()?;
+        self.sustain = r.read()?;
+        self.instrument = r.read()?;
+        self.volume = r.read()?;
+        self.modulator.load_state(r)?;
+        self.carrier.load_state(r)?;
+        self.feedback = r.read()?;
+        ensure!(
+            self.fnum < 0x200 && self.block < 8 && self.instrument < 16,
+            "Invalid VRC7 channel state"
+        );
+        Ok(())
+    }
+}
+
+impl Savestate for Operator {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.phase);
+        w.write(&self.stage);
+        w.write(&self.attenuation);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.phase = r.read()?;
+        self.stage = r.read()?;
+        self.attenuation = r.read()?;
+        Ok(())
+    }
+}
+
+impl StateValue for Stage {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&(*self as u8));
+    }
+
+    fn read(r: &mut StateReader) -> Result<Self> {
+        Ok(match r.read::<u8>()? {
+            0 => Stage::Attack,
+            1 => Stage::Decay,
+            2 => Stage::Sustain,
+            3 => Stage::Release,
+            4 => Stage::Off,
+            n => bail!("Invalid VRC7 envelope stage in save state: {}", n),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_on() {
+        let mut audio = Vrc7Audio::new();
+        let mut write = |reg, value| {
+            audio.select_register(reg);
+            audio.write_register(value);
+        };
+        // Play A4 (fnum 288, block 4) on the flute at full volume.
+        write(0x30, 0x40);
+        write(0x10, 0x20);
+        write(0x20, 0x19);
+
+        let mut peak: f32 = 0.0;
+        for _ in 0..CYCLES_PER_SAMPLE * 2000 {
+            audio.tick();
+            peak = peak.max(audio.output().abs());
+        }
+        assert!(peak > 0.01);
+
+        // Releasing the key should eventually silence the channel.
+        audio.select_register(0x20);
+        audio.write_register(0x08);
+        for _ in 0..CYCLES_PER_SAMPLE * 200_000 {
+            audio.tick();
+        }
+        assert_eq!(audio.output(), 0.0);
+    }
+}
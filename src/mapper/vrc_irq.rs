@@ -0,0 +1,126 @@
+use anyhow::Result;
+
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// The prescaler is decremented by 3 every CPU cycle and reloaded with 341
+/// when it runs out, approximating the length of a scanline in PPU dots.
+const PRESCALER_PERIOD: i16 = 341;
+
+/// IRQ counter shared by Konami's VRC4, VRC6, and VRC7.
+///
+/// Unlike the MMC3, the VRC counters don't watch the PPU at all. Instead, they
+/// count CPU cycles, either directly ("cycle mode") or via a prescaler that
+/// divides the CPU clock down to roughly one tick per scanline ("scanline
+/// mode"). The 8-bit counter counts up, and triggers an IRQ (and reloads from
+/// the latch) when it overflows.
+#[derive(Default)]
+pub(super) struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl VrcIrq {
+    /// Set the value that the counter is reloaded with.
+    pub fn write_latch(&mut self, value: u8) {
+        self.latch = value;
+    }
+
+    /// Set the counter's control bits: ---- -MEA (mode, enable, enable after
+    /// acknowledgement). Enabling the counter reloads it from the latch.
+    pub fn write_control(&mut self, value: u8) {
+        self.enable_after_ack = value & 0x01 > 0;
+        self.enabled = value & 0x02 > 0;
+        self.cycle_mode = value & 0x04 > 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = PRESCALER_PERIOD;
+        }
+    }
+
+    /// Acknowledge a pending interrupt.
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Clock the counter. Called once per CPU cycle.
+    pub fn tick(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.cycle_mode {
+            self.clock_counter();
+        } else {
+            self.prescaler -= 3;
+            if self.prescaler <= 0 {
+                self.prescaler += PRESCALER_PERIOD;
+                self.clock_counter();
+            }
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+}
+
+impl Savestate for VrcIrq {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.latch);
+        w.write(&self.counter);
+        w.write(&self.prescaler);
+        w.write(&self.enabled);
+        w.write(&self.enable_after_ack);
+        w.write(&self.cycle_mode);
+        w.write(&self.pending);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.latch = r.read()?;
+        self.counter = r.read()?;
+        self.prescaler = r.read()?;
+        self.enabled = r.read()?;
+        self.enable_after_ack = r.read()?;
+        self.cycle_mode = r.read()?;
+        self.pending = r.read()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cycle_mode() {
+        let mut irq = VrcIrq::default();
+        irq.write_latch(0xFD);
+        irq.write_control(0x07);
+
+        // The counter overflows after counting up from $FD to $FF and then
+        // wrapping, which takes 3 cycles.
+        irq.tick();
+        irq.tick();
+        assert!(!irq.pending());
+        irq.tick();
+        assert!(irq.pending());
+
+        irq.acknowledge();
+        assert!(!irq.pending());
+    }
+}
@@ -26,7 +26,7 @@ impl Address {
     }
 
     /// Get the raw little-endian bytes of this address.
-    pub fn to_le_bytes(&self) -> [u8; 2] {
+    pub fn to_le_bytes(self) -> [u8; 2] {
         self.0.to_le_bytes()
     }
 }
@@ -80,7 +80,7 @@ impl FromStr for Address {
             _ => bail!("Address is longer than 16 bits: {:?}", s),
         };
 
-        let addr = <[u8; 2]>::from_hex(hex.as_ref())
+        let addr = <[u8; 2]>::from_hex(&*hex)
             .map(u16::from_be_bytes)
             .with_context(|| anyhow!("Invalid hex address: {:?}", s))?;
 
@@ -187,7 +187,6 @@ impl Add<i16> for Address {
     type Output = Self;
 
     fn add(self, other: i16) -> Self {
-        let other = other as i16;
         if other < 0 {
             Self(self.0.wrapping_sub(-other as u16))
         } else {
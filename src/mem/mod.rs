@@ -2,8 +2,14 @@ pub use address::Address;
 
 mod address;
 
+use anyhow::Result;
+
+use crate::apu::Apu;
+use crate::controller::{Controllers, PORT_BITS};
 use crate::io::IoRegister;
-use crate::ppu::{Ppu, PpuBus};
+use crate::mapper::{open_bus, Cartridge};
+use crate::ppu::{Ppu, PpuScreen};
+use crate::savestate::{Savestate, StateReader, StateWriter};
 
 const RAM_SIZE: usize = 2048;
 const RAM_ADDR_BITS: u8 = 11;
@@ -24,14 +30,14 @@ pub trait Bus {
     fn store(&mut self, addr: Address, value: u8);
 
     fn load_range(&mut self, start: Address, output: &mut [u8]) {
-        for i in 0..output.len() {
-            output[i] = self.load(start + i);
+        for (i, byte) in output.iter_mut().enumerate() {
+            *byte = self.load(start + i);
         }
     }
 
     fn store_range(&mut self, start: Address, input: &[u8]) {
-        for i in 0..input.len() {
-            self.store(start + i, input[i]);
+        for (i, &byte) in input.iter().enumerate() {
+            self.store(start + i, byte);
         }
     }
 }
@@ -51,17 +57,24 @@ impl Bus for [u8; 0x10000] {
 /// out-of-range addresses are aliased by dropping the high order bits. This
 /// causes the contents of RAM to be mirrored throughout its portion of the
 /// address space, which is how the NES hardware behaves in practice.
+#[derive(Clone)]
 pub struct Ram([u8; RAM_SIZE]);
 
 impl Ram {
     pub fn new() -> Self {
         Ram([0; RAM_SIZE])
     }
+
+    /// Read a byte without needing mutable access, for debuggers. (Loading
+    /// from RAM has no side effects either way.)
+    pub fn peek(&self, addr: Address) -> u8 {
+        self.0[addr.alias(RAM_ADDR_BITS).as_usize()]
+    }
 }
 
 impl Bus for Ram {
     fn load(&mut self, addr: Address) -> u8 {
-        self.0[addr.alias(RAM_ADDR_BITS).as_usize()]
+        self.peek(addr)
     }
 
     fn store(&mut self, addr: Address, value: u8) {
@@ -87,15 +100,29 @@ impl Bus for Ram {
 /// components to pass to the CPU. The details of the actual memory mapping
 /// are abstracted away; from the CPU's perspective, this is just one big
 /// address space.
-pub struct Memory<'a, M, P> {
+pub struct Memory<'a, C> {
     ram: &'a mut Ram,
-    ppu: &'a mut Ppu<P>,
-    mapper: &'a mut M,
+    ppu: &'a mut Ppu,
+    apu: &'a mut Apu,
+    controllers: &'a mut Controllers,
+    cart: &'a mut C,
 }
 
-impl<'a, M: Bus, P: PpuBus> Memory<'a, M, P> {
-    pub fn new(ram: &'a mut Ram, ppu: &'a mut Ppu<P>, mapper: &'a mut M) -> Self {
-        Self { ram, ppu, mapper }
+impl<'a, C: Cartridge> Memory<'a, C> {
+    pub fn new(
+        ram: &'a mut Ram,
+        ppu: &'a mut Ppu,
+        apu: &'a mut Apu,
+        controllers: &'a mut Controllers,
+        cart: &'a mut C,
+    ) -> Self {
+        Self {
+            ram,
+            ppu,
+            apu,
+            controllers,
+            cart,
+        }
     }
 
     pub fn read_io_register(&mut self, addr: Address) -> u8 {
@@ -122,9 +149,17 @@ impl<'a, M: Bus, P: PpuBus> Memory<'a, M, P> {
             DmcStart => 0,
             DmcLen => 0,
             OamDma => 0,
-            SndChn => 0,
-            Joy1 => 0,
-            Joy2 => 0,
+            SndChn => self.apu.read_status(),
+            Joy1 => {
+                let mut screen = PpuScreen::new(self.ppu, self.cart);
+                let value = self.controllers.read_port1(&mut screen);
+                value & PORT_BITS | open_bus(addr) & !PORT_BITS
+            }
+            Joy2 => {
+                let mut screen = PpuScreen::new(self.ppu, self.cart);
+                let value = self.controllers.read_port2(&mut screen);
+                value & PORT_BITS | open_bus(addr) & !PORT_BITS
+            }
         };
         log::debug!("Read from IO register {} ({}): {:#X}", reg, addr, value);
 
@@ -138,24 +173,11 @@ impl<'a, M: Bus, P: PpuBus> Memory<'a, M, P> {
 
         use IoRegister::*;
         match reg {
-            Sq1Vol => {}
-            Sq1Sweep => {}
-            Sq1Lo => {}
-            Sq1Hi => {}
-            Sq2Vol => {}
-            Sq2Sweep => {}
-            Sq2Lo => {}
-            Sq2Hi => {}
-            TriLinear => {}
-            TriLo => {}
-            TriHi => {}
-            NoiseVol => {}
-            NoiseLo => {}
-            NoiseHi => {}
-            DmcFreq => {}
-            DmcRaw => {}
-            DmcStart => {}
-            DmcLen => {}
+            Sq1Vol | Sq1Sweep | Sq1Lo | Sq1Hi => self.apu.write_register(reg, value),
+            Sq2Vol | Sq2Sweep | Sq2Lo | Sq2Hi => self.apu.write_register(reg, value),
+            TriLinear | TriLo | TriHi => self.apu.write_register(reg, value),
+            NoiseVol | NoiseLo | NoiseHi => self.apu.write_register(reg, value),
+            DmcFreq | DmcRaw | DmcStart | DmcLen => self.apu.write_register(reg, value),
             OamDma => {
                 let mut oam_data = [0u8; 256];
                 let start = Address::from([0, value]);
@@ -164,26 +186,27 @@ impl<'a, M: Bus, P: PpuBus> Memory<'a, M, P> {
                 dbg!(&oam_data);
                 self.ppu.oam_dma(oam_data);
             }
-            SndChn => {}
-            Joy1 => {}
-            Joy2 => {}
+            SndChn => self.apu.write_register(reg, value),
+            Joy1 => self.controllers.write(value),
+            // Writes to $4017 control the APU's frame counter.
+            Joy2 => self.apu.write_register(reg, value),
         };
     }
 }
 
-impl<'a, M: Bus, P: PpuBus> Bus for Memory<'a, M, P> {
+impl<'a, C: Cartridge> Bus for Memory<'a, C> {
     fn load(&mut self, addr: Address) -> u8 {
         if addr < PPU_REG_START {
             // Read from system RAM.
             self.ram.load(addr)
         } else if addr < IO_REG_START {
             // Read from a memory-mapped PPU register.
-            self.ppu.load(addr)
+            self.ppu.read_register(self.cart, addr)
         } else if addr < CART_SPACE_START {
             self.read_io_register(addr)
         } else {
             // Read from the cartridge (via the mapper).
-            self.mapper.load(addr)
+            self.cart.load(addr)
         }
     }
 
@@ -193,12 +216,23 @@ impl<'a, M: Bus, P: PpuBus> Bus for Memory<'a, M, P> {
             self.ram.store(addr, value);
         } else if addr < IO_REG_START {
             // Write to a memory-mapped PPU register.
-            self.ppu.store(addr, value);
+            self.ppu.write_register(self.cart, addr, value);
         } else if addr < CART_SPACE_START {
             self.write_io_register(addr, value);
         } else {
             // Write to the cartidge memory (via the mapper).
-            self.mapper.store(addr, value);
+            self.cart.store(addr, value);
         }
     }
 }
+
+impl Savestate for Ram {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.0);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.0 = r.read()?;
+        Ok(())
+    }
+}
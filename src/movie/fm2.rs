@@ -0,0 +1,226 @@
+//! FCEUX's FM2 movie format, so that existing TASes can be played back here
+//! and movies recorded here can be shared.
+//!
+//! An FM2 file is a header of `key value` lines, followed by one line per
+//! frame of the form `|commands|port0|port1|port2|`. The commands field is a
+//! bit mask (1 for a soft reset, 2 for a power cycle), and each gamepad is
+//! written as `RLDUTSBA` with `.` (or a space) for buttons that aren't held.
+//! With a Four Score, there are four gamepad fields instead of two.
+//!
+//! FCEUX starts each frame at the beginning of vertical blank, so the game's
+//! NMI handler (which is where most games read the controllers) runs at the
+//! start of the frame. We deliver the NMI at the end of each frame instead,
+//! so the input that FCEUX gives on frame N+1 is what our frame N's NMI
+//! needs to see. Movies are therefore shifted one frame earlier on import,
+//! and one frame later on export.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use super::{format_buttons, Movie, MovieFrame, MovieRom};
+use crate::controller::Buttons;
+
+const COMMAND_RESET: u8 = 1;
+const COMMAND_POWER: u8 = 2;
+
+/// Port types in the `port0` and `port1` header fields.
+const PORT_NONE: &str = "0";
+const PORT_GAMEPAD: &str = "1";
+
+/// Version of FCEUX to claim that movies were recorded with. FCEUX uses this
+/// to pick compatibility settings, so it's the latest version whose
+/// conventions we follow.
+const EMU_VERSION: u32 = 22020;
+
+fn parse_gamepad(s: &str) -> Result<Buttons> {
+    ensure!(s.len() == 8, "Expected 8 buttons, got {:?}", s);
+    // Anything other than a space or a period means the button is held,
+    // regardless of which letter is used.
+    let bits = s
+        .bytes()
+        .enumerate()
+        .filter(|&(_, c)| c != b' ' && c != b'.')
+        .fold(0, |bits, (i, _)| bits | 0x80 >> i);
+    Ok(Buttons::from_bits_retain(bits))
+}
+
+pub fn parse(contents: &str) -> Result<Movie> {
+    let mut header = HashMap::new();
+    let mut records = Vec::new();
+    for line in contents.lines() {
+        if line.starts_with('|') {
+            records.push(line);
+        } else if let Some((key, value)) = line.split_once(' ') {
+            header.insert(key, value.trim());
+        }
+    }
+
+    ensure!(
+        header.get("version") == Some(&"3"),
+        "Unsupported FM2 version: {:?}",
+        header.get("version")
+    );
+    if header.get("binary").is_some_and(|&binary| binary != "0") {
+        bail!("Binary FM2 input logs are not supported");
+    }
+    let four_score = header.get("fourscore") == Some(&"1");
+    if !four_score {
+        for key in ["port0", "port1"] {
+            let port = header.get(key).copied().unwrap_or(PORT_GAMEPAD);
+            if port != PORT_GAMEPAD && port != PORT_NONE {
+                bail!("Unsupported device in {}: {}", key, port);
+            }
+        }
+    }
+
+    let rom = match (header.get("romFilename"), header.get("romChecksum")) {
+        (Some(name), Some(checksum)) => {
+            let md5 = checksum
+                .strip_prefix("base64:")
+                .and_then(|checksum| BASE64.decode(checksum).ok())
+                .and_then(|md5| md5.try_into().ok())
+                .ok_or_else(|| anyhow!("Invalid ROM checksum: {:?}", checksum))?;
+            Some(MovieRom {
+                name: name.to_string(),
+                md5,
+            })
+        }
+        _ => None,
+    };
+
+    let players = if four_score { 4 } else { 2 };
+    let mut movie = Movie {
+        players,
+        rom,
+        pal: header.get("palFlag") == Some(&"1"),
+        ..Default::default()
+    };
+    for (i, record) in records.iter().enumerate() {
+        let fields: Vec<&str> = record.split('|').collect();
+        // The record starts and ends with a separator, so the first field is
+        // empty.
+        ensure!(
+            fields.len() >= players + 2,
+            "Too few fields on frame {}: {:?}",
+            i,
+            record
+        );
+        let commands: u8 = fields[1]
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid commands on frame {}", i))?;
+        if commands & COMMAND_POWER > 0 && i > 0 {
+            bail!("Power cycling on frame {} is not supported", i);
+        }
+        if commands & !(COMMAND_RESET | COMMAND_POWER) > 0 {
+            log::warn!("Ignoring unsupported commands on frame {}: {}", i, commands);
+        }
+
+        let mut frame = MovieFrame {
+            reset: commands & COMMAND_RESET > 0,
+            ..Default::default()
+        };
+        for (buttons, field) in frame.buttons.iter_mut().zip(&fields[2..2 + players]) {
+            // Ports without a gamepad have empty fields.
+            if !field.is_empty() {
+                *buttons = parse_gamepad(field)
+                    .with_context(|| format!("Invalid input on frame {}", i))?;
+            }
+        }
+        movie.frames.push(frame);
+    }
+    // See the module documentation for why the first frame is dropped.
+    if !movie.frames.is_empty() {
+        movie.frames.remove(0);
+    }
+    Ok(movie)
+}
+
+pub fn write(movie: &Movie) -> String {
+    let four_score = movie.players > 2;
+    let (name, md5) = match &movie.rom {
+        Some(rom) => (rom.name.as_str(), rom.md5),
+        None => ("", [0; 16]),
+    };
+    let mut out = format!(
+        "version 3\n\
+         emuVersion {}\n\
+         rerecordCount 0\n\
+         palFlag {}\n\
+         romFilename {}\n\
+         romChecksum base64:{}\n\
+         guid 00000000-0000-0000-0000-000000000000\n\
+         fourscore {}\n\
+         microphone 0\n\
+         port0 {}\n\
+         port1 {}\n\
+         port2 0\n\
+         FDS 0\n\
+         NewPPU 0\n",
+        EMU_VERSION,
+        movie.pal as u8,
+        name,
+        BASE64.encode(md5),
+        four_score as u8,
+        if four_score { PORT_NONE } else { PORT_GAMEPAD },
+        if four_score { PORT_NONE } else { PORT_GAMEPAD },
+    );
+
+    // See the module documentation for why an empty frame is added.
+    let players = if four_score { 4 } else { 2 };
+    for frame in std::iter::once(&MovieFrame::default()).chain(&movie.frames) {
+        let commands = if frame.reset { COMMAND_RESET } else { 0 };
+        out.push_str(&format!("|{}|", commands));
+        for &buttons in &frame.buttons[..players] {
+            out.push_str(&format_buttons(buttons));
+            out.push('|');
+        }
+        // The expansion port, which is unused.
+        out.push_str("|\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fm2() {
+        let contents = "version 3\n\
+                        emuVersion 22020\n\
+                        romFilename Super Mario Bros.\n\
+                        romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==\n\
+                        port0 1\n\
+                        port1 0\n\
+                        |2|........|||\n\
+                        |0|....T...|||\n\
+                        |0|R  U   A|||\n\
+                        |1|........|||\n";
+        let movie = parse(contents).unwrap();
+        assert_eq!(movie.rom.as_ref().unwrap().name, "Super Mario Bros.");
+        assert_eq!(movie.frames.len(), 3);
+        assert_eq!(movie.frames[0].buttons[0], Buttons::START);
+        assert_eq!(
+            movie.frames[1].buttons[0],
+            Buttons::RIGHT | Buttons::UP | Buttons::A
+        );
+        assert!(movie.frames[2].reset);
+
+        let written = write(&movie);
+        assert!(written.contains("romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==\n"));
+        assert!(written.ends_with(concat!(
+            "|0|........|........||\n",
+            "|0|....T...|........||\n",
+            "|0|R..U...A|........||\n",
+            "|1|........|........||\n",
+        )));
+        assert_eq!(parse(&written).unwrap().frames, movie.frames);
+
+        assert!(parse("version 3\n|4|........|||\n|2|........|||\n").is_err());
+    }
+}
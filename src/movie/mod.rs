@@ -0,0 +1,298 @@
+//! Movies: recordings of the controller input given on each frame.
+//!
+//! Since the emulator is deterministic, playing a movie back from power on
+//! reproduces the original run exactly, which makes movies useful as
+//! regression tests for entire games.
+//!
+//! Movies are text files with a header line, followed by one line per frame
+//! listing the buttons held on each controller. Each controller is written as
+//! 8 characters in the order `RLDUTSBA` (Right, Left, Down, Up, sTart,
+//! Select, B, A), with `.` for buttons that aren't held, e.g.:
+//!
+//! ```text
+//! nes-movie 1
+//! ........ ........
+//! ...U...A ........
+//! reset ........ ........
+//! ```
+//!
+//! A line starting with `reset` presses the console's reset button before
+//! the frame. Movies in FCEUX's FM2 format (see the `fm2` module) are also
+//! supported, and are recognized by their `.fm2` extension.
+//!
+//! Only standard controllers (including those on a Four Score) are recorded;
+//! the Zapper, Power Pad and Famicom microphone always use live input.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context, Result};
+use clap::ValueEnum;
+
+use crate::controller::Buttons;
+use crate::rom::Rom;
+
+mod fm2;
+
+const HEADER: &str = "nes-movie 1";
+
+/// Characters for each button, from the highest bit (Right) to the lowest (A).
+const BUTTON_CHARS: &[u8; 8] = b"RLDUTSBA";
+
+/// The input for a single frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MovieFrame {
+    /// The buttons held by each player (1 through 4).
+    pub buttons: [Buttons; 4],
+    /// Whether the console is reset before the frame.
+    pub reset: bool,
+}
+
+/// The ROM that a movie was recorded with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MovieRom {
+    /// The ROM's file name, without its extension.
+    pub name: String,
+    /// MD5 of the PRG and CHR ROM, as computed by FCEUX.
+    pub md5: [u8; 16],
+}
+
+impl MovieRom {
+    pub fn new(path: &Path, rom: &Rom) -> Self {
+        let mut data = rom.prg.clone();
+        if !rom.header.has_chr_ram() {
+            data.extend_from_slice(&rom.chr);
+        }
+        Self {
+            name: path
+                .file_stem()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            md5: md5::compute(data).0,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Movie {
+    /// Number of controllers recorded on each line.
+    pub players: usize,
+    pub frames: Vec<MovieFrame>,
+    /// The ROM the movie was recorded with, if known. (This is only stored
+    /// in FM2 files.)
+    pub rom: Option<MovieRom>,
+    /// Whether the movie was recorded with PAL timing. (This is only stored
+    /// in FM2 files.)
+    pub pal: bool,
+}
+
+impl Movie {
+    pub fn new(players: usize) -> Self {
+        Self {
+            players,
+            ..Default::default()
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        log::info!("Loading movie: {:?}", path);
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let movie = if is_fm2(path) {
+            fm2::parse(&contents)
+        } else {
+            parse(&contents)
+        };
+        movie.with_context(|| format!("Failed to parse {:?}", path))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = if is_fm2(path) {
+            fm2::write(self)
+        } else {
+            self.to_string()
+        };
+        fs::write(path, contents).with_context(|| format!("Failed to write {:?}", path))?;
+        log::info!("Saved {} frame movie: {:?}", self.frames.len(), path);
+        Ok(())
+    }
+
+    /// Hash of the input for the first `frames` frames, which identifies a
+    /// point in the movie. Save states made while recording or playing a
+    /// movie store this, so that they can only be loaded into the same movie.
+    pub fn hash(&self, frames: usize) -> [u8; 16] {
+        let mut context = md5::Context::new();
+        for frame in &self.frames[..frames] {
+            context.consume(frame.buttons.map(|buttons| buttons.bits()));
+            context.consume([frame.reset as u8]);
+        }
+        context.compute().0
+    }
+
+    /// Warn if the movie was recorded with a different ROM, since it will
+    /// almost certainly desync.
+    pub fn check_rom(&self, rom: &MovieRom) {
+        match &self.rom {
+            Some(expected) if expected.md5 != rom.md5 => log::warn!(
+                "Movie was recorded with a different ROM ({:?}); playback will probably desync",
+                expected.name
+            ),
+            _ => {}
+        }
+    }
+}
+
+fn is_fm2(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("fm2"))
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        for frame in &self.frames {
+            let mut fields: Vec<String> = frame.buttons[..self.players]
+                .iter()
+                .map(|&buttons| format_buttons(buttons))
+                .collect();
+            if frame.reset {
+                fields.insert(0, "reset".to_string());
+            }
+            writeln!(f, "{}", fields.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+fn format_buttons(buttons: Buttons) -> String {
+    BUTTON_CHARS
+        .iter()
+        .enumerate()
+        .map(|(i, &c)| {
+            if buttons.bits() & (0x80 >> i) > 0 {
+                c as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+fn parse_buttons(s: &str) -> Result<Buttons> {
+    ensure!(s.len() == 8, "Expected 8 buttons, got {:?}", s);
+    let mut bits = 0;
+    for (i, (c, &expected)) in s.bytes().zip(BUTTON_CHARS).enumerate() {
+        match c {
+            b'.' => {}
+            c if c == expected => bits |= 0x80 >> i,
+            _ => bail!("Invalid button {:?} in {:?}", c as char, s),
+        }
+    }
+    Ok(Buttons::from_bits_retain(bits))
+}
+
+fn parse(contents: &str) -> Result<Movie> {
+    let mut lines = contents.lines();
+    if lines.next() != Some(HEADER) {
+        bail!("Not a movie file (expected {:?} header)", HEADER);
+    }
+    let mut movie = Movie::new(0);
+    for (i, line) in lines.enumerate() {
+        let mut frame = MovieFrame::default();
+        let mut controllers: Vec<&str> = line.split_whitespace().collect();
+        if controllers.first() == Some(&"reset") {
+            frame.reset = true;
+            controllers.remove(0);
+        }
+        ensure!(
+            controllers.len() <= frame.buttons.len(),
+            "Too many controllers on frame {}",
+            i
+        );
+        for (buttons, s) in frame.buttons.iter_mut().zip(&controllers) {
+            *buttons = parse_buttons(s).with_context(|| format!("Invalid input on frame {}", i))?;
+        }
+        movie.players = movie.players.max(controllers.len());
+        movie.frames.push(frame);
+    }
+    Ok(movie)
+}
+
+/// What to do once a movie has finished playing.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum MovieEnd {
+    /// Stop the emulator.
+    #[default]
+    Stop,
+    /// Keep running, with live input.
+    Continue,
+}
+
+/// A movie being played back.
+pub struct Playback {
+    movie: Movie,
+    frame: usize,
+    pub end: MovieEnd,
+}
+
+impl Playback {
+    pub fn new(movie: Movie, end: MovieEnd) -> Self {
+        Self {
+            movie,
+            frame: 0,
+            end,
+        }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    /// Number of frames played so far.
+    pub fn position(&self) -> usize {
+        self.frame
+    }
+
+    /// Continue playing from the given frame, e.g. after loading a save
+    /// state.
+    pub fn seek(&mut self, frame: usize) {
+        self.frame = frame;
+    }
+
+    /// Get the input for the next frame, or `None` if the movie has ended.
+    pub fn next_frame(&mut self) -> Option<MovieFrame> {
+        let frame = self.movie.frames.get(self.frame)?;
+        self.frame += 1;
+        Some(*frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut movie = Movie::new(2);
+        movie.frames.push(MovieFrame::default());
+        movie.frames.push(MovieFrame {
+            buttons: [
+                Buttons::UP | Buttons::A,
+                Buttons::RIGHT | Buttons::START,
+                Buttons::empty(),
+                Buttons::empty(),
+            ],
+            reset: true,
+        });
+        let text = movie.to_string();
+        assert_eq!(
+            text,
+            "nes-movie 1\n........ ........\nreset ...U...A R...T...\n"
+        );
+        assert_eq!(parse(&text).unwrap(), movie);
+
+        assert!(parse("nes-movie 1\n...U...X\n").is_err());
+        assert!(parse("........\n").is_err());
+    }
+}
@@ -1,80 +1,1227 @@
-use std::time::Duration;
+use std::cell::Cell;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
+use anyhow::{bail, ensure, Context, Result};
+use winit::event::VirtualKeyCode;
 use winit_input_helper::WinitInputHelper;
 
+use crate::apu::{Apu, ApuSnapshot, Channel};
+use crate::apu_view::{ApuView, VIEW_HEIGHT, VIEW_WIDTH};
+use crate::audio::{AudioOptions, AudioOutput, AudioRecorder, AudioSink, NullSink, SpeedAdapter};
+use crate::clip::ClipBuffer;
+use crate::colors::{self, Colors};
+use crate::config::Config;
+use crate::controller::{Buttons, Controllers, DeviceKind, FourScore, InputSource, PortInput};
 use crate::cpu::Cpu;
-use crate::mapper::{self, CpuMapper, PpuMapper};
-use crate::mem::{Address, Memory, Ram};
-use crate::ppu::{Ppu, FRAME_HEIGHT, FRAME_WIDTH};
-use crate::rom::Rom;
+use crate::debugger::{Command, Console, Debugger};
+use crate::font;
+use crate::hotkeys::{Hotkey, Hotkeys};
+use crate::input::InputMap;
+use crate::input_overlay;
+use crate::mapper::{self, Cart, CpuBus};
+use crate::mem::{Address, Bus, Memory, Ram};
+use crate::movie::{Movie, MovieEnd, MovieFrame, Playback};
+use crate::osd::Osd;
+use crate::ppu::{self, Ppu, FRAME_HEIGHT, FRAME_WIDTH, GREYSCALE_PALETTE};
+use crate::region::Region;
+use crate::rom::{ConsoleType, Rom};
+use crate::save::SaveFile;
+use crate::savestate::{
+    self, Savestate, SectionId, StateBuilder, StateLocation, StateReader, StateSections,
+    StateSlots, StateWriter, NUM_SLOTS,
+};
+use crate::screenshot::Screenshots;
+use crate::stats::Stats;
 use crate::ui::Ui;
+use crate::viewer::Viewer;
+use crate::watch::RomWatch;
 
-const CPU_CYCLES_PER_FRAME: usize = 29781;
+/// How often to write battery-backed RAM to disk while the game is running,
+/// so that a crash doesn't lose much progress.
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How much of each host frame may be spent emulating frames while
+/// fast-forwarding without a speed limit, leaving time to present the last
+/// one.
+const FAST_FORWARD_BUDGET: Duration = Duration::from_millis(12);
+
+/// Speeds that the slow-motion hotkey cycles through.
+const SLOW_MOTION_SPEEDS: [f64; 3] = [1.0, 0.5, 0.25];
+
+/// Save state sections for each component (see the `savestate` module).
+const CONSOLE_SECTION: SectionId = SectionId {
+    tag: *b"NES ",
+    version: 1,
+};
+const CPU_SECTION: SectionId = SectionId {
+    tag: *b"CPU ",
+    version: 1,
+};
+const RAM_SECTION: SectionId = SectionId {
+    tag: *b"RAM ",
+    version: 1,
+};
+const PPU_SECTION: SectionId = SectionId {
+    tag: *b"PPU ",
+    version: 1,
+};
+const APU_SECTION: SectionId = SectionId {
+    tag: *b"APU ",
+    version: 1,
+};
+const CONTROLLERS_SECTION: SectionId = SectionId {
+    tag: *b"CTRL",
+    version: 1,
+};
+const CART_SECTION: SectionId = SectionId {
+    tag: *b"CART",
+    version: 2,
+};
+/// How many CPU cycles into the frame the state was saved, if it was saved
+/// while stopped partway through one in the debugger.
+const FRAME_SECTION: SectionId = SectionId {
+    tag: *b"FRAM",
+    version: 1,
+};
+/// Where the state was saved in the movie being recorded or played, if any:
+/// the number of frames so far, and the hash of their input.
+const MOVIE_SECTION: SectionId = SectionId {
+    tag: *b"MOVI",
+    version: 1,
+};
+
+/// The latest snapshot of the host's input, which the controllers poll when
+/// the game strobes them.
+struct LiveInput(Rc<Cell<[PortInput; 2]>>);
+
+impl InputSource for LiveInput {
+    fn poll(&mut self) -> [PortInput; 2] {
+        self.0.get()
+    }
+}
+
+/// A copy of the console's state, from `Nes::snapshot`.
+///
+/// Most of the console is cloned, but the controllers and the cartridge are
+/// trait objects, which can't be, so their state is serialized the same way
+/// as in a save state. (Cloning the cartridge would also copy its ROM, which
+/// is much bigger than its state.) A snapshot therefore isn't free of
+/// serialization: it costs an allocation and a pass over the cartridge's
+/// registers and RAM, though that's still small next to emulating a frame.
+#[derive(Clone)]
+pub struct Snapshot {
+    cpu: Cpu,
+    ram: Ram,
+    ppu: Ppu,
+    apu: ApuSnapshot,
+    frame_count: u64,
+    frame_cycle: usize,
+    devices: Vec<u8>,
+}
+
+/// A movie that's being recorded or played.
+enum MovieState<'a> {
+    Recording(&'a Movie),
+    Playing(&'a Playback),
+}
+
+impl<'a> MovieState<'a> {
+    fn movie(&self) -> &'a Movie {
+        match self {
+            MovieState::Recording(movie) => movie,
+            MovieState::Playing(playback) => playback.movie(),
+        }
+    }
+
+    /// Number of frames recorded or played so far.
+    fn position(&self) -> usize {
+        match self {
+            MovieState::Recording(movie) => movie.frames.len(),
+            MovieState::Playing(playback) => playback.position(),
+        }
+    }
+}
 
 pub struct Nes {
+    region: Region,
     cpu: Cpu,
     ram: Ram,
-    ppu: Ppu<PpuMapper>,
-    mapper: CpuMapper,
+    ppu: Ppu,
+    apu: Apu,
+    controllers: Controllers,
+    input_map: InputMap,
+    hotkeys: Hotkeys,
+    /// Input for the controllers to poll at strobe time, shared with them
+    /// through `LiveInput`.
+    live_input: Rc<Cell<[PortInput; 2]>>,
+    cart: Cart,
+    audio: Box<dyn AudioSink>,
+    speed: SpeedAdapter,
+    recorder: Option<AudioRecorder>,
+    has_battery: bool,
+    save_file: Option<SaveFile>,
+    last_save: Instant,
+    /// Number of frames emulated since power on.
+    frame_count: u64,
+    /// Number of CPU cycles emulated so far in the current frame. This is
+    /// only nonzero between updates while stopped partway through a frame.
+    frame_cycle: usize,
+    /// The buttons held for the current frame, in case it's finished later.
+    frame_buttons: [Buttons; 4],
+    /// Position of the mouse cursor within the frame, for the Zapper.
+    cursor: Option<(usize, usize)>,
+    /// Number of controllers plugged in (4 with a Four Score).
+    players: usize,
+    /// Whether to draw the controllers' buttons over the game.
+    show_input: bool,
+    /// A movie whose input is used instead of live input.
+    playback: Option<Playback>,
+    /// A movie being recorded, and where to save it.
+    movie_recording: Option<(PathBuf, Movie)>,
+    /// Set once there's nothing left to emulate (e.g., at the end of a movie).
+    finished: bool,
+    state_slots: Option<StateSlots>,
+    /// The slot that the save and load state hotkeys use.
+    slot: u8,
+    /// Messages to show on screen.
+    osd: Osd,
+    /// Where to save the state on exit, for resuming the game later.
+    auto_save: Option<PathBuf>,
+    /// Whether the emulation is kept independent of the host's timing (see
+    /// `set_deterministic`).
+    deterministic: bool,
+    /// Whether fast-forward has been turned on with its toggle hotkey. It's
+    /// also on while its other hotkey is held.
+    fast_forward: bool,
+    /// Maximum speed while fast-forwarding, or `None` for no limit.
+    fast_forward_speed: Option<f64>,
+    /// Speed to run at when not fast-forwarding, which is below 1 in slow
+    /// motion.
+    slow_motion: f64,
+    /// Time towards the next frame in slow motion, in seconds of emulated
+    /// time.
+    frame_time: f64,
+    screenshots: Option<Screenshots>,
+    /// The last frame as the PPU rendered it, and with overlays drawn over
+    /// it (only kept if screenshots of it are wanted).
+    last_frame: Vec<u8>,
+    overlaid_frame: Option<Vec<u8>>,
+    /// The last few seconds of frames, for saving as a clip.
+    clip: Option<ClipBuffer>,
+    /// Performance stats, if they're being shown.
+    stats: Option<Stats>,
+    /// The ROM file, if it's being watched for changes.
+    watch: Option<RomWatch>,
+    /// Debug viewers to show in windows of their own.
+    viewers: Vec<Viewer>,
+    /// State of the debugger's window, if it's one of the viewers.
+    debugger: Debugger,
+    /// Addresses of the instructions to stop before, in order (see
+    /// `add_breakpoint`).
+    breakpoints: Vec<Address>,
+    /// Whether emulation is stopped (see `stop`).
+    stopped: bool,
+    /// Whether to stop again at the next instruction (see
+    /// `step_instruction`).
+    stepping: bool,
+    /// Whether emulation is carrying on from where it stopped, in which case
+    /// the breakpoint that it stopped at mustn't stop it again.
+    resuming: bool,
+    /// Name of the game, for the window title.
+    name: Option<String>,
+    /// Palettes to switch between with the palette hotkey, and the index of
+    /// the one in use.
+    palettes: Vec<(String, Colors)>,
+    palette: usize,
 }
 
 impl Nes {
-    pub fn new(rom: Rom) -> Self {
-        let (mut mapper, ppu_mapper) = mapper::init(rom);
+    /// Insert a ROM into a new console. If no region is given, the region
+    /// the ROM was made for is used, defaulting to NTSC.
+    pub fn new(rom: Rom, region: Option<Region>) -> Result<Self> {
+        if rom.header.console_type != ConsoleType::Nes {
+            bail!("Unsupported console: {}", rom.header.console_type.name());
+        }
+        let region = region.or(rom.header.region).unwrap_or_default();
+        log::info!("Using {:?} timing", region);
+        let has_battery = rom.header.has_battery;
+        let mut cart = mapper::init(rom)?;
 
         let mut cpu = Cpu::new();
         let mut ram = Ram::new();
-        let mut ppu = Ppu::with_mapper(ppu_mapper);
+        let mut ppu = Ppu::new(region);
+        let mut apu = Apu::new(region);
+        let mut controllers = Controllers::new();
 
         // Reset the CPU to set the initial value of the program counter from
-        // the reset vector (loaded from memory via the CPU mapper).
-        let mut memory = Memory::new(&mut ram, &mut ppu, &mut mapper);
+        // the reset vector (loaded from the cartridge).
+        let mut memory = Memory::new(&mut ram, &mut ppu, &mut apu, &mut controllers, &mut cart);
         cpu.reset(&mut memory);
 
-        Self {
+        Ok(Self {
+            region,
             cpu,
             ram,
             ppu,
-            mapper,
+            apu,
+            controllers,
+            input_map: InputMap::default(),
+            hotkeys: Hotkeys::default(),
+            live_input: Rc::default(),
+            cart,
+            audio: Box::new(NullSink),
+            speed: SpeedAdapter::new(),
+            recorder: None,
+            has_battery,
+            save_file: None,
+            last_save: Instant::now(),
+            frame_count: 0,
+            frame_cycle: 0,
+            frame_buttons: [Buttons::empty(); 4],
+            cursor: None,
+            players: 2,
+            show_input: false,
+            playback: None,
+            movie_recording: None,
+            finished: false,
+            state_slots: None,
+            slot: 0,
+            osd: Osd::new(),
+            auto_save: None,
+            deterministic: false,
+            fast_forward: false,
+            fast_forward_speed: None,
+            slow_motion: 1.0,
+            frame_time: 0.0,
+            screenshots: None,
+            last_frame: vec![0; FRAME_WIDTH * FRAME_HEIGHT * 4],
+            overlaid_frame: None,
+            clip: None,
+            stats: None,
+            watch: None,
+            viewers: Vec::new(),
+            debugger: Debugger::new(),
+            breakpoints: Vec::new(),
+            stopped: false,
+            stepping: false,
+            resuming: false,
+            name: None,
+            palettes: colors::palettes(&[]),
+            palette: 0,
+        })
+    }
+
+    /// Load the cartridge's battery-backed RAM from the given save file, and
+    /// keep the file up to date as the game runs. Does nothing if the
+    /// cartridge doesn't have a battery.
+    pub fn load_save_file(&mut self, path: PathBuf) -> Result<()> {
+        if !self.has_battery || self.cart.prg_ram().is_none() {
+            return Ok(());
+        }
+        let (save_file, data) = SaveFile::open(path)?;
+        self.cart.restore_prg_ram(&data);
+        self.save_file = Some(save_file);
+        Ok(())
+    }
+
+    /// Write the cartridge's battery-backed RAM to the save file, if it has
+    /// changed since the last write. This happens every few seconds while
+    /// the game runs, whenever a save state is created, and when the console
+    /// is dropped (including while unwinding from a panic).
+    pub fn flush_save_file(&mut self) {
+        self.last_save = Instant::now();
+        let (Some(save_file), Some(data)) = (&mut self.save_file, self.cart.prg_ram()) else {
+            return;
+        };
+        if let Err(e) = save_file.write(&data) {
+            log::error!("Failed to save game: {:#}", e);
+        }
+    }
+
+    /// Start playing the APU's output on the host's audio device. If audio
+    /// output isn't available, the emulator will continue to run silently.
+    pub fn enable_audio(&mut self, options: AudioOptions) {
+        match AudioOutput::open(options) {
+            Ok(audio) => self.set_audio_sink(Box::new(audio)),
+            Err(e) => log::warn!("Running without audio: {}", e),
+        }
+    }
+
+    /// Send the APU's output to the given sink, replacing the current one.
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>) {
+        self.apu.set_sample_rate(sink.sample_rate() as f64);
+        self.audio = sink;
+    }
+
+    /// Apply settings from the user's config.
+    pub fn configure(&mut self, config: &Config) {
+        for &channel in &config.audio.mute {
+            self.apu.set_muted(channel, true);
+        }
+        for &channel in &config.audio.solo {
+            self.apu.set_soloed(channel, true);
+        }
+        self.apu.set_filters(&config.audio.filters);
+        self.hotkeys = Hotkeys::new(&config.bindings.hotkeys);
+        self.input_map = InputMap::new(&config.bindings, &self.hotkeys);
+        if config.input.four_score {
+            if config.input.port2 != DeviceKind::Controller {
+                log::warn!("Ignoring port 2 device, since a Four Score is plugged in");
+            }
+            self.controllers.port1 = Box::new(FourScore::port1());
+            self.controllers.port2 = Box::new(FourScore::port2());
+            self.players = 4;
+        } else {
+            self.controllers.port2 = config.input.port2.create();
+        }
+        self.show_input = config.input.overlay;
+        self.controllers.filter_opposing = config.input.filter_opposing_directions;
+        let source = (config.input.poll_on_strobe && !self.deterministic)
+            .then(|| Box::new(LiveInput(self.live_input.clone())) as Box<dyn InputSource>);
+        self.controllers.set_source(source);
+        self.fast_forward_speed = match config.emulation.fast_forward_speed {
+            Some(speed) if speed <= 1.0 => {
+                log::warn!(
+                    "Ignoring fast-forward speed of {}, which isn't above 1",
+                    speed
+                );
+                None
+            }
+            speed => speed,
+        };
+        self.palettes = colors::palettes(&config.video.palettes);
+        self.palette = 0;
+        if let Some(name) = &config.video.palette {
+            match self
+                .palettes
+                .iter()
+                .position(|(n, _)| n.eq_ignore_ascii_case(name))
+            {
+                Some(i) => self.palette = i,
+                None => log::warn!("Unknown palette: {}", name),
+            }
+        }
+        self.ppu.set_colors(self.palettes[self.palette].1);
+    }
+
+    /// Make the console's output depend only on the ROM and the input given
+    /// at the start of each frame, so that the same input always produces
+    /// identical frames and save states (and audio, at a given sample rate).
+    /// This is used for movies, and can be turned on with `--deterministic`
+    /// for netplay or regression testing.
+    ///
+    /// The sources of nondeterminism are:
+    /// - The host's input, which is normally also polled when the game
+    ///   strobes the controllers, at whatever point in the frame the host
+    ///   has got to. Only the input from the start of the frame is used.
+    /// - The audio sink, which normally adjusts the APU's sample rate to keep
+    ///   its buffer filled. The nominal rate is used instead. The sink still
+    ///   decides when to run each frame, but not what happens during it.
+    /// - The cartridge's battery-backed RAM and the last session's state,
+    ///   which the caller shouldn't load. Everything else is cleared at power
+    ///   on, and the time between frames is never used.
+    pub fn set_deterministic(&mut self) {
+        self.deterministic = true;
+        self.controllers.set_source(None);
+        self.apu.set_sample_rate(self.audio.sample_rate() as f64);
+    }
+
+    /// Handle a hotkey for muting (or, if shift is held, soloing) an APU
+    /// channel.
+    fn toggle_channel(&mut self, channel: Channel, input: &WinitInputHelper) {
+        if input.held_shift() {
+            let soloed = !self.apu.soloed(channel);
+            self.apu.set_soloed(channel, soloed);
+            let state = if soloed { "soloed" } else { "unsoloed" };
+            self.show_message(format!("{} {}", channel.name(), state));
+        } else {
+            let muted = !self.apu.muted(channel);
+            self.apu.set_muted(channel, muted);
+            let state = if muted { "muted" } else { "unmuted" };
+            self.show_message(format!("{} {}", channel.name(), state));
         }
     }
 
+    /// Set the emulation speed, as a multiple of real time, so that the audio
+    /// can be adapted to match (see `SpeedAdapter`).
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed.set_speed(speed);
+    }
+
+    /// Start recording the APU's output to a WAV file. If `per_channel` is
+    /// set, the output of each channel is also recorded to a separate file.
+    pub fn start_recording(&mut self, path: impl AsRef<Path>, per_channel: bool) -> Result<()> {
+        self.stop_recording();
+        let sample_rate = self.audio.sample_rate();
+        let recorder = AudioRecorder::create(path, sample_rate, per_channel)?;
+        if per_channel {
+            self.apu.capture_channels(Some(sample_rate as f64));
+        }
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    /// Stop recording audio (if a recording is in progress) and finalize the
+    /// WAV files.
+    pub fn stop_recording(&mut self) {
+        self.apu.capture_channels(None);
+        if let Some(recorder) = self.recorder.take() {
+            if let Err(e) = recorder.finish() {
+                log::error!("Failed to finish audio recording: {}", e);
+            }
+        }
+    }
+
+    fn toggle_recording(&mut self) {
+        if self.recorder.is_some() {
+            self.stop_recording();
+            self.show_message("Recording stopped".to_string());
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let path = PathBuf::from(format!("nes-audio-{}.wav", timestamp));
+        match self.start_recording(path, false) {
+            Ok(()) => self.show_message("Recording started".to_string()),
+            Err(e) => {
+                log::error!("Failed to start audio recording: {}", e);
+                self.show_message("Failed to start recording".to_string());
+            }
+        }
+    }
+
+    /// Play back the controller input from a movie, starting on the next
+    /// frame.
+    pub fn play_movie(&mut self, movie: Movie, end: MovieEnd) {
+        // Movies give the input for each frame, so it can't change midway
+        // through one.
+        self.controllers.set_source(None);
+        self.playback = Some(Playback::new(movie, end));
+    }
+
+    /// Record the input to the given (empty) movie, which is saved to the
+    /// given path when the emulator exits.
+    pub fn record_movie(&mut self, path: PathBuf, mut movie: Movie) {
+        movie.pal = self.region == Region::Pal;
+        self.controllers.set_source(None);
+        self.movie_recording = Some((path, movie));
+    }
+
+    fn save_movie(&mut self) {
+        if let Some((path, movie)) = self.movie_recording.take() {
+            if let Err(e) = movie.save(&path) {
+                log::error!("Failed to save movie: {:#}", e);
+            }
+        }
+    }
+
+    /// Get the controller input for the next frame, either from the movie
+    /// being played or from the keyboard.
+    fn next_frame_input(&mut self, input: &WinitInputHelper) -> MovieFrame {
+        if let Some(playback) = &mut self.playback {
+            if let Some(frame) = playback.next_frame() {
+                return frame;
+            }
+            match playback.end {
+                MovieEnd::Stop => {
+                    log::info!("Movie finished");
+                    self.finished = true;
+                }
+                MovieEnd::Continue => log::info!("Movie finished, switching to live input"),
+            }
+            self.playback = None;
+        }
+        MovieFrame {
+            buttons: [0, 1, 2, 3]
+                .map(|player| self.input_map.buttons(input, player, self.frame_count)),
+            reset: false,
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Number of controllers plugged in.
+    pub fn players(&self) -> usize {
+        self.players
+    }
+
+    /// Get the buttons of the given player's controller whose keys were
+    /// pressed since the last update.
+    pub fn pressed_buttons(&self, input: &WinitInputHelper, player: usize) -> Buttons {
+        self.input_map.pressed(input, player)
+    }
+
+    /// Set the name of the game to show in the window title.
+    pub fn set_name(&mut self, name: String) {
+        self.name = Some(name);
+    }
+
+    /// Open the given debug viewers alongside the game when it runs.
+    pub fn set_viewers(&mut self, viewers: Vec<Viewer>) {
+        self.viewers = viewers;
+    }
+
+    /// Reload the ROM whenever the given file changes (see the `watch`
+    /// module).
+    pub fn watch_rom(&mut self, path: PathBuf, entry: Option<String>) {
+        self.watch = Some(RomWatch::new(path, entry));
+    }
+
+    /// Swap in a new ROM and power cycle the console, keeping everything
+    /// that belongs to the host (like settings, controllers and recordings).
+    /// The battery save file stays in place, so a new build of a game can
+    /// pick up where the old one's save left off.
+    pub fn reload(&mut self, rom: Rom) -> Result<()> {
+        self.flush_save_file();
+        let mut new = Nes::new(rom, Some(self.region))?;
+        mem::swap(&mut self.cpu, &mut new.cpu);
+        mem::swap(&mut self.ram, &mut new.ram);
+        mem::swap(&mut self.ppu, &mut new.ppu);
+        // The palette is the host's too.
+        self.ppu.set_colors(*new.ppu.colors());
+        mem::swap(&mut self.cart, &mut new.cart);
+        // Only the APU's state is replaced, since its output settings (like
+        // muted channels) are the host's.
+        self.apu.restore(&new.apu.snapshot());
+        self.has_battery = new.has_battery;
+        self.frame_count = 0;
+        match &self.save_file {
+            Some(save_file) if self.has_battery => self.cart.restore_prg_ram(save_file.data()),
+            _ => self.save_file = None,
+        }
+        if self.playback.is_some() || self.movie_recording.is_some() {
+            log::warn!("ROM reloaded during a movie, which will no longer be in sync");
+        }
+        Ok(())
+    }
+
+    fn poll_watch(&mut self) {
+        let Some(result) = self.watch.as_mut().and_then(RomWatch::poll) else {
+            return;
+        };
+        match result.and_then(|rom| self.reload(rom)) {
+            Ok(()) => self.show_message("ROM reloaded".to_string()),
+            Err(e) => {
+                log::error!("Failed to reload ROM: {:#}", e);
+                self.show_message("ROM reload failed".to_string());
+            }
+        }
+    }
+
+    /// Reset the console, as if the reset button had been pressed.
+    pub fn reset(&mut self) {
+        let mut memory = Memory::new(
+            &mut self.ram,
+            &mut self.ppu,
+            &mut self.apu,
+            &mut self.controllers,
+            &mut self.cart,
+        );
+        self.cpu.reset(&mut memory);
+    }
+
+    /// Save the state of the whole console, so that it can be restored later
+    /// with `load_state`.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateBuilder::new();
+        state.section(CONSOLE_SECTION, |w| {
+            w.write(&(self.region as u8));
+            w.write(&self.frame_count);
+        });
+        state.section(CPU_SECTION, |w| self.cpu.save_state(w));
+        state.section(RAM_SECTION, |w| self.ram.save_state(w));
+        state.section(PPU_SECTION, |w| self.ppu.save_state(w));
+        state.section(APU_SECTION, |w| self.apu.save_state(w));
+        state.section(CONTROLLERS_SECTION, |w| self.controllers.save_state(w));
+        state.section(CART_SECTION, |w| self.cart.save_state(w));
+        if self.frame_cycle > 0 {
+            state.section(FRAME_SECTION, |w| w.write(&(self.frame_cycle as u64)));
+        }
+        if let Some(movie) = self.current_movie() {
+            let frame = movie.position();
+            state.section(MOVIE_SECTION, |w| {
+                w.write(&(frame as u64));
+                w.write(&movie.movie().hash(frame));
+            });
+        }
+        state.finish()
+    }
+
+    /// Restore a state from `save_state`, which must have been saved with the
+    /// same ROM. If the state can't be loaded, the console is left as it was.
+    ///
+    /// While a movie is being recorded or played, the state must have been
+    /// saved earlier in the same movie (or, when playing, anywhere in it).
+    /// Recording continues from the state's frame, dropping everything that
+    /// was recorded after it, and playback jumps to the state's frame.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        let backup = self.save_state();
+        let result = self.read_state(state);
+        if result.is_err() {
+            self.read_state(&backup)
+                .expect("Failed to restore state after a failed load");
+        }
+        result
+    }
+
+    fn read_state(&mut self, state: &[u8]) -> Result<()> {
+        let sections = StateSections::parse(state)?;
+        let movie_frame = self.movie_frame(&sections)?;
+        sections.load(CONSOLE_SECTION, |r| {
+            ensure!(
+                r.read::<u8>()? == self.region as u8,
+                "Save state is for a different region"
+            );
+            self.frame_count = r.read()?;
+            Ok(())
+        })?;
+        sections.load(CPU_SECTION, |r| self.cpu.load_state(r))?;
+        sections.load(RAM_SECTION, |r| self.ram.load_state(r))?;
+        sections.load(PPU_SECTION, |r| self.ppu.load_state(r))?;
+        sections.load(APU_SECTION, |r| self.apu.load_state(r))?;
+        sections.load(CONTROLLERS_SECTION, |r| self.controllers.load_state(r))?;
+        sections.load(CART_SECTION, |r| self.cart.load_state(r))?;
+        self.frame_cycle = if sections.contains(FRAME_SECTION) {
+            sections.load(FRAME_SECTION, |r| Ok(r.read::<u64>()? as usize))?
+        } else {
+            0
+        };
+        if let Some(frame) = movie_frame {
+            self.seek_movie(frame);
+        }
+        Ok(())
+    }
+
+    /// The movie being recorded or played, if any.
+    fn current_movie(&self) -> Option<MovieState<'_>> {
+        match (&self.movie_recording, &self.playback) {
+            (Some((_, movie)), _) => Some(MovieState::Recording(movie)),
+            (None, Some(playback)) => Some(MovieState::Playing(playback)),
+            (None, None) => None,
+        }
+    }
+
+    /// Check that a save state belongs to the current movie (if any), and
+    /// find the frame to continue the movie from.
+    fn movie_frame(&self, sections: &StateSections) -> Result<Option<usize>> {
+        let Some(current) = self.current_movie() else {
+            return Ok(None);
+        };
+        ensure!(
+            sections.contains(MOVIE_SECTION),
+            "Save state wasn't made during this movie, so loading it would desync the movie"
+        );
+        let (frame, hash) = sections.load(MOVIE_SECTION, |r| {
+            Ok((r.read::<u64>()? as usize, r.read::<[u8; 16]>()?))
+        })?;
+        let movie = current.movie();
+        ensure!(
+            frame <= movie.frames.len(),
+            "Save state is from frame {} of the movie, which only has {} frames",
+            frame,
+            movie.frames.len()
+        );
+        ensure!(
+            movie.hash(frame) == hash,
+            "Save state is from a different movie, or from input that has since been rerecorded"
+        );
+        Ok(Some(frame))
+    }
+
+    fn seek_movie(&mut self, frame: usize) {
+        if let Some((_, movie)) = &mut self.movie_recording {
+            if frame < movie.frames.len() {
+                log::info!(
+                    "Rerecording movie from frame {} (dropping {} frames)",
+                    frame,
+                    movie.frames.len() - frame
+                );
+            }
+            movie.frames.truncate(frame);
+        } else if let Some(playback) = &mut self.playback {
+            playback.seek(frame);
+        }
+    }
+
+    /// Take a copy of the console's state, which can be restored with
+    /// `restore`. This is much cheaper than `save_state` (see `Snapshot`),
+    /// so it can be done every frame.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut devices = StateWriter::new();
+        self.controllers.save_state(&mut devices);
+        self.cart.save_state(&mut devices);
+        Snapshot {
+            cpu: self.cpu.clone(),
+            ram: self.ram.clone(),
+            ppu: self.ppu.clone(),
+            apu: self.apu.snapshot(),
+            frame_count: self.frame_count,
+            frame_cycle: self.frame_cycle,
+            devices: devices.into_bytes(),
+        }
+    }
+
+    /// Restore a snapshot taken from this console. This fails if the
+    /// snapshot is from a console with different devices (like another
+    /// cartridge), in which case the controllers and the cartridge may be
+    /// left partially restored, but the rest of the console is untouched.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<()> {
+        let mut devices = StateReader::new(&snapshot.devices);
+        self.controllers
+            .load_state(&mut devices)
+            .and_then(|()| self.cart.load_state(&mut devices))
+            .context("Snapshot is from a console with different devices")?;
+        self.cpu = snapshot.cpu.clone();
+        self.ram = snapshot.ram.clone();
+        let colors = *self.ppu.colors();
+        self.ppu = snapshot.ppu.clone();
+        self.ppu.set_colors(colors);
+        self.apu.restore(&snapshot.apu);
+        self.frame_count = snapshot.frame_count;
+        self.frame_cycle = snapshot.frame_cycle;
+        Ok(())
+    }
+
+    /// Use the given slots for the save and load state hotkeys.
+    pub fn set_state_slots(&mut self, slots: StateSlots) {
+        self.state_slots = Some(slots);
+    }
+
+    /// Load a save state from a slot or a file.
+    pub fn load_state_from(&mut self, location: &StateLocation) -> Result<()> {
+        let state = match location {
+            StateLocation::Slot(slot) => match &self.state_slots {
+                Some(slots) => slots.load(*slot)?,
+                None => bail!("No save state slots for this game"),
+            },
+            StateLocation::File(path) => savestate::read_state(path)?,
+        };
+        self.load_state(&state)
+    }
+
+    fn save_slot(&mut self) {
+        // A save state is a natural checkpoint, so make sure the save file
+        // is no older than it.
+        self.flush_save_file();
+        let Some(slots) = &self.state_slots else {
+            return;
+        };
+        match slots.save(self.slot, &self.save_state()) {
+            Ok(()) => self.show_message(format!("State {} saved", self.slot)),
+            Err(e) => {
+                log::error!("Failed to save state: {:#}", e);
+                self.show_message(format!("Failed to save state {}", self.slot));
+            }
+        }
+    }
+
+    fn load_slot(&mut self) {
+        match self.load_state_from(&StateLocation::Slot(self.slot)) {
+            Ok(()) => self.show_message(format!("State {} loaded", self.slot)),
+            Err(e) => {
+                log::error!("Failed to load state: {:#}", e);
+                self.show_message(format!("Failed to load state {}", self.slot));
+            }
+        }
+    }
+
+    fn select_slot(&mut self, input: &WinitInputHelper) {
+        self.slot = if input.held_shift() {
+            (self.slot + NUM_SLOTS - 1) % NUM_SLOTS
+        } else {
+            (self.slot + 1) % NUM_SLOTS
+        };
+        self.show_message(format!("Slot {}", self.slot));
+    }
+
+    fn select_palette(&mut self, input: &WinitInputHelper) {
+        let len = self.palettes.len();
+        self.palette = if input.held_shift() {
+            (self.palette + len - 1) % len
+        } else {
+            (self.palette + 1) % len
+        };
+        let (name, colors) = &self.palettes[self.palette];
+        self.ppu.set_colors(*colors);
+        self.show_message(format!("Palette: {}", name));
+    }
+
+    /// Save screenshots to the given place with the screenshot hotkey.
+    pub fn set_screenshots(&mut self, screenshots: Screenshots) {
+        self.overlaid_frame = screenshots.save_overlays.then(|| self.last_frame.clone());
+        self.screenshots = Some(screenshots);
+    }
+
+    /// Keep the given number of seconds of frames, for the clip hotkey to
+    /// save.
+    pub fn enable_clips(&mut self, seconds: f64) {
+        self.clip = Some(ClipBuffer::new(seconds, self.region.frame_rate()));
+    }
+
+    fn save_clip(&mut self) {
+        let (Some(screenshots), Some(clip)) = (&self.screenshots, &self.clip) else {
+            return;
+        };
+        if clip.is_empty() {
+            return;
+        }
+        match screenshots.new_path("gif") {
+            Ok(path) => {
+                clip.save(path);
+                self.show_message("Saving clip".to_string());
+            }
+            Err(e) => log::error!("Failed to save clip: {:#}", e),
+        }
+    }
+
+    fn save_screenshot(&mut self) {
+        let Some(screenshots) = &self.screenshots else {
+            return;
+        };
+        match screenshots.save(&self.last_frame, self.overlaid_frame.as_deref()) {
+            Ok(path) => {
+                log::info!("Saved screenshot to {:?}", path);
+                self.show_message("Screenshot saved".to_string());
+            }
+            Err(e) => {
+                log::error!("Failed to save screenshot: {:#}", e);
+                self.show_message("Failed to save screenshot".to_string());
+            }
+        }
+    }
+
+    /// Save the state to the given file when the emulator exits.
+    pub fn set_auto_save(&mut self, path: PathBuf) {
+        self.auto_save = Some(path);
+    }
+
+    fn write_auto_save(&mut self) {
+        if let Some(path) = self.auto_save.take() {
+            self.flush_save_file();
+            if let Err(e) = savestate::write_state(&path, &self.save_state()) {
+                log::error!("Failed to save state for resuming: {:#}", e);
+            }
+        }
+    }
+
+    /// Show a short message over the game for a couple of seconds (see the
+    /// `osd` module).
+    pub fn show_message(&mut self, text: String) {
+        log::info!("{}", text);
+        self.osd.push(text);
+    }
+
+    /// Stop emulating before the instruction at the given address is
+    /// executed, so that the console can be inspected in the debugger. The
+    /// console is stopped as if by `stop`, partway through the frame.
+    pub fn add_breakpoint(&mut self, addr: Address) {
+        if let Err(i) = self.breakpoints.binary_search(&addr) {
+            self.breakpoints.insert(i, addr);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: Address) {
+        self.breakpoints.retain(|&a| a != addr);
+    }
+
+    /// The addresses that have breakpoints, in order.
+    pub fn breakpoints(&self) -> &[Address] {
+        &self.breakpoints
+    }
+
+    /// Stop emulation until `resume` or `step_instruction` is called. Until
+    /// then, running a frame does nothing.
+    pub fn stop(&mut self) {
+        self.stopped = true;
+    }
+
+    /// Carry on emulating from where emulation stopped, which may be partway
+    /// through a frame.
+    pub fn resume(&mut self) {
+        if self.stopped {
+            self.stopped = false;
+            self.resuming = true;
+        }
+    }
+
+    /// Whether emulation is stopped, by `stop` or at a breakpoint.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Carry on emulating until the CPU reaches its next instruction, and
+    /// then stop again. The instruction is run with the next frame (or the
+    /// rest of the current one).
+    pub fn step_instruction(&mut self) {
+        self.resume();
+        self.stepping = true;
+    }
+
+    /// Carry out a command from the debugger's window.
+    fn debug(&mut self, command: Command) {
+        match command {
+            Command::Pause if self.is_stopped() => self.resume(),
+            Command::Pause => self.stop(),
+            Command::Step => self.step_instruction(),
+            Command::ToggleBreakpoint(addr) if self.breakpoints().contains(&addr) => {
+                self.remove_breakpoint(addr)
+            }
+            Command::ToggleBreakpoint(addr) => self.add_breakpoint(addr),
+        }
+    }
+
+    /// Read a byte from the CPU's address space.
+    #[cfg(test)]
+    pub fn peek(&mut self, addr: Address) -> u8 {
+        let mut memory = Memory::new(
+            &mut self.ram,
+            &mut self.ppu,
+            &mut self.apu,
+            &mut self.controllers,
+            &mut self.cart,
+        );
+        memory.load(addr)
+    }
+
+    /// Run a single frame without any audio output or recording, returning
+    /// the frame's audio samples instead.
+    pub fn run_one_frame_headless(&mut self, frame: &mut [u8]) -> Vec<f32> {
+        if !self.stopped {
+            self.emulate_frame(frame);
+        }
+        self.apu.take_samples()
+    }
+
     /// Run the CPU only without any visual output.
     pub fn run_cpu(&mut self, start: Option<Address>) {
         if let Some(start) = start {
             self.cpu.set_pc(start);
         }
         loop {
-            let mut memory = Memory::new(&mut self.ram, &mut self.ppu, &mut self.mapper);
-            self.cpu.step(&mut memory);
+            let mut memory = Memory::new(
+                &mut self.ram,
+                &mut self.ppu,
+                &mut self.apu,
+                &mut self.controllers,
+                &mut self.cart,
+            );
+            let cycles = self.cpu.step(&mut memory);
+
+            // Keep the cartridge's hardware in step with the CPU, so that
+            // games relying on mapper IRQs still work without the PPU.
+            for _ in 0..cycles {
+                self.cart.tick();
+            }
+            self.cpu.set_irq_line(self.cart.irq_asserted());
         }
     }
 
     /// Run the system for the duration of a single frame, writing the contents
     /// of the new frame to the give frame buffer.
-    pub fn run_one_frame(&mut self, frame: &mut [u8], _input: &WinitInputHelper) {
-        for i in 0..CPU_CYCLES_PER_FRAME {
-            if i % 1000 == 0 {
+    ///
+    /// While stopped in the debugger, this does nothing. Once resumed, a
+    /// frame that stopped partway through is finished before a new one is
+    /// started.
+    pub fn run_one_frame(&mut self, frame: &mut [u8], input: &WinitInputHelper) {
+        if self.stopped {
+            return;
+        }
+        if self.frame_cycle > 0 {
+            self.finish_frame(frame);
+            return;
+        }
+        let movie_frame = self.next_frame_input(input);
+        if self.finished {
+            return;
+        }
+        if let Some((_, movie)) = &mut self.movie_recording {
+            movie.frames.push(movie_frame);
+        }
+        self.run_movie_frame(frame, movie_frame, input);
+    }
+
+    /// Run a single frame with the given controller input, instead of the
+    /// input from the keyboard or a movie. The host's input is still used for
+    /// devices that movies don't record (like the Zapper).
+    pub fn run_movie_frame(
+        &mut self,
+        frame: &mut [u8],
+        movie_frame: MovieFrame,
+        input: &WinitInputHelper,
+    ) {
+        if movie_frame.reset {
+            self.reset();
+        }
+        let buttons = movie_frame.buttons;
+        // Players 3 and 4 are plugged into the second socket for each port.
+        let inputs = [0, 1].map(|port| PortInput {
+            buttons: [buttons[port], buttons[port + 2]],
+            cursor: self.cursor,
+            trigger: input.mouse_held(0),
+            power_pad: self.input_map.power_pad(input),
+        });
+        self.controllers.update(&inputs);
+        self.live_input.set(inputs);
+        self.controllers.microphone = self.input_map.microphone(input);
+        self.frame_buttons = buttons;
+        self.finish_frame(frame);
+    }
+
+    /// Emulate the rest of the frame, and then show it and play its audio.
+    fn finish_frame(&mut self, frame: &mut [u8]) {
+        let start = Instant::now();
+        if !self.emulate_frame(frame) {
+            return;
+        }
+        if let Some(stats) = &mut self.stats {
+            stats.record_frame(start.elapsed());
+        }
+        self.last_frame.copy_from_slice(frame);
+        if let Some(clip) = &mut self.clip {
+            clip.push(frame);
+        }
+        if self.show_input {
+            input_overlay::draw(frame, &self.frame_buttons[..self.players]);
+        }
+        self.osd.draw(frame, FRAME_WIDTH);
+        if let Some(stats) = &self.stats {
+            stats.draw(frame, self.audio.buffered(), self.region.frame_rate());
+        }
+        if let Some(overlaid) = &mut self.overlaid_frame {
+            overlaid.copy_from_slice(frame);
+        }
+
+        // Send this frame's audio to the audio sink, and then adjust the APU's
+        // sample rate to keep the sink's buffer (if any) at the target level,
+        // unless the output has to be deterministic. Recordings get all of the
+        // audio, regardless of emulation speed.
+        let samples = self.apu.take_samples();
+        if let Some(recorder) = &mut self.recorder {
+            let channels = self.apu.take_channel_samples();
+            if let Err(e) = recorder.write(&samples, channels.as_deref()) {
+                log::error!("Stopping audio recording: {}", e);
+                self.stop_recording();
+            }
+        }
+        let samples = self.speed.process(samples);
+        if let Err(e) = self.audio.push(&samples) {
+            log::error!("Disabling audio output: {}", e);
+            self.set_audio_sink(Box::new(NullSink));
+        }
+        if !self.deterministic {
+            self.apu.set_sample_rate(self.audio.adjusted_sample_rate());
+        }
+
+        if self.last_save.elapsed() >= SAVE_INTERVAL {
+            self.flush_save_file();
+        }
+    }
+
+    /// Whether it's time to run the next frame in slow motion, given the
+    /// time since the last update. At normal speed, frames are paced by the
+    /// audio sink (or the display) instead, so it's always time. In slow
+    /// motion, the audio is dropped, so frames are paced by scaling the time
+    /// between updates by the speed.
+    fn slow_motion_frame_due(&mut self, dt: Duration) -> bool {
+        if self.slow_motion >= 1.0 {
+            return true;
+        }
+        let frame_duration = 1.0 / self.region.frame_rate();
+        self.frame_time += dt.as_secs_f64() * self.slow_motion;
+        if self.frame_time < frame_duration {
+            return false;
+        }
+        // Don't try to catch up after a long pause between updates.
+        self.frame_time = (self.frame_time - frame_duration).min(frame_duration);
+        true
+    }
+
+    /// Emulate the rest of the current frame, writing it to the frame buffer
+    /// once it's finished. Returns whether it was finished: emulation stops
+    /// partway through the frame at breakpoints, and carries on from there
+    /// the next time this is called.
+    fn emulate_frame(&mut self, frame: &mut [u8]) -> bool {
+        if self.frame_cycle == 0 {
+            self.ppu.start_vblank();
+        }
+        let mut resuming = mem::take(&mut self.resuming);
+        while self.frame_cycle < self.region.cpu_cycles_per_frame() {
+            if self.cpu.at_instruction_boundary() && !resuming {
+                let pc = self.cpu.registers().pc;
+                if self.stepping || self.breakpoints.contains(&pc) {
+                    log::debug!("Stopped at {}", pc);
+                    self.stopped = true;
+                    self.stepping = false;
+                    return false;
+                }
+            }
+            resuming = false;
+            let i = self.frame_cycle;
+            self.frame_cycle += 1;
+            if i.is_multiple_of(1000) {
                 log::debug!("cycle {}", i);
             }
             // Create a view of the CPU's addres space, including all memory-mapped devices.
-            let mut memory = Memory::new(&mut self.ram, &mut self.ppu, &mut self.mapper);
+            let mut memory = Memory::new(
+                &mut self.ram,
+                &mut self.ppu,
+                &mut self.apu,
+                &mut self.controllers,
+                &mut self.cart,
+            );
 
-            // Run the CPU.
+            // Run the CPU, along with any hardware on the cartridge that is
+            // clocked by it.
             self.cpu.tick(&mut memory);
+            self.cart.tick();
+
+            // Run the APU, which is clocked in lockstep with the CPU. If the
+            // DMC needs a new sample byte, fetch it from the CPU's address
+            // space on its behalf.
+            if let Some(addr) = self.apu.dmc_pending_read() {
+                let mut memory = Memory::new(
+                    &mut self.ram,
+                    &mut self.ppu,
+                    &mut self.apu,
+                    &mut self.controllers,
+                    &mut self.cart,
+                );
+                let value = memory.load(addr);
+                self.apu.fill_dmc_buffer(value);
+            }
+            self.apu.tick(self.cart.expansion_audio());
 
-            // // Run the PPU. The PPU's clock runs 3x faster than the CPU's.
-            // for _ in 0..3 {
-            // }
+            // Run the PPU, whose clock runs about 3x faster than the CPU's.
+            for _ in 0..self.region.ppu_dots(i) {
+                self.ppu.step(&mut self.cart);
+            }
+
+            // The IRQ line is shared by the APU and the cartridge.
+            self.cpu
+                .set_irq_line(self.apu.irq() || self.cart.irq_asserted());
         }
-        self.ppu.tick(frame);
+        self.ppu.tick(&mut self.cart, frame);
 
         // Create a view of the CPU's addres space, including all memory-mapped devices.
-        let mut memory = Memory::new(&mut self.ram, &mut self.ppu, &mut self.mapper);
+        let mut memory = Memory::new(
+            &mut self.ram,
+            &mut self.ppu,
+            &mut self.apu,
+            &mut self.controllers,
+            &mut self.cart,
+        );
 
         // Run the CPU.
         self.cpu.nmi(&mut memory);
+        self.frame_count += 1;
+        self.frame_cycle = 0;
+        true
     }
 }
 
@@ -83,31 +1230,350 @@ impl Ui for Nes {
         (FRAME_WIDTH as u32, FRAME_HEIGHT as u32)
     }
 
-    fn update(&mut self, frame: &mut [u8], input: &WinitInputHelper, _dt: Duration) -> Result<()> {
+    fn update(&mut self, frame: &mut [u8], input: &WinitInputHelper, dt: Duration) -> Result<()> {
+        if let Some(stats) = &mut self.stats {
+            stats.record_update();
+        }
+        self.poll_watch();
+        let fast_forward = self.fast_forward || self.hotkeys.held(Hotkey::FastForward, input);
+        let speed = match self.fast_forward_speed {
+            _ if !fast_forward => self.slow_motion,
+            Some(speed) => speed,
+            None => f64::INFINITY,
+        };
+        if self.speed.speed() != speed {
+            self.set_speed(speed);
+        }
+
+        // The UI calls this method at the console's frame rate (or at the
+        // display's, with vsync pacing). If the audio sink is falling behind,
+        // skip this frame to let it catch up. Otherwise, the buffer would
+        // grow without bound (along with the audio latency).
+        if !fast_forward {
+            if !self.audio.is_full() && self.slow_motion_frame_due(dt) {
+                self.run_one_frame(frame, input);
+            }
+            return Ok(());
+        }
+
+        // When fast-forwarding, run several frames per update and only show
+        // the last one. The speed adapter drops enough of the audio for the
+        // sink to throttle the emulator to the speed limit. Without a limit,
+        // all of the audio is dropped, so the time spent is limited instead.
+        let start = Instant::now();
+        let max_frames = self
+            .fast_forward_speed
+            .map_or(usize::MAX, |speed| speed.ceil() as usize);
+        for _ in 0..max_frames {
+            if self.audio.is_full()
+                || self.finished
+                || self.stopped
+                || start.elapsed() >= FAST_FORWARD_BUDGET
+            {
+                break;
+            }
+            self.run_one_frame(frame, input);
+        }
+        Ok(())
+    }
+
+    fn title(&self) -> Option<String> {
+        let speed = self.speed.speed();
+        let speed = if speed > 1.0 {
+            Some("Fast forward".to_string())
+        } else if speed < 1.0 {
+            Some(format!("{}%", speed * 100.0))
+        } else {
+            None
+        };
+        let stopped = self
+            .stopped
+            .then(|| format!("Stopped at ${:04X}", self.cpu.registers().pc.0));
+        let parts: Vec<String> = self
+            .name
+            .iter()
+            .cloned()
+            .chain(speed)
+            .chain(stopped)
+            .collect();
+        (!parts.is_empty()).then(|| parts.join(" - "))
+    }
+
+    fn frame_rate(&self) -> Option<f64> {
+        Some(self.region.frame_rate())
+    }
+
+    /// Run exactly one frame, regardless of the emulation speed or the audio
+    /// sink's buffer.
+    fn advance_frame(&mut self, frame: &mut [u8], input: &WinitInputHelper) -> Result<()> {
         self.run_one_frame(frame, input);
         Ok(())
     }
+
+    fn hotkeys(&self) -> Option<&Hotkeys> {
+        Some(&self.hotkeys)
+    }
+
+    fn handle_hotkey(&mut self, hotkey: Hotkey, input: &WinitInputHelper) {
+        match hotkey {
+            Hotkey::RecordAudio => self.toggle_recording(),
+            Hotkey::ToggleInputOverlay => self.show_input = !self.show_input,
+            Hotkey::ToggleStats => {
+                self.stats = match self.stats {
+                    Some(_) => None,
+                    None => Some(Stats::new()),
+                };
+            }
+            Hotkey::SaveState => self.save_slot(),
+            Hotkey::LoadState => self.load_slot(),
+            Hotkey::NextSlot => self.select_slot(input),
+            Hotkey::NextPalette => self.select_palette(input),
+            Hotkey::Screenshot => self.save_screenshot(),
+            Hotkey::SaveClip => self.save_clip(),
+            Hotkey::ToggleFastForward => {
+                self.fast_forward = !self.fast_forward;
+                let state = if self.fast_forward { "on" } else { "off" };
+                self.show_message(format!("Fast forward {}", state));
+            }
+            Hotkey::SlowMotion => {
+                let i = SLOW_MOTION_SPEEDS
+                    .iter()
+                    .position(|&speed| speed == self.slow_motion)
+                    .unwrap_or(0);
+                self.slow_motion = SLOW_MOTION_SPEEDS[(i + 1) % SLOW_MOTION_SPEEDS.len()];
+                self.frame_time = 0.0;
+                self.show_message(format!("Speed {}%", self.slow_motion * 100.0));
+            }
+            hotkey => {
+                if let Some(i) = Hotkey::MUTE_CHANNEL.iter().position(|&h| h == hotkey) {
+                    self.toggle_channel(Channel::ALL[i], input);
+                }
+            }
+        }
+    }
+
+    fn set_cursor(&mut self, pos: Option<(usize, usize)>) {
+        self.cursor = pos;
+    }
+
+    fn aux_windows(&self) -> Vec<(&'static str, (u32, u32))> {
+        self.viewers
+            .iter()
+            .map(|viewer| (viewer.title(), viewer.size()))
+            .collect()
+    }
+
+    fn draw_aux_window(&mut self, index: usize, frame: &mut [u8]) {
+        let mut console = Console {
+            cpu: &self.cpu,
+            ram: &self.ram,
+            ppu: &self.ppu,
+            cart: &mut self.cart,
+            breakpoints: &self.breakpoints,
+            stopped: self.stopped,
+        };
+        self.viewers[index].render(&mut console, &mut self.debugger, frame);
+    }
+
+    fn click_aux_window(&mut self, index: usize, pos: (usize, usize)) {
+        if self.viewers[index] == Viewer::Debugger {
+            if let Some(command) = self.debugger.click(pos) {
+                self.debug(command);
+            }
+        }
+    }
+
+    fn scroll_aux_window(&mut self, index: usize, lines: i32) {
+        if self.viewers[index] == Viewer::Debugger {
+            self.debugger.scroll(lines);
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+impl Drop for Nes {
+    fn drop(&mut self) {
+        // The player's progress in the game matters most, so save it first.
+        // If we're unwinding from a panic, the rest of the console's state
+        // may be inconsistent, and panicking again would abort before the
+        // save file is written, so don't do anything else.
+        self.flush_save_file();
+        if std::thread::panicking() {
+            return;
+        }
+
+        // Make sure that any in-progress recordings end up as valid files.
+        self.stop_recording();
+        self.save_movie();
+        self.write_auto_save();
+    }
 }
 
-/// Newtype wrapper to provide alternative UI for show-pattern command.
+/// Newtype wrapper to provide alternative UI for show-pattern command. The
+/// game runs in the background (without audio), so that the mapped banks and
+/// the palettes change as they do in the game.
+///
+/// Page Up and Page Down cycle between the pattern tables mapped into the PPU
+/// and each 8 KiB bank of the ROM's CHR data. Home and End cycle between
+/// greyscale and the game's background and sprite palettes. The selection is
+/// shown below the pattern tables.
 pub struct ShowPatternUi {
     nes: Nes,
+    /// The ROM's CHR data (empty if the cartridge has CHR RAM instead).
+    chr: Vec<u8>,
+    /// The bank shown: 0 for the mapped pattern tables, or 1 plus the index
+    /// of a CHR bank.
+    bank: usize,
+    /// The palette used: 0 for greyscale, 1-4 for the background palettes,
+    /// and 5-8 for the sprite palettes.
+    palette: usize,
+    game_frame: Vec<u8>,
 }
 
+/// Size of a CHR bank holding both pattern tables.
+const CHR_BANK_SIZE: usize = 0x2000;
+
+/// Height of the pattern tables in the show-pattern UI, with the status line
+/// below them.
+const PATTERN_TABLE_HEIGHT: usize = 128;
+const STATUS_HEIGHT: usize = 9;
+
 impl ShowPatternUi {
-    pub fn new(nes: Nes) -> Self {
-        ShowPatternUi { nes }
+    pub fn new(nes: Nes, chr: Vec<u8>) -> Self {
+        ShowPatternUi {
+            nes,
+            chr,
+            bank: 0,
+            palette: 0,
+            game_frame: vec![0; FRAME_WIDTH * FRAME_HEIGHT * 4],
+        }
+    }
+
+    fn status(&self) -> String {
+        let banks = self.chr.len() / CHR_BANK_SIZE;
+        let bank = match self.bank {
+            0 => "MAPPED".to_string(),
+            bank => format!("BANK {}/{}", bank, banks),
+        };
+        let palette = match self.palette {
+            0 => "GREYSCALE".to_string(),
+            i @ 1..=4 => format!("BG {}", i - 1),
+            i => format!("SPRITE {}", i - 5),
+        };
+        format!("{}  {}  (PGUP/PGDN, HOME/END)", bank, palette)
+    }
+}
+
+/// Step through `len` choices, wrapping around at either end.
+fn cycle(value: usize, len: usize, forward: bool) -> usize {
+    if forward {
+        (value + 1) % len
+    } else {
+        (value + len - 1) % len
     }
 }
 
 impl Ui for ShowPatternUi {
     fn size(&self) -> (u32, u32) {
         // Enough space to render both pattern tables (128x128) side-by-side.
-        (256, 128)
+        (256, (PATTERN_TABLE_HEIGHT + STATUS_HEIGHT) as u32)
+    }
+
+    fn frame_rate(&self) -> Option<f64> {
+        self.nes.frame_rate()
+    }
+
+    fn update(&mut self, frame: &mut [u8], input: &WinitInputHelper, dt: Duration) -> Result<()> {
+        let banks = 1 + self.chr.len() / CHR_BANK_SIZE;
+        if input.key_pressed(VirtualKeyCode::PageDown) {
+            self.bank = cycle(self.bank, banks, true);
+        }
+        if input.key_pressed(VirtualKeyCode::PageUp) {
+            self.bank = cycle(self.bank, banks, false);
+        }
+        if input.key_pressed(VirtualKeyCode::End) {
+            self.palette = cycle(self.palette, 9, true);
+        }
+        if input.key_pressed(VirtualKeyCode::Home) {
+            self.palette = cycle(self.palette, 9, false);
+        }
+
+        self.nes.update(&mut self.game_frame, input, dt)?;
+
+        let nes = &mut self.nes;
+        let palette = match self.palette {
+            0 => GREYSCALE_PALETTE,
+            i @ 1..=4 => nes.ppu.load_palette(&mut nes.cart, i as u8 - 1, false),
+            i => nes.ppu.load_palette(&mut nes.cart, i as u8 - 5, true),
+        };
+        match self.bank {
+            0 => nes.ppu.render_pattern_table(&mut nes.cart, frame, palette),
+            bank => {
+                let start = (bank - 1) * CHR_BANK_SIZE;
+                let chr = &self.chr[start..start + CHR_BANK_SIZE];
+                ppu::render_chr_bank(chr, frame, palette, nes.ppu.colors());
+            }
+        }
+
+        let status = &mut frame[PATTERN_TABLE_HEIGHT * FRAME_WIDTH * 4..];
+        status.fill(0);
+        for pixel in status.chunks_exact_mut(4) {
+            pixel[3] = 0xFF;
+        }
+        let text = self.status();
+        font::draw_text(status, FRAME_WIDTH, 2, 2, &text, [0xFF; 4]);
+        Ok(())
+    }
+}
+
+/// Newtype wrapper to provide alternative UI for show-apu command. The game
+/// runs as usual (including audio output and hotkeys), but the window shows
+/// the state of the APU's channels instead of the game's video output.
+pub struct ShowApuUi {
+    nes: Nes,
+    view: ApuView,
+    game_frame: Vec<u8>,
+}
+
+impl ShowApuUi {
+    pub fn new(mut nes: Nes) -> Self {
+        nes.apu.capture_scope(true);
+        ShowApuUi {
+            nes,
+            view: ApuView::new(),
+            game_frame: vec![0; FRAME_WIDTH * FRAME_HEIGHT * 4],
+        }
+    }
+}
+
+impl Ui for ShowApuUi {
+    fn size(&self) -> (u32, u32) {
+        (VIEW_WIDTH as u32, VIEW_HEIGHT as u32)
+    }
+
+    fn frame_rate(&self) -> Option<f64> {
+        self.nes.frame_rate()
     }
 
-    fn update(&mut self, frame: &mut [u8], _input: &WinitInputHelper, _dt: Duration) -> Result<()> {
-        self.nes.ppu.render_pattern_table(frame);
+    fn hotkeys(&self) -> Option<&Hotkeys> {
+        self.nes.hotkeys()
+    }
+
+    fn handle_hotkey(&mut self, hotkey: Hotkey, input: &WinitInputHelper) {
+        self.nes.handle_hotkey(hotkey, input);
+    }
+
+    fn update(&mut self, frame: &mut [u8], input: &WinitInputHelper, dt: Duration) -> Result<()> {
+        self.nes.update(&mut self.game_frame, input, dt)?;
+
+        let apu = &mut self.nes.apu;
+        self.view.push(&apu.take_scope());
+        let states = Channel::ALL.map(|channel| apu.channel_state(channel));
+        let audible = Channel::ALL.map(|channel| apu.audible(channel));
+        self.view.draw(frame, &states, &audible);
         Ok(())
     }
 }
@@ -116,10 +1582,13 @@ impl Ui for ShowPatternUi {
 mod tests {
     use super::*;
 
+    use std::cell::RefCell;
     use std::collections::VecDeque;
     use std::env;
     use std::path::PathBuf;
 
+    use crate::controller::{Buttons, Screen};
+    use crate::ppu::PpuScreen;
     use crate::rom::Rom;
 
     #[test]
@@ -131,7 +1600,7 @@ mod tests {
         // Load the "nestest" ROM, which is a comprehensive CPU test.
         let nestest = manifest_dir.join("data/nestest/nestest.nes");
         let rom = Rom::load(nestest).expect("Failed to load nestest ROM");
-        let mut nes = Nes::new(rom);
+        let mut nes = Nes::new(rom, Some(Region::Ntsc)).unwrap();
 
         // Manually set the starting address to 0xC000, which is the intended
         // entry point for running the ROM in a headless/automated context.
@@ -153,9 +1622,203 @@ mod tests {
         // Run the CPU until we reach the end of the log.
         while let Some(expected) = expected_pcs.pop_front() {
             assert_eq!(nes.cpu.registers().pc, expected);
-            let mut memory = Memory::new(&mut nes.ram, &mut nes.ppu, &mut nes.mapper);
+            let mut memory = Memory::new(
+                &mut nes.ram,
+                &mut nes.ppu,
+                &mut nes.apu,
+                &mut nes.controllers,
+                &mut nes.cart,
+            );
             // Don't check cycle timings.
             let _ = nes.cpu.step(&mut memory);
         }
     }
+
+    #[test]
+    fn save_state() {
+        let manifest_dir: PathBuf = env::var("CARGO_MANIFEST_DIR")
+            .expect("CARGO_MANIFEST_DIR environment variable not set")
+            .into();
+        let rom = Rom::load(manifest_dir.join("data/nestest/nestest.nes")).unwrap();
+        let mut nes = Nes::new(rom, Some(Region::Ntsc)).unwrap();
+        let mut frame = vec![0; FRAME_WIDTH * FRAME_HEIGHT * 4];
+        let mut run_frames = |nes: &mut Nes| {
+            for _ in 0..10 {
+                nes.run_one_frame_headless(&mut frame);
+            }
+            (frame.clone(), nes.save_state())
+        };
+
+        run_frames(&mut nes);
+        let state = nes.save_state();
+        let expected = run_frames(&mut nes);
+        nes.load_state(&state).unwrap();
+        assert_eq!(nes.save_state(), state);
+        assert!(run_frames(&mut nes) == expected);
+
+        nes.load_state(&state).unwrap();
+        let snapshot = nes.snapshot();
+        let expected = run_frames(&mut nes);
+        nes.restore(&snapshot).unwrap();
+        assert!(run_frames(&mut nes) == expected);
+
+        // A bad state is rejected without changing anything.
+        let current = nes.save_state();
+        assert!(nes.load_state(&state[..state.len() / 2]).is_err());
+        assert_eq!(nes.save_state(), current);
+    }
+
+    #[test]
+    fn movie_state() {
+        let manifest_dir: PathBuf = env::var("CARGO_MANIFEST_DIR")
+            .expect("CARGO_MANIFEST_DIR environment variable not set")
+            .into();
+        let rom = Rom::load(manifest_dir.join("data/nestest/nestest.nes")).unwrap();
+        let mut nes = Nes::new(rom, Some(Region::Ntsc)).unwrap();
+        let mut frame = vec![0; FRAME_WIDTH * FRAME_HEIGHT * 4];
+        let input = WinitInputHelper::new();
+        let mut movie = Movie::new(1);
+        movie.frames = vec![MovieFrame::default(); 20];
+        let mut other = Movie::new(1);
+        other.frames = movie.frames.clone();
+        other.frames[2].buttons[0] = Buttons::A;
+
+        let unbound = nes.save_state();
+        nes.play_movie(movie, MovieEnd::Continue);
+        for _ in 0..5 {
+            nes.run_one_frame(&mut frame, &input);
+        }
+        let state = nes.save_state();
+        for _ in 0..5 {
+            nes.run_one_frame(&mut frame, &input);
+        }
+
+        // Loading a state made earlier in the movie rewinds playback.
+        nes.load_state(&state).unwrap();
+        assert_eq!(nes.playback.as_ref().unwrap().position(), 5);
+
+        // States from before the movie, or from a different one, are refused.
+        assert!(nes.load_state(&unbound).is_err());
+        nes.play_movie(other, MovieEnd::Continue);
+        assert!(nes.load_state(&state).is_err());
+    }
+
+    /// Sink that keeps the samples it's given, and asks for the sample rate
+    /// to be scaled by the given amount, as a sink playing in real time
+    /// would to keep its buffer filled.
+    struct DriftingSink(Rc<RefCell<Vec<f32>>>, f64);
+
+    impl AudioSink for DriftingSink {
+        fn sample_rate(&self) -> u32 {
+            44100
+        }
+
+        fn push(&mut self, samples: &[f32]) -> Result<()> {
+            self.0.borrow_mut().extend_from_slice(samples);
+            Ok(())
+        }
+
+        fn adjusted_sample_rate(&self) -> f64 {
+            44100.0 * self.1
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        let manifest_dir: PathBuf = env::var("CARGO_MANIFEST_DIR")
+            .expect("CARGO_MANIFEST_DIR environment variable not set")
+            .into();
+        let rom = Rom::load(manifest_dir.join("data/nestest/nestest.nes")).unwrap();
+        let input = WinitInputHelper::new();
+        let run = |drift: f64| {
+            let samples = Rc::default();
+            let mut nes = Nes::new(rom.clone(), Some(Region::Ntsc)).unwrap();
+            nes.set_audio_sink(Box::new(DriftingSink(Rc::clone(&samples), drift)));
+            nes.set_deterministic();
+            let mut frame = vec![0; FRAME_WIDTH * FRAME_HEIGHT * 4];
+            for i in 0..10 {
+                let mut movie_frame = MovieFrame::default();
+                movie_frame.buttons[0] = if i % 2 == 0 {
+                    Buttons::A
+                } else {
+                    Buttons::START
+                };
+                nes.run_movie_frame(&mut frame, movie_frame, &input);
+            }
+            (frame, nes.save_state(), samples.take())
+        };
+
+        // The sink's rate control doesn't change the output.
+        let expected = run(1.0);
+        assert!(!expected.2.is_empty());
+        assert!(run(1.01) == expected);
+    }
+
+    #[test]
+    fn region_from_rom() {
+        let manifest_dir: PathBuf = env::var("CARGO_MANIFEST_DIR")
+            .expect("CARGO_MANIFEST_DIR environment variable not set")
+            .into();
+        let rom = Rom::load(manifest_dir.join("data/nestest/nestest.nes")).unwrap();
+        for region in [Region::Ntsc, Region::Pal] {
+            let mut rom = rom.clone();
+            rom.header.region = Some(region);
+            let mut nes = Nes::new(rom, None).unwrap();
+            assert_eq!(nes.region, region);
+
+            // The PPU keeps time with the CPU, so each frame leaves it back
+            // at the start of vertical blank.
+            let mut frame = vec![0; FRAME_WIDTH * FRAME_HEIGHT * 4];
+            for _ in 0..3 {
+                nes.run_one_frame_headless(&mut frame);
+                let (scanline, dot) = PpuScreen::new(&nes.ppu, &mut nes.cart).beam();
+                assert_eq!(scanline, 241, "{:?}", region);
+                assert!(dot <= 1, "{:?}", region);
+            }
+        }
+    }
+
+    #[test]
+    fn breakpoints() {
+        let manifest_dir: PathBuf = env::var("CARGO_MANIFEST_DIR")
+            .expect("CARGO_MANIFEST_DIR environment variable not set")
+            .into();
+        let rom = Rom::load(manifest_dir.join("data/nestest/nestest.nes")).unwrap();
+        let mut nes = Nes::new(rom.clone(), Some(Region::Ntsc)).unwrap();
+        let mut frame = vec![0; FRAME_WIDTH * FRAME_HEIGHT * 4];
+        let start = nes.cpu.registers().pc;
+
+        // Emulation stops before the instruction, and stays stopped.
+        nes.add_breakpoint(start);
+        for _ in 0..2 {
+            nes.run_one_frame_headless(&mut frame);
+            assert!(nes.is_stopped());
+            assert_eq!(nes.cpu.registers().pc, start);
+        }
+
+        // Stepping runs just that instruction.
+        nes.step_instruction();
+        nes.run_one_frame_headless(&mut frame);
+        assert!(nes.is_stopped());
+        assert_ne!(nes.cpu.registers().pc, start);
+        assert_eq!(nes.frame_count, 0);
+
+        // A frame that stopped partway through is finished as if it never
+        // stopped, including after saving and loading a state partway
+        // through it.
+        let state = nes.save_state();
+        nes.remove_breakpoint(start);
+        nes.resume();
+        nes.run_one_frame_headless(&mut frame);
+        assert!(!nes.is_stopped());
+        let mut expected = Nes::new(rom, Some(Region::Ntsc)).unwrap();
+        let mut expected_frame = frame.clone();
+        expected.run_one_frame_headless(&mut expected_frame);
+        assert_eq!(nes.save_state(), expected.save_state());
+        assert!(frame == expected_frame);
+
+        nes.load_state(&state).unwrap();
+        nes.run_one_frame_headless(&mut frame);
+        assert_eq!(nes.save_state(), expected.save_state());
+    }
 }
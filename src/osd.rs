@@ -0,0 +1,92 @@
+//! On-screen display of short messages about what the emulator is doing
+//! (e.g., "State 3 saved").
+//!
+//! Messages are queued, and each is shown for a couple of seconds in the top
+//! left corner of the frame, with newer messages stacked below older ones. If
+//! too many arrive at once, the oldest are dropped early.
+
+use std::collections::VecDeque;
+
+use crate::font;
+
+/// How long to show each message for, in frames (about 2 seconds).
+const MESSAGE_FRAMES: u32 = 120;
+
+/// The most messages to show at once.
+const MAX_MESSAGES: usize = 4;
+
+const MARGIN: usize = 4;
+
+struct Message {
+    text: String,
+    frames_left: u32,
+}
+
+#[derive(Default)]
+pub struct Osd {
+    messages: VecDeque<Message>,
+}
+
+impl Osd {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a message. If it's the same as the newest message (e.g., from
+    /// pressing a hotkey repeatedly), that one is shown for longer instead.
+    pub fn push(&mut self, text: String) {
+        if let Some(newest) = self.messages.back_mut() {
+            if newest.text == text {
+                newest.frames_left = MESSAGE_FRAMES;
+                return;
+            }
+        }
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(Message {
+            text,
+            frames_left: MESSAGE_FRAMES,
+        });
+    }
+
+    /// Draw the messages over a frame of the given width, and count down the
+    /// time left to show them.
+    pub fn draw(&mut self, frame: &mut [u8], frame_width: usize) {
+        let mut top = MARGIN;
+        for message in &mut self.messages {
+            font::draw_label(frame, frame_width, MARGIN, top, &message.text);
+            top += font::label_size(&message.text).1;
+            message.frames_left -= 1;
+        }
+        self.messages.retain(|message| message.frames_left > 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_osd() {
+        let mut osd = Osd::new();
+        let mut frame = vec![0; 64 * 64 * 4];
+        osd.push("A".to_string());
+        for _ in 0..MESSAGE_FRAMES - 1 {
+            osd.draw(&mut frame, 64);
+        }
+        osd.push("B".to_string());
+        osd.push("B".to_string());
+        assert_eq!(osd.messages.len(), 2);
+
+        osd.draw(&mut frame, 64);
+        let texts: Vec<&str> = osd.messages.iter().map(|m| &m.text[..]).collect();
+        assert_eq!(texts, ["B"]);
+
+        for c in ["C", "D", "E", "F"] {
+            osd.push(c.to_string());
+        }
+        let texts: Vec<&str> = osd.messages.iter().map(|m| &m.text[..]).collect();
+        assert_eq!(texts, ["C", "D", "E", "F"]);
+    }
+}
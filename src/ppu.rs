@@ -1,6 +1,13 @@
 use std::fmt;
+use std::str::FromStr;
 
-use crate::mem::{Address, Bus};
+use anyhow::{bail, ensure, Context, Error, Result};
+
+use crate::colors::{self, Colors};
+use crate::controller::Screen;
+use crate::mem::Address;
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
 
 pub const VRAM_SIZE: usize = 2048;
 
@@ -25,16 +32,14 @@ const PPU_REG_ADDR_BITS: u8 = 3;
 pub const FRAME_WIDTH: usize = 256;
 pub const FRAME_HEIGHT: usize = 240;
 
-/// Array of 3-byte RGB color values corresponding to the colors the NES would
-/// output for a given 6-bit color index. Note that in a real NES, the PPU
-/// directly outputs an analog video signal, which means that there is no exact
-/// mapping between NES color indexes and specific RGB values. As such, the
-/// RGB values specified here are just approximations intended to best reproduce
-/// what each color would have looked like when displayed by a TV set.
-static NES_COLORS: &[u8] = include_bytes!("../data/FBX-Final.pal");
+/// The PPU outputs 341 dots per scanline. Each frame has 240 visible
+/// scanlines, a post-render scanline, the scanlines of vertical blank (whose
+/// number depends on the region), and a pre-render scanline.
+const DOTS_PER_SCANLINE: u16 = 341;
+const VBLANK_SCANLINE: u16 = 241;
 
 /// Hardcoded greyscale palette used for testing.
-const GREYSCALE_PALETTE: Palette = Palette {
+pub const GREYSCALE_PALETTE: Palette = Palette {
     background: 0x0F, // 0 0 0
     color1: 0x00,     // 84 84 84
     color2: 0x10,     // 152 150 152
@@ -101,7 +106,7 @@ impl From<Address> for PpuRegister {
     }
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 struct Registers {
     ctrl: u8,
     mask: u8,
@@ -126,36 +131,64 @@ pub trait PpuBus {
     fn ppu_load(&mut self, vram: &Vram, palette: &[u8; 32], addr: Address) -> u8;
 
     fn ppu_store(&mut self, vram: &mut Vram, palette: &mut [u8; 32], addr: Address, value: u8);
+
+    /// Called with the pattern table addresses that the PPU fetches from while
+    /// rendering, after the fetch itself has gone through `ppu_load`. Some
+    /// mappers snoop on these, either to count scanlines by watching address
+    /// line A12 (MMC3) or to switch banks when particular tiles are drawn
+    /// (MMC2). Accesses made by the CPU via PPUDATA are not reported.
+    fn ppu_observe(&mut self, _addr: Address) {}
 }
 
-pub struct Ppu<M> {
+#[derive(Clone)]
+pub struct Ppu {
     registers: Registers,
+    scanline: u16,
+    dot: u16,
+    /// Number of scanlines per frame, which depends on the region.
+    scanlines_per_frame: u16,
     vram: Vram,
     oam: [u8; 256],
     palette: [u8; 32],
-    mapper: M,
+    /// The RGB values that color indexes are drawn with. This is a setting
+    /// of the host's rather than part of the console's state, so it isn't
+    /// saved in save states.
+    colors: Colors,
 }
 
-impl<M: PpuBus> Ppu<M> {
-    pub fn with_mapper(mapper: M) -> Self {
+/// Methods that access the PPU's address space take the cartridge as an
+/// argument, since it decides where each access goes.
+impl Ppu {
+    pub fn new(region: Region) -> Self {
         Self {
             registers: Registers::default(),
+            scanline: VBLANK_SCANLINE,
+            dot: 0,
+            scanlines_per_frame: region.scanlines_per_frame(),
             vram: Vram::new(),
             oam: [0; 256],
             palette: [0; 32],
-            mapper,
+            colors: Colors::default(),
         }
     }
 
+    pub fn colors(&self) -> &Colors {
+        &self.colors
+    }
+
+    /// Draw with the given colors from now on.
+    pub fn set_colors(&mut self, colors: Colors) {
+        self.colors = colors;
+    }
+
     /// Load a value from PPU memory via the mapper.
-    fn mapper_load(&mut self, addr: Address) -> u8 {
-        self.mapper.ppu_load(&self.vram, &self.palette, addr)
+    fn mapper_load(&self, cart: &mut dyn PpuBus, addr: Address) -> u8 {
+        cart.ppu_load(&self.vram, &self.palette, addr)
     }
 
     /// Store a value to PPU memory via the mapper.
-    fn mapper_store(&mut self, addr: Address, value: u8) {
-        self.mapper
-            .ppu_store(&mut self.vram, &mut self.palette, addr, value);
+    fn mapper_store(&mut self, cart: &mut dyn PpuBus, addr: Address, value: u8) {
+        cart.ppu_store(&mut self.vram, &mut self.palette, addr, value);
     }
 
     /// Replace the entire contents of OAM with the given data.
@@ -163,26 +196,95 @@ impl<M: PpuBus> Ppu<M> {
         self.oam = oam_data;
     }
 
-    pub fn tick(&mut self, frame: &mut [u8]) {
-        self.render_name_table(frame, NAMETABLES[0]);
+    /// Advance the PPU by one dot. The PPU runs 3 times faster than the CPU
+    /// on NTSC, and 3.2 times faster on PAL.
+    ///
+    /// Rendering isn't emulated dot-by-dot yet, but the mapper is still told
+    /// which pattern table the PPU would be fetching from at the points in
+    /// each scanline where that changes.
+    pub fn step(&mut self, cart: &mut dyn PpuBus) {
+        let rendering = self.registers.mask & 0x18 > 0;
+        let render_line =
+            self.scanline < FRAME_HEIGHT as u16 || self.scanline == self.scanlines_per_frame - 1;
+        if rendering && render_line {
+            match self.dot {
+                // Background tile fetches for the current and next scanline.
+                1 | 321 => cart.ppu_observe(self.bg_pattern_table()),
+                // Sprite tile fetches for the next scanline.
+                257 => cart.ppu_observe(self.sprite_pattern_table()),
+                _ => {}
+            }
+        }
+
+        self.dot += 1;
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline = (self.scanline + 1) % self.scanlines_per_frame;
+        }
+    }
+
+    /// Move to the start of vertical blank. Since the CPU and PPU clocks don't
+    /// divide evenly into a frame, this is used to keep the PPU's position in
+    /// sync with the frame boundaries used by the rest of the emulator.
+    pub fn start_vblank(&mut self) {
+        self.scanline = VBLANK_SCANLINE;
+        self.dot = 0;
+    }
+
+    fn bg_pattern_table(&self) -> Address {
+        if self.registers.ctrl & 0x10 > 0 {
+            Address(0x1000)
+        } else {
+            Address(0)
+        }
+    }
+
+    fn sprite_pattern_table(&self) -> Address {
+        // 8x16 sprites select their pattern table per sprite. Most games that
+        // use them put sprites in the second table, so assume that.
+        if self.registers.ctrl & 0x28 > 0 {
+            Address(0x1000)
+        } else {
+            Address(0)
+        }
+    }
+
+    pub fn tick(&mut self, cart: &mut dyn PpuBus, frame: &mut [u8]) {
+        self.render_name_table(cart, frame, NAMETABLES[0]);
     }
 
     /// Render the specified nametable.
-    pub fn render_name_table(&mut self, frame: &mut [u8], table: Address) {
+    pub fn render_name_table(&mut self, cart: &mut dyn PpuBus, frame: &mut [u8], table: Address) {
         for pos in 0..960 {
-            let tile_num = self.mapper_load(table + pos as u16);
-            let tile = self.load_tile(Address(0), tile_num);
+            let tile_num = self.mapper_load(cart, table + pos as u16);
+            let tile = self.fetch_tile(cart, Address(0), tile_num);
 
             let attr_table = table + ATTRIBUTE_TABLE_OFFSET;
-            let attr = self.get_attribute(attr_table, tile_num);
-            let palette = self.load_palette(attr, false);
+            let attr = self.get_attribute(cart, attr_table, tile_num);
+            let palette = self.load_palette(cart, attr, false);
 
-            tile.draw(frame, pos, palette);
+            tile.draw(frame, pos, palette, &self.colors);
         }
     }
 
+    /// Get the color index of the background pixel at the given position in
+    /// the frame, as `render_name_table` would draw it from the PPU's current
+    /// state.
+    fn pixel_color(&self, cart: &mut dyn PpuBus, x: usize, y: usize) -> u8 {
+        let table = NAMETABLES[0];
+        let pos = (y / 8 * (FRAME_WIDTH / 8) + x / 8) as u16;
+        let tile_num = self.mapper_load(cart, table + pos);
+        let tile = self.load_tile(cart, Address(0), tile_num);
+
+        let attr_table = table + ATTRIBUTE_TABLE_OFFSET;
+        let attr = self.get_attribute(cart, attr_table, tile_num);
+        let palette = self.load_palette(cart, attr, false);
+
+        tile.get_pixel(x % 8, y % 8).color(palette)
+    }
+
     /// Get the palette index for a tile from the given attribute table.
-    pub fn get_attribute(&mut self, table: Address, tile_num: u8) -> u8 {
+    pub fn get_attribute(&self, cart: &mut dyn PpuBus, table: Address, tile_num: u8) -> u8 {
         // Get position of the tile within the nametable's 32x30 tile grid.
         let (tile_x, tile_y) = tile_coords(tile_num);
 
@@ -194,7 +296,7 @@ impl<M: PpuBus> Ppu<M> {
         let attr_y = tile_y as u16 / 4;
         let attr_num = attr_y * 8 + attr_x;
 
-        let attr_byte = self.mapper_load(table + attr_num);
+        let attr_byte = self.mapper_load(cart, table + attr_num);
 
         // Identify which quadrant (16x16 block) this tile falls into within the
         // byte, and obtain the attribute by shifting the value accordingly.
@@ -213,26 +315,109 @@ impl<M: PpuBus> Ppu<M> {
     }
 
     /// Read the pattern tables from the PPU's address space and render them as
-    /// a pair of 128x128 greyscale grids. The output buffer must be at least
-    /// 16 KiB in size in order to store 2 * 128 * 128 * 4 bytes (each pixel is
-    /// stored as a 4-byte RGBA sequence).
-    pub fn render_pattern_table(&mut self, frame: &mut [u8]) {
+    /// a pair of 128x128 grids in the given palette. The output buffer must be
+    /// at least 16 KiB in size in order to store 2 * 128 * 128 * 4 bytes (each
+    /// pixel is stored as a 4-byte RGBA sequence).
+    pub fn render_pattern_table(&self, cart: &mut dyn PpuBus, frame: &mut [u8], palette: Palette) {
         assert!(frame.len() >= 0x4000);
         for table in 0..2 {
             // Get address of the nametable we're using.
             let table_addr = Address(table as u16 * 0x1000u16);
             for tile_num in 0..256 {
-                // Get tile position in grid.
-                let tile_x = tile_num % 16;
-                let tile_y = tile_num / 16;
+                let (x, y) = pattern_table_pos(table, tile_num);
+                let tile = self.load_tile(cart, table_addr, tile_num as u8);
+                tile.draw_at(frame, FRAME_WIDTH, x, y, palette, &self.colors);
+            }
+        }
+    }
+
+    /// The PPU's registers and position in the frame, as text for debug
+    /// views.
+    pub fn describe(&self) -> String {
+        let regs = &self.registers;
+        format!(
+            "CTRL {:02X} MASK {:02X} STATUS {:02X} OAMADDR {:02X} LINE {} DOT {}",
+            regs.ctrl, regs.mask, regs.status, regs.oam_addr, self.scanline, self.dot
+        )
+    }
 
-                // Get desired coords of upper left pixel of tile.
-                let x = tile_x * 8 + (128 * table);
-                let y = tile_y * 8;
+    /// Render all four nametables in a 2x2 grid, as the background would be
+    /// drawn from each of them. The output buffer must be twice the size of
+    /// a frame in each direction.
+    pub fn render_name_tables(&self, cart: &mut dyn PpuBus, frame: &mut [u8]) {
+        let width = FRAME_WIDTH * 2;
+        assert!(frame.len() >= width * FRAME_HEIGHT * 2 * 4);
+        for (i, &table) in NAMETABLES.iter().enumerate() {
+            let left = i % 2 * FRAME_WIDTH;
+            let top = i / 2 * FRAME_HEIGHT;
+            for pos in 0..960u16 {
+                let tile_num = self.mapper_load(cart, table + pos);
+                let tile = self.load_tile(cart, self.bg_pattern_table(), tile_num);
+
+                // Each attribute byte covers a 4x4 tile block, with 2 bits for
+                // each 2x2 quadrant.
+                let (tile_x, tile_y) = (pos % 32, pos / 32);
+                let attr_addr = table + ATTRIBUTE_TABLE_OFFSET + tile_y / 4 * 8 + tile_x / 4;
+                let shift = (tile_y / 2 % 2) * 4 + (tile_x / 2 % 2) * 2;
+                let attr = (self.mapper_load(cart, attr_addr) >> shift) & 3;
+                let palette = self.load_palette(cart, attr, false);
+
+                let x = left + tile_x as usize * 8;
+                let y = top + tile_y as usize * 8;
+                tile.draw_at(frame, width, x, y, palette, &self.colors);
+            }
+        }
+    }
+
+    /// Render the 32 palette entries as two rows of 16x16 swatches, with the
+    /// background palettes on top and the sprite palettes below. The output
+    /// buffer must be at least 256x32.
+    pub fn render_palettes(&self, frame: &mut [u8]) {
+        const SWATCH: usize = 16;
+        let width = SWATCH * 16;
+        for (i, &color) in self.palette.iter().enumerate() {
+            let rgba = self.colors.rgba(color);
+            let left = i % 16 * SWATCH;
+            let top = i / 16 * SWATCH;
+            for y in top..top + SWATCH {
+                for x in left..left + SWATCH {
+                    let offset = (y * width + x) * 4;
+                    frame[offset..offset + 4].copy_from_slice(&rgba);
+                }
+            }
+        }
+    }
 
-                // Load and draw tile.
-                let tile = self.load_tile(table_addr, tile_num as u8);
-                tile.draw_at(frame, FRAME_WIDTH, x, y, GREYSCALE_PALETTE);
+    /// Render the 64 sprites in OAM in an 8x8 grid of 16x16 cells, in their
+    /// own palettes. The output buffer must be at least 128x128.
+    pub fn render_sprites(&self, cart: &mut dyn PpuBus, frame: &mut [u8]) {
+        const CELL: usize = 16;
+        let width = CELL * 8;
+        frame[..width * width * 4].fill(0);
+        let tall = self.registers.ctrl & 0x20 > 0;
+        for (i, sprite) in self.oam.chunks_exact(4).enumerate() {
+            let (tile_num, attr) = (sprite[1], sprite[2]);
+            let palette = self.load_palette(cart, attr & 3, true);
+            let left = i % 8 * CELL + 4;
+            let top = i / 8 * CELL;
+            if tall {
+                // 8x16 sprites take their pattern table from bit 0 of the
+                // tile number, and use a pair of tiles.
+                let table = Address((tile_num as u16 & 1) * 0x1000);
+                for half in 0..2 {
+                    let tile = self.load_tile(cart, table, (tile_num & 0xFE) + half);
+                    tile.draw_at(
+                        frame,
+                        width,
+                        left,
+                        top + half as usize * 8,
+                        palette,
+                        &self.colors,
+                    );
+                }
+            } else {
+                let tile = self.load_tile(cart, self.sprite_pattern_table(), tile_num);
+                tile.draw_at(frame, width, left, top + 4, palette, &self.colors);
             }
         }
     }
@@ -243,30 +428,41 @@ impl<M: PpuBus> Ppu<M> {
     /// These two bits are not stored adjacently; instead, the low bits of the
     /// tile are stored first, followed by the high bits. As such, this method
     /// returns 2 arrays containing the low bits and high bits respectively.
-    fn load_tile(&mut self, table: Address, tile_num: u8) -> Tile {
+    fn load_tile(&self, cart: &mut dyn PpuBus, table: Address, tile_num: u8) -> Tile {
         let mut low = [0u8; 8];
         let mut high = [0u8; 8];
         let base = table + tile_num as u16 * 16;
         for i in 0..8 {
-            low[i] = self.mapper_load(base + i as u16);
-            high[i] = self.mapper_load(base + i as u16 + 8u16);
+            low[i] = self.mapper_load(cart, base + i as u16);
+            high[i] = self.mapper_load(cart, base + i as u16 + 8u16);
         }
         Tile { low, high }
     }
 
+    /// Load a tile for rendering. Unlike `load_tile`, this lets the mapper
+    /// observe the fetch, so it should not be used for debug views.
+    fn fetch_tile(&self, cart: &mut dyn PpuBus, table: Address, tile_num: u8) -> Tile {
+        let tile = self.load_tile(cart, table, tile_num);
+        let base = table + tile_num as u16 * 16;
+        for i in 0..16u16 {
+            cart.ppu_observe(base + i);
+        }
+        tile
+    }
+
     /// Load a background or sprite palette from the PPU's memory.
-    fn load_palette(&mut self, palette_num: u8, sprite: bool) -> Palette {
+    pub fn load_palette(&self, cart: &mut dyn PpuBus, palette_num: u8, sprite: bool) -> Palette {
         // The palette number is a 2-bit value.
         assert!(palette_num < 5);
 
         let palettes = if sprite { SPRITE_PALETTES } else { BG_PALETTES };
 
         let addr = palettes[palette_num as usize];
-        let color1 = self.mapper_load(addr);
-        let color2 = self.mapper_load(addr + 1u16);
-        let color3 = self.mapper_load(addr + 2u16);
+        let color1 = self.mapper_load(cart, addr);
+        let color2 = self.mapper_load(cart, addr + 1u16);
+        let color3 = self.mapper_load(cart, addr + 2u16);
 
-        let background = self.mapper_load(BG_COLOR);
+        let background = self.mapper_load(cart, BG_COLOR);
 
         Palette {
             background,
@@ -275,13 +471,11 @@ impl<M: PpuBus> Ppu<M> {
             color3,
         }
     }
-}
 
-/// The CPU can interact with the PPU via its registers, which are mapped into
-/// the CPU's address space. Only the last 3 bits of the address are decoded,
-/// meaning that the registers are mirrored every 8-bits.
-impl<M: PpuBus> Bus for Ppu<M> {
-    fn load(&mut self, addr: Address) -> u8 {
+    /// Read one of the PPU's registers, which are mapped into the CPU's
+    /// address space. Only the last 3 bits of the address are decoded,
+    /// meaning that the registers are mirrored every 8 bytes.
+    pub fn read_register(&mut self, cart: &mut dyn PpuBus, addr: Address) -> u8 {
         use PpuRegister::*;
 
         let value = match addr.into() {
@@ -305,7 +499,7 @@ impl<M: PpuBus> Bus for Ppu<M> {
                 let addr = read_ppuaddr(&self.registers.addr);
                 if addr < PALETTE_BASE_ADDR {
                     // Read from PPU address space via mapper.
-                    self.mapper_load(addr)
+                    self.mapper_load(cart, addr)
                 } else {
                     let i = addr.alias(PALETTE_ADDR_BITS).as_usize();
                     self.palette[i]
@@ -330,7 +524,8 @@ impl<M: PpuBus> Bus for Ppu<M> {
         value
     }
 
-    fn store(&mut self, addr: Address, value: u8) {
+    /// Write to one of the PPU's registers.
+    pub fn write_register(&mut self, cart: &mut dyn PpuBus, addr: Address, value: u8) {
         use PpuRegister::*;
 
         log::debug!(
@@ -355,7 +550,7 @@ impl<M: PpuBus> Bus for Ppu<M> {
             Data => {
                 let addr = read_ppuaddr(&self.registers.addr);
                 if addr < PALETTE_BASE_ADDR {
-                    self.mapper_store(addr, value);
+                    self.mapper_store(cart, addr, value);
                 } else {
                     let i = addr.alias(PALETTE_ADDR_BITS).as_usize();
                     self.palette[i] = value;
@@ -371,6 +566,7 @@ impl<M: PpuBus> Bus for Ppu<M> {
 /// by mapping the remainder of the VRAM address range to the cartridge itself
 /// (which presumably has additional RAM chips). Otherwise, the contents of VRAM
 /// are mirrored to fill up the available address range for nametables.
+#[derive(Clone)]
 pub struct Vram(pub [u8; VRAM_SIZE]);
 
 impl Vram {
@@ -379,6 +575,30 @@ impl Vram {
     }
 }
 
+/// The PPU's output as a light gun sees it, with the beam at the PPU's current
+/// position.
+pub struct PpuScreen<'a> {
+    ppu: &'a Ppu,
+    cart: &'a mut dyn PpuBus,
+}
+
+impl<'a> PpuScreen<'a> {
+    pub fn new(ppu: &'a Ppu, cart: &'a mut dyn PpuBus) -> Self {
+        Self { ppu, cart }
+    }
+}
+
+impl Screen for PpuScreen<'_> {
+    fn beam(&self) -> (u16, u16) {
+        (self.ppu.scanline, self.ppu.dot)
+    }
+
+    fn brightness(&mut self, x: usize, y: usize) -> u8 {
+        let color = self.ppu.pixel_color(self.cart, x, y);
+        colors::reference_luma(color)
+    }
+}
+
 // Emulate the behavior of the PPUADDR and PPUSCROLL registers, which require
 // the CPU to write 2 bytes. Since each register is only mapped to a single
 // byte of the CPU's address space, the CPU must perform 2 writes in succession.
@@ -431,10 +651,11 @@ impl Tile {
         pos_x: usize,
         pos_y: usize,
         palette: Palette,
+        colors: &Colors,
     ) {
         for x in 0..8 {
             for y in 0..8 {
-                let rgba = self.get_pixel(x, y).to_rgba(palette);
+                let rgba = colors.rgba(self.get_pixel(x, y).color(palette));
                 let pos = (pos_y + y) * frame_width_px + pos_x + x;
                 let offset = pos * 4; // 4 bytes per RGBA pixel.
                 frame[offset..offset + 4].copy_from_slice(&rgba[..]);
@@ -446,10 +667,10 @@ impl Tile {
     ///
     /// Assumes that the screen is a 32 x 30 tile grid and the position is
     /// specified as the tile's index in that grid (from 0 to 960).
-    fn draw(&self, frame: &mut [u8], pos: usize, palette: Palette) {
+    fn draw(&self, frame: &mut [u8], pos: usize, palette: Palette, colors: &Colors) {
         let pos_x = pos % (FRAME_WIDTH / 8) * 8;
         let pos_y = pos / (FRAME_WIDTH / 8) * 8;
-        self.draw_at(frame, FRAME_WIDTH, pos_x, pos_y, palette);
+        self.draw_at(frame, FRAME_WIDTH, pos_x, pos_y, palette, colors);
     }
 }
 
@@ -473,28 +694,135 @@ impl Pixel {
             _ => unreachable!(),
         }
     }
-
-    /// Get this pixel's RGBA value using the given palette.
-    fn to_rgba(&self, palette: Palette) -> [u8; 4] {
-        let color = self.color(palette) as usize;
-        let mut rgba = [0xFFu8; 4];
-        rgba[..3].copy_from_slice(&NES_COLORS[color * 3..color * 3 + 3]);
-        rgba
-    }
 }
 
 /// A palette value, consisting of a background color (which is shared by all
 /// palettes) and 3 other colors. The color values are used as indexes for
-/// looking up the color's RGB value from a NES palette file.
+/// looking up the color's RGB value (see the `colors` module).
+///
+/// Can be parsed from four comma-separated hex color indexes (e.g.,
+/// "0F,00,10,30").
 #[derive(Debug, Copy, Clone)]
-struct Palette {
+pub struct Palette {
     background: u8,
     color1: u8,
     color2: u8,
     color3: u8,
 }
 
+impl FromStr for Palette {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let colors = s
+            .split(',')
+            .map(|color| {
+                let color = u8::from_str_radix(color.trim(), 16)
+                    .with_context(|| format!("Invalid color index: {:?}", color))?;
+                ensure!(color < 0x40, "Color index out of range: {:02X}", color);
+                Ok(color)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let &[background, color1, color2, color3] = &colors[..] else {
+            bail!("Expected 4 colors, got {}", colors.len());
+        };
+        Ok(Self {
+            background,
+            color1,
+            color2,
+            color3,
+        })
+    }
+}
+
+/// Render every tile in a block of CHR data (such as a ROM's entire CHR ROM,
+/// rather than just the banks that are mapped in) as a sheet of tiles,
+/// `tiles_per_row` wide. Returns the sheet's width and height in pixels, and
+/// its pixels as 4-byte RGBA sequences.
+pub fn render_chr_sheet(
+    chr: &[u8],
+    tiles_per_row: usize,
+    palette: Palette,
+    colors: &Colors,
+) -> (usize, usize, Vec<u8>) {
+    let num_tiles = chr.len() / 16;
+    let width = tiles_per_row * 8;
+    let height = num_tiles.div_ceil(tiles_per_row) * 8;
+    let mut frame = vec![0; width * height * 4];
+    for (i, bytes) in chr.chunks_exact(16).enumerate() {
+        let mut tile = Tile {
+            low: [0; 8],
+            high: [0; 8],
+        };
+        tile.low.copy_from_slice(&bytes[..8]);
+        tile.high.copy_from_slice(&bytes[8..]);
+        let x = i % tiles_per_row * 8;
+        let y = i / tiles_per_row * 8;
+        tile.draw_at(&mut frame, width, x, y, palette, colors);
+    }
+    (width, height, frame)
+}
+
+/// Render an 8 KiB bank of CHR data the way `Ppu::render_pattern_table`
+/// renders the pattern tables mapped into the PPU, so that banks which aren't
+/// mapped in can be viewed too.
+pub fn render_chr_bank(chr: &[u8], frame: &mut [u8], palette: Palette, colors: &Colors) {
+    for (i, bytes) in chr.chunks_exact(16).take(512).enumerate() {
+        let mut tile = Tile {
+            low: [0; 8],
+            high: [0; 8],
+        };
+        tile.low.copy_from_slice(&bytes[..8]);
+        tile.high.copy_from_slice(&bytes[8..]);
+        let (x, y) = pattern_table_pos(i / 256, i % 256);
+        tile.draw_at(frame, FRAME_WIDTH, x, y, palette, colors);
+    }
+}
+
+/// Get the position of the upper left pixel of a tile when the two pattern
+/// tables are drawn side by side as 16x16 tile grids.
+fn pattern_table_pos(table: usize, tile_num: usize) -> (usize, usize) {
+    let tile_x = tile_num % 16;
+    let tile_y = tile_num / 16;
+    (tile_x * 8 + 128 * table, tile_y * 8)
+}
+
 /// Get the coordinates for the specified tile within a nametable.
 fn tile_coords(tile_num: u8) -> (u8, u8) {
     (tile_num % 32, tile_num / 32)
 }
+
+impl Savestate for Ppu {
+    fn save_state(&self, w: &mut StateWriter) {
+        let regs = &self.registers;
+        w.write(&regs.ctrl);
+        w.write(&regs.mask);
+        w.write(&regs.status);
+        w.write(&regs.oam_addr);
+        w.write(&regs.scroll);
+        w.write(&regs.addr);
+        w.write(&regs.most_recent_value);
+        w.write(&self.scanline);
+        w.write(&self.dot);
+        w.write(&self.vram.0);
+        w.write(&self.oam);
+        w.write(&self.palette);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        let regs = &mut self.registers;
+        regs.ctrl = r.read()?;
+        regs.mask = r.read()?;
+        regs.status = r.read()?;
+        regs.oam_addr = r.read()?;
+        regs.scroll = r.read()?;
+        regs.addr = r.read()?;
+        regs.most_recent_value = r.read()?;
+        self.scanline = r.read()?;
+        self.dot = r.read()?;
+        self.vram.0 = r.read()?;
+        self.oam = r.read()?;
+        self.palette = r.read()?;
+        Ok(())
+    }
+}
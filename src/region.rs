@@ -0,0 +1,137 @@
+//! Differences in timing between NTSC and PAL consoles.
+//!
+//! PAL consoles run their CPU (and thus their APU) at a lower clock rate, and
+//! produce 50 frames per second instead of 60. Since the APU's pitches and
+//! tempos are derived from the CPU clock, the PAL APU also uses different
+//! lookup tables for some of its timers so that music plays (approximately)
+//! in tune. The PAL PPU outputs 312 scanlines per frame rather than 262, all
+//! of the extra ones in vertical blank, and runs 3.2 dots per CPU cycle
+//! rather than 3.
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+}
+
+impl Region {
+    /// Clock rate of the CPU, which also drives the APU.
+    pub fn cpu_clock_hz(self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_773.0,
+            Region::Pal => 1_662_607.0,
+        }
+    }
+
+    /// Number of CPU cycles per frame, rounded up. (The PPU renders a frame
+    /// every 29780.5 CPU cycles on NTSC, and every 33247.5 on PAL.)
+    pub fn cpu_cycles_per_frame(self) -> usize {
+        match self {
+            Region::Ntsc => 29781,
+            Region::Pal => 33248,
+        }
+    }
+
+    /// Number of scanlines the PPU outputs per frame, including 20 scanlines
+    /// of vertical blank on NTSC and 70 on PAL.
+    pub fn scanlines_per_frame(self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal => 312,
+        }
+    }
+
+    /// Number of dots to run the PPU for on the given CPU cycle of a frame.
+    /// The PAL PPU runs 16 dots for every 5 CPU cycles, so every fifth cycle
+    /// gets an extra dot.
+    pub fn ppu_dots(self, cpu_cycle: usize) -> usize {
+        match self {
+            Region::Ntsc => 3,
+            Region::Pal if cpu_cycle % 5 == 4 => 4,
+            Region::Pal => 3,
+        }
+    }
+
+    /// Number of frames the PPU renders per second (about 60.0988 on NTSC,
+    /// and 50.007 on PAL).
+    pub fn frame_rate(self) -> f64 {
+        let cycles_per_frame = match self {
+            Region::Ntsc => 29780.5,
+            Region::Pal => 33247.5,
+        };
+        self.cpu_clock_hz() / cycles_per_frame
+    }
+}
+
+/// Region tags used in the file names of GoodNES and No-Intro sets for
+/// countries with PAL consoles.
+const PAL_TAGS: &[&str] = &[
+    "E",
+    "Europe",
+    "PAL",
+    "A",
+    "Australia",
+    "G",
+    "Germany",
+    "F",
+    "France",
+    "I",
+    "Italy",
+    "S",
+    "Spain",
+    "Sw",
+    "Sweden",
+    "Nl",
+    "Netherlands",
+    "Scandinavia",
+    "UK",
+];
+
+/// Guess the region a ROM was made for from the tags in its file name, e.g.
+/// "Elite (Europe).nes". Returns `None` if there are no region tags, or if
+/// the ROM is also for NTSC countries, since it should then work on either.
+pub fn from_file_name(name: &str) -> Option<Region> {
+    let mut found = None;
+    for tag in name.split('(').skip(1).filter_map(|s| s.split(')').next()) {
+        for country in tag.split(',').map(str::trim) {
+            if PAL_TAGS.contains(&country) {
+                found = Some(Region::Pal);
+            } else if matches!(country, "U" | "USA" | "J" | "Japan" | "JU" | "World") {
+                return None;
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_file_name() {
+        assert_eq!(from_file_name("Elite (Europe).nes"), Some(Region::Pal));
+        assert_eq!(from_file_name("Elite (E) [!].nes"), Some(Region::Pal));
+        assert_eq!(from_file_name("Tetris (USA, Europe).nes"), None);
+        assert_eq!(from_file_name("Tetris (U) [!].nes"), None);
+        assert_eq!(from_file_name("Tetris.nes"), None);
+    }
+
+    #[test]
+    fn test_ppu_dots() {
+        // Running the PPU for a frame's worth of CPU cycles should take it
+        // (to within a dot) through a whole frame.
+        for region in [Region::Ntsc, Region::Pal] {
+            let dots: usize = (0..region.cpu_cycles_per_frame())
+                .map(|cycle| region.ppu_dots(cycle))
+                .sum();
+            let frame = region.scanlines_per_frame() as usize * 341;
+            assert!(dots.abs_diff(frame) <= 1, "{:?}: {} dots", region, dots);
+        }
+    }
+}
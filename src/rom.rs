@@ -1,34 +1,93 @@
 use std::{fs::File, io::prelude::*, path::Path};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use nom::{
-    bytes::complete::{tag, take},
+    bytes::complete::take,
     number::complete::{le_u16, le_u8},
     IResult,
 };
 
+use crate::region::{self, Region};
+
+mod archive;
+mod db;
+mod fix;
+mod info;
+mod nsf;
+mod unif;
+
+pub use db::RomDb;
+pub use fix::fix_header;
+pub use info::{NsfInfo, RomInfo};
+pub use nsf::Nsf;
+
+const INES_MAGIC: &[u8] = b"NES\x1A";
+const FDS_MAGIC: &[u8] = b"FDS\x1A";
+const HEADER_SIZE: usize = 16;
+
 const PRG_BANK_SIZE: usize = 16384; // 16 KiB
 const CHR_BANK_SIZE: usize = 8192; // 8 KiB
+const TRAINER_SIZE: usize = 512;
+
+/// Sizes of the PlayChoice-10 instruction ROM and the PROM used to decrypt
+/// it, which follow the CHR data.
+const PC10_INST_ROM_SIZE: usize = 8192; // 8 KiB
+const PC10_PROM_SIZE: usize = 32;
+
+/// Amount of CHR RAM to provide when the header doesn't say.
+const DEFAULT_CHR_RAM_SIZE: usize = 8192; // 8 KiB
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Header {
     pub num_prg_banks: u8,
     pub num_chr_banks: u8,
     pub num_prg_ram_banks: u8,
+    /// Size of the cartridge's CHR RAM, in bytes. Only specified by NES 2.0
+    /// headers.
+    pub chr_ram_size: Option<usize>,
     pub mirroring: Mirroring,
     pub mapper: u8,
+    /// Distinguishes between incompatible boards that share a mapper number.
+    /// Only specified by NES 2.0 headers; 0 means unspecified.
+    pub submapper: u8,
     pub has_battery: bool,
     pub has_trainer: bool,
     pub is_ines_v2: bool,
+    pub console_type: ConsoleType,
+    /// Timing of the console the ROM was made for. Given by the header in
+    /// NES 2.0; otherwise, guessed from the file name or the ROM database
+    /// when the ROM is loaded.
+    pub region: Option<Region>,
 }
 
+/// Size of each unit of PRG RAM in the iNES 1.0 header.
+const PRG_RAM_BANK_SIZE: usize = 8192; // 8 KiB
+
 impl Header {
-    fn new(num_prg_banks: u8, num_chr_banks: u8, num_prg_ram_banks: u8, flags: u16) -> Self {
+    fn new(
+        num_prg_banks: u8,
+        num_chr_banks: u8,
+        byte8: u8,
+        byte11: u8,
+        byte12: u8,
+        flags: u16,
+    ) -> Self {
+        let mapper = {
+            // The lower 4 bits and upper 4 bits of the 8-bit mapper number are
+            // stored as the top 4 bits of bytes 6 and 7 respectively.
+            let low = (flags & (0x0F << 4)) >> 4;
+            let high = (flags & (0x0F << 12)) >> 8;
+            (low | high) as u8
+        };
+
         let mirroring = {
             let b0 = flags & 0x01 > 0;
             let b3 = flags & 0x08 > 0;
             match (b0, b3) {
-                (_, true) => Mirroring::None,
+                // UNROM 512 (mapper 30) boards use this combination to mean
+                // single-screen mirroring selected by the mapper.
+                (false, true) if mapper == 30 => Mirroring::SingleScreenA,
+                (_, true) => Mirroring::FourScreen,
                 (true, false) => Mirroring::Vertical,
                 (false, false) => Mirroring::Horizonal,
             }
@@ -37,25 +96,96 @@ impl Header {
         let has_battery = flags & 0x02 > 0;
         let has_trainer = flags & 0x04 > 0;
 
-        let mapper = {
-            // The lower 4 bits and upper 4 bits of the 8-bit mapper number are
-            // stored as the top 4 bits of bytes 6 and 7 respectively.
-            let low = (flags & (0x0F << 4)) >> 4;
-            let high = (flags & (0x0F << 12)) >> 8;
-            (low | high) as u8
+        let is_ines_v2 = (flags >> 10) & 0x03 == 2;
+
+        // The low 2 bits of byte 7 are separate VS System and PlayChoice-10
+        // flags in iNES 1.0 (where VS System takes precedence if both are
+        // set), which NES 2.0 turned into a 2-bit field whose last value
+        // means the type is given in byte 13.
+        let console_type = match (flags >> 8) & 0x03 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem,
+            2 => ConsoleType::PlayChoice10,
+            _ if is_ines_v2 => ConsoleType::Extended,
+            _ => ConsoleType::VsSystem,
+        };
+
+        // Byte 8 holds the PRG RAM size in iNES 1.0, but was repurposed by
+        // NES 2.0 to hold the submapper number in its upper 4 bits (and the
+        // upper bits of the mapper number, which we don't support).
+        let (num_prg_ram_banks, submapper) = if is_ines_v2 {
+            (0, byte8 >> 4)
+        } else {
+            (byte8, 0)
+        };
+
+        // NES 2.0 gives the size of volatile CHR RAM in the low 4 bits of
+        // byte 11, as a shift count (64 << n bytes).
+        let chr_ram_size = match byte11 & 0x0F {
+            shift if is_ines_v2 && shift > 0 => Some(64 << shift),
+            _ => None,
         };
 
-        let is_ines_v2 = flags & (0x03 << 10) >> 10 == 2;
+        // NES 2.0 gives the CPU/PPU timing in the low 2 bits of byte 12.
+        // Multi-region ROMs work with either, and we don't emulate Dendy
+        // (Famiclone) timing, so leave the choice to the user for those.
+        let region = match byte12 & 0x03 {
+            0 if is_ines_v2 => Some(Region::Ntsc),
+            1 if is_ines_v2 => Some(Region::Pal),
+            _ => None,
+        };
 
         Self {
             num_prg_banks,
             num_chr_banks,
             num_prg_ram_banks,
+            chr_ram_size,
             mirroring,
             mapper,
+            submapper,
             has_battery,
             has_trainer,
             is_ines_v2,
+            console_type,
+            region,
+        }
+    }
+
+    /// Size of the cartridge's PRG RAM, in bytes. A size of 0 is treated as
+    /// 8 KiB, since most iNES 1.0 headers leave this field unset.
+    pub fn prg_ram_size(&self) -> usize {
+        self.num_prg_ram_banks.max(1) as usize * PRG_RAM_BANK_SIZE
+    }
+
+    /// Whether the cartridge uses CHR RAM rather than CHR ROM, which is
+    /// indicated by the header reporting no CHR ROM.
+    pub fn has_chr_ram(&self) -> bool {
+        self.num_chr_banks == 0
+    }
+}
+
+/// The kind of system the ROM was made for. Only ordinary NES and Famicom
+/// ROMs can be run.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConsoleType {
+    Nes,
+    /// Nintendo's VS. System arcade hardware.
+    VsSystem,
+    /// Nintendo's PlayChoice-10 arcade hardware, which has an extra ROM with
+    /// instructions for each game after the CHR data.
+    PlayChoice10,
+    /// One of the other consoles (mostly Famiclones) that NES 2.0 can
+    /// describe.
+    Extended,
+}
+
+impl ConsoleType {
+    pub fn name(self) -> &'static str {
+        match self {
+            ConsoleType::Nes => "NES/Famicom",
+            ConsoleType::VsSystem => "VS. System",
+            ConsoleType::PlayChoice10 => "PlayChoice-10",
+            ConsoleType::Extended => "extended console type",
         }
     }
 }
@@ -64,38 +194,148 @@ impl Header {
 pub enum Mirroring {
     Horizonal,
     Vertical,
-    None,
+    /// Each nametable has its own 1 KiB of memory, using extra RAM on the
+    /// cartridge.
+    FourScreen,
+    /// All four nametables map to the first (A) or second (B) KiB of VRAM.
+    /// Selectable by some mappers, and only specified by the header for
+    /// mapper 30.
+    SingleScreenA,
+    SingleScreenB,
 }
 
-/// The contents of an iNES-format ROM file.
-#[derive(Debug)]
+/// The contents of a ROM file (in iNES or UNIF format).
+#[derive(Debug, Clone)]
 pub struct Rom {
     pub header: Header,
 
     // Program (PRG) ROM banks.
     pub prg: Vec<u8>,
 
-    // Character (CHR) ROM banks.
+    // Character (CHR) ROM banks, or zero-filled CHR RAM if the cartridge has
+    // no CHR ROM.
     pub chr: Vec<u8>,
+
+    /// 512 bytes of code that some older dumps (mostly of games converted
+    /// from other Famicom boards) need loaded into $7000-$71FF at startup.
+    pub trainer: Option<Vec<u8>>,
 }
 
 impl Rom {
+    /// Load a ROM file, which may be compressed in a zip or gzip archive.
     pub fn load(path: impl AsRef<Path>) -> Result<Self> {
-        let mut buf = Vec::new();
-        let mut f = File::open(path.as_ref())?;
-        f.read_to_end(&mut buf)?;
+        Self::load_entry(path, None)
+    }
 
-        let (_, rom) = parse_rom(&buf).map_err(|_| anyhow!("Failed to parse ROM file"))?;
+    /// Load a ROM file. If it's a zip archive, load the file with the given
+    /// name from it (or the first ROM in the archive if not given).
+    pub fn load_entry(path: impl AsRef<Path>, entry: Option<&str>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let mut rom =
+            Self::from_reader(file, entry).with_context(|| format!("Failed to load {:?}", path))?;
+        if rom.header.region.is_none() {
+            let name = entry.map(Path::new).unwrap_or(path).file_name();
+            rom.header.region =
+                name.and_then(|name| region::from_file_name(&name.to_string_lossy()));
+        }
+        Ok(rom)
+    }
+
+    /// Read and parse a ROM file (or a zip or gzip archive containing one,
+    /// with `entry` picking the file as in `load_entry`).
+    pub fn from_reader(mut reader: impl Read, entry: Option<&str>) -> Result<Self> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Self::from_bytes(&bytes, entry)
+    }
 
+    /// Parse the contents of a ROM file (or a zip or gzip archive containing
+    /// one, with `entry` picking the file as in `load_entry`). If the header
+    /// doesn't give the ROM's region, it's looked up in the ROM database.
+    pub fn from_bytes(bytes: &[u8], entry: Option<&str>) -> Result<Self> {
+        let mut rom = Self::parse(bytes, entry)?;
+        if rom.header.region.is_none() {
+            rom.header.region = rom.region_from_db();
+        }
         Ok(rom)
     }
+
+    /// CRC32 of the PRG and CHR ROM, which identifies the ROM in databases.
+    pub fn crc32(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.prg);
+        if !self.header.has_chr_ram() {
+            hasher.update(&self.chr);
+        }
+        hasher.finalize()
+    }
+
+    /// Look up the ROM's region in the ROM database, if there is one.
+    fn region_from_db(&self) -> Option<Region> {
+        load_db()?.get(self.crc32())?.region
+    }
+
+    /// Look up the game's title in the ROM database, if there is one.
+    pub fn title_from_db(&self) -> Option<String> {
+        load_db()?.get(self.crc32())?.title.clone()
+    }
+
+    fn parse(bytes: &[u8], entry: Option<&str>) -> Result<Self> {
+        let bytes = archive::extract(bytes, entry)?;
+
+        if bytes.starts_with(unif::MAGIC) {
+            return unif::parse(&bytes);
+        }
+
+        parse_rom(&bytes)
+    }
+
+    /// A ROM for the given mapper with the given PRG and CHR ROM, for testing
+    /// mappers. If there's no CHR ROM, the cartridge gets 8 KiB of CHR RAM.
+    #[cfg(test)]
+    pub fn for_test(mapper: u8, prg: Vec<u8>, chr: Vec<u8>) -> Self {
+        let num_chr_banks = (chr.len() / CHR_BANK_SIZE) as u8;
+        let chr = if chr.is_empty() {
+            vec![0; DEFAULT_CHR_RAM_SIZE]
+        } else {
+            chr
+        };
+        Self {
+            header: Header {
+                num_prg_banks: (prg.len() / PRG_BANK_SIZE) as u8,
+                num_chr_banks,
+                num_prg_ram_banks: 0,
+                chr_ram_size: None,
+                mirroring: Mirroring::Vertical,
+                mapper,
+                submapper: 0,
+                has_battery: false,
+                has_trainer: false,
+                is_ines_v2: false,
+                console_type: ConsoleType::Nes,
+                region: None,
+            },
+            prg,
+            chr,
+            trainer: None,
+        }
+    }
 }
 
-/// Parse a the content of an iNES-format ROM file.
-fn parse_rom(bytes: &[u8]) -> IResult<&[u8], Rom> {
-    // Initial 4 byte magic sequence.
-    let (bytes, _) = tag(b"NES\x1A")(bytes.as_ref())?;
+/// Load the ROM database from its default location, if there is one. A
+/// broken database shouldn't stop the ROM from loading, so errors are only
+/// logged.
+fn load_db() -> Option<RomDb> {
+    RomDb::load(None)
+        .map_err(|e| log::warn!("{:#}", e))
+        .ok()
+        .flatten()
+}
 
+/// Parse the 16-byte header of an iNES-format ROM file, after the magic
+/// number.
+fn parse_header(bytes: &[u8]) -> IResult<&[u8], Header> {
     // Number of PRG (program) and CHR (character) ROM banks.
     let (bytes, num_prg_banks) = le_u8(bytes)?;
     let (bytes, num_chr_banks) = le_u8(bytes)?;
@@ -103,32 +343,134 @@ fn parse_rom(bytes: &[u8]) -> IResult<&[u8], Rom> {
     // Get flags from bytes 6 and 7.
     let (bytes, flags) = le_u16(bytes)?;
 
-    // Byte 8 contains an optional PRG RAM size.
-    let (bytes, num_prg_ram_banks) = le_u8(bytes)?;
+    // Byte 8 contains an optional PRG RAM size (or the submapper number).
+    let (bytes, byte8) = le_u8(bytes)?;
 
     // Ignore flag bytes 9 and 10 since these are rarely used iNES format
-    // extensions. Bytes 11-15 are unused padding.
-    let (bytes, _) = take(7usize)(bytes)?;
+    // extensions. In NES 2.0, byte 11 contains the CHR RAM size and byte 12
+    // the console timing, and bytes 13-15 are unused padding.
+    let (bytes, _) = take(2usize)(bytes)?;
+    let (bytes, byte11) = le_u8(bytes)?;
+    let (bytes, byte12) = le_u8(bytes)?;
+    let (bytes, _) = take(3usize)(bytes)?;
 
-    let header = Header::new(num_prg_banks, num_chr_banks, num_prg_ram_banks, flags);
+    let header = Header::new(num_prg_banks, num_chr_banks, byte8, byte11, byte12, flags);
+    Ok((bytes, header))
+}
 
-    // If a trainer is present, skip over it.
-    let bytes = if header.has_trainer {
-        let (bytes, _) = take(512usize)(bytes)?;
-        bytes
+/// Split off the next `len` bytes of the file, or explain what's missing.
+fn section<'a>(bytes: &'a [u8], len: usize, name: &str) -> Result<(&'a [u8], &'a [u8])> {
+    if bytes.len() < len {
+        bail!(
+            "Truncated {}: expected {} bytes, got {} (the file may be corrupt, \
+             or its header may be wrong; see the fix-header command)",
+            name,
+            len,
+            bytes.len()
+        );
+    }
+    let (data, rest) = bytes.split_at(len);
+    Ok((rest, data))
+}
+
+/// Parse the content of an iNES-format ROM file.
+fn parse_rom(bytes: &[u8]) -> Result<Rom> {
+    // Initial 4 byte magic sequence.
+    let Some(bytes) = bytes.strip_prefix(INES_MAGIC) else {
+        if bytes.starts_with(FDS_MAGIC) {
+            bail!("Famicom Disk System images are not supported");
+        }
+        if bytes.starts_with(nsf::MAGIC) {
+            bail!("NSF files contain music rather than a game, and can't be run as ROMs");
+        }
+        bail!(
+            "Not a NES ROM file: expected an iNES or UNIF magic number, found {:02X?}",
+            &bytes[..bytes.len().min(4)]
+        );
+    };
+
+    let (bytes, header) = parse_header(bytes).map_err(|_| {
+        anyhow!(
+            "Truncated header: expected {} bytes, got {}",
+            HEADER_SIZE,
+            bytes.len() + INES_MAGIC.len()
+        )
+    })?;
+    if header.num_prg_banks == 0 {
+        bail!("Invalid header: the ROM has no PRG data");
+    }
+
+    // The trainer, if present, comes before the PRG data.
+    let (bytes, trainer) = if header.has_trainer {
+        let (bytes, trainer) = section(bytes, TRAINER_SIZE, "trainer")?;
+        (bytes, Some(trainer.to_vec()))
     } else {
-        bytes
+        (bytes, None)
     };
 
     // Actual PRG and CHR bank data.
-    let (bytes, prg) = take(num_prg_banks as usize * PRG_BANK_SIZE)(bytes)?;
-    let (bytes, chr) = take(num_chr_banks as usize * CHR_BANK_SIZE)(bytes)?;
+    let prg_size = header.num_prg_banks as usize * PRG_BANK_SIZE;
+    let (bytes, prg) = section(bytes, prg_size, "PRG data")?;
+    let chr_size = header.num_chr_banks as usize * CHR_BANK_SIZE;
+    let (bytes, chr) = section(bytes, chr_size, "CHR data")?;
+    let chr = if header.has_chr_ram() {
+        vec![0; header.chr_ram_size.unwrap_or(DEFAULT_CHR_RAM_SIZE)]
+    } else {
+        chr.to_vec()
+    };
 
-    let rom = Rom {
+    // Skip over the PlayChoice-10 data, which we have no use for. Many dumps
+    // leave out the PROM (and some the INST-ROM as well).
+    let bytes = if header.console_type == ConsoleType::PlayChoice10 {
+        let skip = bytes.len().min(PC10_INST_ROM_SIZE + PC10_PROM_SIZE);
+        &bytes[skip..]
+    } else {
+        bytes
+    };
+    if !bytes.is_empty() {
+        log::warn!("Ignoring {} bytes after the end of the ROM", bytes.len());
+    }
+
+    Ok(Rom {
         header,
         prg: prg.to_vec(),
-        chr: chr.to_vec(),
-    };
+        chr,
+        trainer,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok((bytes, rom))
+    #[test]
+    fn test_from_bytes() {
+        // An NROM-128 ROM with CHR RAM.
+        let mut bytes = b"NES\x1A\x01\x00\x01\x00".to_vec();
+        bytes.resize(16, 0);
+        bytes.resize(16 + PRG_BANK_SIZE, 0xEA);
+
+        let rom = Rom::from_bytes(&bytes, None).unwrap();
+        assert_eq!(rom.header.mapper, 0);
+        assert!(matches!(rom.header.mirroring, Mirroring::Vertical));
+        assert_eq!(rom.prg.len(), PRG_BANK_SIZE);
+        assert_eq!(rom.chr.len(), DEFAULT_CHR_RAM_SIZE);
+
+        let rom = Rom::from_reader(&bytes[..], None).unwrap();
+        assert_eq!(rom.prg, vec![0xEA; PRG_BANK_SIZE]);
+
+        let err = Rom::from_bytes(&bytes[..100], None).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Truncated PRG data: expected 16384 bytes, got 84"));
+        assert!(Rom::from_bytes(b"NES\x1A\x01", None).is_err());
+        assert!(Rom::from_bytes(b"MZ", None).is_err());
+
+        // Flagged as a PlayChoice-10 ROM, with the INST-ROM but no PROM.
+        bytes[7] = 0x02;
+        bytes.resize(bytes.len() + PC10_INST_ROM_SIZE, 0);
+        let rom = Rom::from_bytes(&bytes, None).unwrap();
+        assert_eq!(rom.header.console_type, ConsoleType::PlayChoice10);
+        assert_eq!(rom.prg.len(), PRG_BANK_SIZE);
+    }
 }
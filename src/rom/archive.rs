@@ -0,0 +1,94 @@
+//! Support for loading ROMs from zip and gzip archives, which is how most ROM
+//! collections are distributed.
+
+use std::borrow::Cow;
+use std::io::{Cursor, Read};
+
+use anyhow::{anyhow, Context, Result};
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+
+/// File extensions of the ROM (and music) formats we can load.
+const ROM_EXTENSIONS: &[&str] = &["nes", "unf", "unif", "nsf"];
+
+/// If the given file contents are a zip or gzip archive, extract the ROM from
+/// it. Otherwise, return the contents unchanged.
+///
+/// For zip archives, `entry` names the file to extract; if not given, the
+/// first file with a ROM file extension is used.
+pub fn extract<'a>(bytes: &'a [u8], entry: Option<&str>) -> Result<Cow<'a, [u8]>> {
+    if bytes.starts_with(ZIP_MAGIC) {
+        Ok(extract_zip(bytes, entry)?.into())
+    } else if bytes.starts_with(GZIP_MAGIC) {
+        let mut rom = Vec::new();
+        GzDecoder::new(bytes)
+            .read_to_end(&mut rom)
+            .context("Failed to decompress gzip file")?;
+        Ok(rom.into())
+    } else {
+        Ok(bytes.into())
+    }
+}
+
+fn extract_zip(bytes: &[u8], entry: Option<&str>) -> Result<Vec<u8>> {
+    let mut zip = ZipArchive::new(Cursor::new(bytes)).context("Failed to open zip file")?;
+    let name = match entry {
+        Some(entry) => entry.to_string(),
+        None => (0..zip.len())
+            .filter_map(|i| Some(zip.by_index_raw(i).ok()?.name().to_string()))
+            .find(|name| is_rom_name(name))
+            .ok_or_else(|| anyhow!("No ROM found in zip file"))?,
+    };
+    log::info!("Loading {} from zip file", &name);
+    let mut file = zip
+        .by_name(&name)
+        .with_context(|| format!("Failed to find {} in zip file", &name))?;
+    let mut rom = Vec::new();
+    file.read_to_end(&mut rom)
+        .with_context(|| format!("Failed to extract {} from zip file", &name))?;
+    Ok(rom)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    match name.rsplit_once('.') {
+        Some((_, ext)) => ROM_EXTENSIONS.iter().any(|e| e.eq_ignore_ascii_case(ext)),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in [
+            ("README.txt", b"readme"),
+            ("b.NES", b"rom b!"),
+            ("a.nes", b"rom a!"),
+        ] {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        let zip = zip.finish().unwrap().into_inner();
+        assert_eq!(&*extract(&zip, None).unwrap(), b"rom b!");
+        assert_eq!(&*extract(&zip, Some("a.nes")).unwrap(), b"rom a!");
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(b"rom").unwrap();
+        assert_eq!(&*extract(&gzip.finish().unwrap(), None).unwrap(), b"rom");
+
+        assert_eq!(&*extract(b"NES\x1A", None).unwrap(), b"NES\x1A");
+    }
+}
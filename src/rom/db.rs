@@ -0,0 +1,95 @@
+//! Database of known-good header values, keyed by checksum.
+//!
+//! The database is a TOML file with a table for each ROM, named by the CRC32
+//! of its PRG and CHR data (as reported by `show-header`), e.g.:
+//!
+//! ```toml
+//! [158B0388]
+//! title = "Balloon Fight"
+//! mapper = 0
+//! mirroring = "vertical"
+//! battery = false
+//! prg_rom_size = 16384
+//! chr_rom_size = 8192
+//! region = "ntsc"
+//! ```
+//!
+//! All fields are optional. The database lives next to the config file
+//! (e.g., `~/.config/nes/romdb.toml` on Linux) unless given explicitly.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::region::Region;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DbEntry {
+    /// The game's name, for showing in the window title.
+    pub title: Option<String>,
+    pub mapper: Option<u8>,
+    pub mirroring: Option<DbMirroring>,
+    pub battery: Option<bool>,
+    pub prg_rom_size: Option<usize>,
+    pub chr_rom_size: Option<usize>,
+    pub region: Option<Region>,
+}
+
+/// The mirroring modes that can be specified by an iNES header.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DbMirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
+
+#[derive(Debug, Default)]
+pub struct RomDb {
+    entries: HashMap<String, DbEntry>,
+}
+
+impl RomDb {
+    /// Load the database from the given path, or from the default location if
+    /// no path is given. Returns `None` if there is no database at the
+    /// default location.
+    pub fn load(path: Option<&Path>) -> Result<Option<Self>> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_path() {
+                Some(path) if path.is_file() => path,
+                _ => return Ok(None),
+            },
+        };
+
+        log::info!("Loading ROM database: {:?}", &path);
+        let contents =
+            fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", &path))?;
+        Self::parse(&contents)
+            .map(Some)
+            .with_context(|| format!("Failed to parse {:?}", &path))
+    }
+
+    pub(super) fn parse(contents: &str) -> Result<Self> {
+        let entries: HashMap<String, DbEntry> = toml::from_str(contents)?;
+        Ok(Self {
+            entries: entries
+                .into_iter()
+                .map(|(crc, entry)| (crc.to_ascii_uppercase(), entry))
+                .collect(),
+        })
+    }
+
+    /// Look up a ROM by the CRC32 of its PRG and CHR data.
+    pub fn get(&self, crc: u32) -> Option<&DbEntry> {
+        self.entries.get(&format!("{:08X}", crc))
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("nes").join("romdb.toml"))
+}
@@ -0,0 +1,195 @@
+//! Detection and repair of common problems with iNES headers.
+
+use std::convert::TryInto;
+
+use anyhow::{bail, Result};
+
+use super::archive;
+use super::db::{DbMirroring, RomDb};
+use super::{CHR_BANK_SIZE, HEADER_SIZE, INES_MAGIC, PRG_BANK_SIZE, TRAINER_SIZE};
+
+/// A ROM file with a repaired header, and descriptions of what was wrong.
+pub struct HeaderFix {
+    pub bytes: Vec<u8>,
+    pub problems: Vec<String>,
+}
+
+/// Check the header of an iNES ROM file (which may be in an archive) for
+/// problems, and return a copy of the file with them fixed. If the ROM is in
+/// the database, the header is made to match the database entry.
+pub fn fix_header(bytes: &[u8], db: Option<&RomDb>) -> Result<HeaderFix> {
+    let bytes = archive::extract(bytes, None)?;
+    if !bytes.starts_with(INES_MAGIC) {
+        bail!("Not an iNES ROM file");
+    }
+    if bytes.len() < HEADER_SIZE {
+        bail!("Truncated iNES header");
+    }
+    let mut header: [u8; HEADER_SIZE] = bytes[..HEADER_SIZE].try_into().unwrap();
+    let mut problems = Vec::new();
+
+    // Bytes 8-15 were unused before NES 2.0, so some old rippers (most
+    // famously DiskDude!) signed their dumps there, starting at byte 7. That
+    // also garbles the upper nibble of the mapper number.
+    let is_ines_v2 = header[7] & 0x0C == 0x08;
+    if !is_ines_v2 && header[12..].iter().any(|&b| b != 0) {
+        problems.push(format!(
+            "Cleared garbage in bytes 7-15: {:?}",
+            String::from_utf8_lossy(&header[7..])
+        ));
+        header[7..].fill(0);
+    }
+
+    let data_start = HEADER_SIZE
+        + if header[6] & 0x04 > 0 {
+            TRAINER_SIZE
+        } else {
+            0
+        };
+    let Some(data) = bytes.get(data_start..) else {
+        bail!("ROM file is truncated");
+    };
+    let entry = db.and_then(|db| db.get(crc32fast::hash(data)));
+    if db.is_some() && entry.is_none() {
+        problems.push("ROM not found in database".to_string());
+    }
+
+    // NES 2.0 headers keep the upper bits of the bank counts in byte 9,
+    // which we don't support. The database's sizes take precedence over
+    // guessing from the file size.
+    let db_has_sizes = entry.is_some_and(|e| e.prg_rom_size.is_some() || e.chr_rom_size.is_some());
+    if (!is_ines_v2 || header[9] == 0) && !db_has_sizes {
+        fix_bank_counts(&mut header, data.len(), &mut problems);
+    }
+
+    if let Some(entry) = entry {
+        if let Some(mapper) = entry.mapper {
+            let old = (header[6] >> 4) | (header[7] & 0xF0);
+            if mapper != old {
+                problems.push(format!("Changed mapper from {} to {}", old, mapper));
+                header[6] = (header[6] & 0x0F) | (mapper << 4);
+                header[7] = (header[7] & 0x0F) | (mapper & 0xF0);
+            }
+        }
+        if let Some(mirroring) = entry.mirroring {
+            let bits = match mirroring {
+                DbMirroring::Horizontal => 0x00,
+                DbMirroring::Vertical => 0x01,
+                DbMirroring::FourScreen => 0x08,
+            };
+            if header[6] & 0x09 != bits {
+                problems.push(format!("Changed mirroring to {:?}", mirroring));
+                header[6] = (header[6] & !0x09) | bits;
+            }
+        }
+        if let Some(battery) = entry.battery {
+            if (header[6] & 0x02 > 0) != battery {
+                problems.push(format!("Changed battery flag to {}", battery));
+                header[6] ^= 0x02;
+            }
+        }
+        for (i, size, bank_size, name) in [
+            (4, entry.prg_rom_size, PRG_BANK_SIZE, "PRG"),
+            (5, entry.chr_rom_size, CHR_BANK_SIZE, "CHR"),
+        ] {
+            let Some(banks) = size.map(|size| size / bank_size) else {
+                continue;
+            };
+            if banks != header[i] as usize {
+                problems.push(format!(
+                    "Changed {} bank count from {} to {}",
+                    name, header[i], banks
+                ));
+                header[i] = banks as u8;
+            }
+        }
+    }
+
+    let mut fixed = header.to_vec();
+    fixed.extend_from_slice(&bytes[HEADER_SIZE..]);
+    Ok(HeaderFix {
+        bytes: fixed,
+        problems,
+    })
+}
+
+/// Make the PRG and CHR bank counts agree with the amount of data in the
+/// file, assuming only one of them is wrong.
+fn fix_bank_counts(header: &mut [u8; HEADER_SIZE], len: usize, problems: &mut Vec<String>) {
+    let prg_size = header[4] as usize * PRG_BANK_SIZE;
+    let chr_size = header[5] as usize * CHR_BANK_SIZE;
+    if prg_size + chr_size == len {
+        return;
+    }
+
+    let chr_banks = len
+        .checked_sub(prg_size)
+        .filter(|size| size.is_multiple_of(CHR_BANK_SIZE))
+        .map(|size| size / CHR_BANK_SIZE);
+    let prg_banks = len
+        .checked_sub(chr_size)
+        .filter(|size| size.is_multiple_of(PRG_BANK_SIZE))
+        .map(|size| size / PRG_BANK_SIZE);
+    match (chr_banks, prg_banks) {
+        (Some(banks), _) if banks <= u8::MAX as usize => {
+            problems.push(format!(
+                "Changed CHR bank count from {} to {} to match file size",
+                header[5], banks
+            ));
+            header[5] = banks as u8;
+        }
+        (_, Some(banks)) if banks > 0 && banks <= u8::MAX as usize => {
+            problems.push(format!(
+                "Changed PRG bank count from {} to {} to match file size",
+                header[4], banks
+            ));
+            header[4] = banks as u8;
+        }
+        _ => problems.push(format!(
+            "Bank counts ({} PRG, {} CHR) don't match file size ({} bytes), \
+             and can't be corrected automatically",
+            header[4], header[5], len
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(header: &[u8], len: usize) -> Vec<u8> {
+        let mut rom = header.to_vec();
+        rom.resize(HEADER_SIZE, 0);
+        rom.resize(HEADER_SIZE + len, 0xEA);
+        rom
+    }
+
+    #[test]
+    fn test_fix_header() {
+        // Mapper 4, 2 PRG banks, 1 CHR bank, signed by DiskDude!.
+        let bytes = rom(
+            b"NES\x1A\x02\x01\x40DiskDude!",
+            2 * PRG_BANK_SIZE + CHR_BANK_SIZE,
+        );
+        let fix = fix_header(&bytes, None).unwrap();
+        assert_eq!(fix.problems.len(), 1);
+        assert_eq!(&fix.bytes[..8], b"NES\x1A\x02\x01\x40\x00");
+        assert_eq!(&fix.bytes[HEADER_SIZE..], &bytes[HEADER_SIZE..]);
+
+        // The CHR bank count is wrong, and the mirroring doesn't match the
+        // database.
+        let bytes = rom(
+            b"NES\x1A\x02\x02\x40\x00",
+            2 * PRG_BANK_SIZE + CHR_BANK_SIZE,
+        );
+        let crc = crc32fast::hash(&bytes[HEADER_SIZE..]);
+        let db = RomDb::parse(&format!("[{:08x}]\nmirroring = \"vertical\"", crc)).unwrap();
+        let fix = fix_header(&bytes, Some(&db)).unwrap();
+        assert_eq!(fix.problems.len(), 2);
+        assert_eq!(&fix.bytes[4..8], b"\x02\x01\x41\x00");
+
+        // Nothing to fix.
+        let fix = fix_header(&fix.bytes, Some(&db)).unwrap();
+        assert!(fix.problems.is_empty());
+    }
+}
@@ -0,0 +1,252 @@
+//! Summaries of ROM and NSF files, as printed by the `show-header` command.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::mapper;
+use crate::region::Region;
+
+use super::{Mirroring, Nsf, Rom, CHR_BANK_SIZE, PRG_BANK_SIZE};
+
+#[derive(Debug, Serialize)]
+pub struct RomInfo {
+    pub format: &'static str,
+    pub console: &'static str,
+    /// Timing of the console the ROM was made for, if known.
+    pub region: Option<&'static str>,
+    pub mapper: u8,
+    pub submapper: u8,
+    /// Name of the board (or board family) that uses this mapper, if known.
+    pub board: Option<&'static str>,
+    pub mirroring: &'static str,
+    pub battery: bool,
+    pub trainer: bool,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    /// Size of the CHR RAM that will be provided, if the cartridge has no CHR
+    /// ROM.
+    pub chr_ram_size: Option<usize>,
+    pub prg_ram_size: usize,
+    pub checksums: Checksums,
+}
+
+/// CRC32 checksums of the ROM's contents, excluding the header and trainer.
+/// The combined checksum is the one listed by ROM databases like No-Intro.
+/// Checksums are formatted as hex strings, which is how they're usually
+/// compared.
+#[derive(Debug, Serialize)]
+pub struct Checksums {
+    pub prg: String,
+    /// Absent if the cartridge has no CHR ROM.
+    pub chr: Option<String>,
+    pub rom: String,
+    /// Checksums of each 16 KiB PRG bank and 8 KiB CHR bank, which help to
+    /// locate the changes made by a hack, or spot an overdump (whose extra
+    /// banks are copies of the others).
+    pub prg_banks: Vec<String>,
+    pub chr_banks: Vec<String>,
+}
+
+impl RomInfo {
+    pub fn new(rom: &Rom) -> Self {
+        let header = &rom.header;
+        let chr_rom: &[u8] = if header.has_chr_ram() { &[] } else { &rom.chr };
+        Self {
+            format: if header.is_ines_v2 {
+                "NES 2.0"
+            } else {
+                "iNES 1.0"
+            },
+            console: header.console_type.name(),
+            region: header.region.map(region_name),
+            mapper: header.mapper,
+            submapper: header.submapper,
+            board: mapper::board_name(header.mapper),
+            mirroring: mirroring_name(header.mirroring),
+            battery: header.has_battery,
+            trainer: rom.trainer.is_some(),
+            prg_rom_size: rom.prg.len(),
+            chr_rom_size: chr_rom.len(),
+            chr_ram_size: header.has_chr_ram().then_some(rom.chr.len()),
+            prg_ram_size: header.prg_ram_size(),
+            checksums: Checksums {
+                prg: crc32(&rom.prg),
+                chr: (!chr_rom.is_empty()).then(|| crc32(chr_rom)),
+                rom: format!("{:08X}", rom.crc32()),
+                prg_banks: rom.prg.chunks(PRG_BANK_SIZE).map(crc32).collect(),
+                chr_banks: chr_rom.chunks(CHR_BANK_SIZE).map(crc32).collect(),
+            },
+        }
+    }
+}
+
+fn region_name(region: Region) -> &'static str {
+    match region {
+        Region::Ntsc => "NTSC",
+        Region::Pal => "PAL",
+    }
+}
+
+fn crc32(bytes: &[u8]) -> String {
+    format!("{:08X}", crc32fast::hash(bytes))
+}
+
+fn mirroring_name(mirroring: Mirroring) -> &'static str {
+    match mirroring {
+        Mirroring::Horizonal => "horizontal",
+        Mirroring::Vertical => "vertical",
+        Mirroring::FourScreen => "four-screen",
+        Mirroring::SingleScreenA | Mirroring::SingleScreenB => "single-screen",
+    }
+}
+
+/// Format a size in bytes, using KiB where it divides evenly.
+fn size(bytes: usize) -> String {
+    if bytes > 0 && bytes.is_multiple_of(1024) {
+        format!("{} KiB", bytes / 1024)
+    } else {
+        format!("{} bytes", bytes)
+    }
+}
+
+impl fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Format:     {}", self.format)?;
+        writeln!(f, "Console:    {}", self.console)?;
+        writeln!(f, "Region:     {}", self.region.unwrap_or("unknown"))?;
+        write!(f, "Mapper:     {}", mapper::describe(self.mapper))?;
+        if self.submapper > 0 {
+            write!(f, " (submapper {})", self.submapper)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Mirroring:  {}", self.mirroring)?;
+        writeln!(f, "Battery:    {}", if self.battery { "yes" } else { "no" })?;
+        writeln!(f, "Trainer:    {}", if self.trainer { "yes" } else { "no" })?;
+        writeln!(f, "PRG ROM:    {}", size(self.prg_rom_size))?;
+        match self.chr_ram_size {
+            Some(chr_ram_size) => writeln!(f, "CHR RAM:    {}", size(chr_ram_size))?,
+            None => writeln!(f, "CHR ROM:    {}", size(self.chr_rom_size))?,
+        }
+        writeln!(f, "PRG RAM:    {}", size(self.prg_ram_size))?;
+        writeln!(f, "PRG CRC32:  {}", self.checksums.prg)?;
+        if let Some(chr) = &self.checksums.chr {
+            writeln!(f, "CHR CRC32:  {}", chr)?;
+        }
+        write!(f, "ROM CRC32:  {}", self.checksums.rom)?;
+        write_banks(f, "PRG", &self.checksums.prg_banks, PRG_BANK_SIZE)?;
+        write_banks(f, "CHR", &self.checksums.chr_banks, CHR_BANK_SIZE)
+    }
+}
+
+/// Write a table of the offset and checksum of each bank, noting banks that
+/// are identical to an earlier one.
+fn write_banks(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    checksums: &[String],
+    bank_size: usize,
+) -> fmt::Result {
+    if checksums.is_empty() {
+        return Ok(());
+    }
+    write!(f, "\n{} banks:", name)?;
+    for (i, checksum) in checksums.iter().enumerate() {
+        let start = i * bank_size;
+        write!(
+            f,
+            "\n  {:3}  ${:05X}-${:05X}  {}",
+            i,
+            start,
+            start + bank_size - 1,
+            checksum
+        )?;
+        if let Some(j) = checksums[..i].iter().position(|c| c == checksum) {
+            write!(f, "  (same as bank {})", j)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct NsfInfo {
+    pub format: &'static str,
+    pub version: u8,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub num_songs: u8,
+    pub starting_song: u8,
+    pub load_addr: String,
+    pub init_addr: String,
+    pub play_addr: String,
+    /// Region the music was written for, or `None` if it supports both.
+    pub region: Option<&'static str>,
+    pub ntsc_play_period_us: u16,
+    pub pal_play_period_us: u16,
+    /// Initial banks, if the data is bank switched.
+    pub initial_banks: Option<[u8; 8]>,
+    pub expansion_chips: Vec<&'static str>,
+    pub data_size: usize,
+    pub crc32: String,
+}
+
+impl NsfInfo {
+    pub fn new(nsf: &Nsf) -> Self {
+        Self {
+            format: "NSF",
+            version: nsf.version,
+            title: nsf.title.clone(),
+            artist: nsf.artist.clone(),
+            copyright: nsf.copyright.clone(),
+            num_songs: nsf.num_songs,
+            starting_song: nsf.starting_song,
+            load_addr: nsf.load_addr.to_string(),
+            init_addr: nsf.init_addr.to_string(),
+            play_addr: nsf.play_addr.to_string(),
+            region: nsf.region.map(region_name),
+            ntsc_play_period_us: nsf.ntsc_play_period_us,
+            pal_play_period_us: nsf.pal_play_period_us,
+            initial_banks: nsf.is_bank_switched().then_some(nsf.initial_banks),
+            expansion_chips: nsf.expansion_chips.names(),
+            data_size: nsf.data.len(),
+            crc32: crc32(&nsf.data),
+        }
+    }
+}
+
+impl fmt::Display for NsfInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Format:     {} (version {})", self.format, self.version)?;
+        writeln!(f, "Title:      {}", self.title)?;
+        writeln!(f, "Artist:     {}", self.artist)?;
+        writeln!(f, "Copyright:  {}", self.copyright)?;
+        writeln!(
+            f,
+            "Songs:      {} (starting with {})",
+            self.num_songs, self.starting_song
+        )?;
+        writeln!(f, "Region:     {}", self.region.unwrap_or("NTSC and PAL"))?;
+        writeln!(
+            f,
+            "Addresses:  load {}, init {}, play {}",
+            self.load_addr, self.init_addr, self.play_addr
+        )?;
+        writeln!(
+            f,
+            "Play rate:  every {} us (NTSC), {} us (PAL)",
+            self.ntsc_play_period_us, self.pal_play_period_us
+        )?;
+        match self.initial_banks {
+            Some(banks) => writeln!(f, "Banks:      {:02X?}", banks)?,
+            None => writeln!(f, "Banks:      not bank switched")?,
+        }
+        if self.expansion_chips.is_empty() {
+            writeln!(f, "Chips:      none")?;
+        } else {
+            writeln!(f, "Chips:      {}", self.expansion_chips.join(", "))?;
+        }
+        writeln!(f, "Data:       {}", size(self.data_size))?;
+        write!(f, "CRC32:      {}", self.crc32)
+    }
+}
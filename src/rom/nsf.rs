@@ -0,0 +1,211 @@
+//! Parser for NSF (NES Sound Format) files.
+//!
+//! An NSF file contains the music code and data ripped from a game, along
+//! with a 128-byte header that says where to load it and which routines to
+//! call to start a song (init) and advance it by one tick (play). Rather than
+//! running on a cartridge, the data is loaded into a simple banked memory map
+//! by the player, which may also need to provide one or more of the
+//! expansion sound chips used by Famicom games.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use bitflags::bitflags;
+use nom::{
+    bytes::complete::{tag, take},
+    number::complete::{le_u16, le_u8},
+    IResult,
+};
+
+use crate::mem::Address;
+use crate::region::Region;
+
+use super::archive;
+
+pub const MAGIC: &[u8] = b"NESM\x1A";
+
+bitflags! {
+    /// Expansion sound chips that an NSF uses.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct ExpansionChips: u8 {
+        const VRC6 = 1;
+        const VRC7 = 1 << 1;
+        const FDS = 1 << 2;
+        const MMC5 = 1 << 3;
+        const NAMCO_163 = 1 << 4;
+        const SUNSOFT_5B = 1 << 5;
+    }
+}
+
+impl ExpansionChips {
+    /// Names of the chips in this set.
+    pub fn names(self) -> Vec<&'static str> {
+        self.iter_names()
+            .map(|(name, _)| match name {
+                "NAMCO_163" => "Namco 163",
+                "SUNSOFT_5B" => "Sunsoft 5B",
+                name => name,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct Nsf {
+    pub version: u8,
+    pub num_songs: u8,
+    /// The song to play first, counting from 1.
+    pub starting_song: u8,
+    pub load_addr: Address,
+    pub init_addr: Address,
+    pub play_addr: Address,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    /// How often to call the play routine on each console, in microseconds.
+    pub ntsc_play_period_us: u16,
+    pub pal_play_period_us: u16,
+    /// The region the music was written for, or `None` if it supports both.
+    pub region: Option<Region>,
+    /// Initial banks for each 4 KiB window from $8000 to $FFFF. If these are
+    /// all zero, the data isn't bank switched.
+    pub initial_banks: [u8; 8],
+    pub expansion_chips: ExpansionChips,
+    /// The music code and data, starting at `load_addr` (or, if bank
+    /// switched, at `load_addr` modulo the bank size within bank 0).
+    pub data: Vec<u8>,
+}
+
+impl Nsf {
+    /// Load the given file if it's an NSF file (possibly in a zip or gzip
+    /// archive), or return `None` if it's something else.
+    pub fn probe(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let bytes = archive::extract(&bytes, None)?;
+        if !bytes.starts_with(MAGIC) {
+            return Ok(None);
+        }
+        parse(&bytes)
+            .map(Some)
+            .with_context(|| format!("Failed to load {:?}", path))
+    }
+
+    /// Whether the data is split into 4 KiB banks that are switched in and
+    /// out of $8000-$FFFF.
+    pub fn is_bank_switched(&self) -> bool {
+        self.initial_banks.iter().any(|&bank| bank != 0)
+    }
+}
+
+/// Size of the header, before the data.
+const HEADER_SIZE: usize = 0x80;
+
+/// Read a fixed-size, null-terminated string field.
+fn string(bytes: &[u8]) -> IResult<&[u8], String> {
+    let (bytes, field) = take(32usize)(bytes)?;
+    let field = field.split(|&b| b == 0).next().unwrap_or_default();
+    Ok((bytes, String::from_utf8_lossy(field).into_owned()))
+}
+
+fn header(bytes: &[u8]) -> IResult<&[u8], Nsf> {
+    let (bytes, _) = tag(MAGIC)(bytes)?;
+    let (bytes, version) = le_u8(bytes)?;
+    let (bytes, num_songs) = le_u8(bytes)?;
+    let (bytes, starting_song) = le_u8(bytes)?;
+    let (bytes, load_addr) = le_u16(bytes)?;
+    let (bytes, init_addr) = le_u16(bytes)?;
+    let (bytes, play_addr) = le_u16(bytes)?;
+    let (bytes, title) = string(bytes)?;
+    let (bytes, artist) = string(bytes)?;
+    let (bytes, copyright) = string(bytes)?;
+    let (bytes, ntsc_play_period_us) = le_u16(bytes)?;
+    let (bytes, banks) = take(8usize)(bytes)?;
+    let (bytes, pal_play_period_us) = le_u16(bytes)?;
+    let (bytes, region) = le_u8(bytes)?;
+    let (bytes, chips) = le_u8(bytes)?;
+    // The rest of the header is only used by NSF2.
+    let (bytes, _) = take(4usize)(bytes)?;
+
+    let mut initial_banks = [0; 8];
+    initial_banks.copy_from_slice(banks);
+    let nsf = Nsf {
+        version,
+        num_songs,
+        starting_song,
+        load_addr: Address(load_addr),
+        init_addr: Address(init_addr),
+        play_addr: Address(play_addr),
+        title,
+        artist,
+        copyright,
+        ntsc_play_period_us,
+        pal_play_period_us,
+        // Bit 1 means the music supports both regions.
+        region: match region & 0x03 {
+            0 => Some(Region::Ntsc),
+            1 => Some(Region::Pal),
+            _ => None,
+        },
+        initial_banks,
+        expansion_chips: ExpansionChips::from_bits_truncate(chips),
+        data: Vec::new(),
+    };
+    Ok((bytes, nsf))
+}
+
+/// Parse the contents of an NSF file.
+pub fn parse(bytes: &[u8]) -> Result<Nsf> {
+    let (data, mut nsf) = header(bytes).map_err(|_| {
+        anyhow!(
+            "Truncated NSF header: expected {} bytes, got {}",
+            HEADER_SIZE,
+            bytes.len()
+        )
+    })?;
+    if nsf.num_songs == 0 {
+        bail!("NSF file has no songs");
+    }
+    if nsf.load_addr < Address(0x6000) && !nsf.is_bank_switched() {
+        bail!("Invalid NSF load address: {}", nsf.load_addr);
+    }
+    nsf.data = data.to_vec();
+    Ok(nsf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&[1, 12, 3]);
+        bytes.extend_from_slice(&[0x00, 0x80, 0x03, 0x80, 0x06, 0x80]);
+        let mut title = b"Song Title".to_vec();
+        title.resize(32, 0);
+        bytes.extend_from_slice(&title);
+        bytes.resize(0x6E, 0);
+        bytes.extend_from_slice(&16639u16.to_le_bytes());
+        bytes.extend_from_slice(&[0, 1, 2, 3, 4, 5, 6, 7]);
+        bytes.resize(0x7A, 0);
+        bytes.extend_from_slice(&[0x02, 0x21]);
+        bytes.resize(HEADER_SIZE, 0);
+        bytes.extend_from_slice(&[0xEA; 0x100]);
+
+        let nsf = parse(&bytes).unwrap();
+        assert_eq!(nsf.num_songs, 12);
+        assert_eq!(nsf.starting_song, 3);
+        assert_eq!(nsf.play_addr, Address(0x8006));
+        assert_eq!(nsf.title, "Song Title");
+        assert_eq!(nsf.artist, "");
+        assert_eq!(nsf.ntsc_play_period_us, 16639);
+        assert!(nsf.is_bank_switched());
+        assert_eq!(nsf.region, None);
+        assert_eq!(nsf.expansion_chips.names(), ["VRC6", "Sunsoft 5B"]);
+        assert_eq!(nsf.data.len(), 0x100);
+
+        assert!(parse(&bytes[..0x40]).is_err());
+    }
+}
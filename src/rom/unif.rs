@@ -0,0 +1,235 @@
+//! Parser for UNIF ROM images.
+//!
+//! UNIF was designed as a replacement for iNES that identifies the board by
+//! name instead of by mapper number. It never caught on, but some dumps
+//! (mostly of unlicensed and multicart games) only exist in this format. A
+//! UNIF file consists of a 32-byte header followed by a series of chunks, each
+//! with a 4-byte ID and a 32-bit length. The board name is translated to the
+//! equivalent mapper number so the rest of the emulator doesn't need to know
+//! about UNIF.
+
+use anyhow::{anyhow, bail, Result};
+use nom::{
+    bytes::complete::{tag, take},
+    number::complete::le_u32,
+    IResult,
+};
+
+use super::{
+    ConsoleType, Header, Mirroring, Rom, CHR_BANK_SIZE, DEFAULT_CHR_RAM_SIZE, PRG_BANK_SIZE,
+};
+
+pub const MAGIC: &[u8] = b"UNIF";
+
+/// Size of the header, including the magic number and revision.
+const HEADER_SIZE: usize = 32;
+
+/// Board names and the mapper (and submapper) numbers of the equivalent iNES
+/// boards. Board names are matched without their prefix ("NES-", "UNL-",
+/// etc.), since the same board is often listed under several.
+const BOARDS: &[(&str, u8, u8)] = &[
+    ("NROM", 0, 0),
+    ("NROM-128", 0, 0),
+    ("NROM-256", 0, 0),
+    ("RROM", 0, 0),
+    ("SAROM", 1, 0),
+    ("SBROM", 1, 0),
+    ("SCROM", 1, 0),
+    ("SEROM", 1, 0),
+    ("SGROM", 1, 0),
+    ("SKROM", 1, 0),
+    ("SLROM", 1, 0),
+    ("SNROM", 1, 0),
+    ("SOROM", 1, 0),
+    ("SUROM", 1, 0),
+    ("SXROM", 1, 0),
+    ("UNROM", 2, 0),
+    ("UOROM", 2, 0),
+    ("CNROM", 3, 0),
+    ("TBROM", 4, 0),
+    ("TEROM", 4, 0),
+    ("TFROM", 4, 0),
+    ("TGROM", 4, 0),
+    ("TKROM", 4, 0),
+    ("TLROM", 4, 0),
+    ("TNROM", 4, 0),
+    ("TR1ROM", 4, 0),
+    ("TSROM", 4, 0),
+    ("TVROM", 4, 0),
+    ("B4", 4, 0),
+    ("ELROM", 5, 0),
+    ("EKROM", 5, 0),
+    ("ETROM", 5, 0),
+    ("EWROM", 5, 0),
+    ("AMROM", 7, 0),
+    ("ANROM", 7, 0),
+    ("AOROM", 7, 0),
+    ("PNROM", 9, 0),
+    ("PEEOROM", 9, 0),
+    ("UNROM-512-8", 30, 0),
+    ("UNROM-512-16", 30, 0),
+    ("UNROM-512-32", 30, 0),
+    ("BNROM", 34, 2),
+    ("NINA-01", 34, 1),
+    ("GNROM", 66, 0),
+    ("MHROM", 66, 0),
+    ("BF9093", 71, 0),
+    ("BF9097", 71, 1),
+    ("TLSROM", 118, 0),
+    ("TKSROM", 118, 0),
+    ("TQROM", 119, 0),
+    ("DEROM", 206, 0),
+    ("DE1ROM", 206, 0),
+    ("DRROM", 206, 0),
+    ("ACTION52", 228, 0),
+];
+
+/// Prefixes that identify who made a board rather than the board itself.
+const BOARD_PREFIXES: &[&str] = &["NES-", "HVC-", "UNL-", "BTL-", "BMC-", "AVE-", "MLT-"];
+
+/// Find the mapper and submapper numbers for a UNIF board name.
+fn board_mapper(name: &str) -> Option<(u8, u8)> {
+    let name = BOARD_PREFIXES
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name);
+    // Some dumps use the iNES mapper name for Codemasters boards.
+    let name = name.strip_prefix("CAMERICA-").unwrap_or(name);
+    BOARDS
+        .iter()
+        .find(|(board, _, _)| board.eq_ignore_ascii_case(name))
+        .map(|&(_, mapper, submapper)| (mapper, submapper))
+}
+
+fn header(bytes: &[u8]) -> IResult<&[u8], u32> {
+    let (bytes, _) = tag(MAGIC)(bytes)?;
+    let (bytes, revision) = le_u32(bytes)?;
+    let (bytes, _) = take(HEADER_SIZE - MAGIC.len() - 4)(bytes)?;
+    Ok((bytes, revision))
+}
+
+fn chunk(bytes: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
+    let (bytes, id) = take(4usize)(bytes)?;
+    let (bytes, len) = le_u32(bytes)?;
+    let (bytes, data) = take(len)(bytes)?;
+    Ok((bytes, (id, data)))
+}
+
+/// Parse the contents of a UNIF file.
+pub fn parse(bytes: &[u8]) -> Result<Rom> {
+    let (mut bytes, revision) =
+        header(bytes).map_err(|_| anyhow!("Failed to parse UNIF header"))?;
+    log::debug!("UNIF revision {}", revision);
+
+    let mut board = None;
+    // PRG and CHR data can be split across up to 16 chunks each, numbered
+    // in hex (PRG0-PRGF), which are concatenated in order.
+    let mut prg_chunks: [&[u8]; 16] = [&[]; 16];
+    let mut chr_chunks: [&[u8]; 16] = [&[]; 16];
+    let mut mirroring = Mirroring::Horizonal;
+    let mut has_battery = false;
+
+    while !bytes.is_empty() {
+        let (rest, (id, data)) = chunk(bytes).map_err(|_| anyhow!("Truncated UNIF chunk"))?;
+        bytes = rest;
+        match id {
+            b"MAPR" => {
+                let name = data.split(|&b| b == 0).next().unwrap_or_default();
+                board = Some(String::from_utf8_lossy(name).into_owned());
+            }
+            [b'P', b'R', b'G', n] | [b'C', b'H', b'R', n] => {
+                let Some(i) = (*n as char).to_digit(16) else {
+                    continue;
+                };
+                if id.starts_with(b"PRG") {
+                    prg_chunks[i as usize] = data;
+                } else {
+                    chr_chunks[i as usize] = data;
+                }
+            }
+            b"MIRR" => {
+                mirroring = match data.first() {
+                    Some(1) => Mirroring::Vertical,
+                    Some(2) => Mirroring::SingleScreenA,
+                    Some(3) => Mirroring::SingleScreenB,
+                    Some(4) => Mirroring::FourScreen,
+                    // 5 means the mapper controls mirroring.
+                    _ => Mirroring::Horizonal,
+                };
+            }
+            b"BATR" => has_battery = data.first().is_some_and(|&b| b != 0),
+            _ => log::debug!("Ignoring UNIF chunk {}", String::from_utf8_lossy(id)),
+        }
+    }
+
+    let Some(board) = board else {
+        bail!("UNIF file has no board name");
+    };
+    let Some((mapper, submapper)) = board_mapper(&board) else {
+        bail!("Unsupported UNIF board {}", board);
+    };
+    log::info!("UNIF board {} is mapper {}", board, mapper);
+
+    let prg = prg_chunks.concat();
+    let chr = chr_chunks.concat();
+    let header = Header {
+        num_prg_banks: prg.len().div_ceil(PRG_BANK_SIZE) as u8,
+        num_chr_banks: chr.len().div_ceil(CHR_BANK_SIZE) as u8,
+        num_prg_ram_banks: 0,
+        chr_ram_size: None,
+        mirroring,
+        mapper,
+        submapper,
+        has_battery,
+        has_trainer: false,
+        is_ines_v2: false,
+        console_type: ConsoleType::Nes,
+        region: None,
+    };
+    // Boards without CHR ROM have CHR RAM instead.
+    let chr = if chr.is_empty() {
+        vec![0; DEFAULT_CHR_RAM_SIZE]
+    } else {
+        chr
+    };
+    Ok(Rom {
+        header,
+        prg,
+        chr,
+        trainer: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&7u32.to_le_bytes());
+        bytes.resize(HEADER_SIZE, 0);
+        bytes.extend(chunk(b"MAPR", b"NES-TLROM\0"));
+        bytes.extend(chunk(b"PRG1", &[2; PRG_BANK_SIZE]));
+        bytes.extend(chunk(b"PRG0", &[1; PRG_BANK_SIZE]));
+        bytes.extend(chunk(b"CHR0", &[3; CHR_BANK_SIZE]));
+        bytes.extend(chunk(b"MIRR", &[1]));
+        bytes.extend(chunk(b"BATR", &[1]));
+
+        let rom = parse(&bytes).unwrap();
+        assert_eq!(rom.header.mapper, 4);
+        assert_eq!(rom.header.num_prg_banks, 2);
+        assert!(rom.header.has_battery);
+        assert!(matches!(rom.header.mirroring, Mirroring::Vertical));
+        assert_eq!(rom.prg[0], 1);
+        assert_eq!(rom.prg[PRG_BANK_SIZE], 2);
+        assert_eq!(rom.chr.len(), CHR_BANK_SIZE);
+    }
+}
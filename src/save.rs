@@ -0,0 +1,129 @@
+//! Battery-backed save files.
+//!
+//! Cartridges with a battery keep the contents of their PRG RAM while the
+//! console is off, which is how games like The Legend of Zelda save progress.
+//! We emulate this by storing the RAM in a `.sav` file, named after the ROM
+//! and placed either next to it or in a separate save directory.
+
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+pub struct SaveFile {
+    path: PathBuf,
+    /// Contents of the file as of the last load or write, so that unchanged
+    /// RAM doesn't get rewritten.
+    saved: Vec<u8>,
+}
+
+impl SaveFile {
+    /// Location of the save file for the given ROM. If no save directory is
+    /// given, the save file goes next to the ROM.
+    pub fn path_for(rom_path: &Path, save_dir: Option<&Path>) -> PathBuf {
+        let path = rom_path.with_extension("sav");
+        match (save_dir, path.file_name()) {
+            (Some(dir), Some(name)) => dir.join(name),
+            _ => path,
+        }
+    }
+
+    /// Open the save file at the given path, returning it along with its
+    /// contents (which are empty if the file doesn't exist yet).
+    pub fn open(path: PathBuf) -> Result<(Self, Vec<u8>)> {
+        let saved = match fs::read(&path) {
+            Ok(data) => {
+                log::info!("Loaded save file: {:?}", &path);
+                data
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", &path)),
+        };
+        let data = saved.clone();
+        Ok((Self { path, saved }, data))
+    }
+
+    /// Contents of the file as of the last load or write.
+    pub fn data(&self) -> &[u8] {
+        &self.saved
+    }
+
+    /// Write the given RAM contents to the save file, if they've changed
+    /// since the last write. The data is written to a temporary file first so
+    /// that a crash midway through doesn't destroy the existing save, and is
+    /// synced to disk before replacing it, so that a power loss or system
+    /// crash right afterwards doesn't leave an empty file behind.
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        if data == self.saved {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+            }
+        }
+        let tmp = self.path.with_extension("sav.tmp");
+        write_synced(&tmp, data).with_context(|| format!("Failed to write {:?}", &tmp))?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Failed to write {:?}", &self.path))?;
+        sync_dir(&self.path);
+        log::debug!("Wrote save file: {:?}", &self.path);
+        self.saved = data.to_vec();
+        Ok(())
+    }
+}
+
+fn write_synced(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// Sync the directory containing the given file, so that a rename into it is
+/// durable. Only possible on Unix; elsewhere, this does nothing.
+fn sync_dir(path: &Path) {
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if let Err(e) = File::open(dir).and_then(|dir| dir.sync_all()) {
+            log::warn!("Failed to sync {:?}: {}", dir, e);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_for() {
+        let rom = Path::new("roms/zelda.nes");
+        assert_eq!(SaveFile::path_for(rom, None), Path::new("roms/zelda.sav"));
+        assert_eq!(
+            SaveFile::path_for(rom, Some(Path::new("saves"))),
+            Path::new("saves/zelda.sav")
+        );
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = std::env::temp_dir().join(format!("nes-save-test-{}", std::process::id()));
+        let path = dir.join("game.sav");
+        let (mut save_file, data) = SaveFile::open(path.clone()).unwrap();
+        assert!(data.is_empty());
+
+        save_file.write(&[1, 2, 3]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [1, 2, 3]);
+        assert!(!path.with_extension("sav.tmp").exists());
+
+        let (_, data) = SaveFile::open(path).unwrap();
+        assert_eq!(data, [1, 2, 3]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,699 @@
+//! Save states, which capture the state of the whole console so that the game
+//! can later be resumed from exactly the same point.
+//!
+//! Each component of the console implements `Savestate`, writing its fields
+//! to a `StateWriter` in a fixed order and reading them back in the same order
+//! from a `StateReader`. Values are stored as compact little-endian binary,
+//! with no field names or padding, so the order is the format.
+//!
+//! A save state starts with a header (the magic bytes `NESS` and the version
+//! of the container format), followed by a section for each component. Each
+//! section has a 4-byte tag naming the component, the version of that
+//! component's format, and the length of its data. Sections that aren't
+//! recognized are skipped, so that states from newer versions of the emulator
+//! can still be loaded if the components they share haven't changed. If a
+//! component's section is missing or has a different version, loading fails
+//! rather than misreading the data.
+//!
+//! State files are compressed with zstd, which shrinks them to a fraction of
+//! their size since most of a state is RAM. Files are recognized as
+//! uncompressed if they start with the magic bytes, so states written before
+//! compression was added can still be loaded. States kept in memory aren't
+//! compressed.
+//!
+//! Only the console's state is saved. Anything that comes from the host (like
+//! audio output settings, muted channels and key bindings) is left as it is
+//! when a state is loaded.
+//!
+//! Each game has 10 numbered slots to save states to, which are files in a
+//! directory named after the ROM. By default, these directories are kept in
+//! the platform's data directory (e.g., `~/.local/share/nes/states` on Linux).
+//!
+//! If auto-resume is turned on in the config, a state is also saved when the
+//! emulator exits, and loaded the next time the same game is started. These
+//! are kept in the `auto` directory, named after the ROM's checksum so that
+//! renaming or moving the ROM doesn't lose them.
+
+use std::convert::TryInto;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+
+use crate::mem::Address;
+
+/// Identifies the start of a save state.
+const MAGIC: &[u8; 4] = b"NESS";
+
+/// Version of the container format. This only changes if the layout of the
+/// header or sections does; changes to what a component saves are tracked by
+/// the versions of the sections instead.
+const FORMAT_VERSION: u16 = 1;
+
+/// Level of zstd compression for state files. States are small enough that
+/// higher levels aren't worth the time they take.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Number of save state slots for each game.
+pub const NUM_SLOTS: u8 = 10;
+
+/// Where to load a save state from.
+#[derive(Clone, Debug)]
+pub enum StateLocation {
+    /// One of the game's numbered slots.
+    Slot(u8),
+    File(PathBuf),
+}
+
+impl FromStr for StateLocation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.parse::<u8>() {
+            Ok(slot) if slot < NUM_SLOTS => StateLocation::Slot(slot),
+            _ => StateLocation::File(s.into()),
+        })
+    }
+}
+
+/// The numbered save state slots for a game.
+pub struct StateSlots {
+    dir: PathBuf,
+}
+
+impl StateSlots {
+    /// Slots for the given ROM, kept in a directory named after it within the
+    /// given directory (or the default one, if not given).
+    pub fn for_rom(rom_path: &Path, state_dir: Option<&Path>) -> Self {
+        let name = rom_path.file_stem().unwrap_or_default();
+        Self {
+            dir: state_root(rom_path, state_dir).join(name),
+        }
+    }
+
+    fn path(&self, slot: u8) -> PathBuf {
+        self.dir.join(format!("slot{}.state", slot))
+    }
+
+    pub fn save(&self, slot: u8, state: &[u8]) -> Result<()> {
+        write_state(&self.path(slot), state)
+    }
+
+    pub fn load(&self, slot: u8) -> Result<Vec<u8>> {
+        read_state(&self.path(slot))
+    }
+}
+
+/// Where to save the state on exit when auto-resume is on, for the ROM with
+/// the given checksum.
+pub fn auto_save_path(rom_path: &Path, crc32: u32, state_dir: Option<&Path>) -> PathBuf {
+    state_root(rom_path, state_dir)
+        .join("auto")
+        .join(format!("{:08X}.state", crc32))
+}
+
+/// The directory holding all of the save states, falling back to a directory
+/// next to the ROM if the platform has no data directory.
+fn state_root(rom_path: &Path, state_dir: Option<&Path>) -> PathBuf {
+    match state_dir {
+        Some(dir) => dir.to_path_buf(),
+        None => dirs::data_dir()
+            .map(|dir| dir.join("nes").join("states"))
+            .unwrap_or_else(|| {
+                rom_path
+                    .parent()
+                    .unwrap_or_else(|| Path::new(""))
+                    .join("states")
+            }),
+    }
+}
+
+/// Write a state to a file, compressed, creating its directory if needed.
+pub fn write_state(path: &Path, state: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+    }
+    let data = zstd::encode_all(state, COMPRESSION_LEVEL).context("Failed to compress state")?;
+    fs::write(path, data).with_context(|| format!("Failed to write {:?}", path))
+}
+
+/// Read a state from a file, decompressing it if needed.
+pub fn read_state(path: &Path) -> Result<Vec<u8>> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+    if data.starts_with(MAGIC) {
+        return Ok(data);
+    }
+    zstd::decode_all(&data[..]).with_context(|| format!("{:?} is not a save state", path))
+}
+
+/// Identifies a section of a save state, and the version of its format. The
+/// version must be bumped whenever what the section holds changes.
+#[derive(Copy, Clone, Debug)]
+pub struct SectionId {
+    pub tag: [u8; 4],
+    pub version: u16,
+}
+
+impl SectionId {
+    fn name(&self) -> String {
+        String::from_utf8_lossy(&self.tag).trim_end().to_string()
+    }
+}
+
+/// Builds a save state out of a section for each component.
+pub struct StateBuilder {
+    w: StateWriter,
+}
+
+impl StateBuilder {
+    pub fn new() -> Self {
+        let mut w = StateWriter::new();
+        w.write(MAGIC);
+        w.write(&FORMAT_VERSION);
+        Self { w }
+    }
+
+    /// Add a section, whose contents are written by the given function.
+    pub fn section(&mut self, id: SectionId, save: impl FnOnce(&mut StateWriter)) {
+        let mut section = StateWriter::new();
+        save(&mut section);
+        self.w.write(&id.tag);
+        self.w.write(&id.version);
+        self.w.write(&section.into_bytes());
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.w.into_bytes()
+    }
+}
+
+/// The sections of a save state, ready to be loaded.
+pub struct StateSections<'a> {
+    sections: Vec<(SectionId, &'a [u8])>,
+}
+
+impl<'a> StateSections<'a> {
+    pub fn parse(state: &'a [u8]) -> Result<Self> {
+        let mut r = StateReader::new(state);
+        ensure!(
+            r.read::<[u8; 4]>().ok().as_ref() == Some(MAGIC),
+            "Not a save state"
+        );
+        let version: u16 = r.read()?;
+        ensure!(
+            version == FORMAT_VERSION,
+            "Unsupported save state format (version {}, expected {})",
+            version,
+            FORMAT_VERSION
+        );
+        let mut sections = Vec::new();
+        while !r.data.is_empty() {
+            let id = SectionId {
+                tag: r.read()?,
+                version: r.read()?,
+            };
+            let len = r.read::<u32>()? as usize;
+            sections.push((id, r.read_bytes(len)?));
+        }
+        Ok(Self { sections })
+    }
+
+    fn find(&self, tag: [u8; 4]) -> Option<(SectionId, &'a [u8])> {
+        self.sections
+            .iter()
+            .find(|(section, _)| section.tag == tag)
+            .copied()
+    }
+
+    /// Whether the state has the given section, for sections that are only
+    /// saved some of the time.
+    pub fn contains(&self, id: SectionId) -> bool {
+        self.find(id.tag).is_some()
+    }
+
+    /// Load a section with the given function, which must read all of it.
+    /// Fails if the section is missing or has a different version.
+    pub fn load<T>(
+        &self,
+        id: SectionId,
+        load: impl FnOnce(&mut StateReader<'a>) -> Result<T>,
+    ) -> Result<T> {
+        let (found, data) = self
+            .find(id.tag)
+            .ok_or_else(|| anyhow!("Save state has no {} section", id.name()))?;
+        ensure!(
+            found.version == id.version,
+            "Save state's {} section is version {}, but version {} is needed",
+            id.name(),
+            found.version,
+            id.version
+        );
+        let mut r = StateReader::new(data);
+        load(&mut r)
+            .and_then(|value| r.finish().map(|()| value))
+            .with_context(|| format!("Failed to load {} section", id.name()))
+    }
+}
+
+/// How two save states differ, section by section. Useful for tracking down
+/// where two runs that should be identical diverge.
+pub struct StateDiff {
+    pub sections: Vec<SectionDiff>,
+}
+
+pub struct SectionDiff {
+    /// The section's tag, e.g. `CPU`.
+    pub name: String,
+    pub change: SectionChange,
+}
+
+pub enum SectionChange {
+    /// The section is only in the first state.
+    Removed,
+    /// The section is only in the second state.
+    Added,
+    /// The sections have different versions, so their contents can't be
+    /// compared.
+    Version(u16, u16),
+    /// Runs of bytes that differ between the two sections.
+    Data(Vec<ByteRange>),
+}
+
+/// A run of differing bytes, at an offset into the section's data. Since the
+/// RAM section is just the contents of RAM, its offsets are CPU addresses.
+pub struct ByteRange {
+    pub offset: usize,
+    pub first: Vec<u8>,
+    pub second: Vec<u8>,
+}
+
+/// Compare two save states.
+pub fn compare(first: &[u8], second: &[u8]) -> Result<StateDiff> {
+    let first = StateSections::parse(first).context("Failed to parse first state")?;
+    let second = StateSections::parse(second).context("Failed to parse second state")?;
+    let mut sections = Vec::new();
+    let mut ids: Vec<SectionId> = Vec::new();
+    for &(id, _) in first.sections.iter().chain(&second.sections) {
+        if !ids.iter().any(|seen| seen.tag == id.tag) {
+            ids.push(id);
+        }
+    }
+    for id in ids {
+        let change = match (first.find(id.tag), second.find(id.tag)) {
+            (Some(_), None) => SectionChange::Removed,
+            (None, Some(_)) => SectionChange::Added,
+            (Some((a, _)), Some((b, _))) if a.version != b.version => {
+                SectionChange::Version(a.version, b.version)
+            }
+            (Some((_, a)), Some((_, b))) => SectionChange::Data(diff_bytes(a, b)),
+            (None, None) => unreachable!(),
+        };
+        if !matches!(&change, SectionChange::Data(ranges) if ranges.is_empty()) {
+            sections.push(SectionDiff {
+                name: id.name(),
+                change,
+            });
+        }
+    }
+    Ok(StateDiff { sections })
+}
+
+/// Find the runs of bytes that differ. If one side is longer, its extra bytes
+/// are a run of their own.
+fn diff_bytes(first: &[u8], second: &[u8]) -> Vec<ByteRange> {
+    let len = first.len().max(second.len());
+    let mut ranges: Vec<ByteRange> = Vec::new();
+    for i in 0..len {
+        let (a, b) = (first.get(i), second.get(i));
+        if a == b {
+            continue;
+        }
+        let range = match ranges.last_mut() {
+            Some(range) if range.offset + range.first.len().max(range.second.len()) == i => range,
+            _ => {
+                ranges.push(ByteRange {
+                    offset: i,
+                    first: Vec::new(),
+                    second: Vec::new(),
+                });
+                ranges.last_mut().unwrap()
+            }
+        };
+        range.first.extend(a);
+        range.second.extend(b);
+    }
+    ranges
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.sections.is_empty()
+    }
+
+    /// Describe the differences, listing at most `max_ranges` runs of bytes
+    /// for each section.
+    pub fn report(&self, max_ranges: usize) -> String {
+        let mut report = String::new();
+        for section in &self.sections {
+            let line = match &section.change {
+                SectionChange::Removed => "only in the first state".to_string(),
+                SectionChange::Added => "only in the second state".to_string(),
+                SectionChange::Version(a, b) => format!("version {} vs. {}", a, b),
+                SectionChange::Data(ranges) => {
+                    let bytes: usize = ranges
+                        .iter()
+                        .map(|range| range.first.len().max(range.second.len()))
+                        .sum();
+                    format!("{} bytes differ", bytes)
+                }
+            };
+            report += &format!("{}: {}\n", section.name, line);
+            let SectionChange::Data(ranges) = &section.change else {
+                continue;
+            };
+            for range in ranges.iter().take(max_ranges) {
+                report += &format!(
+                    "  {:04X}: {} -> {}\n",
+                    range.offset,
+                    hex_bytes(&range.first),
+                    hex_bytes(&range.second)
+                );
+            }
+            if ranges.len() > max_ranges {
+                report += &format!("  ... and {} more\n", ranges.len() - max_ranges);
+            }
+        }
+        report
+    }
+}
+
+/// Format bytes as hex, shortening long runs.
+fn hex_bytes(bytes: &[u8]) -> String {
+    const MAX_BYTES: usize = 8;
+    if bytes.is_empty() {
+        return "(none)".to_string();
+    }
+    let mut s: Vec<String> = bytes
+        .iter()
+        .take(MAX_BYTES)
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    if bytes.len() > MAX_BYTES {
+        s.push(format!("... ({} bytes)", bytes.len()));
+    }
+    s.join(" ")
+}
+
+/// A part of the console whose state can be saved and restored.
+pub trait Savestate {
+    fn save_state(&self, w: &mut StateWriter);
+
+    /// Restore the state written by `save_state`. On error, the component may
+    /// be left partially restored.
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()>;
+}
+
+/// A value that can be written to (and read from) a save state.
+pub trait StateValue: Sized {
+    fn write(&self, w: &mut StateWriter);
+
+    fn read(r: &mut StateReader) -> Result<Self>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write<T: StateValue>(&mut self, value: &T) {
+        value.write(self);
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn read<T: StateValue>(&mut self) -> Result<T> {
+        T::read(self)
+    }
+
+    /// Read variable-length data (written as a `Vec<u8>`) into a buffer of
+    /// the same length, like cartridge RAM whose size comes from the ROM.
+    pub fn read_into(&mut self, dest: &mut [u8]) -> Result<()> {
+        let len = self.read::<u32>()? as usize;
+        ensure!(
+            len == dest.len(),
+            "Save state has {} bytes of memory where {} are needed",
+            len,
+            dest.len()
+        );
+        dest.copy_from_slice(self.read_bytes(len)?);
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        ensure!(self.data.len() >= len, "Save state is truncated");
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    /// Check that the whole state was read, since anything left over means
+    /// that it wasn't read the same way it was written.
+    pub fn finish(self) -> Result<()> {
+        ensure!(
+            self.data.is_empty(),
+            "Save state has {} unexpected trailing bytes",
+            self.data.len()
+        );
+        Ok(())
+    }
+}
+
+macro_rules! num_state_value {
+    ($($t:ty),*) => {
+        $(
+            impl StateValue for $t {
+                fn write(&self, w: &mut StateWriter) {
+                    w.write_bytes(&self.to_le_bytes());
+                }
+
+                fn read(r: &mut StateReader) -> Result<Self> {
+                    let bytes = r.read_bytes(std::mem::size_of::<$t>())?;
+                    Ok(<$t>::from_le_bytes(bytes.try_into().unwrap()))
+                }
+            }
+        )*
+    };
+}
+
+num_state_value!(u8, u16, u32, u64, i16, f32);
+
+/// Sizes and indices (like bank numbers), which are stored as 32 bits.
+impl StateValue for usize {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&(*self as u32));
+    }
+
+    fn read(r: &mut StateReader) -> Result<Self> {
+        Ok(r.read::<u32>()? as usize)
+    }
+}
+
+impl StateValue for bool {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&(*self as u8));
+    }
+
+    fn read(r: &mut StateReader) -> Result<Self> {
+        match r.read::<u8>()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            n => bail!("Invalid boolean in save state: {}", n),
+        }
+    }
+}
+
+impl StateValue for Address {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&self.0);
+    }
+
+    fn read(r: &mut StateReader) -> Result<Self> {
+        Ok(Address(r.read()?))
+    }
+}
+
+impl<T: StateValue> StateValue for Option<T> {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&self.is_some());
+        if let Some(value) = self {
+            w.write(value);
+        }
+    }
+
+    fn read(r: &mut StateReader) -> Result<Self> {
+        Ok(if r.read()? { Some(r.read()?) } else { None })
+    }
+}
+
+impl<A: StateValue, B: StateValue> StateValue for (A, B) {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&self.0);
+        w.write(&self.1);
+    }
+
+    fn read(r: &mut StateReader) -> Result<Self> {
+        Ok((r.read()?, r.read()?))
+    }
+}
+
+impl<T: StateValue + Copy + Default, const N: usize> StateValue for [T; N] {
+    fn write(&self, w: &mut StateWriter) {
+        for value in self {
+            w.write(value);
+        }
+    }
+
+    fn read(r: &mut StateReader) -> Result<Self> {
+        let mut values = [T::default(); N];
+        for value in &mut values {
+            *value = r.read()?;
+        }
+        Ok(values)
+    }
+}
+
+/// Variable-length data (like cartridge RAM), prefixed with its length.
+impl StateValue for Vec<u8> {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&(self.len() as u32));
+        w.write_bytes(self);
+    }
+
+    fn read(r: &mut StateReader) -> Result<Self> {
+        let len = r.read::<u32>()? as usize;
+        Ok(r.read_bytes(len)?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTION_A: SectionId = SectionId {
+        tag: *b"A   ",
+        version: 1,
+    };
+    const SECTION_B: SectionId = SectionId {
+        tag: *b"B   ",
+        version: 1,
+    };
+
+    #[test]
+    fn test_sections() {
+        let mut builder = StateBuilder::new();
+        builder.section(SECTION_A, |w| w.write(&1u8));
+        builder.section(SECTION_B, |w| w.write(&2u16));
+        let state = builder.finish();
+
+        let sections = StateSections::parse(&state).unwrap();
+        sections
+            .load(SECTION_B, |r| {
+                assert_eq!(r.read::<u16>()?, 2);
+                Ok(())
+            })
+            .unwrap();
+        // Sections have to be read completely.
+        assert!(sections.load(SECTION_A, |_| Ok(())).is_err());
+        let newer = SectionId {
+            version: 2,
+            ..SECTION_A
+        };
+        assert!(sections.load(newer, |_| Ok(())).is_err());
+        let missing = SectionId {
+            tag: *b"C   ",
+            version: 1,
+        };
+        assert!(sections.load(missing, |_| Ok(())).is_err());
+
+        let mut state = state;
+        state[4] = 2;
+        assert!(StateSections::parse(&state).is_err());
+        assert!(StateSections::parse(b"nope").is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        let state = |a: [u8; 4], b: Option<u8>| {
+            let mut builder = StateBuilder::new();
+            builder.section(SECTION_A, |w| w.write(&a));
+            if let Some(b) = b {
+                builder.section(SECTION_B, |w| w.write(&b));
+            }
+            builder.finish()
+        };
+        let first = state([1, 2, 3, 4], Some(5));
+        assert!(compare(&first, &first).unwrap().is_empty());
+
+        let diff = compare(&first, &state([1, 9, 9, 4], None)).unwrap();
+        assert_eq!(
+            diff.report(10),
+            "A: 2 bytes differ\n  0001: 02 03 -> 09 09\nB: only in the first state\n"
+        );
+    }
+
+    #[test]
+    fn test_state_location() {
+        assert!(matches!("3".parse(), Ok(StateLocation::Slot(3))));
+        assert!(matches!("10".parse(), Ok(StateLocation::File(_))));
+        assert!(matches!(
+            "game.state".parse(),
+            Ok(StateLocation::File(path)) if path == Path::new("game.state")
+        ));
+
+        let slots = StateSlots::for_rom(Path::new("roms/zelda.nes"), Some(Path::new("/states")));
+        assert_eq!(slots.path(2), Path::new("/states/zelda/slot2.state"));
+    }
+
+    #[test]
+    fn test_compression() {
+        let dir = std::env::temp_dir().join(format!("nes-state-test-{}", std::process::id()));
+        let path = dir.join("slot0.state");
+        let mut builder = StateBuilder::new();
+        builder.section(SECTION_A, |w| w.write(&vec![0u8; 2048]));
+        let state = builder.finish();
+
+        write_state(&path, &state).unwrap();
+        assert!(fs::read(&path).unwrap().len() < state.len() / 4);
+        assert_eq!(read_state(&path).unwrap(), state);
+
+        // Uncompressed states are still read.
+        fs::write(&path, &state).unwrap();
+        assert_eq!(read_state(&path).unwrap(), state);
+
+        fs::write(&path, b"junk").unwrap();
+        assert!(read_state(&path).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
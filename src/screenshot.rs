@@ -0,0 +1,80 @@
+//! Screenshots, saved as PNG files.
+//!
+//! A screenshot is the frame exactly as the PPU rendered it, without anything
+//! the emulator draws over the game (like the input overlay and messages).
+//! Optionally, the frame with those overlays is saved alongside it. Neither
+//! has the CRT filter applied, which only happens on the way to the window.
+//!
+//! Screenshots taken with the hotkey are named after the ROM and the time they
+//! were taken, and saved in the current directory unless another one is set
+//! in the config. Clips (see the `clip` module) are saved there too.
+
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::ppu::{FRAME_HEIGHT, FRAME_WIDTH};
+
+/// Where to save screenshots of a game.
+pub struct Screenshots {
+    dir: PathBuf,
+    name: String,
+    /// Also save the frame with overlays drawn over it.
+    pub save_overlays: bool,
+}
+
+impl Screenshots {
+    /// Screenshots of the given ROM, saved in the given directory (or the
+    /// current one, if not given).
+    pub fn for_rom(rom_path: &Path, dir: Option<&Path>) -> Self {
+        let name = rom_path.file_stem().unwrap_or_default();
+        Self {
+            dir: dir.unwrap_or_else(|| Path::new("")).to_path_buf(),
+            name: name.to_string_lossy().into_owned(),
+            save_overlays: false,
+        }
+    }
+
+    /// A new path in the screenshot directory with the given extension,
+    /// named after the ROM and the current time. The directory is created if
+    /// needed.
+    pub fn new_path(&self, extension: &str) -> Result<PathBuf> {
+        if !self.dir.as_os_str().is_empty() {
+            fs::create_dir_all(&self.dir)
+                .with_context(|| format!("Failed to create {:?}", &self.dir))?;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        Ok(self
+            .dir
+            .join(format!("{}-{}.{}", self.name, timestamp, extension)))
+    }
+
+    /// Save a frame, and the frame with overlays if given, returning the
+    /// path of the first.
+    pub fn save(&self, frame: &[u8], overlaid: Option<&[u8]>) -> Result<PathBuf> {
+        let path = self.new_path("png")?;
+        write_png(&path, FRAME_WIDTH, FRAME_HEIGHT, frame)?;
+        if let Some(overlaid) = overlaid {
+            let path = path.with_extension("overlays.png");
+            write_png(&path, FRAME_WIDTH, FRAME_HEIGHT, overlaid)?;
+        }
+        Ok(path)
+    }
+}
+
+/// Write an RGBA image to a PNG file.
+pub fn write_png(path: &Path, width: usize, height: usize, pixels: &[u8]) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create {:?}", path))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    writer.finish()?;
+    Ok(())
+}
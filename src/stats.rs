@@ -0,0 +1,109 @@
+//! On-screen display of how the emulator is performing.
+//!
+//! The overlay shows, from top to bottom:
+//! - `FPS`: updates per second from the host's event loop, which is roughly
+//!   the rate the window is redrawn at.
+//! - `EMU`: frames emulated per second, which should match the console's
+//!   frame rate (about 60.1 on NTSC) at normal speed.
+//! - `AUDIO`: how much audio is buffered for the audio device, if there is
+//!   one. This hovers around the configured latency when all is well.
+//! - `FRAME`: the average time taken to emulate a frame, and how much of the
+//!   frame's duration on the console that is. Above 100%, the host can't keep
+//!   up.
+//!
+//! Rates and times are averaged over half a second, so that they're readable.
+
+use std::time::{Duration, Instant};
+
+use crate::font;
+use crate::ppu::FRAME_WIDTH;
+
+/// How often the displayed numbers change.
+const INTERVAL: Duration = Duration::from_millis(500);
+
+const MARGIN: usize = 4;
+
+/// Averages that are shown on screen.
+#[derive(Default)]
+struct Averages {
+    updates_per_sec: f64,
+    frames_per_sec: f64,
+    frame_time: Duration,
+}
+
+/// Counts of what has happened since the start of the interval, and the
+/// averages over the last interval.
+pub struct Stats {
+    start: Instant,
+    updates: u32,
+    frames: u32,
+    emulation_time: Duration,
+    averages: Averages,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            updates: 0,
+            frames: 0,
+            emulation_time: Duration::ZERO,
+            averages: Averages::default(),
+        }
+    }
+
+    /// Count an update from the host.
+    pub fn record_update(&mut self) {
+        self.updates += 1;
+        let elapsed = self.start.elapsed();
+        if elapsed < INTERVAL {
+            return;
+        }
+        let secs = elapsed.as_secs_f64();
+        self.averages = Averages {
+            updates_per_sec: self.updates as f64 / secs,
+            frames_per_sec: self.frames as f64 / secs,
+            frame_time: self.emulation_time / self.frames.max(1),
+        };
+        self.start = Instant::now();
+        self.updates = 0;
+        self.frames = 0;
+        self.emulation_time = Duration::ZERO;
+    }
+
+    /// Count an emulated frame, which took the given time to emulate.
+    pub fn record_frame(&mut self, time: Duration) {
+        self.frames += 1;
+        self.emulation_time += time;
+    }
+
+    /// Draw the stats in the top right corner of the frame, given the audio
+    /// that's buffered (if any) and the console's frame rate.
+    pub fn draw(&self, frame: &mut [u8], audio_buffered: Option<Duration>, frame_rate: f64) {
+        let averages = &self.averages;
+        let budget = averages.frame_time.as_secs_f64() * frame_rate;
+        let mut lines = vec![
+            format!("FPS {:.1}", averages.updates_per_sec),
+            format!("EMU {:.1}", averages.frames_per_sec),
+        ];
+        if let Some(buffered) = audio_buffered {
+            lines.push(format!("AUDIO {} MS", buffered.as_millis()));
+        }
+        lines.push(format!(
+            "FRAME {:.1} MS {:.0}%",
+            averages.frame_time.as_secs_f64() * 1000.0,
+            budget * 100.0
+        ));
+
+        let width = lines
+            .iter()
+            .map(|line| font::label_size(line).0)
+            .max()
+            .unwrap_or(0);
+        let left = FRAME_WIDTH - MARGIN - width;
+        for (i, line) in lines.iter().enumerate() {
+            let (_, height) = font::label_size(line);
+            font::draw_label(frame, FRAME_WIDTH, left, MARGIN + i * height, line);
+        }
+    }
+}
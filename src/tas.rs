@@ -0,0 +1,153 @@
+//! TAS mode, for building a movie one frame at a time.
+//!
+//! The game is paused between frames. Pressing a controller's key toggles
+//! that button for the next frame (and the buttons stay set until they're
+//! toggled again), which is shown on screen by the input overlay. The frame
+//! advance hotkey runs the next frame with that input and appends it to the
+//! movie, and the undo hotkey removes the last frame so that its input can be
+//! changed.
+//!
+//! Emulation is deterministic given the input, so undoing a frame is done by
+//! going back to the last checkpoint (a snapshot of the console, taken every
+//! second of the movie) and replaying the movie from there. The movie is
+//! saved when the window is closed.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+use winit_input_helper::WinitInputHelper;
+
+use crate::hotkeys::{Hotkey, Hotkeys};
+use crate::input_overlay;
+use crate::movie::{Movie, MovieFrame};
+use crate::nes::{Nes, Snapshot};
+use crate::ppu::{FRAME_HEIGHT, FRAME_WIDTH};
+use crate::ui::Ui;
+
+/// Number of frames between checkpoints. Snapshots are cheap, but keeping one
+/// for every frame of a long movie would take a lot of memory.
+const CHECKPOINT_INTERVAL: usize = 60;
+
+pub struct TasUi {
+    nes: Nes,
+    /// The console's state and screen after every `CHECKPOINT_INTERVAL`
+    /// frames of the movie, starting from power on.
+    checkpoints: Vec<(Snapshot, Vec<u8>)>,
+    movie: Movie,
+    path: PathBuf,
+    /// The input to use on the next frame.
+    next: MovieFrame,
+    /// The most recently emulated frame.
+    screen: Vec<u8>,
+}
+
+impl TasUi {
+    /// Start editing the given movie on a freshly powered-on console. The
+    /// movie is saved to the given path on exit. Any frames already in the
+    /// movie are replayed first.
+    pub fn new(nes: Nes, movie: Movie, path: PathBuf) -> Self {
+        let screen = vec![0; FRAME_WIDTH * FRAME_HEIGHT * 4];
+        let mut ui = Self {
+            checkpoints: vec![(nes.snapshot(), screen.clone())],
+            nes,
+            movie,
+            path,
+            next: MovieFrame::default(),
+            screen,
+        };
+        ui.replay(0, &WinitInputHelper::new());
+        ui
+    }
+
+    /// Run the frames of the movie from the given one onwards, taking
+    /// checkpoints along the way.
+    fn replay(&mut self, start: usize, input: &WinitInputHelper) {
+        for i in start..self.movie.frames.len() {
+            self.run_frame(i, input);
+        }
+        log::info!("Frame {}", self.movie.frames.len());
+    }
+
+    fn run_frame(&mut self, i: usize, input: &WinitInputHelper) {
+        let frame = self.movie.frames[i];
+        self.nes.run_movie_frame(&mut self.screen, frame, input);
+        let frames_run = i + 1;
+        if frames_run.is_multiple_of(CHECKPOINT_INTERVAL)
+            && self.checkpoints.len() == frames_run / CHECKPOINT_INTERVAL
+        {
+            self.checkpoints
+                .push((self.nes.snapshot(), self.screen.clone()));
+        }
+    }
+
+    fn advance(&mut self, input: &WinitInputHelper) {
+        self.movie.frames.push(self.next);
+        self.run_frame(self.movie.frames.len() - 1, input);
+        log::info!("Frame {}", self.movie.frames.len());
+    }
+
+    fn undo(&mut self, input: &WinitInputHelper) -> Result<()> {
+        let Some(frame) = self.movie.frames.pop() else {
+            return Ok(());
+        };
+        self.next = frame;
+        let len = self.movie.frames.len();
+        let checkpoint = len / CHECKPOINT_INTERVAL;
+        self.checkpoints.truncate(checkpoint + 1);
+        let (snapshot, screen) = &self.checkpoints[checkpoint];
+        self.nes.restore(snapshot)?;
+        self.screen.copy_from_slice(screen);
+        self.replay(checkpoint * CHECKPOINT_INTERVAL, input);
+        Ok(())
+    }
+}
+
+impl Ui for TasUi {
+    fn size(&self) -> (u32, u32) {
+        (FRAME_WIDTH as u32, FRAME_HEIGHT as u32)
+    }
+
+    fn update(&mut self, frame: &mut [u8], input: &WinitInputHelper, _dt: Duration) -> Result<()> {
+        let players = self.nes.players();
+        for (player, buttons) in self.next.buttons[..players].iter_mut().enumerate() {
+            *buttons ^= self.nes.pressed_buttons(input, player);
+        }
+        frame.copy_from_slice(&self.screen);
+        input_overlay::draw(frame, &self.next.buttons[..players]);
+        Ok(())
+    }
+
+    fn advance_frame(&mut self, frame: &mut [u8], input: &WinitInputHelper) -> Result<()> {
+        self.advance(input);
+        self.update(frame, input, Duration::ZERO)
+    }
+
+    fn hotkeys(&self) -> Option<&Hotkeys> {
+        self.nes.hotkeys()
+    }
+
+    fn handle_hotkey(&mut self, hotkey: Hotkey, input: &WinitInputHelper) {
+        match hotkey {
+            Hotkey::FrameAdvance => self.advance(input),
+            Hotkey::UndoFrame => {
+                if let Err(e) = self.undo(input) {
+                    log::error!("Failed to undo frame: {:#}", e);
+                }
+            }
+            hotkey => self.nes.handle_hotkey(hotkey, input),
+        }
+    }
+
+    fn set_cursor(&mut self, pos: Option<(usize, usize)>) {
+        self.nes.set_cursor(pos);
+    }
+}
+
+impl Drop for TasUi {
+    fn drop(&mut self) {
+        if let Err(e) = self.movie.save(&self.path) {
+            log::error!("Failed to save movie: {:#}", e);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+//! Harness for running test ROMs headlessly.
+//!
+//! Most of the widely used NES test ROMs (including all of blargg's newer test
+//! suites) report their results via a common protocol: once a test starts, it
+//! writes the signature bytes $DE $B0 $61 to $6001-$6003 and sets $6000 to a
+//! status code. The status is $80 while the test is running, $81 if the test
+//! needs the console to be reset, and the test's result code (0 for success)
+//! once it has finished. A human-readable report is written as a NUL-terminated
+//! string starting at $6004.
+//!
+//! Test ROMs that only report their results on screen (such as Holy
+//! Mapperel) are instead checked against a hash of the frame they display
+//! after running for a fixed amount of time.
+//!
+//! The test ROMs themselves aren't distributed with this repository; see
+//! `data/blargg/README` and `data/mappers/README` for where to put them. Tests
+//! whose ROMs are missing are skipped.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+
+use crate::mem::Address;
+use crate::nes::Nes;
+use crate::ppu::{FRAME_HEIGHT, FRAME_WIDTH};
+use crate::region::Region;
+use crate::rom::Rom;
+
+const STATUS_ADDR: Address = Address(0x6000);
+const SIGNATURE_ADDR: Address = Address(0x6001);
+const TEXT_ADDR: Address = Address(0x6004);
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_NEEDS_RESET: u8 = 0x81;
+
+/// Number of frames to wait after a test requests a reset before resetting
+/// the console. Tests require a short delay so they can tell that a reset
+/// actually happened (rather than, e.g., a crash).
+const RESET_DELAY_FRAMES: usize = 6;
+
+/// Give up on a test if it hasn't finished after this many frames (about a
+/// minute of emulated time).
+const MAX_FRAMES: usize = 60 * 60;
+
+/// Number of frames to run tests that report their results on screen before
+/// checking what they display.
+const SCREEN_TEST_FRAMES: usize = 10 * 60;
+
+/// Outcome of a test ROM that ran to completion.
+pub struct TestResult {
+    /// Result code reported by the test; 0 means success.
+    pub status: u8,
+    /// Text output of the test.
+    pub text: String,
+    /// All of the audio generated while the test was running.
+    pub samples: Vec<f32>,
+}
+
+fn data_dir() -> PathBuf {
+    let manifest_dir: PathBuf = env::var("CARGO_MANIFEST_DIR")
+        .expect("CARGO_MANIFEST_DIR environment variable not set")
+        .into();
+    manifest_dir.join("data")
+}
+
+/// Find all of the ROMs in the given subdirectory of `data/`, or `None` if
+/// the directory doesn't exist.
+pub fn find_roms(dir: impl AsRef<Path>) -> Option<Vec<PathBuf>> {
+    let dir = data_dir().join(dir);
+    let mut roms: Vec<_> = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension() == Some("nes".as_ref()))
+        .collect();
+    roms.sort();
+    Some(roms)
+}
+
+/// Run a test ROM until it reports that it has finished.
+pub fn run(path: impl AsRef<Path>) -> Result<TestResult> {
+    let path = path.as_ref();
+    let mut nes = Nes::new(Rom::load(path)?, Some(Region::Ntsc))?;
+    let mut frame = vec![0u8; FRAME_WIDTH * FRAME_HEIGHT * 4];
+    let mut samples = Vec::new();
+
+    let mut started = false;
+    let mut reset_countdown = None;
+    for _ in 0..MAX_FRAMES {
+        samples.extend(nes.run_one_frame_headless(&mut frame));
+
+        // Don't trust the status byte until the signature has been written,
+        // since the contents of RAM are arbitrary before then.
+        let mut signature = [0u8; 3];
+        for (i, byte) in signature.iter_mut().enumerate() {
+            *byte = nes.peek(SIGNATURE_ADDR + i);
+        }
+        if signature != SIGNATURE {
+            if started {
+                bail!("{:?}: test signature was overwritten", path);
+            }
+            continue;
+        }
+        started = true;
+
+        match nes.peek(STATUS_ADDR) {
+            STATUS_RUNNING => {}
+            STATUS_NEEDS_RESET => match reset_countdown {
+                Some(0) => {
+                    nes.reset();
+                    reset_countdown = None;
+                }
+                Some(n) => reset_countdown = Some(n - 1),
+                None => reset_countdown = Some(RESET_DELAY_FRAMES),
+            },
+            status => {
+                return Ok(TestResult {
+                    status,
+                    text: read_text(&mut nes),
+                    samples,
+                })
+            }
+        }
+    }
+
+    if started {
+        bail!(
+            "{:?}: timed out; output so far: {}",
+            path,
+            read_text(&mut nes)
+        );
+    } else {
+        bail!("{:?}: test never started", path);
+    }
+}
+
+/// Run a test ROM that reports its results on screen for a fixed number of
+/// frames, and return a hash of the final frame.
+pub fn run_screen_test(path: impl AsRef<Path>, frames: usize) -> Result<u64> {
+    let mut nes = Nes::new(Rom::load(path.as_ref())?, Some(Region::Ntsc))?;
+    let mut frame = vec![0u8; FRAME_WIDTH * FRAME_HEIGHT * 4];
+    for _ in 0..frames {
+        nes.run_one_frame_headless(&mut frame);
+    }
+    Ok(fnv1a(&frame))
+}
+
+/// 64-bit FNV-1a hash, used to compare frames against known-good output.
+/// (Unlike `DefaultHasher`, its output is guaranteed to be stable.)
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xCBF29CE484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001B3)
+    })
+}
+
+fn read_text(nes: &mut Nes) -> String {
+    let bytes: Vec<u8> = (0..0x1000u16)
+        .map(|i| nes.peek(TEXT_ADDR + i))
+        .take_while(|&byte| byte != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).trim().to_string()
+}
+
+/// Root-mean-square amplitude of the given samples.
+fn rms(samples: &[f32]) -> f32 {
+    let sum: f32 = samples.iter().map(|s| s * s).sum();
+    (sum / samples.len().max(1) as f32).sqrt()
+}
+
+/// Run every ROM in the given directory, and check that each one passes
+/// according to `check`. All of the ROMs are run before reporting failures.
+fn run_suite(dir: &str, check: impl Fn(&TestResult) -> bool) {
+    let roms = match find_roms(dir) {
+        Some(roms) if !roms.is_empty() => roms,
+        _ => {
+            eprintln!("Skipping {}: test ROMs not found", dir);
+            return;
+        }
+    };
+
+    let mut failures = Vec::new();
+    for rom in roms {
+        match run(&rom) {
+            Ok(result) if result.status == 0 && check(&result) => {}
+            Ok(result) => failures.push(format!(
+                "{:?} failed with status {}: {}",
+                rom, result.status, result.text
+            )),
+            Err(e) => failures.push(e.to_string()),
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn apu_test() {
+    run_suite("blargg/apu_test", |_| true);
+}
+
+/// Each of the mixer tests plays a tone on one of the channels, along with an
+/// inverted copy of the same tone generated using the DMC. If the mixer's
+/// nonlinearity is emulated correctly, the two cancel out and the test is
+/// (nearly) silent. The ROMs can't check this themselves, so check the audio
+/// output instead.
+#[test]
+fn apu_mixer() {
+    // Threshold below which residual tones are effectively inaudible. The
+    // output filters remove DC, so any remaining signal is the tone itself.
+    const MAX_RMS: f32 = 0.01;
+    run_suite("blargg/apu_mixer", |result| rms(&result.samples) < MAX_RMS);
+}
+
+/// Run the test ROMs for each mapper in `data/mappers/`, which contains a
+/// subdirectory for each mapper named after its number. ROMs that use the
+/// $6000 protocol must report success. ROMs that only report their results on
+/// screen need a `.frame` file next to them containing the hash of the frame
+/// they display when they pass, in hex.
+#[test]
+fn mapper_conformance() {
+    let mut dirs: Vec<(u8, PathBuf)> = match fs::read_dir(data_dir().join("mappers")) {
+        Ok(entries) => entries
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name();
+                let mapper = name.to_str()?.parse().ok()?;
+                Some((mapper, Path::new("mappers").join(name)))
+            })
+            .collect(),
+        Err(_) => {
+            eprintln!("Skipping mappers: test ROMs not found");
+            return;
+        }
+    };
+    dirs.sort();
+
+    let mut failures = Vec::new();
+    for (mapper, dir) in dirs {
+        for rom in find_roms(&dir).unwrap_or_default() {
+            if let Err(e) = check_mapper_rom(mapper, &rom) {
+                failures.push(format!("{:?}: {:#}", rom, e));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+fn check_mapper_rom(mapper: u8, rom: &Path) -> Result<()> {
+    // Make sure the ROM is exercising the mapper it's filed under.
+    let header_mapper = Rom::load(rom)?.header.mapper;
+    if header_mapper != mapper {
+        bail!("ROM uses mapper {}", header_mapper);
+    }
+
+    let frame_path = rom.with_extension("frame");
+    if frame_path.exists() {
+        let expected = fs::read_to_string(&frame_path)?;
+        let expected = u64::from_str_radix(expected.trim(), 16)?;
+        let actual = run_screen_test(rom, SCREEN_TEST_FRAMES)?;
+        if actual != expected {
+            bail!(
+                "final frame hash is {:016x}, expected {:016x}",
+                actual,
+                expected
+            );
+        }
+    } else {
+        let result = run(rom)?;
+        if result.status != 0 {
+            bail!("failed with status {}: {}", result.status, result.text);
+        }
+    }
+    Ok(())
+}
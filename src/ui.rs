@@ -1,50 +1,307 @@
 use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use pixels::{Pixels, SurfaceTexture};
+use anyhow::{Context, Error, Result};
+use clap::ValueEnum;
+use pixels::{Pixels, PixelsBuilder, SurfaceTexture};
+use serde::Deserialize;
 use winit::dpi::LogicalSize;
-use winit::event::Event;
+use winit::event::{ElementState, Event, MouseButton, MouseScrollDelta, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
-use winit::window::WindowBuilder;
+use winit::platform::run_return::EventLoopExtRunReturn;
+use winit::window::{Window, WindowBuilder};
 use winit_input_helper::WinitInputHelper;
 
-pub trait Ui: Sized + 'static {
+use crate::crt;
+use crate::font;
+use crate::hotkeys::{Hotkey, Hotkeys};
+
+/// How often to update the frame rate shown in the window's title.
+const TITLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far a touchpad has to scroll to count as one line of a mouse wheel,
+/// in pixels.
+const SCROLL_LINE_PIXELS: f64 = 16.0;
+
+/// How many frames behind the clock the event loop may fall before it stops
+/// trying to catch up (e.g., after the window was dragged).
+const MAX_LATE_FRAMES: u32 = 4;
+
+/// How the event loop decides when to update a UI that has a frame rate.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pacing {
+    /// Update at the UI's frame rate, timed by the host's clock. Games run at
+    /// the right speed on any display, but vsync is off, so there may be
+    /// tearing.
+    #[default]
+    Timer,
+    /// Update once for each frame the display shows, with vsync. This is
+    /// smoothest if the display refreshes at about the console's frame rate,
+    /// but games run at the display's rate instead.
+    Vsync,
+}
+
+/// Options for a UI's window and event loop.
+#[derive(Clone, Debug)]
+pub struct UiOptions {
+    /// Start with emulation paused (e.g., for debugging a game's startup
+    /// code), as if the pause hotkey had been pressed.
+    pub start_paused: bool,
+    pub pacing: Pacing,
+    /// Initial size of the window, as a multiple of the UI's size. The
+    /// window can't be made smaller than 1x.
+    pub scale: u32,
+    /// Start with the CRT filter on (see the `crt` module).
+    pub crt: bool,
+    /// Pause while none of the UI's windows are focused. The audio goes
+    /// quiet while paused, since nothing is being emulated.
+    pub pause_on_focus_loss: bool,
+}
+
+impl Default for UiOptions {
+    fn default() -> Self {
+        Self {
+            start_paused: false,
+            pacing: Pacing::default(),
+            scale: 1,
+            crt: false,
+            pause_on_focus_loss: false,
+        }
+    }
+}
+
+pub trait Ui: Sized {
     fn size(&self) -> (u32, u32);
 
+    /// What to show in the window's title (e.g., the name of the game and
+    /// how fast it's running). The event loop adds whether it's paused and
+    /// the rate it's updating at.
+    fn title(&self) -> Option<String> {
+        None
+    }
+
     fn update(&mut self, frame: &mut [u8], input: &WinitInputHelper, dt: Duration) -> Result<()>;
 
-    fn run(mut self) -> Result<()> {
+    /// The rate at which to call `update`, in frames per second. UIs without
+    /// one are updated as often as the display refreshes.
+    fn frame_rate(&self) -> Option<f64> {
+        None
+    }
+
+    /// The hotkeys that this UI responds to. Pressed hotkeys are passed to
+    /// `handle_hotkey` before each update.
+    fn hotkeys(&self) -> Option<&Hotkeys> {
+        None
+    }
+
+    fn handle_hotkey(&mut self, _hotkey: Hotkey, _input: &WinitInputHelper) {}
+
+    /// Tell the UI where the mouse cursor is within the frame, or `None` if
+    /// it's outside of the frame. Called before each update.
+    fn set_cursor(&mut self, _pos: Option<(usize, usize)>) {}
+
+    /// Run a single frame while paused, for the frame advance hotkey.
+    fn advance_frame(&mut self, frame: &mut [u8], input: &WinitInputHelper) -> Result<()> {
+        self.update(frame, input, Duration::ZERO)
+    }
+
+    /// Extra windows to open alongside the main one (e.g., debug viewers),
+    /// given as a title and size for each.
+    fn aux_windows(&self) -> Vec<(&'static str, (u32, u32))> {
+        Vec::new()
+    }
+
+    /// Draw the contents of the extra window with the given index. Called
+    /// after each update, for each window that's still open.
+    fn draw_aux_window(&mut self, _index: usize, _frame: &mut [u8]) {}
+
+    /// Handle a click of the left mouse button at the given position within
+    /// the frame of the extra window with the given index.
+    fn click_aux_window(&mut self, _index: usize, _pos: (usize, usize)) {}
+
+    /// Handle the mouse wheel being scrolled over the extra window with the
+    /// given index, by the given number of lines (positive for down).
+    fn scroll_aux_window(&mut self, _index: usize, _lines: i32) {}
+
+    /// Whether the UI has nothing more to show, and the window should close.
+    fn is_finished(&self) -> bool {
+        false
+    }
+
+    fn run(&mut self) -> Result<()> {
+        self.run_with(UiOptions::default())
+    }
+
+    /// Run the UI, handling the pause hotkey (for UIs that have hotkeys) by
+    /// no longer calling `update` until it's pressed again. While paused,
+    /// the frame advance hotkey runs one frame at a time.
+    ///
+    /// Updates are paced as given by the options. Hotkeys are handled as
+    /// soon as they're pressed, even while waiting for the next update.
+    ///
+    /// The UI draws into a frame of its own size, which is shown as it is or
+    /// through the CRT filter, which can be toggled with a hotkey.
+    ///
+    /// Extra windows can be closed on their own, while closing the main
+    /// window exits. Keys pressed in an extra window are handled as if they
+    /// were pressed in the main one, while clicks and scrolling are passed
+    /// to the UI for that window, which is redrawn straight away.
+    ///
+    /// Returns once the windows have closed, with the error that stopped the
+    /// UI if there was one, so that the caller can clean up afterwards.
+    fn run_with(&mut self, options: UiOptions) -> Result<()> {
         log::info!("Starting UI");
 
-        let event_loop = EventLoop::new();
+        let mut event_loop = EventLoop::new();
 
         let (width, height) = self.size();
         let logical_size = LogicalSize::new(width, height);
+        let scaled_size = LogicalSize::new(width * options.scale, height * options.scale);
+        let mut title = window_title(self.title(), options.start_paused, None);
         let window = WindowBuilder::new()
-            .with_title("NES Emulator")
-            .with_inner_size(logical_size)
+            .with_title(&title)
+            .with_inner_size(scaled_size)
             .with_min_inner_size(logical_size)
             .build(&event_loop)?;
 
+        // With timer pacing, waiting for vsync would hold up the next update.
+        let period = match options.pacing {
+            Pacing::Timer => self
+                .frame_rate()
+                .map(|rate| Duration::from_secs_f64(1.0 / rate)),
+            Pacing::Vsync => None,
+        };
         let phys_size = window.inner_size();
         let surface_texture = SurfaceTexture::new(phys_size.width, phys_size.height, &window);
-        let mut pixels = Pixels::new(width as u32, height as u32, surface_texture)?;
+        let mut crt = options.crt;
+        let buffer_scale = if crt { crt::SCALE as u32 } else { 1 };
+        let mut pixels =
+            PixelsBuilder::new(width * buffer_scale, height * buffer_scale, surface_texture)
+                .enable_vsync(period.is_none())
+                .build()?;
+        let mut frame = vec![0; width as usize * height as usize * 4];
+        let mut aux_windows = self
+            .aux_windows()
+            .into_iter()
+            .enumerate()
+            .map(|(index, (title, size))| AuxWindow::new(&event_loop, index, title, size))
+            .collect::<Result<Vec<_>>>()?;
 
         let mut input = WinitInputHelper::new();
 
         let mut time = Instant::now();
+        // When the next update is due, with timer pacing.
+        let mut next_frame = time;
+        let mut flow = ControlFlow::Poll;
+
+        // Updates since the frame rate in the title was last updated.
+        let mut updates = 0;
+        let mut title_time = time;
+        let mut fps = None;
+
+        // Whether one of the windows is focused, and whether the UI was paused
+        // because none of them were.
+        let mut focused = true;
+        let mut focus_paused = false;
+
+        // While paused, the frame from before pausing, to put back when
+        // resuming.
+        let mut paused = options
+            .start_paused
+            .then(|| pause(&mut frame, width as usize));
+        present(&frame, width, &mut pixels, crt);
 
-        event_loop.run(move |event, _, control_flow| {
+        let mut result = Ok(());
+        event_loop.run_return(|event, _, control_flow| {
             log::trace!("UI event: {:?}", &event);
 
-            *control_flow = ControlFlow::Poll;
+            *control_flow = flow;
 
-            if let Event::RedrawRequested(_) = event {
-                if let Err(e) = pixels.render() {
-                    log::error!("Exiting due to render error: {}", e);
-                    *control_flow = ControlFlow::Exit;
+            // When focus moves from one of the windows to another, the first
+            // loses focus before the second gains it, so this is only acted
+            // on once the events have all been handled.
+            if let Event::WindowEvent {
+                event: WindowEvent::Focused(is_focused),
+                ..
+            } = &event
+            {
+                focused = *is_focused;
+            }
+
+            match &event {
+                Event::RedrawRequested(id) if *id != window.id() => {
+                    if let Some(aux) = aux_windows.iter().find(|aux| aux.window.id() == *id) {
+                        if let Err(e) = aux.pixels.render() {
+                            log::error!("Failed to render {}: {}", aux.title, e);
+                        }
+                    }
                     return;
                 }
+                Event::RedrawRequested(_) => {
+                    if let Err(e) = pixels.render() {
+                        result = Err(Error::new(e).context("Failed to render frame"));
+                        *control_flow = ControlFlow::Exit;
+                        return;
+                    }
+                }
+                Event::WindowEvent {
+                    window_id,
+                    event: window_event,
+                } if *window_id != window.id() => {
+                    let Some(i) = aux_windows
+                        .iter()
+                        .position(|aux| aux.window.id() == *window_id)
+                    else {
+                        return;
+                    };
+                    match window_event {
+                        WindowEvent::CloseRequested => {
+                            aux_windows.remove(i);
+                        }
+                        WindowEvent::Resized(size) => {
+                            if let Err(e) = aux_windows[i]
+                                .pixels
+                                .resize_surface(size.width, size.height)
+                            {
+                                log::error!("Failed to resize window: {}", e);
+                            }
+                        }
+                        WindowEvent::KeyboardInput { .. } | WindowEvent::ModifiersChanged(_) => {
+                            input.update(&event);
+                        }
+                        WindowEvent::CursorMoved { position, .. } => {
+                            let pos = (position.x as f32, position.y as f32);
+                            aux_windows[i].cursor =
+                                aux_windows[i].pixels.window_pos_to_pixel(pos).ok();
+                        }
+                        WindowEvent::CursorLeft { .. } => aux_windows[i].cursor = None,
+                        WindowEvent::MouseInput {
+                            state: ElementState::Pressed,
+                            button: MouseButton::Left,
+                            ..
+                        } => {
+                            if let Some(pos) = aux_windows[i].cursor {
+                                self.click_aux_window(aux_windows[i].index, pos);
+                                aux_windows[i].redraw(self);
+                            }
+                        }
+                        WindowEvent::MouseWheel { delta, .. } => {
+                            let lines = match delta {
+                                MouseScrollDelta::LineDelta(_, y) => -y.round() as i32,
+                                MouseScrollDelta::PixelDelta(pos) => {
+                                    (-pos.y / SCROLL_LINE_PIXELS).round() as i32
+                                }
+                            };
+                            if lines != 0 {
+                                self.scroll_aux_window(aux_windows[i].index, lines);
+                                aux_windows[i].redraw(self);
+                            }
+                        }
+                        _ => {}
+                    }
+                    return;
+                }
+                _ => {}
             }
 
             if !input.update(&event) {
@@ -63,18 +320,219 @@ pub trait Ui: Sized + 'static {
                 }
             };
 
-            let now = Instant::now();
-            let dt = now.duration_since(time);
-            time = now;
+            let pressed = self
+                .hotkeys()
+                .map(|hotkeys| hotkeys.pressed(&input))
+                .unwrap_or_default();
+            let mut advance = false;
+            let mut toggle_pause = false;
+            if options.pause_on_focus_loss {
+                if !focused && paused.is_none() {
+                    toggle_pause = true;
+                    focus_paused = true;
+                } else if focused && focus_paused {
+                    toggle_pause = paused.is_some();
+                    focus_paused = false;
+                }
+            }
+            for hotkey in pressed {
+                match hotkey {
+                    Hotkey::Pause => {
+                        toggle_pause = !toggle_pause;
+                        focus_paused = false;
+                    }
+                    Hotkey::FrameAdvance if paused.is_some() => advance = true,
+                    Hotkey::ToggleCrt => {
+                        crt = !crt;
+                        let scale = if crt { crt::SCALE as u32 } else { 1 };
+                        if let Err(e) = pixels.resize_buffer(width * scale, height * scale) {
+                            log::error!("Failed to resize frame: {}", e);
+                        }
+                    }
+                    hotkey => self.handle_hotkey(hotkey, &input),
+                }
+            }
+            if toggle_pause {
+                match paused.take() {
+                    Some(original) => {
+                        log::info!("Resumed");
+                        frame.copy_from_slice(&original);
+                        time = Instant::now();
+                        next_frame = time;
+                    }
+                    None => paused = Some(pause(&mut frame, width as usize)),
+                }
+            }
+            if title_time.elapsed() >= TITLE_INTERVAL {
+                fps = Some(updates as f64 / title_time.elapsed().as_secs_f64());
+                updates = 0;
+                title_time = Instant::now();
+            }
+            let new_title = window_title(self.title(), paused.is_some(), fps);
+            if new_title != title {
+                window.set_title(&new_title);
+                title = new_title;
+            }
+
+            let scale = if crt { crt::SCALE } else { 1 };
+            let cursor = input
+                .mouse()
+                .and_then(|pos| pixels.window_pos_to_pixel(pos).ok())
+                .map(|(x, y)| (x / scale, y / scale));
+            self.set_cursor(cursor);
 
-            log::trace!("Updating frame after: {:?}", &dt);
-            if let Err(e) = self.update(pixels.frame_mut(), &input, dt) {
-                log::error!("Exiting due to emulation error: {}", e);
+            let update = match &mut paused {
+                None => {
+                    let now = Instant::now();
+                    if let Some(period) = period {
+                        if now < next_frame {
+                            flow = ControlFlow::WaitUntil(next_frame);
+                            *control_flow = flow;
+                            return;
+                        }
+                        // Updates that are late are caught up on straight
+                        // away, unless the host has stalled for a while.
+                        next_frame += period;
+                        if next_frame + period * MAX_LATE_FRAMES < now {
+                            next_frame = now;
+                        }
+                        flow = ControlFlow::WaitUntil(next_frame);
+                    } else {
+                        flow = ControlFlow::Poll;
+                    }
+                    *control_flow = flow;
+                    let dt = now.duration_since(time);
+                    time = now;
+                    log::trace!("Updating frame after: {:?}", &dt);
+                    updates += 1;
+                    self.update(&mut frame, &input, dt)
+                }
+                Some(original) if advance => {
+                    // Show the new frame without the pause overlay, so that
+                    // it can be inspected.
+                    frame.copy_from_slice(original);
+                    let result = self.advance_frame(&mut frame, &input);
+                    original.copy_from_slice(&frame);
+                    result
+                }
+                Some(_) => {
+                    // Nothing changes until the next event.
+                    flow = ControlFlow::Wait;
+                    *control_flow = flow;
+                    present(&frame, width, &mut pixels, crt);
+                    window.request_redraw();
+                    return;
+                }
+            };
+            if let Err(e) = update {
+                result = Err(e).context("Emulation error");
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+            if self.is_finished() {
+                log::info!("Exiting since emulation has finished");
                 *control_flow = ControlFlow::Exit;
                 return;
             }
 
+            present(&frame, width, &mut pixels, crt);
             window.request_redraw();
+            for aux in &mut aux_windows {
+                aux.redraw(self);
+            }
         });
+        result
+    }
+}
+
+/// An extra window opened alongside the UI's main one.
+struct AuxWindow {
+    /// The window's index in the list returned by `Ui::aux_windows`.
+    index: usize,
+    title: &'static str,
+    window: Window,
+    pixels: Pixels,
+    /// Position of the mouse cursor within the window's frame, if it's over
+    /// the frame.
+    cursor: Option<(usize, usize)>,
+}
+
+impl AuxWindow {
+    fn new(
+        event_loop: &EventLoop<()>,
+        index: usize,
+        title: &'static str,
+        (width, height): (u32, u32),
+    ) -> Result<Self> {
+        let window = WindowBuilder::new()
+            .with_title(title)
+            .with_inner_size(LogicalSize::new(width * 2, height * 2))
+            .with_min_inner_size(LogicalSize::new(width, height))
+            .build(event_loop)?;
+        let size = window.inner_size();
+        let surface_texture = SurfaceTexture::new(size.width, size.height, &window);
+        // Waiting for vsync here would hold up the main window's updates.
+        let pixels = PixelsBuilder::new(width, height, surface_texture)
+            .enable_vsync(false)
+            .build()?;
+        Ok(Self {
+            index,
+            title,
+            window,
+            pixels,
+            cursor: None,
+        })
+    }
+
+    /// Have the UI draw the window's contents, and show them.
+    fn redraw(&mut self, ui: &mut impl Ui) {
+        ui.draw_aux_window(self.index, self.pixels.frame_mut());
+        self.window.request_redraw();
+    }
+}
+
+/// The window's title, given the UI's title, whether it's paused, and the
+/// rate it's being updated at (once that's known).
+fn window_title(ui_title: Option<String>, paused: bool, fps: Option<f64>) -> String {
+    let mut parts: Vec<String> = ui_title.into_iter().collect();
+    if paused {
+        parts.push("Paused".to_string());
+    } else if let Some(fps) = fps {
+        parts.push(format!("{:.0} FPS", fps));
+    }
+    parts.push("NES Emulator".to_string());
+    parts.join(" - ")
+}
+
+/// Copy the UI's frame to the buffer that's drawn to the window, through the
+/// CRT filter if it's on.
+fn present(frame: &[u8], width: u32, pixels: &mut Pixels, crt: bool) {
+    if crt {
+        crt::apply(frame, width as usize, pixels.frame_mut());
+    } else {
+        pixels.frame_mut().copy_from_slice(frame);
+    }
+}
+
+/// Dim the frame and label it as paused, returning a copy of the frame as it
+/// was.
+fn pause(frame: &mut [u8], width: usize) -> Vec<u8> {
+    log::info!("Paused");
+    let original = frame.to_vec();
+    for pixel in frame.chunks_exact_mut(4) {
+        for c in &mut pixel[..3] {
+            *c /= 2;
+        }
     }
+    let text = "PAUSED";
+    let (label_width, label_height) = font::label_size(text);
+    let height = frame.len() / 4 / width;
+    font::draw_label(
+        frame,
+        width,
+        width.saturating_sub(label_width) / 2,
+        height.saturating_sub(label_height) / 2,
+        text,
+    );
+    original
 }
@@ -0,0 +1,60 @@
+//! Debug viewers for the console's state, which can be opened in windows of
+//! their own alongside the game. They're redrawn after every update, so they
+//! follow the game as it runs.
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::debugger::{self, Console, Debugger};
+use crate::ppu::{FRAME_HEIGHT, FRAME_WIDTH, GREYSCALE_PALETTE};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Viewer {
+    /// Both pattern tables, in greyscale.
+    Patterns,
+    /// All four nametables, as the background would be drawn from them.
+    Nametables,
+    /// The background and sprite palettes.
+    Palettes,
+    /// The sprites in OAM, with their palettes.
+    Sprites,
+    /// The CPU's registers, code and memory, with breakpoints (see the
+    /// `debugger` module).
+    Debugger,
+}
+
+impl Viewer {
+    pub fn title(self) -> &'static str {
+        match self {
+            Viewer::Patterns => "Pattern Tables",
+            Viewer::Nametables => "Nametables",
+            Viewer::Palettes => "Palettes",
+            Viewer::Sprites => "Sprites",
+            Viewer::Debugger => "Debugger",
+        }
+    }
+
+    pub fn size(self) -> (u32, u32) {
+        match self {
+            Viewer::Patterns => (256, 128),
+            Viewer::Nametables => (FRAME_WIDTH as u32 * 2, FRAME_HEIGHT as u32 * 2),
+            Viewer::Palettes => (256, 32),
+            Viewer::Sprites => (128, 128),
+            Viewer::Debugger => (debugger::VIEW_WIDTH as u32, debugger::VIEW_HEIGHT as u32),
+        }
+    }
+
+    /// Draw the view into a frame of the view's size. The debugger's window
+    /// has state of its own, which is kept by the caller.
+    pub fn render(self, console: &mut Console, debugger: &mut Debugger, frame: &mut [u8]) {
+        let ppu = console.ppu;
+        match self {
+            Viewer::Patterns => ppu.render_pattern_table(console.cart, frame, GREYSCALE_PALETTE),
+            Viewer::Nametables => ppu.render_name_tables(console.cart, frame),
+            Viewer::Palettes => ppu.render_palettes(frame),
+            Viewer::Sprites => ppu.render_sprites(console.cart, frame),
+            Viewer::Debugger => debugger.draw(frame, console),
+        }
+    }
+}
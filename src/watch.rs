@@ -0,0 +1,71 @@
+//! Watching the ROM file for changes, so that a game being developed can be
+//! rebuilt and tested without restarting the emulator.
+//!
+//! The file's modification time is polled rather than using OS notifications,
+//! which keeps this simple and works the same on every platform. Assemblers
+//! often write the ROM in several steps, so a change is only picked up once
+//! the modification time has stayed the same for a whole interval.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Result;
+
+use crate::rom::Rom;
+
+/// How often to check the ROM file's modification time.
+const INTERVAL: Duration = Duration::from_millis(500);
+
+pub struct RomWatch {
+    path: PathBuf,
+    /// Name of the ROM within a zip archive, if it's in one.
+    entry: Option<String>,
+    last_check: Instant,
+    /// Modification time of the ROM that's loaded (or failed to load).
+    loaded: Option<SystemTime>,
+    /// Modification time seen at the last check, if it differed from the
+    /// loaded ROM's.
+    pending: Option<SystemTime>,
+}
+
+impl RomWatch {
+    pub fn new(path: PathBuf, entry: Option<String>) -> Self {
+        let loaded = modified(&path);
+        Self {
+            path,
+            entry,
+            last_check: Instant::now(),
+            loaded,
+            pending: None,
+        }
+    }
+
+    /// Check whether the file has changed, returning the new ROM (or the
+    /// error loading it) if it has. A ROM that fails to load isn't retried
+    /// until the file changes again.
+    pub fn poll(&mut self) -> Option<Result<Rom>> {
+        if self.last_check.elapsed() < INTERVAL {
+            return None;
+        }
+        self.last_check = Instant::now();
+        let current = modified(&self.path);
+        if current.is_none() || current == self.loaded {
+            self.pending = None;
+            return None;
+        }
+        if current != self.pending {
+            // Wait for the file to settle before loading it.
+            self.pending = current;
+            return None;
+        }
+        self.loaded = current;
+        self.pending = None;
+        log::info!("Reloading ROM: {:?}", &self.path);
+        Some(Rom::load_entry(&self.path, self.entry.as_deref()))
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}